@@ -0,0 +1,14 @@
+extern crate sdl2;
+extern crate gl;
+extern crate game;
+
+mod window;
+
+use game::demos;
+
+// runs any of the demos in a window: `cargo run --bin demo -- deferred`.
+// the same scenes can be rendered without a window, see demos::render_offscreen
+fn main() {
+    let name = std::env::args().nth(1).unwrap_or_else(|| demos::NAMES[0].to_string());
+    window::run(&name);
+}
@@ -0,0 +1,19 @@
+#version 330 core
+
+#include "lights.glsl"
+#include "oit.glsl"
+
+in vec3 FragPos;
+in vec3 Normal;
+in vec2 TexCoords;
+
+uniform vec3 viewPos;
+uniform vec4 tint;
+
+void main()
+{
+	vec3 norm = normalize(Normal);
+	vec3 viewDir = normalize(viewPos - FragPos);
+	vec3 result = calcLights(FragPos, norm, viewDir, tint.rgb, vec3(1.0), 64.0);
+	writeTransparent(vec4(result, tint.a));
+}
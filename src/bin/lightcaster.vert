@@ -8,14 +8,16 @@ out vec2 TexCoords;
 out vec3 Normal;
 out vec3 FragPos;
 
-uniform mat4 model;
+#include "instancing.glsl"
+
 uniform mat4 view;
 uniform mat4 perspective;
 
 void main()
 {
-    FragPos = vec3(model * vec4(aPosition, 1.0));
-    Normal = mat3(transpose(inverse(model))) * normal;
+    mat4 world = modelMatrix();
+    FragPos = vec3(world * vec4(aPosition, 1.0));
+    Normal = mat3(transpose(inverse(world))) * normal;
     TexCoords = texCoords;
 
     gl_Position = perspective * view * vec4(FragPos, 1.0);
@@ -0,0 +1,10 @@
+extern crate sdl2;
+extern crate gl;
+extern crate game;
+
+mod window;
+
+// see demos/pbr.rs for the scene and its keys
+fn main() {
+    window::run("pbr");
+}
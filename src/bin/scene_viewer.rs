@@ -0,0 +1,113 @@
+extern crate sdl2;
+extern crate gl;
+extern crate game;
+extern crate env_logger;
+
+use game::demos::scene::SceneDemo;
+use game::demos::Demo;
+use game::render_gl::debug::{self, DebugSettings};
+use game::render_gl::framebuffer::Framebuffer;
+use game::render_gl::profiler::Profiler;
+use game::render_gl::scene_file::SceneDesc;
+use game::render_gl::state;
+
+// renders any scene file: `cargo run --bin scene_viewer -- scenes/spheres.json`.
+// WASD moves, F5 reloads the file after editing it, C switches between
+// occlusion queries, the CPU hi-z test and neither, F3 prints frame times
+// and how many objects were culled.
+// `scene_viewer <in> --convert <out>` rewrites a scene in the format of
+// <out>'s extension (.ron or .json) and exits without opening a window
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args: Vec<String> = std::env::args().collect();
+    let path = match args.get(1) {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("usage: scene_viewer <scene.ron|scene.json> [--convert <out>]");
+            std::process::exit(1);
+        }
+    };
+    if args.get(2).map(|a| a.as_str()) == Some("--convert") {
+        let converted = match args.get(3) {
+            Some(out) => SceneDesc::load(&path).and_then(|desc| desc.save(out)),
+            None => Err("--convert needs an output file".to_string())
+        };
+        if let Err(e) = converted {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let sdl = sdl2::init().unwrap();
+    let video_subsystem = sdl.video().unwrap();
+
+    let gl_attr = video_subsystem.gl_attr();
+    gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
+    gl_attr.set_context_version(3, 3);
+    gl_attr.set_context_flags().debug().set();
+
+    let window = video_subsystem
+        .window(&format!("Game - {}", path), 800, 600)
+        .opengl()
+        .resizable()
+        .build()
+        .unwrap();
+
+    let _gl_context = window.gl_create_context().unwrap();
+    let _gl = gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
+    debug::install(DebugSettings::default());
+
+    let load = |width: u32, height: u32| match SceneDemo::load(&path, width, height) {
+        Ok(viewer) => Some(viewer),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    };
+    let mut viewer = match load(800, 600) {
+        Some(viewer) => viewer,
+        None => std::process::exit(1)
+    };
+
+    let mut profiler = Profiler::new(true);
+    let mut event_pump = sdl.event_pump().unwrap();
+    'main: loop {
+        profiler.begin_frame();
+        for event in event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit {..} => break 'main,
+                sdl2::event::Event::Window{win_event: sdl2::event::WindowEvent::Resized(w, h), ..} => {
+                    if let Some((w, h)) = Framebuffer::window_size(w, h) {
+                        if let Err(e) = viewer.resize(w, h) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                }
+                // a broken edit keeps the scene that was loaded before
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F5), ..} => {
+                    let (width, height) = window.drawable_size();
+                    if let Some(reloaded) = load(width, height) {
+                        viewer = reloaded;
+                    }
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F3), ..} => {
+                    print!("{}", profiler.summary());
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(k), ..} => viewer.key(&k.name()),
+                _ => {},
+            }
+        }
+        profiler.begin_gpu("render");
+        if let Err(e) = viewer.render(None) {
+            eprintln!("{}", e);
+            break;
+        }
+        profiler.end();
+        viewer.report(&mut profiler);
+        debug::check("the frame");
+        profiler.scope("swap", |_| window.gl_swap_window());
+        profiler.end_frame();
+        state::end_frame();
+    }
+}
@@ -0,0 +1,146 @@
+use game::demos;
+use game::render_gl::capture::{self, CaptureFormat, Recorder, Source};
+use game::render_gl::debug::{self, DebugSettings};
+use game::render_gl::profiler::{self, Profiler};
+use game::render_gl::state::{self, StateCounters};
+use game::render_gl::framebuffer::Framebuffer;
+use std::path::Path;
+
+// F9 records frames here at a fixed 60 fps of simulated time
+const RECORD_DIR: &str = "capture";
+const RECORD_TIMESTEP: f32 = 1.0 / 60.0;
+// F4 starts a Chrome trace and writes it here when pressed again
+const TRACE_FILE: &str = "trace.json";
+
+// the window loop every binary runs: opens an 800x600 window with a GL 3.3
+// core context and hands the demo called `name` its resizes, key presses
+// and frame times. the scenes themselves live in src/demos.
+// F12 saves a screenshot, F9 starts and stops recording numbered frames,
+// F3 prints frame timings and state cache counters, F4 starts and stops a trace
+pub fn run(name: &str) {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let sdl = sdl2::init().unwrap();
+    let video_subsystem = sdl.video().unwrap();
+    let mut timer = sdl.timer().unwrap();
+
+    let gl_attr = video_subsystem.gl_attr();
+    gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
+    gl_attr.set_context_version(3, 3);
+    gl_attr.set_context_flags().debug().set();
+
+    let window = video_subsystem
+        .window(&format!("Game - {}", name), 800, 600)
+        .opengl()
+        .resizable()
+        .build()
+        .unwrap();
+
+    let _gl_context = window.gl_create_context().unwrap();
+    let _gl = gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
+    debug::install(DebugSettings::default());
+
+    let mut demo = match demos::create(name, 800, 600) {
+        Ok(demo) => demo,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut recorder: Option<Recorder> = None;
+    let mut screenshot = false;
+    let mut profiler = Profiler::new(true);
+    let mut state_calls = StateCounters::default();
+    let mut last_time = timer.ticks();
+    let mut event_pump = sdl.event_pump().unwrap();
+    'main: loop {
+        profiler.begin_frame();
+        profiler.begin("events");
+        for event in event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit {..} => break 'main,
+                sdl2::event::Event::Window{win_event: sdl2::event::WindowEvent::Resized(w, h), ..} => {
+                    if let Some((w, h)) = Framebuffer::window_size(w, h) {
+                        if let Err(e) = demo.resize(w, h) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F12), ..} => screenshot = true,
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F9), ..} => {
+                    recorder = match recorder.take() {
+                        Some(mut r) => {
+                            match r.finish() {
+                                Ok(frames) => println!("recorded {} frames into {}", frames, RECORD_DIR),
+                                Err(e) => eprintln!("recording failed: {}", e)
+                            }
+                            None
+                        }
+                        None => match Recorder::new(Path::new(RECORD_DIR), RECORD_TIMESTEP, CaptureFormat::Png) {
+                            Ok(r) => Some(r),
+                            Err(e) => {
+                                eprintln!("can't record: {}", e);
+                                None
+                            }
+                        }
+                    };
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F3), ..} => {
+                    print!("{}{}", profiler.summary(), profiler.histogram().to_text(40));
+                    println!("gl state calls last frame: {} issued, {} skipped", state_calls.issued, state_calls.skipped);
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::F4), ..} => {
+                    if profiler.is_tracing() {
+                        let written = profiler::chrome_trace_json(&profiler.stop_trace())
+                            .and_then(|json| std::fs::write(TRACE_FILE, json).map_err(|e| e.to_string()));
+                        match written {
+                            Ok(()) => println!("wrote {}", TRACE_FILE),
+                            Err(e) => eprintln!("trace failed: {}", e)
+                        }
+                    } else {
+                        profiler.start_trace();
+                    }
+                }
+                sdl2::event::Event::KeyDown{keycode: Some(k), ..} => demo.key(&k.name()),
+                _ => {},
+            }
+        }
+        profiler.end();
+        let now = timer.ticks();
+        // while recording the scene advances by the fixed step, however long
+        // the frame really took
+        let dt = match recorder {
+            Some(ref r) => r.timestep,
+            None => (now - last_time) as f32 / 1000.0
+        };
+        profiler.scope("update", |_| demo.update(dt));
+        last_time = now;
+        profiler.begin_gpu("render");
+        demo.render(None).unwrap();
+        profiler.end();
+        demo.report(&mut profiler);
+
+        // the back buffer has to be read before swapping
+        profiler.begin("capture");
+        let (width, height) = window.drawable_size();
+        let source = Source::Window { width: width, height: height };
+        if screenshot {
+            screenshot = false;
+            let path = format!("screenshot_{}_{}.png", demo.name(), now);
+            match capture::screenshot(source, Path::new(&path)) {
+                Ok(()) => println!("saved {}", path),
+                Err(e) => eprintln!("screenshot failed: {}", e)
+            }
+        }
+        if let Some(ref mut r) = recorder {
+            if let Err(e) = r.capture(source) {
+                eprintln!("recording failed: {}", e);
+            }
+        }
+        profiler.end();
+        debug::check("the frame");
+        profiler.scope("swap", |_| window.gl_swap_window());
+        profiler.end_frame();
+        state_calls = state::end_frame();
+    }
+}
@@ -0,0 +1,139 @@
+use crate::glm::*;
+use crate::demos::{begin_target, generate_pixels, move_camera, pixel_texture, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::state;
+use crate::render_gl::{load_cube_vertices, Program, Shader};
+use gl::types::*;
+use std::ffi::CString;
+
+const TEXTURE_SIZE: u32 = 64;
+
+// the first scene: three spinning cubes with a brick texture blended
+// with a smiley face
+pub struct CubeDemo {
+    program: Program,
+    cube: Mesh,
+    positions: Vec<Vec3>,
+    camera: Camera,
+    time: f32,
+    // degrees a second
+    pub spin: f32,
+    wall: GLuint,
+    smiley: GLuint,
+    width: u32,
+    height: u32
+}
+
+impl CubeDemo {
+    pub fn new(width: u32, height: u32) -> Result<CubeDemo, String> {
+        let program = Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("../bin/triangle.vert")).unwrap())?,
+            Shader::from_frag_source(&CString::new(include_str!("../bin/triangle.frag")).unwrap())?
+        ])?;
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 8.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        Ok(CubeDemo {
+            program: program,
+            cube: Mesh::new(&load_cube_vertices(), &[3, 2], None),
+            positions: vec![
+                make_vec3(&[0.0, 0.0, 0.0]),
+                make_vec3(&[2.0, 5.0, -10.0]),
+                make_vec3(&[-1.5, -2.2, -2.5])
+            ],
+            camera: camera,
+            time: 0.0,
+            spin: 20.0,
+            wall: pixel_texture(TEXTURE_SIZE, TEXTURE_SIZE, &generate_pixels(TEXTURE_SIZE, &brick)),
+            smiley: pixel_texture(TEXTURE_SIZE, TEXTURE_SIZE, &generate_pixels(TEXTURE_SIZE, &smiley)),
+            width: width,
+            height: height
+        })
+    }
+}
+
+impl Demo for CubeDemo {
+    fn name(&self) -> &'static str {
+        "cube"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.time += dt;
+    }
+
+    fn key(&mut self, key: &str) {
+        move_camera(&mut self.camera, key);
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        begin_target(target, self.width, self.height);
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.2, 0.4, 1.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            state::active_texture(gl::TEXTURE0);
+            state::bind_texture(gl::TEXTURE_2D, self.wall);
+            state::active_texture(gl::TEXTURE1);
+            state::bind_texture(gl::TEXTURE_2D, self.smiley);
+        }
+        let p = &self.program;
+        p.set_used();
+        p.set_uniform_1i("texture1", 0);
+        p.set_uniform_1i("texture2", 1);
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        for (i, position) in self.positions.iter().enumerate() {
+            let angle = (self.spin * self.time + 30.0 * i as f32).to_radians();
+            let model = rotate(&translate(&Mat4::identity(), position), angle, &make_vec3(&[1.0, 0.3, 0.5]));
+            p.set_uniform_mat4("model", &model);
+            self.cube.draw();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CubeDemo {
+    fn drop(&mut self) {
+        state::delete_texture(self.wall);
+        state::delete_texture(self.smiley);
+    }
+}
+
+// red bricks in grey mortar, four rows of two
+fn brick(u: f32, v: f32) -> [u8; 4] {
+    let row = (v * 4.0).floor();
+    let x = u * 2.0 + if row as i32 % 2 == 0 { 0.0 } else { 0.5 };
+    let (bu, bv) = (x.fract(), (v * 4.0).fract());
+    if bu < 0.05 || bv < 0.1 {
+        [170, 165, 160, 255]
+    } else {
+        let shade = 0.85 + 0.15 * ((row * 7.0 + x.floor() * 3.0) % 4.0) / 4.0;
+        [(180.0 * shade) as u8, (70.0 * shade) as u8, (50.0 * shade) as u8, 255]
+    }
+}
+
+// a yellow face on a transparent background
+fn smiley(u: f32, v: f32) -> [u8; 4] {
+    let (x, y) = (u * 2.0 - 1.0, v * 2.0 - 1.0);
+    let r = (x * x + y * y).sqrt();
+    if r > 0.9 {
+        return [0, 0, 0, 0];
+    }
+    let eye = |ex: f32| ((x - ex) * (x - ex) + (y - 0.3) * (y - 0.3)).sqrt() < 0.12;
+    let mouth = y < -0.1 && (r - 0.55).abs() < 0.07;
+    if r > 0.84 || eye(-0.3) || eye(0.3) || mouth {
+        [40, 30, 0, 255]
+    } else {
+        [255, 220, 40, 255]
+    }
+}
@@ -0,0 +1,190 @@
+use crate::glm::*;
+use crate::demos::{lit_program, move_camera, solid_texture, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::debug::DebugGroup;
+use crate::render_gl::deferred::{self, DeferredRenderer};
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::light::{DirectionalLight, LightSet, PointLight};
+use crate::render_gl::material::BlendMode;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::ssao::{Rng, Ssao, SsaoSettings};
+use crate::render_gl::Program;
+use crate::render_gl::state;
+use crate::render_gl::transparency::{self, TransparentQueue, WeightedBlended};
+use gl::types::*;
+
+const LIGHTS: usize = 64;
+const GRID: i32 = 5;
+
+// a field of cubes lit by a dim sun and dozens of coloured point lights
+// circling above them, shaded deferred with ambient occlusion, and three
+// intersecting glass panes in the middle. M toggles ambient occlusion, O
+// switches the glass between weighted blended and sorted transparency
+pub struct DeferredDemo {
+    renderer: DeferredRenderer,
+    program: Program,
+    ssao: Ssao,
+    pub use_ssao: bool,
+    glass_program: Program,
+    oit: WeightedBlended,
+    pub order_independent: bool,
+    // rotation about y (degrees) and tint of each pane
+    panes: Vec<(f32, Vec4)>,
+    cube: Mesh,
+    models: Vec<Mat4>,
+    lights: LightSet,
+    // orbit radius, height and angular speed (radians a second) of each light
+    orbits: Vec<(f32, f32, f32)>,
+    time: f32,
+    camera: Camera,
+    diffuse: GLuint,
+    specular: GLuint
+}
+
+impl DeferredDemo {
+    pub fn new(width: u32, height: u32) -> Result<DeferredDemo, String> {
+        let mut models = vec![scale(&translate(&Mat4::identity(), &make_vec3(&[0.0, -0.6, 0.0])),
+                                    &make_vec3(&[20.0, 0.2, 20.0]))];
+        for x in -GRID / 2..=GRID / 2 {
+            for z in -GRID / 2..=GRID / 2 {
+                let position = make_vec3(&[x as f32 * 3.0, 0.0, z as f32 * 3.0]);
+                models.push(rotate(&translate(&Mat4::identity(), &position),
+                                   (x * 17 + z * 31) as f32, &make_vec3(&[0.0, 1.0, 0.0])));
+            }
+        }
+
+        let mut lights = LightSet::new();
+        lights.directional.push(DirectionalLight::new(&make_vec3(&[-0.3, -1.0, -0.4]), &make_vec3(&[0.15, 0.15, 0.2])));
+        let mut rng = Rng::new(7);
+        let mut orbits = Vec::with_capacity(LIGHTS);
+        for _ in 0..LIGHTS {
+            let color = make_vec3(&[rng.next_f32(), rng.next_f32(), rng.next_f32()]) * 0.8 + make_vec3(&[0.2, 0.2, 0.2]);
+            lights.point.push(PointLight::new(&make_vec3(&[0.0, 0.0, 0.0]), &color, 6.0));
+            orbits.push((1.0 + rng.next_f32() * 8.0, 0.5 + rng.next_f32() * 1.5, (rng.next_f32() - 0.5) * 2.0));
+        }
+
+        let mut camera = Camera::new(&make_vec3(&[0.0, 6.0, 14.0]), &normalize(&make_vec3(&[0.0, -6.0, -14.0])),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        let mut demo = DeferredDemo {
+            renderer: DeferredRenderer::new(width, height)?,
+            program: deferred::geometry_program()?,
+            ssao: Ssao::new(width, height, SsaoSettings::default())?,
+            use_ssao: true,
+            glass_program: lit_program(include_str!("../bin/glass.frag"))?,
+            oit: WeightedBlended::new(width, height)?,
+            order_independent: true,
+            panes: vec![
+                (0.0, make_vec4(&[0.2, 0.8, 1.0, 0.4])),
+                (60.0, make_vec4(&[1.0, 0.3, 0.8, 0.4])),
+                (120.0, make_vec4(&[1.0, 0.9, 0.2, 0.4]))
+            ],
+            cube: Mesh::cube(),
+            models: models,
+            lights: lights,
+            orbits: orbits,
+            time: 0.0,
+            camera: camera,
+            diffuse: solid_texture([200, 190, 170, 255]),
+            specular: solid_texture([128, 128, 128, 255])
+        };
+        demo.place_lights();
+        Ok(demo)
+    }
+
+    fn place_lights(&mut self) {
+        for (light, &(radius, height, speed)) in self.lights.point.iter_mut().zip(self.orbits.iter()) {
+            let angle = radius * 1.7 + self.time * speed;
+            light.position = make_vec3(&[radius * angle.cos(), height, radius * angle.sin()]);
+        }
+    }
+}
+
+impl Demo for DeferredDemo {
+    fn name(&self) -> &'static str {
+        "deferred"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.renderer.resize(width, height)?;
+        self.ssao.resize(width, height)?;
+        self.oit.resize(width, height)?;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.time += dt;
+        self.place_lights();
+    }
+
+    fn key(&mut self, key: &str) {
+        if move_camera(&mut self.camera, key) {
+            return;
+        }
+        match key {
+            "M" => self.use_ssao = !self.use_ssao,
+            "O" => self.order_independent = !self.order_independent,
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        let geometry = DebugGroup::new("geometry");
+        self.renderer.begin_geometry();
+        let p = &self.program;
+        p.set_used();
+        state::active_texture(gl::TEXTURE0);
+        state::bind_texture(gl::TEXTURE_2D, self.diffuse);
+        state::active_texture(gl::TEXTURE1);
+        state::bind_texture(gl::TEXTURE_2D, self.specular);
+        p.set_uniform_1i("material.diffuse", 0);
+        p.set_uniform_1i("material.specular", 1);
+        p.set_uniform_1f("material.shininess", 32.0);
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        for model in &self.models {
+            p.set_uniform_mat4("model", model);
+            self.cube.draw();
+        }
+        drop(geometry);
+
+        if self.use_ssao {
+            self.ssao.render(self.renderer.gbuffer(), &self.camera);
+        }
+        let occlusion = if self.use_ssao { Some(&self.ssao) } else { None };
+        self.renderer.light(&self.camera, &self.lights, None, occlusion, target);
+
+        // the panes all go through the centre, so no order is right for them
+        let _glass = DebugGroup::new("glass");
+        let center = make_vec3(&[0.0, 1.2, 0.0]);
+        let mut queue = TransparentQueue::new();
+        for pane in &self.panes {
+            queue.push(&center, BlendMode::WeightedBlended, pane);
+        }
+        let p = &self.glass_program;
+        p.set_used();
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        self.lights.apply(p);
+        let cube = &self.cube;
+        let oit = if self.order_independent { Some(&self.oit) } else { None };
+        queue.draw(&self.camera.position(), oit, target, &mut |(angle, tint), mode| {
+            p.set_used();
+            transparency::set_blend_mode(p, mode);
+            let model = rotate(&translate(&Mat4::identity(), &center), angle.to_radians(), &make_vec3(&[0.0, 1.0, 0.0]));
+            p.set_uniform_mat4("model", &scale(&model, &make_vec3(&[2.4, 1.6, 0.05])));
+            p.set_uniform_vec4("tint", tint);
+            cube.draw();
+        });
+        Ok(())
+    }
+}
+
+impl Drop for DeferredDemo {
+    fn drop(&mut self) {
+        state::delete_texture(self.diffuse);
+        state::delete_texture(self.specular);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::glm::*;
+use crate::demos::{begin_target, move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::{Program, Shader};
+use std::ffi::CString;
+
+// the first lit scene: one coral cube with ambient and diffuse light from
+// a single white light
+pub struct DiffuseDemo {
+    program: Program,
+    cube: Mesh,
+    camera: Camera,
+    light_position: Vec3,
+    width: u32,
+    height: u32
+}
+
+impl DiffuseDemo {
+    pub fn new(width: u32, height: u32) -> Result<DiffuseDemo, String> {
+        let program = Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("../bin/diffuse.vert")).unwrap())?,
+            Shader::from_frag_source(&CString::new(include_str!("../bin/diffuse.frag")).unwrap())?
+        ])?;
+        let mut camera = Camera::new(&make_vec3(&[0.0, 1.0, 5.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        Ok(DiffuseDemo {
+            program: program,
+            cube: Mesh::cube(),
+            camera: camera,
+            light_position: make_vec3(&[2.0, 5.0, 2.0]),
+            width: width,
+            height: height
+        })
+    }
+}
+
+impl Demo for DiffuseDemo {
+    fn name(&self) -> &'static str {
+        "diffuse"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str) {
+        move_camera(&mut self.camera, key);
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        begin_target(target, self.width, self.height);
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        let p = &self.program;
+        p.set_used();
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        p.set_uniform_mat4("model", &rotate(&Mat4::identity(), 45f32.to_radians(), &make_vec3(&[0.0, 1.0, 0.0])));
+        p.set_uniform_vec3("lightPos", &self.light_position);
+        p.set_uniform_vec3("lightColor", &make_vec3(&[1.0, 1.0, 1.0]));
+        p.set_uniform_vec3("objectColor", &make_vec3(&[1.0, 0.5, 0.31]));
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        self.cube.draw();
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use crate::demos;
+pub use crate::render_gl::capture::Image;
+use std::path::{Path, PathBuf};
+
+// set to 1 to write the rendered images as the new references
+pub const BLESS_VAR: &str = "GAME_BLESS";
+
+// how far a render may drift from its reference, e.g between GPU drivers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tolerance {
+    // a pixel differs when any channel is off by more than this
+    pub channel_threshold: u8,
+    // percentage of pixels that may differ
+    pub max_differing: f32,
+    // structural similarity (0..1) the images must reach, if checked at all
+    pub min_ssim: Option<f32>
+}
+
+impl Default for Tolerance {
+    fn default() -> Tolerance {
+        Tolerance {
+            channel_threshold: 8,
+            max_differing: 0.5,
+            min_ssim: Some(0.98)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub differing: usize,
+    // percentage of differing pixels
+    pub differing_percent: f32,
+    pub max_channel_delta: u8,
+    pub ssim: Option<f32>,
+    // differing pixels in red over a faded copy of the reference
+    pub diff: Image
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.differing_percent <= tolerance.max_differing
+            && match (tolerance.min_ssim, self.ssim) {
+                (Some(min), Some(ssim)) => ssim >= min,
+                _ => true
+            }
+    }
+}
+
+pub fn compare(expected: &Image, actual: &Image, tolerance: &Tolerance) -> Result<Comparison, String> {
+    if expected.width != actual.width || expected.height != actual.height {
+        return Err(format!("size mismatch: expected {}x{}, got {}x{}",
+                           expected.width, expected.height, actual.width, actual.height));
+    }
+    let mut differing = 0;
+    let mut max_channel_delta = 0;
+    let mut diff = Vec::with_capacity(expected.pixels.len());
+    for (e, a) in expected.pixels.chunks(4).zip(actual.pixels.chunks(4)) {
+        let delta = e.iter().zip(a.iter()).map(|(x, y)| x.max(y) - x.min(y)).max().unwrap();
+        max_channel_delta = max_channel_delta.max(delta);
+        if delta > tolerance.channel_threshold {
+            differing += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            let grey = (luma(e) * 0.3) as u8;
+            diff.extend_from_slice(&[grey, grey, grey, 255]);
+        }
+    }
+    let total = (expected.width * expected.height).max(1) as f32;
+    let ssim = tolerance.min_ssim.map(|_| ssim(expected, actual));
+    Ok(Comparison {
+        differing: differing,
+        differing_percent: differing as f32 * 100.0 / total,
+        max_channel_delta: max_channel_delta,
+        ssim: ssim,
+        diff: Image::new(expected.width, expected.height, diff)
+    })
+}
+
+fn luma(p: &[u8]) -> f32 {
+    0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+}
+
+const SSIM_WINDOW: u32 = 8;
+
+// mean structural similarity of the luma over 8x8 windows, half overlapping.
+// Source: Wang et al. 2004, "Image quality assessment: from error visibility to structural similarity"
+pub fn ssim(a: &Image, b: &Image) -> f32 {
+    let c1 = (0.01f32 * 255.0).powi(2);
+    let c2 = (0.03f32 * 255.0).powi(2);
+    let window = SSIM_WINDOW.min(a.width).min(a.height);
+    if window == 0 {
+        return 1.0;
+    }
+    let step = (window / 2).max(1);
+    let mut total = 0.0;
+    let mut count = 0;
+    let mut y = 0;
+    while y + window <= a.height {
+        let mut x = 0;
+        while x + window <= a.width {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for wy in y..y + window {
+                for wx in x..x + window {
+                    let la = luma(a.pixel(wx, wy));
+                    let lb = luma(b.pixel(wx, wy));
+                    sa += la;
+                    sb += lb;
+                    saa += la * la;
+                    sbb += lb * lb;
+                    sab += la * lb;
+                }
+            }
+            let n = (window * window) as f32;
+            let (ma, mb) = (sa / n, sb / n);
+            let va = saa / n - ma * ma;
+            let vb = sbb / n - mb * mb;
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + c1) * (2.0 * cov + c2)) / ((ma * ma + mb * mb + c1) * (va + vb + c2));
+            count += 1;
+            x += step;
+        }
+        y += step;
+    }
+    total / count as f32
+}
+
+// renders a demo headless at a fixed size without advancing it, i.e from
+// its starting camera, and returns the image
+pub fn render(name: &str, width: u32, height: u32) -> Result<Image, String> {
+    let mut demo = demos::create(name, width, height)?;
+    let pixels = demos::render_offscreen(demo.as_mut(), width, height, 0, 0.0)?;
+    Ok(Image::from_gl(width, height, &pixels))
+}
+
+// compares `actual` with `<references>/<name>.png`. on failure the render and
+// a diff image are written to `<failures>/<name>.actual.png` and
+// `<name>.diff.png`. with GAME_BLESS=1 the reference is replaced instead
+pub fn check(name: &str, actual: &Image, references: &Path, failures: &Path, tolerance: &Tolerance) -> Result<(), String> {
+    let reference = references.join(format!("{}.png", name));
+    if blessing() {
+        return actual.save(&reference);
+    }
+    if !reference.exists() {
+        return Err(format!("{} has no reference image, run with {}=1 to create it", reference.display(), BLESS_VAR));
+    }
+    let expected = Image::load(&reference)?;
+    let comparison = compare(&expected, actual, tolerance)?;
+    if comparison.passes(tolerance) {
+        return Ok(());
+    }
+    let actual_path = failure_path(failures, name, "actual");
+    let diff_path = failure_path(failures, name, "diff");
+    actual.save(&actual_path)?;
+    comparison.diff.save(&diff_path)?;
+    Err(format!("{} differs from {}: {} pixels ({:.2}%) over the threshold, max channel delta {}, ssim {}. see {} and {}",
+                name, reference.display(), comparison.differing, comparison.differing_percent,
+                comparison.max_channel_delta,
+                comparison.ssim.map(|s| format!("{:.4}", s)).unwrap_or_else(|| "not checked".to_string()),
+                actual_path.display(), diff_path.display()))
+}
+
+fn failure_path(failures: &Path, name: &str, kind: &str) -> PathBuf {
+    failures.join(format!("{}.{}.png", name, kind))
+}
+
+fn blessing() -> bool {
+    std::env::var(BLESS_VAR).map(|v| v == "1" || v == "true").unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> Image {
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&[(x * 16) as u8, (y * 16) as u8, 128, 255]);
+            }
+        }
+        Image::new(width, height, pixels)
+    }
+
+    #[test]
+    fn identical_images_pass() {
+        let image = gradient(16, 16);
+        let comparison = compare(&image, &image, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.differing, 0);
+        assert!((comparison.ssim.unwrap() - 1.0).abs() < 1e-4);
+        assert!(comparison.passes(&Tolerance::default()));
+    }
+
+    #[test]
+    fn small_drift_stays_under_the_threshold() {
+        let expected = gradient(16, 16);
+        let mut actual = expected.clone();
+        for p in actual.pixels.chunks_mut(4) {
+            p[0] = p[0].saturating_add(3);
+        }
+        let comparison = compare(&expected, &actual, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.differing, 0);
+        assert_eq!(comparison.max_channel_delta, 3);
+        assert!(comparison.passes(&Tolerance::default()));
+    }
+
+    #[test]
+    fn differing_pixels_are_counted_and_marked() {
+        let expected = gradient(10, 10);
+        let mut actual = expected.clone();
+        actual.pixels[0] = 255;
+        actual.pixels[1] = 255;
+        let tolerance = Tolerance { channel_threshold: 8, max_differing: 0.5, min_ssim: None };
+        let comparison = compare(&expected, &actual, &tolerance).unwrap();
+        assert_eq!(comparison.differing, 1);
+        assert!((comparison.differing_percent - 1.0).abs() < 1e-6);
+        assert_eq!(comparison.diff.pixel(0, 0), &[255, 0, 0, 255]);
+        assert!(comparison.ssim.is_none());
+        assert!(!comparison.passes(&tolerance));
+        assert!(comparison.passes(&Tolerance { max_differing: 1.0, ..tolerance }));
+    }
+
+    #[test]
+    fn ssim_drops_for_structural_changes() {
+        let expected = gradient(16, 16);
+        let mut noisy = expected.clone();
+        for (i, p) in noisy.pixels.chunks_mut(4).enumerate() {
+            if i % 2 == 0 {
+                p[1] = 255 - p[1];
+            }
+        }
+        assert!(ssim(&expected, &noisy) < 0.9);
+        assert!(compare(&expected, &Image::new(1, 1, vec![0; 4]), &Tolerance::default()).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use crate::glm::*;
+use crate::demos::{begin_target, crate_textures, lit_program, move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::camera_path::{CameraPlayer, CameraTrack, Interpolation};
+use crate::render_gl::debug::DebugGroup;
+use crate::render_gl::deferred::{self, DeferredRenderer};
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::instancing::{self, InstanceBatcher, InstanceBuffer, InstanceData};
+use crate::render_gl::light::{DirectionalLight, LightSet, PointLight, SpotLight};
+use crate::render_gl::material::BlendMode;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::scene::{Scene, Transform};
+use crate::render_gl::shadow::{ShadowRenderer, ShadowSettings};
+use crate::render_gl::ssao::{self, Rng, Ssao, SsaoSettings};
+use crate::render_gl::state;
+use crate::render_gl::transparency::{self, TransparentQueue};
+use crate::render_gl::{Program, Shader};
+use gl::types::*;
+use std::ffi::CString;
+use std::rc::Rc;
+
+const SWARM: usize = 256;
+const CAMERA_PATH_FILE: &str = "camera_path.json";
+
+// crates above a floor kept in a scene graph, a shadow casting sun, a
+// flashlight held at the camera, four coloured lamps, a swarm of small
+// lights between the crates and three tinted glass panes drawn back to
+// front. the forward shaders only see the first MAX_POINT_LIGHTS lights,
+// the deferred path lights them all.
+// R switches between forward and deferred shading, M toggles ambient
+// occlusion, [ and ] change its radius, - and = its sample count.
+// K records a camera keyframe, O saves the path and P loads and replays it
+pub struct LightcasterDemo {
+    program: Program,
+    gbuffer_program: Program,
+    lamp_program: Program,
+    glass_program: Program,
+    renderer: DeferredRenderer,
+    ssao: Ssao,
+    pub use_deferred: bool,
+    pub use_ssao: bool,
+    cube: Rc<Mesh>,
+    scene: Scene,
+    lamps: usize,
+    panes: Vec<(Vec3, Vec4)>,
+    lights: LightSet,
+    shadows: ShadowRenderer,
+    instances: InstanceBuffer,
+    camera: Camera,
+    track: CameraTrack,
+    record_start: Option<f32>,
+    player: Option<CameraPlayer>,
+    time: f32,
+    diffuse: GLuint,
+    specular: GLuint,
+    width: u32,
+    height: u32
+}
+
+impl LightcasterDemo {
+    pub fn new(width: u32, height: u32) -> Result<LightcasterDemo, String> {
+        let lamp_program = Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("../bin/lamp.vert")).unwrap())?,
+            Shader::from_frag_source(&CString::new(include_str!("../bin/lamp.frag")).unwrap())?
+        ])?;
+
+        let positions = [
+            [0.0, 0.0, 0.0], [2.0, 5.0, -15.0], [-1.5, -2.2, -2.5], [-3.8, -2.0, -12.3], [2.4, -0.4, -3.5],
+            [-1.7, 3.0, -7.5], [1.3, -2.0, -2.5], [1.5, 2.0, -2.5], [1.5, 0.2, -1.5], [-1.3, 1.0, -1.5]
+        ];
+        let cube = Rc::new(Mesh::cube());
+        let mut scene = Scene::new();
+        let crates = scene.add("crates", Transform::identity());
+        for (i, p) in positions.iter().enumerate() {
+            let node = scene.add_child(crates, &format!("crate {}", i), Transform::from_translation(&make_vec3(p)));
+            scene.node_mut(node).mesh = Some(cube.clone());
+        }
+        // a flattened cube under the scene so there is something to cast onto
+        let floor = scene.add("floor", Transform::from_translation(&make_vec3(&[0.0, -4.0, -6.0]))
+            .scaled(&make_vec3(&[30.0, 0.2, 30.0])));
+        scene.node_mut(floor).mesh = Some(cube.clone());
+
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 7.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+
+        let mut lights = LightSet::new();
+        let mut flashlight = SpotLight::new(&camera.position(), &camera.front(), &make_vec3(&[1.0, 1.0, 1.0]), 50.0);
+        flashlight.cast_shadows = true;
+        lights.spot.push(flashlight);
+        let mut sun = DirectionalLight::new(&make_vec3(&[-0.2, -1.0, -0.3]), &make_vec3(&[0.4, 0.4, 0.35]));
+        sun.cast_shadows = true;
+        lights.directional.push(sun);
+        let lamps = [
+            ([0.7, 0.2, 2.0], [1.0, 0.6, 0.2]),
+            ([2.3, -3.3, -4.0], [0.2, 0.4, 1.0]),
+            ([-4.0, 2.0, -12.0], [0.2, 1.0, 0.3]),
+            ([0.0, 0.0, -3.0], [1.0, 0.2, 0.2])
+        ];
+        for (position, color) in lamps.iter() {
+            lights.point.push(PointLight::new(&make_vec3(position), &make_vec3(color), 13.0));
+        }
+        lights.point[3].cast_shadows = true;
+        let mut rng = Rng::new(12345);
+        for _ in 0..SWARM {
+            let position = make_vec3(&[rng.next_f32() * 12.0 - 6.0, rng.next_f32() * 8.0 - 4.0, rng.next_f32() * -16.0 + 1.0]);
+            let color = make_vec3(&[rng.next_f32(), rng.next_f32(), rng.next_f32()]);
+            lights.point.push(PointLight::new(&position, &color, 7.0));
+        }
+
+        let (diffuse, specular) = crate_textures();
+        Ok(LightcasterDemo {
+            program: lit_program(include_str!("../bin/lightcaster.frag"))?,
+            gbuffer_program: deferred::geometry_program()?,
+            lamp_program: lamp_program,
+            glass_program: lit_program(include_str!("../bin/glass.frag"))?,
+            renderer: DeferredRenderer::new(width, height)?,
+            ssao: Ssao::new(width, height, SsaoSettings::default())?,
+            use_deferred: false,
+            use_ssao: true,
+            cube: cube,
+            scene: scene,
+            lamps: lamps.len(),
+            panes: vec![
+                (make_vec3(&[0.0, 0.0, 1.2]), make_vec4(&[0.2, 0.6, 1.0, 0.35])),
+                (make_vec3(&[-1.5, -1.0, -1.0]), make_vec4(&[1.0, 0.3, 0.3, 0.5])),
+                (make_vec3(&[1.8, 0.5, -1.0]), make_vec4(&[0.3, 1.0, 0.4, 0.4]))
+            ],
+            lights: lights,
+            shadows: ShadowRenderer::new(ShadowSettings::default())?,
+            instances: InstanceBuffer::new(),
+            camera: camera,
+            track: CameraTrack::new(Interpolation::CatmullRom),
+            record_start: None,
+            player: None,
+            time: 0.0,
+            diffuse: diffuse,
+            specular: specular,
+            width: width,
+            height: height
+        })
+    }
+
+    fn bind_textures(&self, program: &Program) {
+        state::active_texture(gl::TEXTURE0);
+        state::bind_texture(gl::TEXTURE_2D, self.diffuse);
+        state::active_texture(gl::TEXTURE1);
+        state::bind_texture(gl::TEXTURE_2D, self.specular);
+        program.set_uniform_1i("material.diffuse", 0);
+        program.set_uniform_1i("material.specular", 1);
+        program.set_uniform_1f("material.shininess", 32.0);
+    }
+
+    fn replay_path(&mut self) {
+        match CameraTrack::load(CAMERA_PATH_FILE) {
+            Ok(track) => {
+                let mut player = CameraPlayer::new(track);
+                player.play();
+                self.player = Some(player);
+            }
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+}
+
+impl Demo for LightcasterDemo {
+    fn name(&self) -> &'static str {
+        "lightcaster"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.renderer.resize(width, height)?;
+        self.ssao.resize(width, height)?;
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.time += dt;
+        if let Some(ref mut player) = self.player {
+            player.advance(dt, &mut self.camera);
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if move_camera(&mut self.camera, key) {
+            return;
+        }
+        let settings = &mut self.ssao.settings;
+        match key {
+            "R" => self.use_deferred = !self.use_deferred,
+            "M" => self.use_ssao = !self.use_ssao,
+            "[" => settings.radius *= 0.8,
+            "]" => settings.radius *= 1.25,
+            "-" => settings.samples = settings.samples.saturating_sub(8).max(8),
+            "=" => settings.samples = (settings.samples + 8).min(ssao::MAX_KERNEL_SIZE),
+            "K" => {
+                let start = *self.record_start.get_or_insert(self.time);
+                self.track.record(self.time - start, &self.camera);
+            }
+            "O" => {
+                if let Err(e) = self.track.save(CAMERA_PATH_FILE) {
+                    eprintln!("{}", e);
+                }
+            }
+            "P" => self.replay_path(),
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        // the flashlight follows the camera
+        {
+            let flashlight = &mut self.lights.spot[0];
+            flashlight.position = self.camera.position();
+            flashlight.direction = self.camera.front();
+        }
+        // the crates all share the cube and the program, so they end up in
+        // one instanced draw per pass
+        let mut batcher = InstanceBatcher::new();
+        let program = &self.program;
+        self.scene.traverse(&mut |_, node, world| {
+            if let Some(mesh) = &node.mesh {
+                batcher.push(mesh, program, InstanceData::new(world));
+            }
+        });
+        batcher.upload(&mut self.instances);
+        let instances = &self.instances;
+        self.shadows.render(&self.camera, &self.lights, &mut |depth_program| {
+            batcher.draw(instances, &mut |_| instancing::set_instanced(depth_program, true));
+        })?;
+
+        let opaque = DebugGroup::new("opaque");
+        let view = self.camera.look_at();
+        let projection = self.camera.projection();
+
+        // the forward path needs the G-buffer too when SSAO is on
+        if self.use_deferred || self.use_ssao {
+            self.renderer.begin_geometry();
+            let p = &self.gbuffer_program;
+            p.set_used();
+            self.bind_textures(p);
+            p.set_uniform_mat4("view", &view);
+            p.set_uniform_mat4("perspective", &projection);
+            batcher.draw(instances, &mut |_| instancing::set_instanced(p, true));
+        }
+        if self.use_ssao {
+            self.ssao.render(self.renderer.gbuffer(), &self.camera);
+        }
+        let occlusion = if self.use_ssao { Some(&self.ssao) } else { None };
+        if self.use_deferred {
+            self.renderer.light(&self.camera, &self.lights, Some(&self.shadows), occlusion, target);
+        } else {
+            begin_target(target, self.width, self.height);
+            PipelineState::opaque().apply();
+            unsafe {
+                gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            }
+            let p = &self.program;
+            p.set_used();
+            self.bind_textures(p);
+            p.set_uniform_vec3("viewPos", &self.camera.position());
+            p.set_uniform_mat4("view", &view);
+            p.set_uniform_mat4("perspective", &projection);
+            self.lights.apply(p);
+            self.shadows.apply(p);
+            ssao::apply_ssao(p, occlusion);
+            batcher.draw(instances, &mut |program| {
+                program.set_used();
+                instancing::set_instanced(program, true);
+            });
+        }
+        drop(opaque);
+
+        let lamps = DebugGroup::new("lamps");
+        let p = &self.lamp_program;
+        p.set_used();
+        p.set_uniform_mat4("view", &view);
+        p.set_uniform_mat4("perspective", &projection);
+        for lamp in self.lights.point.iter().take(self.lamps) {
+            p.set_uniform_mat4("model", &Transform::from_translation(&lamp.position).uniformly_scaled(0.2).matrix());
+            self.cube.draw();
+        }
+        drop(lamps);
+
+        // transparent objects go last in both paths, back to front
+        let _glass = DebugGroup::new("glass");
+        let mut queue = TransparentQueue::new();
+        for (position, tint) in &self.panes {
+            queue.push(position, BlendMode::Alpha, (position, tint));
+        }
+        let p = &self.glass_program;
+        p.set_used();
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        p.set_uniform_mat4("view", &view);
+        p.set_uniform_mat4("perspective", &projection);
+        self.lights.apply(p);
+        self.shadows.apply(p);
+        let cube = &self.cube;
+        queue.draw(&self.camera.position(), None, target, &mut |(position, tint), mode| {
+            p.set_used();
+            transparency::set_blend_mode(p, mode);
+            p.set_uniform_mat4("model", &Transform::from_translation(position).scaled(&make_vec3(&[1.2, 1.2, 0.05])).matrix());
+            p.set_uniform_vec4("tint", tint);
+            cube.draw();
+        });
+        Ok(())
+    }
+}
+
+impl Drop for LightcasterDemo {
+    fn drop(&mut self) {
+        state::delete_texture(self.diffuse);
+        state::delete_texture(self.specular);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::glm::*;
+use crate::demos::{begin_target, crate_textures, move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::material::{Material, MaterialDesc, MaterialParam, ParamDesc};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::scene::Transform;
+use crate::render_gl::texture::Texture;
+use crate::render_gl::{Program, Shader};
+use std::ffi::CString;
+use std::rc::Rc;
+
+// a crate with diffuse and specular maps and the lamp lighting it. the
+// shaders and light colours come from materials/container.json, read
+// relative to the working directory; the maps it names are replaced with
+// generated ones
+pub struct LightingMapDemo {
+    material: Material,
+    lamp_program: Program,
+    cube: Mesh,
+    camera: Camera,
+    light_position: Vec3,
+    width: u32,
+    height: u32
+}
+
+impl LightingMapDemo {
+    pub fn new(width: u32, height: u32) -> Result<LightingMapDemo, String> {
+        let mut desc = MaterialDesc::load("materials/container.json")?;
+        desc.params.retain(|_, param| !matches!(param, ParamDesc::Texture { .. }));
+        let mut material = desc.build()?;
+        let (diffuse, specular) = crate_textures();
+        material.set("material.diffuse", MaterialParam::Texture(Rc::new(Texture::owned(diffuse, &"crate".to_string()))));
+        material.set("material.specular", MaterialParam::Texture(Rc::new(Texture::owned(specular, &"crate specular".to_string()))));
+
+        let lamp_program = Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("../bin/lamp.vert")).unwrap())?,
+            Shader::from_frag_source(&CString::new(include_str!("../bin/lamp.frag")).unwrap())?
+        ])?;
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 7.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        Ok(LightingMapDemo {
+            material: material,
+            lamp_program: lamp_program,
+            cube: Mesh::cube(),
+            camera: camera,
+            light_position: make_vec3(&[1.2, 1.8, 1.5]),
+            width: width,
+            height: height
+        })
+    }
+}
+
+impl Demo for LightingMapDemo {
+    fn name(&self) -> &'static str {
+        "lightingmap"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str) {
+        move_camera(&mut self.camera, key);
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        begin_target(target, self.width, self.height);
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        let view = self.camera.look_at();
+        let projection = self.camera.projection();
+
+        self.material.apply(None);
+        let p = self.material.program();
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        p.set_uniform_vec3("light.position", &self.light_position);
+        p.set_uniform_mat4("view", &view);
+        p.set_uniform_mat4("perspective", &projection);
+        p.set_uniform_mat4("model", &rotate(&Mat4::identity(), 45f32.to_radians(), &make_vec3(&[0.0, 1.0, 0.0])));
+        self.cube.draw();
+
+        let p = &self.lamp_program;
+        p.set_used();
+        p.set_uniform_mat4("view", &view);
+        p.set_uniform_mat4("perspective", &projection);
+        p.set_uniform_mat4("model", &Transform::from_translation(&self.light_position).uniformly_scaled(0.2).matrix());
+        self.cube.draw();
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::glm::*;
+use crate::demos::{begin_target, move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::material::{Material, MaterialParam};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::{Program, Shader};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::rc::Rc;
+
+// the materials scene: a single phong shaded cube and a point light.
+// the cube turns at `spin` degrees a second
+pub struct MaterialsDemo {
+    program: Rc<Program>,
+    material: Material,
+    cube: Mesh,
+    camera: Camera,
+    light_position: Vec3,
+    angle: f32,
+    pub spin: f32,
+    width: u32,
+    height: u32
+}
+
+impl MaterialsDemo {
+    pub fn new(width: u32, height: u32) -> Result<MaterialsDemo, String> {
+        let program = Rc::new(Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("../bin/diffuse.vert")).unwrap())?,
+            Shader::from_frag_source(&CString::new(include_str!("../bin/materials.frag")).unwrap())?
+        ])?);
+
+        let mut params = BTreeMap::new();
+        params.insert("material.ambient".to_string(), MaterialParam::Vec3(make_vec3(&[1.0, 0.5, 0.31])));
+        params.insert("material.diffuse".to_string(), MaterialParam::Vec3(make_vec3(&[1.0, 0.5, 0.3])));
+        params.insert("material.specular".to_string(), MaterialParam::Vec3(make_vec3(&[0.5, 0.5, 0.5])));
+        params.insert("light.ambient".to_string(), MaterialParam::Vec3(make_vec3(&[0.2, 0.2, 0.2])));
+        params.insert("light.diffuse".to_string(), MaterialParam::Vec3(make_vec3(&[0.5, 0.5, 0.5])));
+        params.insert("light.specular".to_string(), MaterialParam::Vec3(make_vec3(&[1.0, 1.0, 1.0])));
+        let material = Material::new(program.clone(), params);
+
+        let position = make_vec3(&[0.0, 1.0, 5.0]);
+        let mut camera = Camera::new(&position, &normalize(&-position), &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        Ok(MaterialsDemo {
+            program: program,
+            material: material,
+            cube: Mesh::cube(),
+            camera: camera,
+            light_position: make_vec3(&[2.2, 1.0, 2.0]),
+            angle: 45.0,
+            spin: 20.0,
+            width: width,
+            height: height
+        })
+    }
+}
+
+impl Demo for MaterialsDemo {
+    fn name(&self) -> &'static str {
+        "materials"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.angle = (self.angle + self.spin * dt) % 360.0;
+    }
+
+    fn key(&mut self, key: &str) {
+        move_camera(&mut self.camera, key);
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        begin_target(target, self.width, self.height);
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        let model = rotate(&rotate(&Mat4::identity(), self.angle.to_radians(), &make_vec3(&[0.0, 1.0, 0.0])),
+                           10f32.to_radians(), &make_vec3(&[1.0, 0.0, 0.0]));
+        self.material.apply(None);
+        let p = &self.program;
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        p.set_uniform_mat4("model", &model);
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        p.set_uniform_vec3("light.position", &self.light_position);
+        self.cube.draw();
+        Ok(())
+    }
+}
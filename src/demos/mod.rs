@@ -0,0 +1,213 @@
+use crate::render_gl::camera::{Camera, CameraMovement};
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::headless;
+use crate::render_gl::profiler::Profiler;
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+
+pub mod cube;
+pub mod diffuse;
+pub mod sphere;
+pub mod materials;
+pub mod lightingmap;
+pub mod lightcaster;
+pub mod pbr;
+pub mod deferred;
+pub mod scene;
+pub mod golden;
+
+// a scene that can be shown in a window or rendered headless. all GL
+// objects are created by the constructor, so a context has to be current
+pub trait Demo {
+    fn name(&self) -> &'static str;
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String>;
+
+    // advances animations by `dt` seconds. demos only move through this,
+    // so the same steps always produce the same frame
+    fn update(&mut self, _dt: f32) {}
+
+    // named keys as SDL reports them, e.g "W", "Space" or "-"
+    fn key(&mut self, _key: &str) {}
+
+    // draws a frame into `target`, or the default framebuffer when None
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String>;
+
+    // counts what the last frame did into `profiler`, e.g how many objects
+    // were culled. see Profiler::count
+    fn report(&self, _profiler: &mut Profiler) {}
+}
+
+pub const NAMES: [&str; 8] = ["cube", "diffuse", "sphere", "materials", "lightingmap", "lightcaster", "pbr", "deferred"];
+
+pub fn create(name: &str, width: u32, height: u32) -> Result<Box<dyn Demo>, String> {
+    let demo: Box<dyn Demo> = match name {
+        "cube" => Box::new(cube::CubeDemo::new(width, height)?),
+        "diffuse" => Box::new(diffuse::DiffuseDemo::new(width, height)?),
+        "sphere" => Box::new(sphere::SphereDemo::new(width, height)?),
+        "materials" => Box::new(materials::MaterialsDemo::new(width, height)?),
+        "lightingmap" => Box::new(lightingmap::LightingMapDemo::new(width, height)?),
+        "lightcaster" => Box::new(lightcaster::LightcasterDemo::new(width, height)?),
+        "pbr" => Box::new(pbr::PbrDemo::new(width, height)?),
+        "deferred" => Box::new(deferred::DeferredDemo::new(width, height)?),
+        _ => return Err(format!("unknown demo '{}', expected one of {}", name, NAMES.join(", ")))
+    };
+    Ok(demo)
+}
+
+// runs `frames` updates of `dt` seconds and renders the last one into an
+// RGBA8 target, returning its pixels bottom row first. needs a current
+// context, e.g a headless::HeadlessContext
+pub fn render_offscreen(demo: &mut dyn Demo, width: u32, height: u32, frames: u32, dt: f32) -> Result<Vec<u8>, String> {
+    let target = headless::offscreen_target(width, height)?;
+    demo.resize(width, height)?;
+    for _ in 0..frames {
+        demo.update(dt);
+    }
+    demo.render(Some(&target))?;
+    unsafe {
+        gl::Finish();
+    }
+    Ok(headless::read_pixels(&target, 0))
+}
+
+// WASD camera movement shared by the demos
+pub fn move_camera(camera: &mut Camera, key: &str) -> bool {
+    let movement = match key {
+        "W" => CameraMovement::Front,
+        "S" => CameraMovement::Back,
+        "A" => CameraMovement::Left,
+        "D" => CameraMovement::Right,
+        _ => return false
+    };
+    camera.update_movement(movement);
+    true
+}
+
+// binds `target` for drawing, or the default framebuffer sized width x height
+fn begin_target(target: Option<&Framebuffer>, width: u32, height: u32) {
+    match target {
+        Some(framebuffer) => framebuffer.begin(),
+        None => unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            state::viewport(0, 0, width as GLsizei, height as GLsizei);
+        }
+    }
+}
+
+// lightcaster.vert with one of the fragment shaders that use the shared
+// lights.glsl (position, normal and uv vertices)
+fn lit_program(fragment: &str) -> Result<Program, String> {
+    Program::from_shaders(&[
+        Shader::from_vert_source(&shader_lib::preprocess(include_str!("../bin/lightcaster.vert"))?)?,
+        Shader::from_frag_source(&shader_lib::preprocess(fragment)?)?
+    ])
+}
+
+// an RGBA8 texture from pixels, bottom row first. repeating and mipmapped
+fn pixel_texture(width: u32, height: u32, rgba: &[u8]) -> GLuint {
+    let mut tex: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_2D, tex);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA8 as GLint, width as i32, height as i32, 0, gl::RGBA,
+                       gl::UNSIGNED_BYTE, rgba.as_ptr() as *const GLvoid);
+        gl::GenerateMipmap(gl::TEXTURE_2D);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        state::bind_texture(gl::TEXTURE_2D, 0);
+    }
+    tex
+}
+
+// a 1x1 texture of a single colour, standing in for image files the
+// demos shouldn't depend on
+fn solid_texture(rgba: [u8; 4]) -> GLuint {
+    pixel_texture(1, 1, &rgba)
+}
+
+// size x size pixels from a function of the texel centre in [0, 1]^2
+fn generate_pixels(size: u32, texel: &dyn Fn(f32, f32) -> [u8; 4]) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let u = (x as f32 + 0.5) / size as f32;
+            let v = (y as f32 + 0.5) / size as f32;
+            pixels.extend_from_slice(&texel(u, v));
+        }
+    }
+    pixels
+}
+
+const CRATE_TEXTURE_SIZE: u32 = 64;
+
+// diffuse and specular maps of a wooden crate with a steel rim, standing
+// in for container2.png and container2_specular.png
+fn crate_textures() -> (GLuint, GLuint) {
+    let size = CRATE_TEXTURE_SIZE;
+    (pixel_texture(size, size, &generate_pixels(size, &|u, v| crate_texel(u, v).0)),
+     pixel_texture(size, size, &generate_pixels(size, &|u, v| crate_texel(u, v).1)))
+}
+
+fn crate_texel(u: f32, v: f32) -> ([u8; 4], [u8; 4]) {
+    let rim = !(0.08..=0.92).contains(&u) || !(0.08..=0.92).contains(&v);
+    if rim {
+        ([110, 110, 115, 255], [200, 200, 200, 255])
+    } else {
+        // planks with a darker seam between them
+        let plank = (v * 5.0).fract();
+        let grain = 0.9 + 0.1 * (u * 40.0 + (v * 5.0).floor() * 2.0).sin();
+        let seam = if plank < 0.06 { 0.6 } else { 1.0 };
+        let shade = grain * seam;
+        ([(160.0 * shade) as u8, (105.0 * shade) as u8, (55.0 * shade) as u8, 255], [20, 20, 20, 255])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::headless::HeadlessContext;
+    use crate::glm::*;
+
+    #[test]
+    fn generates_pixels_row_by_row() {
+        let pixels = generate_pixels(2, &|u, v| [(u * 100.0) as u8, (v * 100.0) as u8, 0, 255]);
+        assert_eq!(pixels.len(), 16);
+        assert_eq!(&pixels[0..4], &[25, 25, 0, 255]);
+        assert_eq!(&pixels[4..8], &[75, 25, 0, 255]);
+        assert_eq!(&pixels[8..12], &[25, 75, 0, 255]);
+    }
+
+    #[test]
+    fn wasd_moves_the_camera() {
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 5.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 1.0);
+        assert!(move_camera(&mut camera, "W"));
+        assert_eq!(camera.position(), make_vec3(&[0.0, 0.0, 4.0]));
+        assert!(!move_camera(&mut camera, "Q"));
+        assert_eq!(camera.position(), make_vec3(&[0.0, 0.0, 4.0]));
+    }
+
+    // every demo renders something other than its clear colour
+    #[test]
+    fn demos_render_headless() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let context = match HeadlessContext::for_test("headless rendering") {
+            Some(context) => context,
+            None => return
+        };
+        eprintln!("rendering with {} on {}", context.backend().name(), context.renderer());
+        let (width, height) = (64, 48);
+        for name in NAMES.iter() {
+            let mut demo = create(name, width, height).unwrap();
+            let pixels = render_offscreen(demo.as_mut(), width, height, 2, 1.0 / 60.0).unwrap();
+            assert_eq!(pixels.len(), (width * height * 4) as usize);
+            let first = &pixels[0..4];
+            assert!(pixels.chunks(4).any(|p| p != first), "{} rendered a blank image", name);
+        }
+    }
+}
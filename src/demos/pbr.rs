@@ -0,0 +1,230 @@
+use crate::glm::*;
+use crate::demos::{move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::debug::DebugGroup;
+use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::light::{Attenuation, LightSet, PointLight};
+use crate::render_gl::material::Material;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pbr::{self, IblMaps, IblSettings, PbrMaterial};
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::postprocess::{Lut3D, PostProcessor, PostSettings};
+use crate::render_gl::Program;
+use crate::render_gl::state;
+use gl::types::*;
+use std::rc::Rc;
+
+// a grid of spheres, metallic increasing upwards and roughness to the right.
+// rendered into a multisampled target that is resolved into the HDR input
+// of the post processing chain.
+// I image based lighting, B bloom, F FXAA, V vignette, G colour grading,
+// C greyscale custom pass, T cycles tone mappers, -/= exposure
+pub struct PbrDemo {
+    program: Rc<Program>,
+    sphere: Mesh,
+    spheres: Vec<(Mat4, Material)>,
+    lights: LightSet,
+    environment: GLuint,
+    ibl: IblMaps,
+    pub use_ibl: bool,
+    camera: Camera,
+    target: Framebuffer,
+    pub post: PostProcessor,
+    greyscale: bool
+}
+
+impl PbrDemo {
+    pub fn new(width: u32, height: u32) -> Result<PbrDemo, String> {
+        let program = Rc::new(pbr::program()?);
+
+        let rows = 7;
+        let columns = 7;
+        let spacing = 2.5;
+        let mut spheres = Vec::new();
+        for row in 0..rows {
+            for column in 0..columns {
+                let pbr_material = PbrMaterial::new(
+                    &make_vec3(&[0.5, 0.0, 0.0]),
+                    row as f32 / (rows - 1) as f32,
+                    (column as f32 / (columns - 1) as f32).max(0.05));
+                let position = make_vec3(&[
+                    (column as f32 - (columns / 2) as f32) * spacing,
+                    (row as f32 - (rows / 2) as f32) * spacing,
+                    0.0]);
+                spheres.push((translate(&Mat4::identity(), &position), pbr_material.material(program.clone())));
+            }
+        }
+
+        let mut lights = LightSet::new();
+        for &(x, y) in [(-10.0, 10.0), (10.0, 10.0), (-10.0, -10.0), (10.0, -10.0)].iter() {
+            lights.point.push(PointLight::new(&make_vec3(&[x, y, 10.0]), &make_vec3(&[300.0, 300.0, 300.0]), 3250.0));
+        }
+        for light in lights.point.iter_mut() {
+            // physically based lights fall off with the inverse square of the distance
+            light.attenuation = Attenuation::new(0.0, 0.0, 1.0);
+        }
+
+        let environment_size = 64;
+        let environment = sky_cubemap(environment_size);
+        let ibl = IblMaps::generate(environment, environment_size, &IblSettings::default())?;
+
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 20.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        let target = Framebuffer::new(FramebufferDesc::new(width, height)
+            .color(ColorFormat::Rgba16F)
+            .depth_renderbuffer(DepthFormat::Depth24Stencil8)
+            .samples(4))?;
+        let mut post = PostProcessor::new(width, height, PostSettings::default())?;
+        post.set_lut(Some(Lut3D::identity(16)));
+        post.add_pass("greyscale", GREYSCALE)?;
+        post.set_pass_enabled("greyscale", false);
+
+        Ok(PbrDemo {
+            program: program,
+            sphere: Mesh::sphere(64, 32),
+            spheres: spheres,
+            lights: lights,
+            environment: environment,
+            ibl: ibl,
+            use_ibl: true,
+            camera: camera,
+            target: target,
+            post: post,
+            greyscale: false
+        })
+    }
+}
+
+impl Demo for PbrDemo {
+    fn name(&self) -> &'static str {
+        "pbr"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.target.resize(width, height)?;
+        self.post.resize(width, height)?;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str) {
+        if move_camera(&mut self.camera, key) {
+            return;
+        }
+        let settings = &mut self.post.settings;
+        match key {
+            "I" => self.use_ibl = !self.use_ibl,
+            "B" => settings.bloom = !settings.bloom,
+            "F" => settings.fxaa = !settings.fxaa,
+            "V" => settings.vignette = !settings.vignette,
+            "G" => settings.color_grading = !settings.color_grading,
+            "T" => settings.tone_mapper = settings.tone_mapper.next(),
+            "-" => settings.exposure *= 0.8,
+            "=" => settings.exposure *= 1.25,
+            "C" => {
+                self.greyscale = !self.greyscale;
+                self.post.set_pass_enabled("greyscale", self.greyscale);
+            }
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        let spheres = DebugGroup::new("spheres");
+        self.target.begin();
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.1, 0.1, 0.1, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+
+        let program = &self.program;
+        program.set_used();
+        program.set_uniform_mat4("view", &self.camera.look_at());
+        program.set_uniform_mat4("perspective", &self.camera.projection());
+        program.set_uniform_vec3("viewPos", &self.camera.position());
+        self.lights.apply(program);
+        pbr::apply_ibl(program, if self.use_ibl { Some(&self.ibl) } else { None });
+
+        let mut previous = None;
+        for (model, material) in &self.spheres {
+            material.apply(previous);
+            program.set_uniform_mat4("model", model);
+            self.sphere.draw();
+            previous = Some(material);
+        }
+        drop(spheres);
+        self.target.resolve(self.post.scene());
+        self.post.finish(target);
+        Ok(())
+    }
+}
+
+impl Drop for PbrDemo {
+    fn drop(&mut self) {
+        state::delete_texture(self.environment);
+    }
+}
+
+const GREYSCALE: &str = "#version 330 core
+in vec2 TexCoords;
+out vec4 FragColor;
+uniform sampler2D image;
+void main()
+{
+    vec3 color = texture(image, TexCoords).rgb;
+    FragColor = vec4(vec3(dot(color, vec3(0.299, 0.587, 0.114))), 1.0);
+}
+";
+
+// a simple sky: bright blue above the horizon, dark brown ground below
+pub fn sky_cubemap(size: u32) -> GLuint {
+    let horizon = make_vec3(&[0.9, 0.85, 0.8]);
+    let zenith = make_vec3(&[0.2, 0.4, 0.9]);
+    let ground = make_vec3(&[0.15, 0.1, 0.05]);
+    let mut tex: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, tex);
+    }
+    for face in 0..6 {
+        let mut pixels: Vec<f32> = Vec::with_capacity((size * size * 3) as usize);
+        for y in 0..size {
+            for x in 0..size {
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                // direction through the texel, following the GL cube map face conventions
+                let dir = match face {
+                    0 => make_vec3(&[1.0, -v, -u]),
+                    1 => make_vec3(&[-1.0, -v, u]),
+                    2 => make_vec3(&[u, 1.0, v]),
+                    3 => make_vec3(&[u, -1.0, -v]),
+                    4 => make_vec3(&[u, -v, 1.0]),
+                    _ => make_vec3(&[-u, -v, -1.0])
+                };
+                let up = normalize(&dir).y;
+                let color = if up >= 0.0 {
+                    horizon * (1.0 - up) + zenith * up
+                } else {
+                    let t = (-up * 4.0).min(1.0);
+                    horizon * (1.0 - t) + ground * t
+                };
+                pixels.extend_from_slice(&[color.x, color.y, color.z]);
+            }
+        }
+        unsafe {
+            gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + face, 0, gl::RGB16F as GLint,
+                           size as i32, size as i32, 0, gl::RGB, gl::FLOAT,
+                           pixels.as_ptr() as *const GLvoid);
+        }
+    }
+    unsafe {
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+    tex
+}
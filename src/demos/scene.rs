@@ -0,0 +1,141 @@
+use crate::glm::*;
+use crate::demos::{begin_target, move_camera, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::light::LightSet;
+use crate::render_gl::profiler::Profiler;
+use crate::render_gl::scene_file::LoadedScene;
+use crate::render_gl::shadow::{ShadowRenderer, ShadowSettings};
+
+// whatever a scene file describes, see render_gl::scene_file. the view
+// starts at the file's camera and moves like in the other demos. C cycles
+// through the ways of finding hidden objects
+pub struct SceneDemo {
+    loaded: LoadedScene,
+    lights: LightSet,
+    shadows: ShadowRenderer,
+    camera: Camera,
+    width: u32,
+    height: u32
+}
+
+impl SceneDemo {
+    pub fn load(path: &str, width: u32, height: u32) -> Result<SceneDemo, String> {
+        let loaded = LoadedScene::load(path)?;
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 5.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+        loaded.apply_camera(&mut camera)?;
+        Ok(SceneDemo {
+            lights: loaded.scene.lights(),
+            loaded: loaded,
+            shadows: ShadowRenderer::new(ShadowSettings::default())?,
+            camera: camera,
+            width: width,
+            height: height
+        })
+    }
+
+    pub fn loaded(&self) -> &LoadedScene {
+        &self.loaded
+    }
+}
+
+impl Demo for SceneDemo {
+    fn name(&self) -> &'static str {
+        "scene"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str) {
+        if move_camera(&mut self.camera, key) {
+            return;
+        }
+        if key == "C" {
+            self.loaded.occlusion = self.loaded.occlusion.next();
+            log::info!("occlusion culling: {}", self.loaded.occlusion.name());
+        }
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        let loaded = &self.loaded;
+        self.shadows.render(&self.camera, &self.lights, &mut |depth_program| loaded.draw_geometry(depth_program))?;
+        begin_target(target, self.width, self.height);
+        unsafe {
+            gl::ClearColor(0.05, 0.05, 0.08, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        self.loaded.draw(&self.camera, &self.lights, Some(&self.shadows), target);
+        Ok(())
+    }
+
+    fn report(&self, profiler: &mut Profiler) {
+        let culling = self.loaded.culler.stats();
+        let occlusion = self.loaded.occlusion_stats();
+        profiler.count("objects", culling.objects as f32);
+        profiler.count("outside the view", culling.culled as f32);
+        profiler.count("bvh box tests", culling.tested as f32);
+        profiler.count("occlusion tested", occlusion.tested as f32);
+        profiler.count("occluded", occlusion.occluded as f32);
+        profiler.count("occlusion queries", occlusion.queries as f32);
+        profiler.count("queries not ready", occlusion.waiting as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::render_offscreen;
+    use crate::render_gl::headless::HeadlessContext;
+    use crate::render_gl::mock;
+    use crate::render_gl::occlusion::OcclusionMode;
+
+    #[test]
+    fn renders_the_example_scene() {
+        let _lock = mock::lock_gl();
+        let _context = match HeadlessContext::for_test("the example scene") {
+            Some(context) => context,
+            None => return
+        };
+        let mut demo = SceneDemo::load("scenes/spheres.json", 96, 64).unwrap();
+        assert_eq!(demo.loaded().scene.find("eye"), demo.loaded().camera);
+        let pixels = render_offscreen(&mut demo, 96, 64, 1, 0.0).unwrap();
+        let first = &pixels[0..4];
+        assert!(pixels.chunks(4).any(|p| p != first), "rendered a blank image");
+        let stats = demo.loaded().culler.stats();
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.visible + stats.culled, stats.objects);
+    }
+
+    #[test]
+    fn occlusion_culling_leaves_the_image_alone() {
+        let _lock = mock::lock_gl();
+        let _context = match HeadlessContext::for_test("occlusion culling") {
+            Some(context) => context,
+            None => return
+        };
+        let mut demo = SceneDemo::load("scenes/rooms.json", 96, 64).unwrap();
+        demo.loaded.occlusion = OcclusionMode::Off;
+        let everything = render_offscreen(&mut demo, 96, 64, 1, 0.0).unwrap();
+        assert_eq!(demo.loaded().culler.stats().visible, 8);
+
+        // the floor and the wall hide the four balls behind the wall
+        demo.loaded.occlusion = OcclusionMode::HiZ;
+        assert!(render_offscreen(&mut demo, 96, 64, 1, 0.0).unwrap() == everything);
+        let stats = demo.loaded().occlusion_stats();
+        assert_eq!((stats.tested, stats.occluded), (6, 4));
+
+        // queries find the same a frame later
+        demo.loaded.occlusion = OcclusionMode::Queries;
+        render_offscreen(&mut demo, 96, 64, 1, 0.0).unwrap();
+        assert!(render_offscreen(&mut demo, 96, 64, 1, 0.0).unwrap() == everything);
+        let stats = demo.loaded().occlusion_stats();
+        assert_eq!((stats.tested, stats.occluded), (8, 4), "{:?}", stats);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::glm::*;
+use crate::demos::{begin_target, lit_program, move_camera, solid_texture, Demo};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::light::{DirectionalLight, LightSet, PointLight, SpotLight};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::Program;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::state;
+use gl::types::*;
+
+// a single sphere lit by a flashlight held at the camera and a lamp
+// circling it at `orbit_speed` degrees a second. Q and E move the lamp
+pub struct SphereDemo {
+    program: Program,
+    sphere: Mesh,
+    lights: LightSet,
+    camera: Camera,
+    angle: f32,
+    pub orbit_speed: f32,
+    diffuse: GLuint,
+    specular: GLuint,
+    width: u32,
+    height: u32
+}
+
+const ORBIT_RADIUS: f32 = 6.0;
+
+impl SphereDemo {
+    pub fn new(width: u32, height: u32) -> Result<SphereDemo, String> {
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 7.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        camera.set_aspect(width as f32 / height as f32);
+
+        let mut lights = LightSet::new();
+        lights.directional.push(DirectionalLight::new(&make_vec3(&[-0.2, -1.0, -0.3]), &make_vec3(&[0.2, 0.2, 0.1])));
+        lights.point.push(PointLight::new(&make_vec3(&[0.0, 1.0, ORBIT_RADIUS]), &make_vec3(&[0.3, 0.4, 0.3]), 32.0));
+        let mut flashlight = SpotLight::new(&camera.position(), &camera.front(), &make_vec3(&[1.0, 1.0, 1.0]), 50.0);
+        flashlight.inner_cutoff = 10.0;
+        flashlight.outer_cutoff = 14.0;
+        lights.spot.push(flashlight);
+
+        let mut demo = SphereDemo {
+            program: lit_program(include_str!("../bin/lightcaster.frag"))?,
+            sphere: Mesh::sphere(50, 60),
+            lights: lights,
+            camera: camera,
+            angle: 45.0,
+            orbit_speed: 30.0,
+            diffuse: solid_texture([90, 140, 100, 255]),
+            specular: solid_texture([255, 255, 255, 255]),
+            width: width,
+            height: height
+        };
+        demo.place_lamp();
+        Ok(demo)
+    }
+
+    fn place_lamp(&mut self) {
+        let angle = self.angle.to_radians();
+        self.lights.point[0].position = make_vec3(&[ORBIT_RADIUS * angle.cos(), 1.0, ORBIT_RADIUS * angle.sin()]);
+    }
+}
+
+impl Demo for SphereDemo {
+    fn name(&self) -> &'static str {
+        "sphere"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect(width as f32 / height as f32);
+        Ok(())
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.angle = (self.angle + self.orbit_speed * dt) % 360.0;
+        self.place_lamp();
+    }
+
+    fn key(&mut self, key: &str) {
+        if move_camera(&mut self.camera, key) {
+            return;
+        }
+        match key {
+            "Q" => self.angle -= 10.0,
+            "E" => self.angle += 10.0,
+            _ => return
+        }
+        self.place_lamp();
+    }
+
+    fn render(&mut self, target: Option<&Framebuffer>) -> Result<(), String> {
+        begin_target(target, self.width, self.height);
+        PipelineState::opaque().apply();
+        unsafe {
+            gl::ClearColor(0.5, 0.4, 0.3, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            state::active_texture(gl::TEXTURE0);
+            state::bind_texture(gl::TEXTURE_2D, self.diffuse);
+            state::active_texture(gl::TEXTURE1);
+            state::bind_texture(gl::TEXTURE_2D, self.specular);
+        }
+        {
+            let flashlight = &mut self.lights.spot[0];
+            flashlight.position = self.camera.position();
+            flashlight.direction = self.camera.front();
+        }
+        let p = &self.program;
+        p.set_used();
+        p.set_uniform_1i("material.diffuse", 0);
+        p.set_uniform_1i("material.specular", 1);
+        p.set_uniform_1f("material.shininess", 32.0);
+        p.set_uniform_vec3("viewPos", &self.camera.position());
+        p.set_uniform_mat4("view", &self.camera.look_at());
+        p.set_uniform_mat4("perspective", &self.camera.projection());
+        p.set_uniform_mat4("model", &scale(&Mat4::identity(), &make_vec3(&[2.0, 2.0, 2.0])));
+        self.lights.apply(p);
+        self.sphere.draw();
+        Ok(())
+    }
+}
+
+impl Drop for SphereDemo {
+    fn drop(&mut self) {
+        state::delete_texture(self.diffuse);
+        state::delete_texture(self.specular);
+    }
+}
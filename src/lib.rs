@@ -1,3 +1,8 @@
 extern crate gl;
+extern crate log;
 extern crate nalgebra_glm as glm;
+extern crate serde;
+extern crate serde_json;
+extern crate ron;
 pub mod render_gl;
+pub mod demos;
@@ -0,0 +1,515 @@
+use crate::render_gl::frustum::{Aabb, Frustum, Intersection};
+
+// how many buckets the surface area heuristic sorts centroids into
+const SAH_BUCKETS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProxyId(usize);
+
+// what a query looked at. `tested` counts box tests, which is what the
+// hierarchy saves compared to testing every object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub objects: usize,
+    pub visible: usize,
+    pub culled: usize,
+    pub tested: usize
+}
+
+struct Proxy<T> {
+    // the box the object was given. the tree holds `fat`, which contains
+    // it and only changes once the object leaves it
+    aabb: Aabb,
+    fat: Aabb,
+    node: usize,
+    item: T
+}
+
+enum Kind {
+    Leaf(usize),
+    Branch(usize, usize)
+}
+
+struct TreeNode {
+    aabb: Aabb,
+    parent: Option<usize>,
+    kind: Kind
+}
+
+// a bounding volume hierarchy with one object per leaf. rebuild() sorts
+// everything top down with the surface area heuristic, which suits static
+// objects. insert, remove and update change the tree in place for moving
+// ones: a moving object gets a box enlarged by `margin` and its leaf only
+// moves when it leaves that box, so small movements cost nothing
+pub struct Bvh<T> {
+    pub margin: f32,
+    proxies: Vec<Option<Proxy<T>>>,
+    free_proxies: Vec<usize>,
+    nodes: Vec<Option<TreeNode>>,
+    free_nodes: Vec<usize>,
+    root: Option<usize>,
+    len: usize
+}
+
+impl<T> Default for Bvh<T> {
+    fn default() -> Bvh<T> {
+        Bvh::new()
+    }
+}
+
+impl<T> Bvh<T> {
+    pub fn new() -> Bvh<T> {
+        Bvh {
+            margin: 0.1,
+            proxies: Vec::new(),
+            free_proxies: Vec::new(),
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            root: None,
+            len: 0
+        }
+    }
+
+    // builds the tree top down in one go
+    pub fn build(items: Vec<(Aabb, T)>) -> Bvh<T> {
+        let mut bvh = Bvh::new();
+        for (aabb, item) in items {
+            bvh.proxies.push(Some(Proxy { aabb: aabb, fat: aabb, node: 0, item: item }));
+            bvh.len += 1;
+        }
+        bvh.rebuild();
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, id: ProxyId) -> Option<&T> {
+        self.proxies.get(id.0).and_then(|p| p.as_ref()).map(|p| &p.item)
+    }
+
+    pub fn aabb(&self, id: ProxyId) -> Option<&Aabb> {
+        self.proxies.get(id.0).and_then(|p| p.as_ref()).map(|p| &p.aabb)
+    }
+
+    // the box around everything, None when empty
+    pub fn bounds(&self) -> Option<&Aabb> {
+        self.root.map(|r| &self.node(r).aabb)
+    }
+
+    // longest path from the root to a leaf, 0 when empty
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending: Vec<(usize, usize)> = self.root.iter().map(|r| (*r, 1)).collect();
+        while let Some((index, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            if let Kind::Branch(left, right) = self.node(index).kind {
+                pending.push((left, depth + 1));
+                pending.push((right, depth + 1));
+            }
+        }
+        deepest
+    }
+
+    // inserted objects are taken to move, build() is for static ones
+    pub fn insert(&mut self, aabb: &Aabb, item: T) -> ProxyId {
+        let proxy = Proxy { aabb: *aabb, fat: aabb.expanded(self.margin), node: 0, item: item };
+        let id = match self.free_proxies.pop() {
+            Some(id) => {
+                self.proxies[id] = Some(proxy);
+                id
+            }
+            None => {
+                self.proxies.push(Some(proxy));
+                self.proxies.len() - 1
+            }
+        };
+        self.len += 1;
+        self.insert_leaf(id);
+        ProxyId(id)
+    }
+
+    pub fn remove(&mut self, id: ProxyId) -> Option<T> {
+        let proxy = self.proxies.get_mut(id.0)?.take()?;
+        self.remove_leaf(proxy.node);
+        self.free_proxies.push(id.0);
+        self.len -= 1;
+        Some(proxy.item)
+    }
+
+    // gives an object a new box. returns whether the tree had to change
+    pub fn update(&mut self, id: ProxyId, aabb: &Aabb) -> bool {
+        let margin = self.margin;
+        let proxy = match self.proxies.get_mut(id.0).and_then(|p| p.as_mut()) {
+            Some(proxy) => proxy,
+            None => return false
+        };
+        proxy.aabb = *aabb;
+        if proxy.fat.contains_aabb(aabb) {
+            return false;
+        }
+        proxy.fat = aabb.expanded(margin);
+        let node = proxy.node;
+        self.remove_leaf(node);
+        self.insert_leaf(id.0);
+        true
+    }
+
+    // sorts every object into a new tree, e.g after loading a level or
+    // when many updates have left the tree unbalanced. moving objects
+    // keep their enlarged boxes
+    pub fn rebuild(&mut self) {
+        self.nodes.clear();
+        self.free_nodes.clear();
+        let mut leaves: Vec<usize> = (0..self.proxies.len()).filter(|i| self.proxies[*i].is_some()).collect();
+        self.root = if leaves.is_empty() { None } else { Some(self.build_node(&mut leaves, None)) };
+    }
+
+    fn build_node(&mut self, leaves: &mut [usize], parent: Option<usize>) -> usize {
+        let index = self.nodes.len();
+        if leaves.len() == 1 {
+            let proxy = self.proxies[leaves[0]].as_mut().unwrap();
+            proxy.node = index;
+            self.nodes.push(Some(TreeNode { aabb: proxy.fat, parent: parent, kind: Kind::Leaf(leaves[0]) }));
+            return index;
+        }
+        let fat = |i: &usize| self.proxies[*i].as_ref().unwrap().fat;
+        let aabb = leaves.iter().map(fat).fold(fat(&leaves[0]), |a, b| a.union(&b));
+        // reserve the slot, the children get the ones after it
+        self.nodes.push(Some(TreeNode { aabb: aabb, parent: parent, kind: Kind::Leaf(0) }));
+        let split = self.split(leaves);
+        let (left, right) = leaves.split_at_mut(split);
+        let left = self.build_node(left, Some(index));
+        let right = self.build_node(right, Some(index));
+        self.node_mut(index).kind = Kind::Branch(left, right);
+        index
+    }
+
+    // orders `leaves` so the first `split` of them go left. buckets the
+    // centroids along the widest axis and picks the cheapest boundary by
+    // surface area, falling back to halves when they all share a bucket
+    fn split(&self, leaves: &mut [usize]) -> usize {
+        let center = |i: usize| self.proxies[i].as_ref().unwrap().fat.center();
+        let first = center(leaves[0]);
+        let mut centroids = Aabb::new(&first, &first);
+        for i in leaves.iter() {
+            centroids = centroids.union(&Aabb::new(&center(*i), &center(*i)));
+        }
+        let size = centroids.max - centroids.min;
+        let axis = if size.x >= size.y && size.x >= size.z { 0 } else if size.y >= size.z { 1 } else { 2 };
+        let half = leaves.len() / 2;
+        if size[axis] <= 0.0 {
+            return half;
+        }
+        let bucket = |i: usize| {
+            let t = (center(i)[axis] - centroids.min[axis]) / size[axis];
+            ((t * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+        };
+
+        let mut boxes: Vec<Option<Aabb>> = vec![None; SAH_BUCKETS];
+        let mut counts = [0usize; SAH_BUCKETS];
+        for i in leaves.iter() {
+            let b = bucket(*i);
+            let fat = self.proxies[*i].as_ref().unwrap().fat;
+            boxes[b] = Some(boxes[b].map_or(fat, |a| a.union(&fat)));
+            counts[b] += 1;
+        }
+        let cost = |range: std::ops::Range<usize>| -> f32 {
+            let count: usize = counts[range.clone()].iter().sum();
+            let aabb = boxes[range].iter().flatten().fold(None, |a: Option<Aabb>, b| Some(a.map_or(*b, |a| a.union(b))));
+            aabb.map_or(0.0, |a| a.surface_area() * count as f32)
+        };
+        let best = (1..SAH_BUCKETS)
+            .filter(|b| counts[..*b].iter().sum::<usize>() > 0 && counts[*b..].iter().sum::<usize>() > 0)
+            .min_by(|a, b| (cost(0..*a) + cost(*a..SAH_BUCKETS)).partial_cmp(&(cost(0..*b) + cost(*b..SAH_BUCKETS))).unwrap());
+        match best {
+            Some(boundary) => {
+                leaves.sort_by_key(|i| bucket(*i) >= boundary);
+                leaves.iter().filter(|i| bucket(**i) < boundary).count()
+            }
+            None => {
+                leaves.sort_by(|a, b| center(*a)[axis].partial_cmp(&center(*b)[axis]).unwrap());
+                half
+            }
+        }
+    }
+
+    fn node(&self, index: usize) -> &TreeNode {
+        self.nodes[index].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut TreeNode {
+        self.nodes[index].as_mut().unwrap()
+    }
+
+    fn allocate(&mut self, node: TreeNode) -> usize {
+        match self.free_nodes.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // walks down to the sibling that grows the least, the cheap version of
+    // the branch and bound search in Box2D's b2DynamicTree
+    fn insert_leaf(&mut self, proxy: usize) {
+        let fat = self.proxies[proxy].as_ref().unwrap().fat;
+        let leaf = self.allocate(TreeNode { aabb: fat, parent: None, kind: Kind::Leaf(proxy) });
+        self.proxies[proxy].as_mut().unwrap().node = leaf;
+        let mut sibling = match self.root {
+            Some(root) => root,
+            None => {
+                self.root = Some(leaf);
+                return;
+            }
+        };
+        while let Kind::Branch(left, right) = self.node(sibling).kind {
+            let here = self.node(sibling).aabb;
+            let combined = here.union(&fat).surface_area();
+            // pairing with this node costs a new parent around both. going
+            // further down costs growing this node, plus the child's growth
+            let pair = 2.0 * combined;
+            let inherited = 2.0 * (combined - here.surface_area());
+            let descend = |child: usize| {
+                let child = self.node(child);
+                let grown = child.aabb.union(&fat).surface_area();
+                match child.kind {
+                    Kind::Leaf(_) => grown + inherited,
+                    Kind::Branch(..) => grown - child.aabb.surface_area() + inherited
+                }
+            };
+            let (cost_left, cost_right) = (descend(left), descend(right));
+            if pair < cost_left && pair < cost_right {
+                break;
+            }
+            sibling = if cost_left < cost_right { left } else { right };
+        }
+
+        let old_parent = self.node(sibling).parent;
+        let aabb = self.node(sibling).aabb.union(&fat);
+        let parent = self.allocate(TreeNode { aabb: aabb, parent: old_parent, kind: Kind::Branch(sibling, leaf) });
+        self.node_mut(sibling).parent = Some(parent);
+        self.node_mut(leaf).parent = Some(parent);
+        match old_parent {
+            Some(p) => self.replace_child(p, sibling, parent),
+            None => self.root = Some(parent)
+        }
+        self.refit(old_parent);
+    }
+
+    fn free(&mut self, index: usize) {
+        self.nodes[index] = None;
+        self.free_nodes.push(index);
+    }
+
+    // the leaf's sibling takes the place of their parent
+    fn remove_leaf(&mut self, leaf: usize) {
+        let parent = self.node(leaf).parent;
+        self.free(leaf);
+        let parent = match parent {
+            Some(parent) => parent,
+            None => {
+                self.root = None;
+                return;
+            }
+        };
+        let sibling = match self.node(parent).kind {
+            Kind::Branch(left, right) => if left == leaf { right } else { left },
+            Kind::Leaf(_) => unreachable!("leaves have no children")
+        };
+        let grandparent = self.node(parent).parent;
+        self.node_mut(sibling).parent = grandparent;
+        match grandparent {
+            Some(g) => self.replace_child(g, parent, sibling),
+            None => self.root = Some(sibling)
+        }
+        self.free(parent);
+        self.refit(grandparent);
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        if let Kind::Branch(left, right) = self.node(parent).kind {
+            self.node_mut(parent).kind = if left == old { Kind::Branch(new, right) } else { Kind::Branch(left, new) };
+        }
+    }
+
+    // recomputes the boxes from `index` up to the root
+    fn refit(&mut self, mut index: Option<usize>) {
+        while let Some(i) = index {
+            if let Kind::Branch(left, right) = self.node(i).kind {
+                let aabb = self.node(left).aabb.union(&self.node(right).aabb);
+                self.node_mut(i).aabb = aabb;
+            }
+            index = self.node(i).parent;
+        }
+    }
+
+    // calls `visit` for every object whose box is at least partly inside
+    // the frustum. subtrees entirely inside aren't tested any further
+    pub fn cull(&self, frustum: &Frustum, visit: &mut dyn FnMut(ProxyId, &T)) -> CullStats {
+        let mut stats = CullStats { objects: self.len, ..CullStats::default() };
+        let mut pending: Vec<(usize, bool)> = self.root.iter().map(|r| (*r, false)).collect();
+        while let Some((index, inside)) = pending.pop() {
+            let node = self.node(index);
+            let inside = inside || {
+                stats.tested += 1;
+                match frustum.test_aabb(&node.aabb) {
+                    Intersection::Outside => continue,
+                    Intersection::Intersecting => false,
+                    Intersection::Inside => true
+                }
+            };
+            match node.kind {
+                Kind::Leaf(id) => {
+                    let proxy = self.proxies[id].as_ref().unwrap();
+                    // the enlarged box can reach into the frustum when the
+                    // object itself doesn't
+                    if !inside && proxy.fat != proxy.aabb {
+                        stats.tested += 1;
+                        if !frustum.intersects_aabb(&proxy.aabb) {
+                            continue;
+                        }
+                    }
+                    stats.visible += 1;
+                    visit(ProxyId(id), &proxy.item);
+                }
+                Kind::Branch(left, right) => {
+                    pending.push((right, inside));
+                    pending.push((left, inside));
+                }
+            }
+        }
+        stats.culled = stats.objects - stats.visible;
+        stats
+    }
+
+    // every object whose box overlaps `aabb`
+    pub fn query(&self, aabb: &Aabb, visit: &mut dyn FnMut(ProxyId, &T)) {
+        let mut pending: Vec<usize> = self.root.iter().cloned().collect();
+        while let Some(index) = pending.pop() {
+            let node = self.node(index);
+            if !node.aabb.intersects(aabb) {
+                continue;
+            }
+            match node.kind {
+                Kind::Leaf(id) => {
+                    let proxy = self.proxies[id].as_ref().unwrap();
+                    if proxy.aabb.intersects(aabb) {
+                        visit(ProxyId(id), &proxy.item);
+                    }
+                }
+                Kind::Branch(left, right) => {
+                    pending.push(right);
+                    pending.push(left);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glm::*;
+
+    fn unit_box(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb::from_center_extents(&make_vec3(&[x, y, z]), &make_vec3(&[0.5, 0.5, 0.5]))
+    }
+
+    // looking down -z from the origin
+    fn frustum() -> Frustum {
+        let view = look_at(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]), &make_vec3(&[0.0, 1.0, 0.0]));
+        Frustum::from_matrix(&(perspective(1.0, 1.0, 0.1, 100.0) * view))
+    }
+
+    fn grid() -> Vec<(Aabb, usize)> {
+        let mut items = Vec::new();
+        for x in -10..10 {
+            for z in -10..10 {
+                items.push((unit_box(x as f32 * 4.0, 0.0, z as f32 * 4.0), items.len()));
+            }
+        }
+        items
+    }
+
+    fn culled(bvh: &Bvh<usize>) -> (Vec<usize>, CullStats) {
+        let mut visible = Vec::new();
+        let stats = bvh.cull(&frustum(), &mut |_, i| visible.push(*i));
+        visible.sort();
+        (visible, stats)
+    }
+
+    #[test]
+    fn culls_the_same_objects_as_testing_each() {
+        let items = grid();
+        let expected: Vec<usize> = items.iter().filter(|(a, _)| frustum().intersects_aabb(a)).map(|(_, i)| *i).collect();
+        let bvh = Bvh::build(items);
+        assert_eq!(bvh.len(), 400);
+        assert!(bvh.depth() < 20, "depth {}", bvh.depth());
+
+        let (visible, stats) = culled(&bvh);
+        assert_eq!(visible, expected);
+        assert_eq!(stats.objects, 400);
+        assert_eq!(stats.visible, expected.len());
+        assert_eq!(stats.culled, 400 - expected.len());
+        // only the part of the tree near the frustum gets looked at
+        assert!(stats.tested < 400, "{:?}", stats);
+
+        let mut near = Vec::new();
+        bvh.query(&unit_box(0.0, 0.0, 0.0).expanded(1.0), &mut |_, i| near.push(*i));
+        assert_eq!(near, vec![210]);
+    }
+
+    #[test]
+    fn inserted_and_removed_objects_match_a_fresh_build() {
+        let mut bvh = Bvh::new();
+        let ids: Vec<ProxyId> = grid().into_iter().map(|(aabb, i)| bvh.insert(&aabb, i)).collect();
+        for id in ids.iter().step_by(3) {
+            bvh.remove(*id);
+        }
+        assert_eq!(bvh.remove(ids[0]), None);
+        let remaining: Vec<(Aabb, usize)> = grid().into_iter().enumerate().filter(|(n, _)| n % 3 != 0).map(|(_, item)| item).collect();
+        assert_eq!(bvh.len(), remaining.len());
+        assert_eq!(culled(&bvh).0, culled(&Bvh::build(remaining)).0);
+
+        // freed proxies are reused
+        let id = bvh.insert(&unit_box(0.0, 0.0, -5.0), 1000);
+        assert!(ids.contains(&id));
+        assert!(culled(&bvh).0.contains(&1000));
+        bvh.rebuild();
+        assert_eq!(bvh.get(id), Some(&1000));
+        assert!(culled(&bvh).0.contains(&1000));
+    }
+
+    #[test]
+    fn small_moves_stay_inside_the_enlarged_box() {
+        let mut bvh = Bvh::build(grid());
+        let id = bvh.insert(&unit_box(0.0, 0.0, 50.0), 1000);
+        assert!(!culled(&bvh).0.contains(&1000));
+
+        // inserted objects get the enlarged box, moves within it don't touch the tree
+        assert!(!bvh.update(id, &unit_box(0.0, 0.0, 49.95)));
+        assert!(!bvh.update(id, &unit_box(0.0, 0.0, 50.05)));
+        assert_eq!(bvh.aabb(id), Some(&unit_box(0.0, 0.0, 50.05)));
+        assert!(bvh.update(id, &unit_box(0.0, 0.0, 49.8)));
+        assert!(bvh.update(id, &unit_box(0.0, 0.0, -5.0)));
+        assert!(culled(&bvh).0.contains(&1000));
+
+        // right behind the near plane the enlarged box pokes into the
+        // frustum but the object doesn't
+        assert!(bvh.update(id, &unit_box(0.0, 0.0, 0.45)));
+        assert!(!bvh.update(id, &unit_box(0.0, 0.0, 0.5)));
+        assert!(!culled(&bvh).0.contains(&1000));
+        assert!(bvh.bounds().unwrap().contains_aabb(&unit_box(0.0, 0.0, 0.5)));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::glm::*;
 use crate::render_gl::to_radians;
+use crate::render_gl::frustum::{Frustum, Ray};
 
 pub struct Camera {
     position: Vec3,
@@ -7,7 +8,12 @@ pub struct Camera {
     up: Vec3,
     speed: f32,
     yaw: f32,
-    pitch: f32
+    pitch: f32,
+    // vertical field of view, in degrees
+    fov: f32,
+    aspect: f32,
+    near: f32,
+    far: f32
 }
 pub enum CameraMovement {
     Left,
@@ -28,8 +34,94 @@ impl Camera {
             speed: speed,
             yaw: -90.0,
             pitch: 0.0,
+            fov: 45.0,
+            aspect: 800.0 / 600.0,
+            near: 0.1,
+            far: 100.0,
         }
     }
+
+    pub fn set_perspective(&mut self, fov: f32, aspect: f32, near: f32, far: f32) {
+        self.fov = fov;
+        self.aspect = aspect;
+        self.near = near;
+        self.far = far;
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+    }
+
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    pub fn near(&self) -> f32 {
+        self.near
+    }
+
+    pub fn far(&self) -> f32 {
+        self.far
+    }
+
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov;
+    }
+
+    // places the camera directly, e.g when it is driven by a CameraPlayer
+    pub fn set_pose(&mut self, pos: &Vec3, front: &Vec3, up: &Vec3) {
+        self.position = pos.clone();
+        self.front = normalize(front);
+        self.up = normalize(up);
+        // keep the angles in step so the next update_angle starts from here
+        self.pitch = self.front.y.clamp(-1.0, 1.0).asin().to_degrees();
+        self.yaw = self.front.z.atan2(self.front.x).to_degrees();
+    }
+
+    pub fn projection(&self) -> Mat4 {
+        perspective(self.aspect, to_radians(self.fov), self.near, self.far)
+    }
+
+    pub fn view_projection(&self) -> Mat4 {
+        self.projection() * self.look_at()
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(&self.view_projection())
+    }
+
+    // (x, y) are window co-ordinates with the origin at the top left,
+    // as reported by SDL mouse events
+    pub fn unproject(&self, x: f32, y: f32, width: f32, height: f32) -> Ray {
+        let ndc_x = 2.0 * x / width - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / height;
+        let inv = inverse(&self.view_projection());
+        let unproject_ndc = |z: f32| {
+            let p = inv * make_vec4(&[ndc_x, ndc_y, z, 1.0]);
+            make_vec3(&[p.x / p.w, p.y / p.w, p.z / p.w])
+        };
+        let near = unproject_ndc(-1.0);
+        let far = unproject_ndc(1.0);
+        Ray::new(&near, &(far - near))
+    }
+
+    // returns window co-ordinates (origin top left) in x and y and the
+    // [0, 1] depth in z. None if the point is behind the camera
+    pub fn project(&self, point: &Vec3, width: f32, height: f32) -> Option<Vec3> {
+        let clip = self.view_projection() * make_vec4(&[point.x, point.y, point.z, 1.0]);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let ndc = make_vec3(&[clip.x / clip.w, clip.y / clip.w, clip.z / clip.w]);
+        Some(make_vec3(&[
+            (ndc.x + 1.0) * 0.5 * width,
+            (1.0 - ndc.y) * 0.5 * height,
+            (ndc.z + 1.0) * 0.5]))
+    }
     
     pub fn look_at(&self) -> Mat4 {
        look_at(&self.position, &&(self.front + self.position), &self.up)
@@ -43,6 +135,10 @@ impl Camera {
         self.front.clone()
     }
 
+    pub fn up(&self) -> Vec3 {
+        self.up.clone()
+    }
+
     pub fn update_angle(&mut self, pitch_offset: f32, yaw_offset: f32) {
         self.pitch += pitch_offset;
         self.yaw   += yaw_offset;
@@ -97,4 +193,63 @@ mod tests {
         let cam = Camera::new(&v, &g, &m, 3.0);
         assert_eq!(cam.position, make_vec3(&[0.0, 3.0, 5.0]));
     }
+
+    fn test_camera() -> Camera {
+        Camera::new(&make_vec3(&[0.0, 0.0, 5.0]),
+                    &make_vec3(&[0.0, 0.0, -1.0]),
+                    &make_vec3(&[0.0, 1.0, 0.0]), 0.3)
+    }
+
+    #[test]
+    fn screen_center_unprojects_along_front() {
+        let cam = test_camera();
+        let ray = cam.unproject(400.0, 300.0, 800.0, 600.0);
+        assert!(distance(&ray.direction, &cam.front()) < 1e-4);
+        // the ray starts on the near plane
+        assert!(distance(&ray.origin, &make_vec3(&[0.0, 0.0, 4.9])) < 1e-4);
+    }
+
+    #[test]
+    fn project_then_unproject_round_trips() {
+        let cam = test_camera();
+        let point = make_vec3(&[1.0, -0.5, -3.0]);
+        let screen = cam.project(&point, 800.0, 600.0).unwrap();
+        assert!(screen.z > 0.0 && screen.z < 1.0);
+        let ray = cam.unproject(screen.x, screen.y, 800.0, 600.0);
+        let to_point = normalize(&(point - ray.origin));
+        assert!(distance(&to_point, &ray.direction) < 1e-3);
+    }
+
+    #[test]
+    fn points_behind_camera_do_not_project() {
+        let cam = test_camera();
+        assert_eq!(cam.project(&make_vec3(&[0.0, 0.0, 10.0]), 800.0, 600.0), None);
+    }
+
+    #[test]
+    fn frustum_culls_objects_behind_and_beside() {
+        use crate::render_gl::frustum::{Aabb, BoundingSphere};
+        let cam = test_camera();
+        let frustum = cam.frustum();
+        assert!(frustum.contains_point(&make_vec3(&[0.0, 0.0, 0.0])));
+        assert!(!frustum.contains_point(&make_vec3(&[0.0, 0.0, 6.0])));
+        assert!(!frustum.contains_point(&make_vec3(&[0.0, 0.0, -200.0])));
+        let visible = Aabb::from_center_extents(&make_vec3(&[0.0, 0.0, -10.0]), &make_vec3(&[1.0, 1.0, 1.0]));
+        let off_screen = Aabb::from_center_extents(&make_vec3(&[50.0, 0.0, 0.0]), &make_vec3(&[1.0, 1.0, 1.0]));
+        assert!(frustum.intersects_aabb(&visible));
+        assert!(!frustum.intersects_aabb(&off_screen));
+        assert!(frustum.intersects_sphere(&BoundingSphere::new(&make_vec3(&[0.0, 0.0, 5.5]), 1.0)));
+        assert!(!frustum.intersects_sphere(&BoundingSphere::new(&make_vec3(&[0.0, 0.0, 8.0]), 1.0)));
+    }
+
+    #[test]
+    fn set_pose_updates_yaw_and_pitch() {
+        let mut cam = test_camera();
+        cam.set_pose(&make_vec3(&[1.0, 2.0, 3.0]), &make_vec3(&[1.0, 1.0, 0.0]), &make_vec3(&[0.0, 1.0, 0.0]));
+        assert!((cam.yaw - 0.0).abs() < 1e-3);
+        assert!((cam.pitch - 45.0).abs() < 1e-3);
+        cam.set_pose(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]), &make_vec3(&[0.0, 1.0, 0.0]));
+        assert!((cam.yaw + 90.0).abs() < 1e-3);
+        assert!(cam.pitch.abs() < 1e-3);
+    }
 }
@@ -0,0 +1,380 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Linear,
+    CatmullRom,
+    // cubic bezier through consecutive keyframes, with the inner control
+    // points taken from each keyframe's tangent (zero tangents ease in/out)
+    Bezier
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    pub position: Vec3,
+    // rotates the camera's local -z axis onto its front vector and +y onto up
+    pub orientation: Qua<f32>,
+    // vertical field of view in degrees
+    pub fov: f32,
+    pub tangent: Vec3
+}
+
+impl Keyframe {
+    pub fn new(time: f32, position: &Vec3, orientation: &Qua<f32>, fov: f32) -> Keyframe {
+        Keyframe {
+            time: time,
+            position: position.clone(),
+            orientation: orientation.clone(),
+            fov: fov,
+            tangent: make_vec3(&[0.0, 0.0, 0.0])
+        }
+    }
+
+    pub fn from_camera(time: f32, camera: &Camera) -> Keyframe {
+        Keyframe::new(time, &camera.position(), &orientation_from_vectors(&camera.front(), &camera.up()), camera.fov())
+    }
+}
+
+// the state of the camera at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPose {
+    pub position: Vec3,
+    pub orientation: Qua<f32>,
+    pub fov: f32
+}
+
+impl CameraPose {
+    pub fn front(&self) -> Vec3 {
+        quat_rotate_vec3(&self.orientation, &make_vec3(&[0.0, 0.0, -1.0]))
+    }
+
+    pub fn up(&self) -> Vec3 {
+        quat_rotate_vec3(&self.orientation, &make_vec3(&[0.0, 1.0, 0.0]))
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.set_pose(&self.position, &self.front(), &self.up());
+        camera.set_fov(self.fov);
+    }
+}
+
+pub fn orientation_from_vectors(front: &Vec3, up: &Vec3) -> Qua<f32> {
+    let front = normalize(front);
+    let right = normalize(&front.cross(up));
+    let up = right.cross(&front);
+    quat_normalize(&mat3_to_quat(&Mat3::from_columns(&[right, up, -front])))
+}
+
+pub struct CameraTrack {
+    pub interpolation: Interpolation,
+    keyframes: Vec<Keyframe>
+}
+
+impl CameraTrack {
+    pub fn new(interpolation: Interpolation) -> CameraTrack {
+        CameraTrack { interpolation: interpolation, keyframes: Vec::new() }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    // keeps the keyframes sorted by time. a keyframe at an existing time replaces it
+    pub fn add_keyframe(&mut self, keyframe: Keyframe) {
+        match self.keyframes.iter().position(|k| k.time >= keyframe.time) {
+            Some(i) if self.keyframes[i].time == keyframe.time => self.keyframes[i] = keyframe,
+            Some(i) => self.keyframes.insert(i, keyframe),
+            None => self.keyframes.push(keyframe)
+        }
+    }
+
+    pub fn record(&mut self, time: f32, camera: &Camera) {
+        self.add_keyframe(Keyframe::from_camera(time, camera));
+    }
+
+    pub fn duration(&self) -> f32 {
+        match self.keyframes.last() {
+            Some(k) => k.time,
+            None => 0.0
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> Option<CameraPose> {
+        let n = self.keyframes.len();
+        if n == 0 {
+            return None;
+        }
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[n - 1];
+        if n == 1 || time <= first.time {
+            return Some(pose_of(first));
+        }
+        if time >= last.time {
+            return Some(pose_of(last));
+        }
+        // index of the keyframe that starts the segment containing `time`
+        let i = self.keyframes.iter().rposition(|k| k.time <= time).unwrap();
+        let k1 = &self.keyframes[i];
+        let k2 = &self.keyframes[i + 1];
+        let t = (time - k1.time) / (k2.time - k1.time);
+
+        let position = match self.interpolation {
+            Interpolation::Linear => lerp(&k1.position, &k2.position, t),
+            Interpolation::CatmullRom => {
+                let p0 = if i == 0 { &k1.position } else { &self.keyframes[i - 1].position };
+                let p3 = if i + 2 >= n { &k2.position } else { &self.keyframes[i + 2].position };
+                catmull_rom(p0, &k1.position, &k2.position, p3, t)
+            }
+            Interpolation::Bezier => {
+                let c1 = k1.position + k1.tangent / 3.0;
+                let c2 = k2.position - k2.tangent / 3.0;
+                cubic_bezier(&k1.position, &c1, &c2, &k2.position, t)
+            }
+        };
+        // take the short way around
+        let q2 = if quat_dot(&k1.orientation, &k2.orientation) < 0.0 { -k2.orientation } else { k2.orientation };
+        Some(CameraPose {
+            position: position,
+            orientation: quat_normalize(&quat_slerp(&k1.orientation, &q2, t)),
+            fov: k1.fov + (k2.fov - k1.fov) * t
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        let file = TrackFile {
+            interpolation: self.interpolation,
+            keyframes: self.keyframes.iter().map(KeyframeFile::from).collect()
+        };
+        serde_json::to_string_pretty(&file).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(source: &str) -> Result<CameraTrack, String> {
+        let file: TrackFile = serde_json::from_str(source).map_err(|e| e.to_string())?;
+        let mut track = CameraTrack::new(file.interpolation);
+        for k in file.keyframes {
+            track.add_keyframe(k.into_keyframe());
+        }
+        Ok(track)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_json()?).map_err(|e| format!("unable to write {}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<CameraTrack, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        CameraTrack::from_json(&source)
+    }
+}
+
+fn pose_of(k: &Keyframe) -> CameraPose {
+    CameraPose { position: k.position.clone(), orientation: k.orientation.clone(), fov: k.fov }
+}
+
+fn catmull_rom(p0: &Vec3, p1: &Vec3, p2: &Vec3, p3: &Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3) * 0.5
+}
+
+fn cubic_bezier(p0: &Vec3, p1: &Vec3, p2: &Vec3, p3: &Vec3, t: f32) -> Vec3 {
+    let u = 1.0 - t;
+    p0 * (u * u * u) + p1 * (3.0 * u * u * t) + p2 * (3.0 * u * t * t) + p3 * (t * t * t)
+}
+
+// drives a camera along a track in real time
+pub struct CameraPlayer {
+    pub track: CameraTrack,
+    pub looping: bool,
+    time: f32,
+    playing: bool
+}
+
+impl CameraPlayer {
+    pub fn new(track: CameraTrack) -> CameraPlayer {
+        CameraPlayer { track: track, looping: false, time: 0.0, playing: false }
+    }
+
+    pub fn play(&mut self) {
+        self.time = 0.0;
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    // dt is in seconds
+    pub fn advance(&mut self, dt: f32, camera: &mut Camera) {
+        if !self.playing {
+            return;
+        }
+        self.time += dt;
+        let duration = self.track.duration();
+        if self.time > duration {
+            if self.looping && duration > 0.0 {
+                self.time %= duration;
+            } else {
+                self.time = duration;
+                self.playing = false;
+            }
+        }
+        if let Some(pose) = self.track.sample(self.time) {
+            pose.apply(camera);
+        }
+    }
+}
+
+// on disk representation. glm types are written out as plain arrays
+#[derive(Serialize, Deserialize)]
+struct TrackFile {
+    interpolation: Interpolation,
+    keyframes: Vec<KeyframeFile>
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyframeFile {
+    time: f32,
+    position: [f32; 3],
+    // x, y, z, w
+    orientation: [f32; 4],
+    fov: f32,
+    #[serde(default)]
+    tangent: [f32; 3]
+}
+
+impl<'a> From<&'a Keyframe> for KeyframeFile {
+    fn from(k: &'a Keyframe) -> KeyframeFile {
+        let q = &k.orientation.coords;
+        KeyframeFile {
+            time: k.time,
+            position: [k.position.x, k.position.y, k.position.z],
+            orientation: [q.x, q.y, q.z, q.w],
+            fov: k.fov,
+            tangent: [k.tangent.x, k.tangent.y, k.tangent.z]
+        }
+    }
+}
+
+impl KeyframeFile {
+    fn into_keyframe(self) -> Keyframe {
+        let o = self.orientation;
+        let mut k = Keyframe::new(self.time, &make_vec3(&self.position), &quat(o[0], o[1], o[2], o[3]), self.fov);
+        k.tangent = make_vec3(&self.tangent);
+        k
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(interpolation: Interpolation) -> CameraTrack {
+        let up = make_vec3(&[0.0, 1.0, 0.0]);
+        let mut track = CameraTrack::new(interpolation);
+        let positions = [[0.0, 0.0, 5.0], [5.0, 0.0, 0.0], [0.0, 0.0, -5.0], [-5.0, 1.0, 0.0]];
+        for (i, p) in positions.iter().enumerate() {
+            let position = make_vec3(p);
+            let orientation = orientation_from_vectors(&(-position), &up);
+            track.add_keyframe(Keyframe::new(i as f32, &position, &orientation, 45.0 + i as f32));
+        }
+        track
+    }
+
+    #[test]
+    fn orientation_round_trips_front_and_up() {
+        let front = normalize(&make_vec3(&[1.0, -0.5, -1.0]));
+        let q = orientation_from_vectors(&front, &make_vec3(&[0.0, 1.0, 0.0]));
+        let pose = CameraPose { position: make_vec3(&[0.0, 0.0, 0.0]), orientation: q, fov: 45.0 };
+        assert!(distance(&pose.front(), &front) < 1e-5);
+        assert!(dot(&pose.up(), &front).abs() < 1e-5);
+        assert!(pose.up().y > 0.0);
+    }
+
+    #[test]
+    fn samples_hit_keyframes_exactly() {
+        for interpolation in [Interpolation::Linear, Interpolation::CatmullRom, Interpolation::Bezier].iter() {
+            let track = track(*interpolation);
+            for k in track.keyframes() {
+                let pose = track.sample(k.time).unwrap();
+                assert!(distance(&pose.position, &k.position) < 1e-4);
+                assert!((pose.fov - k.fov).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_is_clamped_to_the_track() {
+        let track = track(Interpolation::CatmullRom);
+        assert_eq!(track.sample(-1.0).unwrap().position, track.keyframes()[0].position);
+        assert_eq!(track.sample(100.0).unwrap().position, track.keyframes()[3].position);
+        assert!(CameraTrack::new(Interpolation::Linear).sample(0.0).is_none());
+    }
+
+    #[test]
+    fn linear_midpoint_and_slerp() {
+        let track = track(Interpolation::Linear);
+        let pose = track.sample(0.5).unwrap();
+        assert!(distance(&pose.position, &make_vec3(&[2.5, 0.0, 2.5])) < 1e-5);
+        assert!((pose.fov - 45.5).abs() < 1e-5);
+        // halfway between looking down -z and looking down -x
+        let expected = normalize(&make_vec3(&[-1.0, 0.0, -1.0]));
+        assert!(distance(&pose.front(), &expected) < 1e-4);
+    }
+
+    #[test]
+    fn keyframes_stay_sorted() {
+        let mut track = track(Interpolation::Linear);
+        let q = quat_identity();
+        track.add_keyframe(Keyframe::new(1.5, &make_vec3(&[1.0, 1.0, 1.0]), &q, 60.0));
+        track.add_keyframe(Keyframe::new(1.0, &make_vec3(&[2.0, 2.0, 2.0]), &q, 60.0));
+        let times: Vec<f32> = track.keyframes().iter().map(|k| k.time).collect();
+        assert_eq!(times, vec![0.0, 1.0, 1.5, 2.0, 3.0]);
+        assert_eq!(track.keyframes()[1].position, make_vec3(&[2.0, 2.0, 2.0]));
+    }
+
+    #[test]
+    fn json_round_trip_is_exact() {
+        let mut original = track(Interpolation::Bezier);
+        let mut k = Keyframe::new(0.1234567, &make_vec3(&[0.1, 0.2, 0.3]),
+                                  &orientation_from_vectors(&make_vec3(&[0.3, 0.1, -1.0]), &make_vec3(&[0.0, 1.0, 0.0])), 33.3);
+        k.tangent = make_vec3(&[1.0 / 3.0, 0.0, -2.0]);
+        original.add_keyframe(k);
+        let loaded = CameraTrack::from_json(&original.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.interpolation, original.interpolation);
+        assert_eq!(loaded.keyframes(), original.keyframes());
+        for i in 0..100 {
+            let t = i as f32 * 0.031;
+            assert_eq!(loaded.sample(t), original.sample(t));
+        }
+    }
+
+    #[test]
+    fn player_drives_camera_and_stops_at_the_end() {
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        let mut player = CameraPlayer::new(track(Interpolation::Linear));
+        player.play();
+        player.advance(0.5, &mut camera);
+        assert!(distance(&camera.position(), &make_vec3(&[2.5, 0.0, 2.5])) < 1e-5);
+        player.advance(10.0, &mut camera);
+        assert!(!player.is_playing());
+        assert!(distance(&camera.position(), &make_vec3(&[-5.0, 1.0, 0.0])) < 1e-5);
+        assert!((camera.fov() - 48.0).abs() < 1e-5);
+    }
+}
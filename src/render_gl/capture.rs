@@ -0,0 +1,487 @@
+use crate::render_gl::framebuffer::Framebuffer;
+use gl::types::*;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+// an RGBA8 image, top row first like image files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Image {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        Image { width: width, height: height, pixels: pixels }
+    }
+
+    // from pixels read back from GL, bottom row first
+    pub fn from_gl(width: u32, height: u32, pixels: &[u8]) -> Image {
+        Image::new(width, height, flip_rows(pixels, (width * 4) as usize))
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &[u8] {
+        let i = ((y * self.width + x) * 4) as usize;
+        &self.pixels[i..i + 4]
+    }
+
+    pub fn load(path: &Path) -> Result<Image, String> {
+        let image = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let rgba = image.as_rgba8().ok_or_else(|| format!("{}: not an RGBA8 image", path.display()))?;
+        Ok(Image::new(rgba.width(), rgba.height(), rgba.clone().into_raw()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        create_parent(path)?;
+        let buffer: image::RgbaImage = image::ImageBuffer::from_raw(self.width, self.height, self.pixels.clone())
+            .ok_or_else(|| format!("{}: bad image size", path.display()))?;
+        buffer.save(path).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// a floating point RGBA image, top row first, for HDR targets
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<f32>
+}
+
+impl HdrImage {
+    pub fn from_gl(width: u32, height: u32, pixels: &[f32]) -> HdrImage {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        HdrImage { width: width, height: height, pixels: flip_rows(pixels, (width * 4) as usize) }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        create_parent(path)?;
+        std::fs::write(path, encode_exr(self.width, self.height, &self.pixels))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// GL stores the bottom row first, image files the top one
+pub fn flip_rows<T: Clone>(pixels: &[T], row_length: usize) -> Vec<T> {
+    let mut flipped = Vec::with_capacity(pixels.len());
+    for row in pixels.chunks(row_length).rev() {
+        flipped.extend_from_slice(row);
+    }
+    flipped
+}
+
+fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() =>
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e)),
+        _ => Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureFormat {
+    // 8 bits per channel, what ends up on screen
+    Png,
+    // 32 bit float OpenEXR, keeps HDR values
+    Exr
+}
+
+impl CaptureFormat {
+    pub fn from_path(path: &Path) -> Result<CaptureFormat, String> {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()) {
+            Some(ref e) if e == "png" => Ok(CaptureFormat::Png),
+            Some(ref e) if e == "exr" => Ok(CaptureFormat::Exr),
+            _ => Err(format!("{}: captures are written as .png or .exr", path.display()))
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Png => "png",
+            CaptureFormat::Exr => "exr"
+        }
+    }
+}
+
+// what to read back: the window's back buffer, which has to be read before
+// swapping, or a colour attachment of a framebuffer
+#[derive(Clone, Copy)]
+pub enum Source<'a> {
+    Window { width: u32, height: u32 },
+    Attachment(&'a Framebuffer, usize)
+}
+
+impl<'a> Source<'a> {
+    pub fn size(&self) -> (u32, u32) {
+        match *self {
+            Source::Window { width, height } => (width, height),
+            Source::Attachment(framebuffer, _) => (framebuffer.width(), framebuffer.height())
+        }
+    }
+
+    fn bind(&self) {
+        unsafe {
+            match *self {
+                Source::Window { .. } => {
+                    gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+                    gl::ReadBuffer(gl::BACK);
+                }
+                Source::Attachment(framebuffer, index) => {
+                    gl::BindFramebuffer(gl::READ_FRAMEBUFFER, framebuffer.id());
+                    gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + index as GLenum);
+                }
+            }
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        }
+    }
+}
+
+// RGBA8 pixels, bottom row first as GL stores them. waits for the GPU
+pub fn read_pixels(source: Source) -> Vec<u8> {
+    let (width, height) = source.size();
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    source.bind();
+    unsafe {
+        gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGBA, gl::UNSIGNED_BYTE,
+                       pixels.as_mut_ptr() as *mut GLvoid);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+    }
+    pixels
+}
+
+// RGBA floats, bottom row first. values outside 0..1 survive for float targets
+pub fn read_pixels_f32(source: Source) -> Vec<f32> {
+    let (width, height) = source.size();
+    let mut pixels = vec![0f32; (width * height * 4) as usize];
+    source.bind();
+    unsafe {
+        gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGBA, gl::FLOAT,
+                       pixels.as_mut_ptr() as *mut GLvoid);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+    }
+    pixels
+}
+
+pub fn capture(source: Source) -> Image {
+    let (width, height) = source.size();
+    Image::from_gl(width, height, &read_pixels(source))
+}
+
+pub fn capture_hdr(source: Source) -> HdrImage {
+    let (width, height) = source.size();
+    HdrImage::from_gl(width, height, &read_pixels_f32(source))
+}
+
+// writes `source` to a .png or .exr file
+pub fn screenshot(source: Source, path: &Path) -> Result<(), String> {
+    match CaptureFormat::from_path(path)? {
+        CaptureFormat::Png => capture(source).save(path),
+        CaptureFormat::Exr => capture_hdr(source).save(path)
+    }
+}
+
+struct Pending {
+    buffer: GLuint,
+    fence: GLsync,
+    width: u32,
+    height: u32,
+    tag: u64
+}
+
+// reads RGBA8 pixels into pixel buffer objects and hands them out once the
+// GPU is done, so capturing doesn't stall the frame. with two or more
+// buffers a frame can be copied while the previous one is still in flight
+pub struct PixelReadback {
+    free: Vec<GLuint>,
+    pending: VecDeque<Pending>
+}
+
+impl PixelReadback {
+    pub fn new(buffers: usize) -> PixelReadback {
+        let mut free = vec![0; buffers.max(1)];
+        unsafe {
+            gl::GenBuffers(free.len() as GLsizei, free.as_mut_ptr());
+        }
+        PixelReadback { free: free, pending: VecDeque::new() }
+    }
+
+    // starts copying `source`. `tag` comes back with the image, e.g a frame
+    // number. false when every buffer is still waiting to be collected
+    pub fn request(&mut self, source: Source, tag: u64) -> bool {
+        let buffer = match self.free.pop() {
+            Some(buffer) => buffer,
+            None => return false
+        };
+        let (width, height) = source.size();
+        source.bind();
+        let fence = unsafe {
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, buffer);
+            gl::BufferData(gl::PIXEL_PACK_BUFFER, (width * height * 4) as GLsizeiptr, std::ptr::null(), gl::STREAM_READ);
+            gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGBA, gl::UNSIGNED_BYTE, std::ptr::null_mut());
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+            gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0)
+        };
+        self.pending.push_back(Pending { buffer: buffer, fence: fence, width: width, height: height, tag: tag });
+        true
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // the oldest readback if it has finished
+    pub fn poll(&mut self) -> Option<(u64, Image)> {
+        self.collect(0)
+    }
+
+    // the oldest readback, waiting for it if needed
+    pub fn wait(&mut self) -> Option<(u64, Image)> {
+        self.collect(std::u64::MAX)
+    }
+
+    fn collect(&mut self, timeout: u64) -> Option<(u64, Image)> {
+        let ready = match self.pending.front() {
+            Some(p) => unsafe {
+                let status = gl::ClientWaitSync(p.fence, gl::SYNC_FLUSH_COMMANDS_BIT, timeout);
+                status == gl::ALREADY_SIGNALED || status == gl::CONDITION_SATISFIED || status == gl::WAIT_FAILED
+            },
+            None => false
+        };
+        if !ready {
+            return None;
+        }
+        let p = self.pending.pop_front().unwrap();
+        let size = (p.width * p.height * 4) as usize;
+        let mut pixels = vec![0u8; size];
+        unsafe {
+            gl::DeleteSync(p.fence);
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, p.buffer);
+            let mapped = gl::MapBufferRange(gl::PIXEL_PACK_BUFFER, 0, size as GLsizeiptr, gl::MAP_READ_BIT);
+            if !mapped.is_null() {
+                std::ptr::copy_nonoverlapping(mapped as *const u8, pixels.as_mut_ptr(), size);
+                gl::UnmapBuffer(gl::PIXEL_PACK_BUFFER);
+            }
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+        }
+        self.free.push(p.buffer);
+        Some((p.tag, Image::from_gl(p.width, p.height, &pixels)))
+    }
+}
+
+impl Drop for PixelReadback {
+    fn drop(&mut self) {
+        unsafe {
+            for p in self.pending.drain(..) {
+                gl::DeleteSync(p.fence);
+                self.free.push(p.buffer);
+            }
+            gl::DeleteBuffers(self.free.len() as GLsizei, self.free.as_ptr());
+        }
+    }
+}
+
+// dumps numbered frames (frame_00000.png, ...) into a directory for
+// assembling videos. the caller should advance the scene by `timestep`
+// every frame while recording, so the video plays at 1 / timestep fps
+// whatever the real frame rate was
+pub struct Recorder {
+    dir: PathBuf,
+    pub timestep: f32,
+    format: CaptureFormat,
+    frame: u32,
+    readback: PixelReadback
+}
+
+impl Recorder {
+    pub fn new(dir: &Path, timestep: f32, format: CaptureFormat) -> Result<Recorder, String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            timestep: timestep,
+            format: format,
+            frame: 0,
+            readback: PixelReadback::new(3)
+        })
+    }
+
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        self.dir.join(frame_file_name(frame, self.format))
+    }
+
+    // frames captured so far
+    pub fn frames(&self) -> u32 {
+        self.frame
+    }
+
+    // call once per frame after drawing it. PNG frames are read back
+    // asynchronously and written a frame or two later, EXR frames right away
+    pub fn capture(&mut self, source: Source) -> Result<(), String> {
+        match self.format {
+            CaptureFormat::Exr => capture_hdr(source).save(&self.frame_path(self.frame))?,
+            CaptureFormat::Png => {
+                self.write_ready()?;
+                if !self.readback.request(source, self.frame as u64) {
+                    // every buffer is busy, wait for the oldest
+                    self.write_next()?;
+                    self.readback.request(source, self.frame as u64);
+                }
+            }
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    // writes the frames still in flight, returns how many were recorded
+    pub fn finish(&mut self) -> Result<u32, String> {
+        while self.readback.pending() > 0 {
+            self.write_next()?;
+        }
+        Ok(self.frame)
+    }
+
+    fn write_ready(&mut self) -> Result<(), String> {
+        while let Some((frame, image)) = self.readback.poll() {
+            image.save(&self.frame_path(frame as u32))?;
+        }
+        Ok(())
+    }
+
+    fn write_next(&mut self) -> Result<(), String> {
+        if let Some((frame, image)) = self.readback.wait() {
+            image.save(&self.frame_path(frame as u32))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn frame_file_name(frame: u32, format: CaptureFormat) -> String {
+    format!("frame_{:05}.{}", frame, format.extension())
+}
+
+// an uncompressed scanline OpenEXR file with 32 bit float A, B, G and R
+// channels. `rgba` is top row first.
+// Source: https://www.openexr.com/documentation/openexrfilelayout.pdf
+pub fn encode_exr(width: u32, height: u32, rgba: &[f32]) -> Vec<u8> {
+    assert_eq!(rgba.len(), (width * height * 4) as usize);
+    fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.extend_from_slice(kind.as_bytes());
+        out.push(0);
+        out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+    // channels are stored in alphabetical order
+    const CHANNELS: [(&str, usize); 4] = [("A", 3), ("B", 2), ("G", 1), ("R", 0)];
+    const FLOAT: i32 = 2;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    out.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut channels = Vec::new();
+    for (name, _) in CHANNELS.iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&FLOAT.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    attribute(&mut out, "channels", "chlist", &channels);
+    attribute(&mut out, "compression", "compression", &[0]);
+    attribute(&mut out, "dataWindow", "box2i", &window);
+    attribute(&mut out, "displayWindow", "box2i", &window);
+    attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0);
+
+    // one block per scanline: y, byte count, then each channel's row
+    let row_bytes = (width * 4 * 4) as usize;
+    let table_end = out.len() + height as usize * 8;
+    for y in 0..height as usize {
+        out.extend_from_slice(&((table_end + y * (8 + row_bytes)) as u64).to_le_bytes());
+    }
+    for y in 0..height as usize {
+        out.extend_from_slice(&(y as i32).to_le_bytes());
+        out.extend_from_slice(&(row_bytes as i32).to_le_bytes());
+        let row = &rgba[y * width as usize * 4..(y + 1) * width as usize * 4];
+        for (_, channel) in CHANNELS.iter() {
+            for pixel in row.chunks(4) {
+                out.extend_from_slice(&pixel[*channel].to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_flipped() {
+        let pixels = [1, 1, 2, 2, 3, 3];
+        assert_eq!(flip_rows(&pixels, 2), vec![3, 3, 2, 2, 1, 1]);
+        let image = Image::from_gl(1, 2, &[1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(image.pixel(0, 0), &[2, 2, 2, 2]);
+        assert_eq!(image.pixel(0, 1), &[1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn format_follows_the_extension() {
+        assert_eq!(CaptureFormat::from_path(Path::new("shot.PNG")), Ok(CaptureFormat::Png));
+        assert_eq!(CaptureFormat::from_path(Path::new("out/hdr.exr")), Ok(CaptureFormat::Exr));
+        assert!(CaptureFormat::from_path(Path::new("shot.jpg")).is_err());
+        assert!(CaptureFormat::from_path(Path::new("shot")).is_err());
+    }
+
+    #[test]
+    fn frames_are_numbered_for_sorting() {
+        assert_eq!(frame_file_name(7, CaptureFormat::Png), "frame_00007.png");
+        assert_eq!(frame_file_name(12345, CaptureFormat::Exr), "frame_12345.exr");
+    }
+
+    #[test]
+    fn exr_layout() {
+        let (width, height) = (3, 2);
+        let pixels: Vec<f32> = (0..width * height * 4).map(|i| i as f32).collect();
+        let exr = encode_exr(width, height, &pixels);
+        assert_eq!(&exr[0..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        let row_bytes = (width * 4 * 4) as usize;
+        let blocks = height as usize * (8 + row_bytes);
+        let table = exr.len() - blocks - height as usize * 8;
+        let offset = |y: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&exr[table + y * 8..table + y * 8 + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        assert_eq!(offset(0), table + height as usize * 8);
+        assert_eq!(offset(1), offset(0) + 8 + row_bytes);
+        // header ends right before the offset table
+        assert_eq!(exr[table - 1], 0);
+
+        // second scanline: y = 1, then the alpha of its first pixel
+        let block = offset(1);
+        let read_f32 = |at: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&exr[at..at + 4]);
+            f32::from_le_bytes(bytes)
+        };
+        assert_eq!(&exr[block..block + 4], &1i32.to_le_bytes());
+        assert_eq!(read_f32(block + 8), pixels[(width * 4 + 3) as usize]);
+        // the red channel comes last
+        assert_eq!(read_f32(block + 8 + 3 * width as usize * 4), pixels[(width * 4) as usize]);
+    }
+}
@@ -0,0 +1,531 @@
+use gl::types::*;
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+// log target of everything the driver reports
+pub const LOG_TARGET: &str = "gl";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugSource {
+    Api,
+    WindowSystem,
+    ShaderCompiler,
+    ThirdParty,
+    Application,
+    Other
+}
+
+impl DebugSource {
+    pub fn from_gl(source: GLenum) -> DebugSource {
+        match source {
+            gl::DEBUG_SOURCE_API => DebugSource::Api,
+            gl::DEBUG_SOURCE_WINDOW_SYSTEM => DebugSource::WindowSystem,
+            gl::DEBUG_SOURCE_SHADER_COMPILER => DebugSource::ShaderCompiler,
+            gl::DEBUG_SOURCE_THIRD_PARTY => DebugSource::ThirdParty,
+            gl::DEBUG_SOURCE_APPLICATION => DebugSource::Application,
+            _ => DebugSource::Other
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugType {
+    Error,
+    DeprecatedBehavior,
+    UndefinedBehavior,
+    Portability,
+    Performance,
+    Marker,
+    PushGroup,
+    PopGroup,
+    Other
+}
+
+impl DebugType {
+    pub fn from_gl(kind: GLenum) -> DebugType {
+        match kind {
+            gl::DEBUG_TYPE_ERROR => DebugType::Error,
+            gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR => DebugType::DeprecatedBehavior,
+            gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR => DebugType::UndefinedBehavior,
+            gl::DEBUG_TYPE_PORTABILITY => DebugType::Portability,
+            gl::DEBUG_TYPE_PERFORMANCE => DebugType::Performance,
+            gl::DEBUG_TYPE_MARKER => DebugType::Marker,
+            gl::DEBUG_TYPE_PUSH_GROUP => DebugType::PushGroup,
+            gl::DEBUG_TYPE_POP_GROUP => DebugType::PopGroup,
+            _ => DebugType::Other
+        }
+    }
+}
+
+// ordered from least to most severe, so settings can filter with >=
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DebugSeverity {
+    Notification,
+    Low,
+    Medium,
+    High
+}
+
+impl DebugSeverity {
+    pub fn from_gl(severity: GLenum) -> DebugSeverity {
+        match severity {
+            gl::DEBUG_SEVERITY_HIGH => DebugSeverity::High,
+            gl::DEBUG_SEVERITY_MEDIUM => DebugSeverity::Medium,
+            gl::DEBUG_SEVERITY_LOW => DebugSeverity::Low,
+            _ => DebugSeverity::Notification
+        }
+    }
+
+    pub fn level(&self) -> log::Level {
+        match self {
+            DebugSeverity::High => log::Level::Error,
+            DebugSeverity::Medium => log::Level::Warn,
+            DebugSeverity::Low => log::Level::Info,
+            DebugSeverity::Notification => log::Level::Debug
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugMessage {
+    pub source: DebugSource,
+    pub kind: DebugType,
+    pub severity: DebugSeverity,
+    pub id: u32,
+    pub text: String
+}
+
+impl DebugMessage {
+    pub fn is_error(&self) -> bool {
+        self.kind == DebugType::Error || self.severity == DebugSeverity::High
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugSettings {
+    // quieter messages are dropped
+    pub min_severity: DebugSeverity,
+    // message ids to ignore, e.g the driver's chatty buffer usage hints
+    pub suppressed: Vec<u32>,
+    // panic on errors. the callback runs inside the driver, which can't be
+    // unwound through, so it only records the error and the panic happens
+    // in the next gl_check! or check(), right after the failing call when
+    // that call is wrapped
+    pub panic_on_error: bool
+}
+
+impl Default for DebugSettings {
+    fn default() -> DebugSettings {
+        DebugSettings {
+            min_severity: DebugSeverity::Low,
+            // NVIDIA: buffer placed in video memory, shader recompiled,
+            // program being recompiled, texture state usage warning
+            suppressed: vec![131185, 131218, 131204, 131169],
+            panic_on_error: cfg!(debug_assertions)
+        }
+    }
+}
+
+impl DebugSettings {
+    pub fn allows(&self, message: &DebugMessage) -> bool {
+        message.severity >= self.min_severity && !self.suppressed.contains(&message.id)
+    }
+}
+
+// how errors get noticed on the current context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugMode {
+    // KHR_debug or GL 4.3: the driver calls us for every message
+    Callback,
+    // no debug output. glGetError is checked after each call wrapped in
+    // gl_check!; an error from any other call only shows up at the next
+    // check(), which can't tell which call it came from
+    GetError
+}
+
+static SETTINGS: Mutex<Option<DebugSettings>> = Mutex::new(None);
+// errors since the last take_errors, for tests to assert on
+static ERRORS: Mutex<Vec<DebugMessage>> = Mutex::new(Vec::new());
+static CHECK_ERRORS: AtomicBool = AtomicBool::new(false);
+// an error the callback saw with panic_on_error set, raised by check()
+static PENDING_PANIC: Mutex<Option<DebugMessage>> = Mutex::new(None);
+static HAS_PENDING_PANIC: AtomicBool = AtomicBool::new(false);
+// whether KHR_debug is there: 0 not checked yet, 1 yes, 2 no
+static SUPPORT: AtomicU8 = AtomicU8::new(0);
+const MAX_KEPT_ERRORS: usize = 64;
+
+// routes driver messages to the log crate for the current context. call
+// once after loading the GL functions
+pub fn install(settings: DebugSettings) -> DebugMode {
+    *SETTINGS.lock().unwrap() = Some(settings);
+    SUPPORT.store(0, Ordering::Relaxed);
+    if !debug_supported() {
+        CHECK_ERRORS.store(true, Ordering::Relaxed);
+        log::info!(target: LOG_TARGET, "KHR_debug is missing, checking glGetError after wrapped calls");
+        return DebugMode::GetError;
+    }
+    CHECK_ERRORS.store(false, Ordering::Relaxed);
+    unsafe {
+        gl::Enable(gl::DEBUG_OUTPUT);
+        // so messages arrive on the thread and inside the call that caused them
+        gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+        gl::DebugMessageCallback(Some(callback), std::ptr::null());
+        gl::DebugMessageControl(gl::DONT_CARE, gl::DONT_CARE, gl::DONT_CARE, 0, std::ptr::null(), gl::TRUE);
+    }
+    DebugMode::Callback
+}
+
+pub fn mode() -> DebugMode {
+    if CHECK_ERRORS.load(Ordering::Relaxed) {
+        DebugMode::GetError
+    } else {
+        DebugMode::Callback
+    }
+}
+
+pub fn take_errors() -> Vec<DebugMessage> {
+    std::mem::take(&mut *ERRORS.lock().unwrap())
+}
+
+pub fn has_extension(name: &str) -> bool {
+    unsafe {
+        let mut count = 0;
+        gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+        (0..count as GLuint).any(|i| {
+            let extension = gl::GetStringi(gl::EXTENSIONS, i);
+            !extension.is_null() && CStr::from_ptr(extension as *const _).to_bytes() == name.as_bytes()
+        })
+    }
+}
+
+// for when the functions behind gl::* are swapped, e.g by the mock
+pub(crate) fn forget_support() {
+    SUPPORT.store(0, Ordering::Relaxed);
+}
+
+// KHR_debug or GL 4.3, checked once. labels and groups are skipped without it
+pub fn debug_supported() -> bool {
+    match SUPPORT.load(Ordering::Relaxed) {
+        1 => return true,
+        2 => return false,
+        _ => {}
+    }
+    // nothing to ask before the functions are loaded, so don't remember it
+    if !gl::DebugMessageCallback::is_loaded() || !gl::GetIntegerv::is_loaded() {
+        return false;
+    }
+    let (mut major, mut minor) = (0, 0);
+    unsafe {
+        gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+        gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+    }
+    let supported = (major, minor) >= (4, 3) || has_extension("GL_KHR_debug");
+    SUPPORT.store(if supported { 1 } else { 2 }, Ordering::Relaxed);
+    supported
+}
+
+// names an object for capture tools like RenderDoc and apitrace, and for
+// driver messages. `identifier` is GL_TEXTURE, GL_BUFFER, GL_PROGRAM etc
+pub fn label(identifier: GLenum, name: GLuint, label: &str) {
+    if name == 0 || !debug_supported() {
+        return;
+    }
+    unsafe {
+        gl::ObjectLabel(identifier, name, label.len() as GLsizei, label.as_ptr() as *const GLchar);
+    }
+}
+
+pub fn label_texture(texture: GLuint, text: &str) {
+    label(gl::TEXTURE, texture, text);
+}
+
+pub fn label_buffer(buffer: GLuint, text: &str) {
+    label(gl::BUFFER, buffer, text);
+}
+
+// the label given to an object, empty if it has none
+pub fn object_label(identifier: GLenum, name: GLuint) -> String {
+    if name == 0 || !debug_supported() {
+        return String::new();
+    }
+    let mut buffer = vec![0u8; 256];
+    let mut length = 0;
+    unsafe {
+        gl::GetObjectLabel(identifier, name, buffer.len() as GLsizei, &mut length, buffer.as_mut_ptr() as *mut GLchar);
+    }
+    buffer.truncate(length.max(0) as usize);
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+// marks a region of commands, e.g a render pass, as a named group in
+// capture tools. the group ends when this is dropped:
+// let _group = DebugGroup::new("shadows");
+pub struct DebugGroup {
+    pushed: bool
+}
+
+impl DebugGroup {
+    pub fn new(name: &str) -> DebugGroup {
+        if !debug_supported() {
+            return DebugGroup { pushed: false };
+        }
+        unsafe {
+            gl::PushDebugGroup(gl::DEBUG_SOURCE_APPLICATION, 0, name.len() as GLsizei, name.as_ptr() as *const GLchar);
+        }
+        DebugGroup { pushed: true }
+    }
+}
+
+impl Drop for DebugGroup {
+    fn drop(&mut self) {
+        if self.pushed {
+            unsafe {
+                gl::PopDebugGroup();
+            }
+        }
+    }
+}
+
+// called by the driver, so nothing may unwind out of here: errors to panic
+// on are left for check(), and a panic in the logger aborts
+extern "system" fn callback(source: GLenum, kind: GLenum, id: GLuint, severity: GLenum, length: GLsizei,
+                            text: *const GLchar, _user: *mut c_void) {
+    let received = std::panic::catch_unwind(|| {
+        let text = unsafe {
+            if length >= 0 {
+                String::from_utf8_lossy(std::slice::from_raw_parts(text as *const u8, length as usize)).into_owned()
+            } else {
+                CStr::from_ptr(text).to_string_lossy().into_owned()
+            }
+        };
+        let message = DebugMessage {
+            source: DebugSource::from_gl(source),
+            kind: DebugType::from_gl(kind),
+            severity: DebugSeverity::from_gl(severity),
+            id: id,
+            text: text.trim_end().to_string()
+        };
+        if report(&message) {
+            let mut pending = PENDING_PANIC.lock().unwrap_or_else(|e| e.into_inner());
+            if pending.is_none() {
+                *pending = Some(message);
+            }
+            HAS_PENDING_PANIC.store(true, Ordering::Relaxed);
+        }
+    });
+    if received.is_err() {
+        eprintln!("panic in the GL debug callback, aborting");
+        std::process::abort();
+    }
+}
+
+// logs and records the message. true if it is an error to panic on
+fn report(message: &DebugMessage) -> bool {
+    let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default();
+    if !settings.allows(message) {
+        return false;
+    }
+    log::log!(target: LOG_TARGET, message.severity.level(), "[{:?} {:?} {}] {}",
+              message.source, message.kind, message.id, message.text);
+    if !message.is_error() {
+        return false;
+    }
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    if errors.len() < MAX_KEPT_ERRORS {
+        errors.push(message.clone());
+    }
+    settings.panic_on_error
+}
+
+pub fn error_name(error: GLenum) -> &'static str {
+    match error {
+        gl::INVALID_ENUM => "GL_INVALID_ENUM",
+        gl::INVALID_VALUE => "GL_INVALID_VALUE",
+        gl::INVALID_OPERATION => "GL_INVALID_OPERATION",
+        gl::INVALID_FRAMEBUFFER_OPERATION => "GL_INVALID_FRAMEBUFFER_OPERATION",
+        gl::OUT_OF_MEMORY => "GL_OUT_OF_MEMORY",
+        gl::STACK_UNDERFLOW => "GL_STACK_UNDERFLOW",
+        gl::STACK_OVERFLOW => "GL_STACK_OVERFLOW",
+        _ => "unknown GL error"
+    }
+}
+
+// drains glGetError after `call`, reporting each error like a debug message
+pub fn check_errors(call: &str, file: &str, line: u32) {
+    drain_errors(&format!("{} at {}:{}", call, file, line));
+}
+
+fn drain_errors(after: &str) {
+    loop {
+        let error = unsafe { gl::GetError() };
+        if error == gl::NO_ERROR {
+            break;
+        }
+        let message = DebugMessage {
+            source: DebugSource::Api,
+            kind: DebugType::Error,
+            severity: DebugSeverity::High,
+            id: error,
+            text: format!("{} after {}", error_name(error), after)
+        };
+        if report(&message) {
+            panic!("GL error {}: {}", message.id, message.text);
+        }
+    }
+}
+
+// panics with an error the callback recorded, if panic_on_error is set
+fn raise_pending() {
+    if !HAS_PENDING_PANIC.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if let Some(message) = PENDING_PANIC.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        panic!("GL error {}: {}", message.id, message.text);
+    }
+}
+
+#[doc(hidden)]
+pub fn after_call(call: &str, file: &str, line: u32) {
+    raise_pending();
+    if CHECK_ERRORS.load(Ordering::Relaxed) {
+        check_errors(call, file, line);
+    }
+}
+
+// reports errors from calls that aren't wrapped in gl_check!. without debug
+// output glGetError only tells something failed since the last check, so
+// the binaries call this once per frame: debug::check("the frame")
+pub fn check(after: &str) {
+    raise_pending();
+    if CHECK_ERRORS.load(Ordering::Relaxed) {
+        drain_errors(after);
+    }
+}
+
+// wraps a GL call so glGetError is checked after it when the context has no
+// debug output, and errors the callback recorded are raised right after the
+// call. an atomic load otherwise: gl_check!(gl::DrawArrays(gl::TRIANGLES, 0, 3)).
+// only the calls wrapped in it are checked one by one, and not every GL
+// call in the crate is
+#[macro_export]
+macro_rules! gl_check {
+    ($call:expr) => {{
+        let result = $call;
+        $crate::render_gl::debug::after_call(stringify!($call), file!(), line!());
+        result
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::headless::HeadlessContext;
+
+    fn message(severity: DebugSeverity, id: u32) -> DebugMessage {
+        DebugMessage {
+            source: DebugSource::Api,
+            kind: DebugType::Other,
+            severity: severity,
+            id: id,
+            text: String::new()
+        }
+    }
+
+    #[test]
+    fn enums_are_decoded() {
+        assert_eq!(DebugSource::from_gl(gl::DEBUG_SOURCE_SHADER_COMPILER), DebugSource::ShaderCompiler);
+        assert_eq!(DebugType::from_gl(gl::DEBUG_TYPE_PERFORMANCE), DebugType::Performance);
+        assert_eq!(DebugSeverity::from_gl(gl::DEBUG_SEVERITY_MEDIUM), DebugSeverity::Medium);
+        assert_eq!(DebugSeverity::from_gl(gl::DEBUG_SEVERITY_NOTIFICATION), DebugSeverity::Notification);
+        assert_eq!(DebugSeverity::High.level(), log::Level::Error);
+        assert_eq!(error_name(gl::INVALID_OPERATION), "GL_INVALID_OPERATION");
+    }
+
+    #[test]
+    fn settings_filter_severity_and_ids() {
+        let settings = DebugSettings { min_severity: DebugSeverity::Medium, suppressed: vec![7], panic_on_error: false };
+        assert!(settings.allows(&message(DebugSeverity::High, 1)));
+        assert!(settings.allows(&message(DebugSeverity::Medium, 1)));
+        assert!(!settings.allows(&message(DebugSeverity::Low, 1)));
+        assert!(!settings.allows(&message(DebugSeverity::High, 7)));
+        assert!(message(DebugSeverity::High, 1).is_error());
+        assert!(!message(DebugSeverity::Low, 1).is_error());
+    }
+
+    #[test]
+    fn callback_errors_panic_later_in_rust() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let settings = SETTINGS.lock().unwrap().replace(DebugSettings { panic_on_error: true, ..DebugSettings::default() });
+        let checked = CHECK_ERRORS.swap(false, Ordering::Relaxed);
+        take_errors();
+        let text = "from the driver";
+        callback(gl::DEBUG_SOURCE_API, gl::DEBUG_TYPE_ERROR, 1280, gl::DEBUG_SEVERITY_HIGH,
+                 text.len() as GLsizei, text.as_ptr() as *const GLchar, std::ptr::null_mut());
+        assert_eq!(take_errors().len(), 1);
+        let raised = std::panic::catch_unwind(|| check("the test"));
+        // once only
+        check("the test");
+        *SETTINGS.lock().unwrap() = settings;
+        CHECK_ERRORS.store(checked, Ordering::Relaxed);
+        let payload = raised.expect_err("the error wasn't raised");
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "GL error 1280: from the driver");
+    }
+
+    #[test]
+    fn driver_messages_reach_the_callback() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let _context = match HeadlessContext::for_test("debug output") {
+            Some(context) => context,
+            None => return
+        };
+        if install(DebugSettings { panic_on_error: false, ..DebugSettings::default() }) != DebugMode::Callback {
+            eprintln!("skipping debug output: no KHR_debug");
+            return;
+        }
+        let text = "inserted by the test";
+        unsafe {
+            gl::DebugMessageInsert(gl::DEBUG_SOURCE_APPLICATION, gl::DEBUG_TYPE_ERROR, 4242,
+                                   gl::DEBUG_SEVERITY_HIGH, text.len() as GLsizei, text.as_ptr() as *const GLchar);
+        }
+        let errors = take_errors();
+        let inserted = errors.iter().find(|m| m.id == 4242).expect("message didn't arrive");
+        assert_eq!(inserted.source, DebugSource::Application);
+        assert_eq!(inserted.text, text);
+    }
+
+    #[test]
+    fn labels_and_groups() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let _context = match HeadlessContext::for_test("labels") {
+            Some(context) => context,
+            None => return
+        };
+        if !debug_supported() {
+            eprintln!("skipping labels: no KHR_debug");
+            return;
+        }
+        let mut framebuffer = crate::render_gl::headless::offscreen_target(4, 4).unwrap();
+        framebuffer.set_label("test target");
+        assert_eq!(object_label(gl::FRAMEBUFFER, framebuffer.id()), "test target");
+        assert_eq!(object_label(gl::TEXTURE, framebuffer.color_texture(0)), "test target color0");
+        // attachments are recreated on resize and keep their names
+        framebuffer.resize(8, 8).unwrap();
+        assert_eq!(object_label(gl::TEXTURE, framebuffer.color_texture(0)), "test target color0");
+
+        let mut depth = 0;
+        {
+            let _outer = DebugGroup::new("outer");
+            let _inner = DebugGroup::new("inner");
+            unsafe {
+                gl::GetIntegerv(gl::DEBUG_GROUP_STACK_DEPTH, &mut depth);
+            }
+        }
+        assert_eq!(depth, 3);
+        unsafe {
+            gl::GetIntegerv(gl::DEBUG_GROUP_STACK_DEPTH, &mut depth);
+        }
+        assert_eq!(depth, 1);
+    }
+}
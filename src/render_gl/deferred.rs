@@ -0,0 +1,307 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use crate::render_gl::debug::{self, DebugGroup};
+use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::light::{self, LightSet, PointLight};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::{CullMode, PipelineState};
+use crate::render_gl::shadow::ShadowRenderer;
+use crate::render_gl::sphere::generate_sphere;
+use crate::render_gl::ssao::{self, Ssao};
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+
+// floats per light in the instance buffer:
+// position (3), radius (1), diffuse (3), specular (3), attenuation (3), ambient (3)
+pub const INSTANCE_STRIDE: usize = 16;
+
+// light volumes never grow beyond this, e.g for lights without falloff
+pub const MAX_VOLUME_RADIUS: f32 = 500.0;
+
+const VOLUME_SECTORS: u32 = 16;
+const VOLUME_STACKS: u32 = 12;
+
+// the distance at which the light's brightest channel drops below 5/256,
+// i.e where it stops making a visible difference
+// Source: https://learnopengl.com/Advanced-Lighting/Deferred-Shading
+pub fn light_volume_radius(light: &PointLight) -> f32 {
+    let a = &light.attenuation;
+    let brightest = light.diffuse.x.max(light.diffuse.y).max(light.diffuse.z)
+        .max(light.specular.x.max(light.specular.y).max(light.specular.z));
+    let target = brightest * 256.0 / 5.0;
+    if target <= a.constant {
+        return 0.0;
+    }
+    let radius = if a.quadratic > 0.0 {
+        (-a.linear + (a.linear * a.linear - 4.0 * a.quadratic * (a.constant - target)).sqrt()) / (2.0 * a.quadratic)
+    } else if a.linear > 0.0 {
+        (target - a.constant) / a.linear
+    } else {
+        MAX_VOLUME_RADIUS
+    };
+    radius.min(MAX_VOLUME_RADIUS)
+}
+
+// per instance data for the light volumes, see shaders/deferred_point.vert.
+// lights that can't light anything are left out
+pub fn volume_instances(lights: &[PointLight]) -> Vec<f32> {
+    let mut data = Vec::with_capacity(lights.len() * INSTANCE_STRIDE);
+    for light in lights {
+        let radius = light_volume_radius(light);
+        if radius <= 0.0 {
+            continue;
+        }
+        let a = &light.attenuation;
+        data.extend_from_slice(&[light.position.x, light.position.y, light.position.z, radius]);
+        data.extend_from_slice(&[light.diffuse.x, light.diffuse.y, light.diffuse.z]);
+        data.extend_from_slice(&[light.specular.x, light.specular.y, light.specular.z]);
+        data.extend_from_slice(&[a.constant, a.linear, a.quadratic]);
+        data.extend_from_slice(&[light.ambient.x, light.ambient.y, light.ambient.z]);
+    }
+    data
+}
+
+// the G-buffer program. uses the same vertex layout and `material` struct
+// as the forward lighting shaders (diffuse and specular maps, shininess)
+pub fn geometry_program() -> Result<Program, String> {
+    let program = Program::from_shaders(&[
+        Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/pbr.vert"))?)?,
+        Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/gbuffer.frag"))?)?
+    ])?;
+    program.set_label("gbuffer");
+    Ok(program)
+}
+
+// renders opaque geometry into a G-buffer, then lights it: directional and
+// spot lights in one full screen pass and every point light as an
+// instanced sphere covering the pixels it can reach. transparent objects
+// are drawn forward afterwards, against the G-buffer depth
+pub struct DeferredRenderer {
+    gbuffer: Framebuffer,
+    light_program: Program,
+    point_program: Program,
+    quad: Mesh,
+    volume: Mesh,
+    instance_vbo: GLuint,
+    instance_count: i32
+}
+
+impl DeferredRenderer {
+    pub fn new(width: u32, height: u32) -> Result<DeferredRenderer, String> {
+        let mut gbuffer = Framebuffer::new(FramebufferDesc::new(width, height)
+            .color(ColorFormat::Rgba16F)
+            .color(ColorFormat::Rgba16F)
+            .color(ColorFormat::Rgba8)
+            .depth_renderbuffer(DepthFormat::Depth24Stencil8))?;
+        gbuffer.set_label("gbuffer");
+        let light_program = Program::from_shaders(&[
+            Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/fullscreen.vert"))?)?,
+            Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/deferred_light.frag"))?)?
+        ])?;
+        let point_program = Program::from_shaders(&[
+            Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/deferred_point.vert"))?)?,
+            Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/deferred_point.frag"))?)?
+        ])?;
+
+        // the polygon sits inside the sphere it approximates, so grow it
+        // until it covers the whole sphere
+        let inflate = 1.0 / ((pi::<f32>() / VOLUME_SECTORS as f32).cos() * (pi::<f32>() / VOLUME_STACKS as f32).cos());
+        let (vertices, indices) = generate_sphere(VOLUME_SECTORS, VOLUME_STACKS, inflate, false);
+        let volume = Mesh::new(&vertices, &[3, 3], Some(&indices));
+        volume.set_label("light volume");
+        light_program.set_label("deferred light");
+        point_program.set_label("deferred point light");
+
+        let mut instance_vbo: GLuint = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut instance_vbo);
+            state::bind_vertex_array(volume.vao());
+            gl::BindBuffer(gl::ARRAY_BUFFER, instance_vbo);
+            let stride = (INSTANCE_STRIDE * std::mem::size_of::<f32>()) as GLint;
+            let attributes: [(GLuint, GLint); 6] = [(2, 3), (3, 1), (4, 3), (5, 3), (6, 3), (7, 3)];
+            let mut offset = 0;
+            for (location, size) in attributes.iter() {
+                gl::EnableVertexAttribArray(*location);
+                gl::VertexAttribPointer(*location, *size, gl::FLOAT, gl::FALSE, stride,
+                                        (offset * std::mem::size_of::<f32>()) as *const GLvoid);
+                gl::VertexAttribDivisor(*location, 1);
+                offset += *size as usize;
+            }
+            state::bind_vertex_array(0);
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+        }
+        debug::label_buffer(instance_vbo, "light volume instances");
+
+        Ok(DeferredRenderer {
+            gbuffer: gbuffer,
+            light_program: light_program,
+            point_program: point_program,
+            quad: Mesh::quad(),
+            volume: volume,
+            instance_vbo: instance_vbo,
+            instance_count: 0
+        })
+    }
+
+    pub fn gbuffer(&self) -> &Framebuffer {
+        &self.gbuffer
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.gbuffer.resize(width, height)?;
+        Ok(())
+    }
+
+    // binds and clears the G-buffer. draw the opaque geometry with a
+    // geometry_program() afterwards
+    pub fn begin_geometry(&self) {
+        self.gbuffer.begin();
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        PipelineState::opaque().apply();
+    }
+
+    // lights the G-buffer into `output` (the default framebuffer when None)
+    // and copies the G-buffer depth there, so forward passes can follow.
+    // unlike the forward shaders, every point light in `lights` is used
+    pub fn light(&mut self, camera: &Camera, lights: &LightSet, shadows: Option<&ShadowRenderer>,
+                 ambient_occlusion: Option<&Ssao>, output: Option<&Framebuffer>) {
+        let _group = DebugGroup::new("deferred lighting");
+        match output {
+            Some(framebuffer) => framebuffer.begin(),
+            None => unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                state::viewport(0, 0, self.gbuffer.width() as GLsizei, self.gbuffer.height() as GLsizei);
+            }
+        }
+        unsafe {
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        PipelineState::fullscreen().apply();
+        self.bind_gbuffer();
+
+        let p = &self.light_program;
+        p.set_used();
+        set_gbuffer_samplers(p);
+        p.set_uniform_vec3("viewPos", &camera.position());
+        lights.apply(p);
+        ssao::apply_ssao(p, ambient_occlusion);
+        if let Some(shadows) = shadows {
+            shadows.apply(p);
+        }
+        self.quad.draw();
+
+        let _volumes = DebugGroup::new("point light volumes");
+        let data = volume_instances(&lights.point);
+        self.instance_count = (data.len() / INSTANCE_STRIDE) as i32;
+        unsafe {
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.instance_vbo);
+            gl::BufferData(gl::ARRAY_BUFFER, (data.len() * std::mem::size_of::<f32>()) as GLsizeiptr,
+                           data.as_ptr() as *const GLvoid, gl::STREAM_DRAW);
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+        }
+        // back faces only, so the volume still shows up with the camera inside it
+        PipelineState::additive().depth_test(false).cull(CullMode::Front).apply();
+        let p = &self.point_program;
+        p.set_used();
+        set_gbuffer_samplers(p);
+        light::set_sampler_units(p);
+        p.set_uniform_1i("useSsao", ambient_occlusion.is_some() as i32);
+        p.set_uniform_vec3("viewPos", &camera.position());
+        p.set_uniform_mat4("view", &camera.look_at());
+        p.set_uniform_mat4("perspective", &camera.projection());
+        p.set_uniform_vec2("screenSize", &make_vec2(&[self.gbuffer.width() as f32, self.gbuffer.height() as f32]));
+        unsafe {
+            state::bind_vertex_array(self.volume.vao());
+            crate::gl_check!(gl::DrawElementsInstanced(gl::TRIANGLES, self.volume.count(), gl::UNSIGNED_INT,
+                                                       std::ptr::null(), self.instance_count));
+            state::bind_vertex_array(0);
+        }
+        PipelineState::opaque().apply();
+
+        self.gbuffer.blit(0, output, gl::DEPTH_BUFFER_BIT, gl::NEAREST);
+        match output {
+            Some(framebuffer) => framebuffer.bind(),
+            None => Framebuffer::bind_default()
+        }
+    }
+
+    // number of light volumes drawn by the last call to light
+    pub fn light_count(&self) -> i32 {
+        self.instance_count
+    }
+
+    // depth tested against the opaque scene but not written, alpha blended.
+    // draw transparent objects back to front between this and end_forward
+    pub fn begin_forward(&self) {
+        PipelineState::alpha_blended().apply();
+    }
+
+    pub fn end_forward(&self) {
+        PipelineState::opaque().apply();
+    }
+
+    fn bind_gbuffer(&self) {
+        for (unit, texture) in self.gbuffer.color_textures().iter().enumerate() {
+            state::active_texture(gl::TEXTURE0 + unit as GLuint);
+            state::bind_texture(gl::TEXTURE_2D, *texture);
+        }
+        state::active_texture(gl::TEXTURE0);
+    }
+}
+
+fn set_gbuffer_samplers(program: &Program) {
+    program.set_uniform_1i("gPosition", 0);
+    program.set_uniform_1i("gNormal", 1);
+    program.set_uniform_1i("gAlbedoSpec", 2);
+}
+
+impl Drop for DeferredRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.instance_vbo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::light::Attenuation;
+
+    fn white_light(range: f32) -> PointLight {
+        PointLight::new(&make_vec3(&[1.0, 2.0, 3.0]), &make_vec3(&[1.0, 1.0, 1.0]), range)
+    }
+
+    #[test]
+    fn volumes_end_where_the_light_fades_out() {
+        let light = white_light(50.0);
+        let radius = light_volume_radius(&light);
+        // 5/256 of the light is left at the edge
+        assert!((light.attenuation.at(radius) - 5.0 / 256.0).abs() < 1e-4);
+        assert!(light_volume_radius(&white_light(100.0)) > radius);
+    }
+
+    #[test]
+    fn lights_without_falloff_are_capped() {
+        let mut light = white_light(50.0);
+        light.attenuation = Attenuation::new(1.0, 0.0, 0.0);
+        assert_eq!(light_volume_radius(&light), MAX_VOLUME_RADIUS);
+        light.attenuation = Attenuation::new(1.0, 0.5, 0.0);
+        assert!((light_volume_radius(&light) - (256.0 / 5.0 - 1.0) / 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn dark_lights_get_no_volume() {
+        let mut dark = white_light(50.0);
+        dark.diffuse = make_vec3(&[0.0, 0.0, 0.0]);
+        dark.specular = make_vec3(&[0.0, 0.0, 0.0]);
+        let data = volume_instances(&[white_light(50.0), dark]);
+        assert_eq!(data.len(), INSTANCE_STRIDE);
+        assert_eq!(&data[0..3], &[1.0, 2.0, 3.0]);
+        assert_eq!(&data[10..13], &[1.0, 0.09, 0.032]);
+    }
+}
@@ -0,0 +1,567 @@
+use crate::render_gl::debug;
+use crate::render_gl::state;
+use gl::types::*;
+use std::fmt;
+
+// the minimum every GL 3.3 implementation supports
+pub const MAX_COLOR_ATTACHMENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorFormat {
+    R8,
+    Rg8,
+    Rgb8,
+    Rgba8,
+    Srgb8Alpha8,
+    R16F,
+    Rg16F,
+    Rgb16F,
+    Rgba16F,
+    R32F,
+    Rg32F,
+    Rgba32F,
+    R11G11B10F
+}
+
+impl ColorFormat {
+    pub fn internal_format(&self) -> GLenum {
+        match self {
+            ColorFormat::R8 => gl::R8,
+            ColorFormat::Rg8 => gl::RG8,
+            ColorFormat::Rgb8 => gl::RGB8,
+            ColorFormat::Rgba8 => gl::RGBA8,
+            ColorFormat::Srgb8Alpha8 => gl::SRGB8_ALPHA8,
+            ColorFormat::R16F => gl::R16F,
+            ColorFormat::Rg16F => gl::RG16F,
+            ColorFormat::Rgb16F => gl::RGB16F,
+            ColorFormat::Rgba16F => gl::RGBA16F,
+            ColorFormat::R32F => gl::R32F,
+            ColorFormat::Rg32F => gl::RG32F,
+            ColorFormat::Rgba32F => gl::RGBA32F,
+            ColorFormat::R11G11B10F => gl::R11F_G11F_B10F
+        }
+    }
+
+    // the client side format used when allocating the texture
+    pub fn format(&self) -> GLenum {
+        match self {
+            ColorFormat::R8 | ColorFormat::R16F | ColorFormat::R32F => gl::RED,
+            ColorFormat::Rg8 | ColorFormat::Rg16F | ColorFormat::Rg32F => gl::RG,
+            ColorFormat::Rgb8 | ColorFormat::Rgb16F | ColorFormat::R11G11B10F => gl::RGB,
+            ColorFormat::Rgba8 | ColorFormat::Srgb8Alpha8 | ColorFormat::Rgba16F | ColorFormat::Rgba32F => gl::RGBA
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        match self {
+            ColorFormat::R8 | ColorFormat::Rg8 | ColorFormat::Rgb8 | ColorFormat::Rgba8 | ColorFormat::Srgb8Alpha8 => false,
+            _ => true
+        }
+    }
+
+    pub fn data_type(&self) -> GLenum {
+        if self.is_float() { gl::FLOAT } else { gl::UNSIGNED_BYTE }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthFormat {
+    Depth16,
+    Depth24,
+    Depth32F,
+    Depth24Stencil8,
+    Depth32FStencil8
+}
+
+impl DepthFormat {
+    pub fn internal_format(&self) -> GLenum {
+        match self {
+            DepthFormat::Depth16 => gl::DEPTH_COMPONENT16,
+            DepthFormat::Depth24 => gl::DEPTH_COMPONENT24,
+            DepthFormat::Depth32F => gl::DEPTH_COMPONENT32F,
+            DepthFormat::Depth24Stencil8 => gl::DEPTH24_STENCIL8,
+            DepthFormat::Depth32FStencil8 => gl::DEPTH32F_STENCIL8
+        }
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        match self {
+            DepthFormat::Depth24Stencil8 | DepthFormat::Depth32FStencil8 => true,
+            _ => false
+        }
+    }
+
+    pub fn attachment(&self) -> GLenum {
+        if self.has_stencil() { gl::DEPTH_STENCIL_ATTACHMENT } else { gl::DEPTH_ATTACHMENT }
+    }
+
+    fn format(&self) -> GLenum {
+        if self.has_stencil() { gl::DEPTH_STENCIL } else { gl::DEPTH_COMPONENT }
+    }
+
+    fn data_type(&self) -> GLenum {
+        match self {
+            DepthFormat::Depth24Stencil8 => gl::UNSIGNED_INT_24_8,
+            DepthFormat::Depth32FStencil8 => gl::FLOAT_32_UNSIGNED_INT_24_8_REV,
+            _ => gl::FLOAT
+        }
+    }
+}
+
+// a texture can be sampled afterwards (shadow maps, SSAO, soft particles),
+// a renderbuffer is cheaper when the depth is only needed for testing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthAttachment {
+    None,
+    Texture(DepthFormat),
+    Renderbuffer(DepthFormat)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramebufferError {
+    InvalidSize { width: u32, height: u32 },
+    NoAttachments,
+    TooManyColorAttachments(usize),
+    // GL_FRAMEBUFFER_INCOMPLETE_* and friends, see from_status
+    IncompleteAttachment,
+    MissingAttachment,
+    IncompleteDrawBuffer,
+    IncompleteReadBuffer,
+    Unsupported,
+    IncompleteMultisample,
+    IncompleteLayerTargets,
+    Undefined,
+    Unknown(GLenum)
+}
+
+impl FramebufferError {
+    // None for GL_FRAMEBUFFER_COMPLETE
+    pub fn from_status(status: GLenum) -> Option<FramebufferError> {
+        match status {
+            gl::FRAMEBUFFER_COMPLETE => None,
+            gl::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => Some(FramebufferError::IncompleteAttachment),
+            gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => Some(FramebufferError::MissingAttachment),
+            gl::FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER => Some(FramebufferError::IncompleteDrawBuffer),
+            gl::FRAMEBUFFER_INCOMPLETE_READ_BUFFER => Some(FramebufferError::IncompleteReadBuffer),
+            gl::FRAMEBUFFER_UNSUPPORTED => Some(FramebufferError::Unsupported),
+            gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => Some(FramebufferError::IncompleteMultisample),
+            gl::FRAMEBUFFER_INCOMPLETE_LAYER_TARGETS => Some(FramebufferError::IncompleteLayerTargets),
+            gl::FRAMEBUFFER_UNDEFINED => Some(FramebufferError::Undefined),
+            other => Some(FramebufferError::Unknown(other))
+        }
+    }
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FramebufferError::InvalidSize { width, height } => write!(f, "invalid framebuffer size {}x{}", width, height),
+            FramebufferError::NoAttachments => write!(f, "framebuffer has no attachments"),
+            FramebufferError::TooManyColorAttachments(n) =>
+                write!(f, "{} colour attachments requested, at most {} are supported", n, MAX_COLOR_ATTACHMENTS),
+            FramebufferError::IncompleteAttachment => write!(f, "framebuffer incomplete: attachment"),
+            FramebufferError::MissingAttachment => write!(f, "framebuffer incomplete: missing attachment"),
+            FramebufferError::IncompleteDrawBuffer => write!(f, "framebuffer incomplete: draw buffer"),
+            FramebufferError::IncompleteReadBuffer => write!(f, "framebuffer incomplete: read buffer"),
+            FramebufferError::Unsupported => write!(f, "framebuffer format combination unsupported"),
+            FramebufferError::IncompleteMultisample => write!(f, "framebuffer incomplete: multisample"),
+            FramebufferError::IncompleteLayerTargets => write!(f, "framebuffer incomplete: layer targets"),
+            FramebufferError::Undefined => write!(f, "framebuffer undefined"),
+            FramebufferError::Unknown(status) => write!(f, "framebuffer incomplete: 0x{:x}", status)
+        }
+    }
+}
+
+// so framebuffer errors can be propagated with ? like every other error in render_gl
+impl From<FramebufferError> for String {
+    fn from(e: FramebufferError) -> String {
+        e.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FramebufferDesc {
+    pub width: u32,
+    pub height: u32,
+    // one per draw buffer, in GL_COLOR_ATTACHMENT0 + i order
+    pub colors: Vec<ColorFormat>,
+    pub depth: DepthAttachment,
+    // 0 or 1 for a regular framebuffer. multisampled targets can't be
+    // sampled directly, resolve them into a single sampled one first
+    pub samples: u32
+}
+
+impl FramebufferDesc {
+    pub fn new(width: u32, height: u32) -> FramebufferDesc {
+        FramebufferDesc { width: width, height: height, colors: Vec::new(), depth: DepthAttachment::None, samples: 0 }
+    }
+
+    pub fn color(mut self, format: ColorFormat) -> FramebufferDesc {
+        self.colors.push(format);
+        self
+    }
+
+    pub fn depth_texture(mut self, format: DepthFormat) -> FramebufferDesc {
+        self.depth = DepthAttachment::Texture(format);
+        self
+    }
+
+    pub fn depth_renderbuffer(mut self, format: DepthFormat) -> FramebufferDesc {
+        self.depth = DepthAttachment::Renderbuffer(format);
+        self
+    }
+
+    pub fn samples(mut self, samples: u32) -> FramebufferDesc {
+        self.samples = samples;
+        self
+    }
+
+    pub fn is_multisampled(&self) -> bool {
+        self.samples > 1
+    }
+
+    // the checks that don't need a GL context
+    pub fn validate(&self) -> Result<(), FramebufferError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(FramebufferError::InvalidSize { width: self.width, height: self.height });
+        }
+        if self.colors.is_empty() && self.depth == DepthAttachment::None {
+            return Err(FramebufferError::NoAttachments);
+        }
+        if self.colors.len() > MAX_COLOR_ATTACHMENTS {
+            return Err(FramebufferError::TooManyColorAttachments(self.colors.len()));
+        }
+        Ok(())
+    }
+
+    pub fn draw_buffers(&self) -> Vec<GLenum> {
+        (0..self.colors.len()).map(|i| gl::COLOR_ATTACHMENT0 + i as GLenum).collect()
+    }
+
+    fn texture_target(&self) -> GLenum {
+        if self.is_multisampled() { gl::TEXTURE_2D_MULTISAMPLE } else { gl::TEXTURE_2D }
+    }
+}
+
+// an offscreen render target. attachments are (re)created from the desc,
+// so a resize keeps the formats and just reallocates storage
+pub struct Framebuffer {
+    desc: FramebufferDesc,
+    fbo: GLuint,
+    colors: Vec<GLuint>,
+    depth_texture: GLuint,
+    depth_renderbuffer: GLuint,
+    label: Option<String>
+}
+
+impl Framebuffer {
+    pub fn new(desc: FramebufferDesc) -> Result<Framebuffer, FramebufferError> {
+        desc.validate()?;
+        let mut fbo: GLuint = 0;
+        unsafe {
+            gl::GenFramebuffers(1, &mut fbo);
+        }
+        let mut framebuffer = Framebuffer { desc: desc, fbo: fbo, colors: Vec::new(), depth_texture: 0, depth_renderbuffer: 0, label: None };
+        framebuffer.create_attachments()?;
+        Ok(framebuffer)
+    }
+
+    pub fn desc(&self) -> &FramebufferDesc {
+        &self.desc
+    }
+
+    pub fn id(&self) -> GLuint {
+        self.fbo
+    }
+
+    pub fn width(&self) -> u32 {
+        self.desc.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.desc.height
+    }
+
+    pub fn color_texture(&self, index: usize) -> GLuint {
+        self.colors[index]
+    }
+
+    pub fn color_textures(&self) -> &[GLuint] {
+        &self.colors
+    }
+
+    // names the framebuffer and its attachments ("<label> color0",
+    // "<label> depth") for capture tools. kept across resizes
+    pub fn set_label(&mut self, label: &str) {
+        self.label = Some(label.to_string());
+        self.apply_label();
+    }
+
+    fn apply_label(&self) {
+        let label = match self.label {
+            Some(ref label) => label,
+            None => return
+        };
+        debug::label(gl::FRAMEBUFFER, self.fbo, label);
+        for (i, tex) in self.colors.iter().enumerate() {
+            debug::label_texture(*tex, &format!("{} color{}", label, i));
+        }
+        debug::label_texture(self.depth_texture, &format!("{} depth", label));
+        debug::label(gl::RENDERBUFFER, self.depth_renderbuffer, &format!("{} depth", label));
+    }
+
+    // GL_TEXTURE_2D, or GL_TEXTURE_2D_MULTISAMPLE for multisampled targets
+    pub fn texture_target(&self) -> GLenum {
+        self.desc.texture_target()
+    }
+
+    // None unless the depth attachment is a texture
+    pub fn depth_texture(&self) -> Option<GLuint> {
+        if self.depth_texture != 0 { Some(self.depth_texture) } else { None }
+    }
+
+    // reallocates the attachments when the size changed. returns whether it did
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<bool, FramebufferError> {
+        if width == self.desc.width && height == self.desc.height {
+            return Ok(false);
+        }
+        let mut desc = self.desc.clone();
+        desc.width = width;
+        desc.height = height;
+        desc.validate()?;
+        self.delete_attachments();
+        self.desc = desc;
+        self.create_attachments()?;
+        Ok(true)
+    }
+
+    // the size to resize to for a window size SDL reported. minimizing
+    // reports 0x0, which nothing can be resized to, so that gives None
+    pub fn window_size(width: i32, height: i32) -> Option<(u32, u32)> {
+        if width > 0 && height > 0 {
+            Some((width as u32, height as u32))
+        } else {
+            None
+        }
+    }
+
+    pub fn bind(&self) {
+        unsafe {
+            crate::gl_check!(gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo));
+        }
+    }
+
+    // binds and sets the viewport to cover the whole target
+    pub fn begin(&self) {
+        self.bind();
+        state::viewport(0, 0, self.desc.width as GLsizei, self.desc.height as GLsizei);
+    }
+
+    pub fn bind_default() {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // copies `mask` (GL_COLOR_BUFFER_BIT etc) from colour attachment
+    // `index` into `target`, or the default framebuffer when None. this is
+    // also how multisampled targets are resolved
+    pub fn blit(&self, index: usize, target: Option<&Framebuffer>, mask: GLbitfield, filter: GLenum) {
+        let (fbo, width, height, draw_buffer) = match target {
+            Some(t) => (t.fbo, t.desc.width, t.desc.height, gl::COLOR_ATTACHMENT0 + index as GLenum),
+            None => (0, self.desc.width, self.desc.height, gl::BACK)
+        };
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, fbo);
+            if mask & gl::COLOR_BUFFER_BIT != 0 {
+                gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + index as GLenum);
+                gl::DrawBuffer(draw_buffer);
+            }
+            gl::BlitFramebuffer(0, 0, self.desc.width as GLint, self.desc.height as GLint,
+                                0, 0, width as GLint, height as GLint, mask, filter);
+            if mask & gl::COLOR_BUFFER_BIT != 0 {
+                gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+                if let Some(t) = target {
+                    let buffers = t.desc.draw_buffers();
+                    gl::DrawBuffers(buffers.len() as GLsizei, buffers.as_ptr());
+                }
+            }
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // resolves every colour attachment, and depth when both have one, into
+    // `target`, which must be the same size and not multisampled
+    pub fn resolve(&self, target: &Framebuffer) {
+        for i in 0..self.colors.len().min(target.colors.len()) {
+            self.blit(i, Some(target), gl::COLOR_BUFFER_BIT, gl::NEAREST);
+        }
+        if self.desc.depth != DepthAttachment::None && target.desc.depth != DepthAttachment::None {
+            self.blit(0, Some(target), gl::DEPTH_BUFFER_BIT, gl::NEAREST);
+        }
+    }
+
+    fn create_attachments(&mut self) -> Result<(), FramebufferError> {
+        let desc = self.desc.clone();
+        let (width, height) = (desc.width as GLsizei, desc.height as GLsizei);
+        let samples = desc.samples as GLsizei;
+        let target = desc.texture_target();
+        unsafe {
+            crate::gl_check!(gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo));
+            for (i, format) in desc.colors.iter().enumerate() {
+                let mut tex: GLuint = 0;
+                gl::GenTextures(1, &mut tex);
+                state::bind_texture(target, tex);
+                if desc.is_multisampled() {
+                    crate::gl_check!(gl::TexImage2DMultisample(target, samples, format.internal_format(), width, height, gl::TRUE));
+                } else {
+                    crate::gl_check!(gl::TexImage2D(target, 0, format.internal_format() as GLint, width, height, 0,
+                                                    format.format(), format.data_type(), std::ptr::null()));
+                    gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+                    gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+                    gl::TexParameteri(target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                    gl::TexParameteri(target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+                }
+                crate::gl_check!(gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0 + i as GLenum, target, tex, 0));
+                self.colors.push(tex);
+            }
+            state::bind_texture(target, 0);
+
+            match desc.depth {
+                DepthAttachment::None => {}
+                DepthAttachment::Texture(format) => {
+                    gl::GenTextures(1, &mut self.depth_texture);
+                    state::bind_texture(target, self.depth_texture);
+                    if desc.is_multisampled() {
+                        crate::gl_check!(gl::TexImage2DMultisample(target, samples, format.internal_format(), width, height, gl::TRUE));
+                    } else {
+                        crate::gl_check!(gl::TexImage2D(target, 0, format.internal_format() as GLint, width, height, 0,
+                                                        format.format(), format.data_type(), std::ptr::null()));
+                        gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+                        gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+                        gl::TexParameteri(target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                        gl::TexParameteri(target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+                    }
+                    crate::gl_check!(gl::FramebufferTexture2D(gl::FRAMEBUFFER, format.attachment(), target, self.depth_texture, 0));
+                    state::bind_texture(target, 0);
+                }
+                DepthAttachment::Renderbuffer(format) => {
+                    gl::GenRenderbuffers(1, &mut self.depth_renderbuffer);
+                    gl::BindRenderbuffer(gl::RENDERBUFFER, self.depth_renderbuffer);
+                    if desc.is_multisampled() {
+                        crate::gl_check!(gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples, format.internal_format(), width, height));
+                    } else {
+                        crate::gl_check!(gl::RenderbufferStorage(gl::RENDERBUFFER, format.internal_format(), width, height));
+                    }
+                    crate::gl_check!(gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, format.attachment(), gl::RENDERBUFFER, self.depth_renderbuffer));
+                    gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+                }
+            }
+
+            if desc.colors.is_empty() {
+                gl::DrawBuffer(gl::NONE);
+                gl::ReadBuffer(gl::NONE);
+            } else {
+                let buffers = desc.draw_buffers();
+                crate::gl_check!(gl::DrawBuffers(buffers.len() as GLsizei, buffers.as_ptr()));
+            }
+        }
+        let status = unsafe { gl::CheckFramebufferStatus(gl::FRAMEBUFFER) };
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        match FramebufferError::from_status(status) {
+            Some(e) => Err(e),
+            None => {
+                self.apply_label();
+                Ok(())
+            }
+        }
+    }
+
+    fn delete_attachments(&mut self) {
+        unsafe {
+            if !self.colors.is_empty() {
+                state::delete_textures(&self.colors);
+            }
+            if self.depth_texture != 0 {
+                state::delete_texture(self.depth_texture);
+            }
+            if self.depth_renderbuffer != 0 {
+                gl::DeleteRenderbuffers(1, &self.depth_renderbuffer);
+            }
+        }
+        self.colors.clear();
+        self.depth_texture = 0;
+        self.depth_renderbuffer = 0;
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        self.delete_attachments();
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptions_are_validated() {
+        assert_eq!(FramebufferDesc::new(0, 600).color(ColorFormat::Rgba8).validate(),
+                   Err(FramebufferError::InvalidSize { width: 0, height: 600 }));
+        assert_eq!(FramebufferDesc::new(800, 600).validate(), Err(FramebufferError::NoAttachments));
+        let mut desc = FramebufferDesc::new(800, 600);
+        for _ in 0..MAX_COLOR_ATTACHMENTS + 1 {
+            desc = desc.color(ColorFormat::Rgba16F);
+        }
+        assert_eq!(desc.validate(), Err(FramebufferError::TooManyColorAttachments(MAX_COLOR_ATTACHMENTS + 1)));
+        // depth only targets, like shadow maps, are fine
+        assert!(FramebufferDesc::new(1024, 1024).depth_texture(DepthFormat::Depth24).validate().is_ok());
+    }
+
+    #[test]
+    fn draw_buffers_follow_the_colour_attachments() {
+        let desc = FramebufferDesc::new(800, 600)
+            .color(ColorFormat::Rgba16F)
+            .color(ColorFormat::Rgba8)
+            .color(ColorFormat::Rg16F)
+            .depth_renderbuffer(DepthFormat::Depth24Stencil8)
+            .samples(4);
+        assert_eq!(desc.draw_buffers(), vec![gl::COLOR_ATTACHMENT0, gl::COLOR_ATTACHMENT1, gl::COLOR_ATTACHMENT2]);
+        assert!(desc.is_multisampled());
+        assert_eq!(desc.texture_target(), gl::TEXTURE_2D_MULTISAMPLE);
+    }
+
+    #[test]
+    fn formats_map_to_gl() {
+        assert_eq!(ColorFormat::Rgba16F.internal_format(), gl::RGBA16F);
+        assert_eq!(ColorFormat::Rgba16F.data_type(), gl::FLOAT);
+        assert_eq!(ColorFormat::Rg8.format(), gl::RG);
+        assert_eq!(ColorFormat::Srgb8Alpha8.data_type(), gl::UNSIGNED_BYTE);
+        assert_eq!(DepthFormat::Depth24Stencil8.attachment(), gl::DEPTH_STENCIL_ATTACHMENT);
+        assert_eq!(DepthFormat::Depth32F.attachment(), gl::DEPTH_ATTACHMENT);
+    }
+
+    #[test]
+    fn statuses_become_typed_errors() {
+        assert_eq!(FramebufferError::from_status(gl::FRAMEBUFFER_COMPLETE), None);
+        assert_eq!(FramebufferError::from_status(gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE),
+                   Some(FramebufferError::IncompleteMultisample));
+        assert_eq!(FramebufferError::from_status(0x1234), Some(FramebufferError::Unknown(0x1234)));
+        let message: String = FramebufferError::Unsupported.into();
+        assert!(message.contains("unsupported"));
+    }
+
+    #[test]
+    fn minimized_windows_have_no_size_to_resize_to() {
+        assert_eq!(Framebuffer::window_size(1024, 768), Some((1024, 768)));
+        assert_eq!(Framebuffer::window_size(0, 0), None);
+        assert_eq!(Framebuffer::window_size(640, 0), None);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::glm::*;
+
+// a half-line starting at origin. direction is always normalized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3
+}
+
+impl Ray {
+    pub fn new(origin: &Vec3, direction: &Vec3) -> Ray {
+        Ray { origin: origin.clone(), direction: normalize(direction) }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+
+    // slab test. returns the distance along the ray to the first hit, if any
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = std::f32::MAX;
+        for i in 0..3 {
+            if self.direction[i].abs() < std::f32::EPSILON {
+                if self.origin[i] < aabb.min[i] || self.origin[i] > aabb.max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let inv = 1.0 / self.direction[i];
+            let mut t0 = (aabb.min[i] - self.origin[i]) * inv;
+            let mut t1 = (aabb.max[i] - self.origin[i]) * inv;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_min > t_max {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+}
+
+// plane in the form dot(normal, p) + d = 0, with the normal pointing
+// towards the "inside" half space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: Vec3,
+    pub d: f32
+}
+
+impl Plane {
+    // builds a plane from the raw (a, b, c, d) coefficients and normalizes it
+    // so that signed_distance returns real world units
+    pub fn from_coefficients(coefficients: &Vec4) -> Plane {
+        let normal = make_vec3(&[coefficients.x, coefficients.y, coefficients.z]);
+        let len = length(&normal);
+        Plane { normal: normal / len, d: coefficients.w / len }
+    }
+
+    pub fn signed_distance(&self, point: &Vec3) -> f32 {
+        dot(&self.normal, point) + self.d
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    pub fn new(min: &Vec3, max: &Vec3) -> Aabb {
+        Aabb { min: min.clone(), max: max.clone() }
+    }
+
+    pub fn from_center_extents(center: &Vec3, extents: &Vec3) -> Aabb {
+        Aabb { min: center - extents, max: center + extents }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    // half size along each axis
+    pub fn extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn contains(&self, point: &Vec3) -> bool {
+        (0..3).all(|i| point[i] >= self.min[i] && point[i] <= self.max[i])
+    }
+
+    // the smallest box around the points, None if there are none
+    pub fn from_points(points: &[Vec3]) -> Option<Aabb> {
+        let first = points.first()?;
+        let mut aabb = Aabb::new(first, first);
+        for p in points.iter().skip(1) {
+            aabb.min = aabb.min.inf(p);
+            aabb.max = aabb.max.sup(p);
+        }
+        Some(aabb)
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb { min: self.min.inf(&other.min), max: self.max.sup(&other.max) }
+    }
+
+    pub fn expanded(&self, margin: f32) -> Aabb {
+        let margin = make_vec3(&[margin, margin, margin]);
+        Aabb { min: self.min - margin, max: self.max + margin }
+    }
+
+    pub fn contains_aabb(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| other.min[i] >= self.min[i] && other.max[i] <= self.max[i])
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| other.min[i] <= self.max[i] && other.max[i] >= self.min[i])
+    }
+
+    // bit 0 of the index picks max.x over min.x, bit 1 y and bit 2 z
+    pub fn corners(&self) -> [Vec3; 8] {
+        let mut corners = [self.min; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            for axis in 0..3 {
+                if i & (1 << axis) != 0 {
+                    corner[axis] = self.max[axis];
+                }
+            }
+        }
+        corners
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // the box around this one after transforming it by `m`, which is
+    // usually larger than the box around the transformed contents
+    // Source: Arvo, "Transforming Axis-Aligned Bounding Boxes", Graphics Gems
+    pub fn transformed(&self, m: &Mat4) -> Aabb {
+        let center = self.center();
+        let extents = self.extents();
+        let center = (m * make_vec4(&[center.x, center.y, center.z, 1.0])).xyz();
+        let mut transformed = make_vec3(&[0.0, 0.0, 0.0]);
+        for i in 0..3 {
+            for j in 0..3 {
+                transformed[i] += m[(i, j)].abs() * extents[j];
+            }
+        }
+        Aabb::from_center_extents(&center, &transformed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vec3,
+    pub radius: f32
+}
+
+impl BoundingSphere {
+    pub fn new(center: &Vec3, radius: f32) -> BoundingSphere {
+        BoundingSphere { center: center.clone(), radius: radius }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intersection {
+    Outside,
+    Intersecting,
+    Inside
+}
+
+pub const FRUSTUM_LEFT: usize = 0;
+pub const FRUSTUM_RIGHT: usize = 1;
+pub const FRUSTUM_BOTTOM: usize = 2;
+pub const FRUSTUM_TOP: usize = 3;
+pub const FRUSTUM_NEAR: usize = 4;
+pub const FRUSTUM_FAR: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    pub planes: [Plane; 6]
+}
+
+impl Frustum {
+    // Gribb/Hartmann plane extraction. `m` is a projection * view matrix
+    // with OpenGL clip space conventions (z in [-w, w]).
+    // Source: http://www.cs.otago.ac.nz/postgrads/alexis/planeExtraction.pdf
+    pub fn from_matrix(m: &Mat4) -> Frustum {
+        let row = |i: usize| make_vec4(&[m[(i, 0)], m[(i, 1)], m[(i, 2)], m[(i, 3)]]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        Frustum {
+            planes: [
+                Plane::from_coefficients(&(r3 + r0)),
+                Plane::from_coefficients(&(r3 - r0)),
+                Plane::from_coefficients(&(r3 + r1)),
+                Plane::from_coefficients(&(r3 - r1)),
+                Plane::from_coefficients(&(r3 + r2)),
+                Plane::from_coefficients(&(r3 - r2)),
+            ]
+        }
+    }
+
+    pub fn contains_point(&self, point: &Vec3) -> bool {
+        self.planes.iter().all(|p| p.signed_distance(point) >= 0.0)
+    }
+
+    pub fn test_sphere(&self, sphere: &BoundingSphere) -> Intersection {
+        let mut result = Intersection::Inside;
+        for plane in self.planes.iter() {
+            let distance = plane.signed_distance(&sphere.center);
+            if distance < -sphere.radius {
+                return Intersection::Outside;
+            }
+            if distance < sphere.radius {
+                result = Intersection::Intersecting;
+            }
+        }
+        result
+    }
+
+    // uses the "positive/negative vertex" trick: for every plane only the
+    // corner of the box furthest along the plane normal (and its opposite)
+    // need to be checked
+    pub fn test_aabb(&self, aabb: &Aabb) -> Intersection {
+        let mut result = Intersection::Inside;
+        for plane in self.planes.iter() {
+            let mut positive = aabb.min.clone();
+            let mut negative = aabb.max.clone();
+            for i in 0..3 {
+                if plane.normal[i] >= 0.0 {
+                    positive[i] = aabb.max[i];
+                    negative[i] = aabb.min[i];
+                }
+            }
+            if plane.signed_distance(&positive) < 0.0 {
+                return Intersection::Outside;
+            }
+            if plane.signed_distance(&negative) < 0.0 {
+                result = Intersection::Intersecting;
+            }
+        }
+        result
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.test_sphere(sphere) != Intersection::Outside
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.test_aabb(aabb) != Intersection::Outside
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_cube_frustum() -> Frustum {
+        // an orthographic projection maps the [-1, 1] cube onto clip space
+        // (with z flipped), which makes plane positions easy to reason about
+        Frustum::from_matrix(&ortho(-1.0, 1.0, -1.0, 1.0, -1.0, 1.0))
+    }
+
+    #[test]
+    fn planes_are_normalized() {
+        let frustum = Frustum::from_matrix(&perspective(4.0 / 3.0, 0.78, 0.1, 100.0));
+        for plane in frustum.planes.iter() {
+            assert!((length(&plane.normal) - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn ortho_planes_are_at_the_box_faces() {
+        let frustum = unit_cube_frustum();
+        let left = frustum.planes[FRUSTUM_LEFT];
+        assert!((left.signed_distance(&make_vec3(&[-1.0, 0.0, 0.0]))).abs() < 1e-5);
+        assert!(left.signed_distance(&make_vec3(&[0.0, 0.0, 0.0])) > 0.0);
+        let near = frustum.planes[FRUSTUM_NEAR];
+        assert!((near.signed_distance(&make_vec3(&[0.0, 0.0, 1.0]))).abs() < 1e-5);
+    }
+
+    #[test]
+    fn aabb_classification() {
+        let frustum = unit_cube_frustum();
+        let inside = Aabb::from_center_extents(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.5, 0.5, 0.5]));
+        let straddling = Aabb::from_center_extents(&make_vec3(&[1.0, 0.0, 0.0]), &make_vec3(&[0.5, 0.5, 0.5]));
+        let outside = Aabb::from_center_extents(&make_vec3(&[3.0, 0.0, 0.0]), &make_vec3(&[0.5, 0.5, 0.5]));
+        assert_eq!(frustum.test_aabb(&inside), Intersection::Inside);
+        assert_eq!(frustum.test_aabb(&straddling), Intersection::Intersecting);
+        assert_eq!(frustum.test_aabb(&outside), Intersection::Outside);
+    }
+
+    #[test]
+    fn sphere_classification() {
+        let frustum = unit_cube_frustum();
+        let inside = BoundingSphere::new(&make_vec3(&[0.0, 0.0, 0.0]), 0.5);
+        let straddling = BoundingSphere::new(&make_vec3(&[0.0, 1.2, 0.0]), 0.5);
+        let outside = BoundingSphere::new(&make_vec3(&[0.0, 0.0, 2.0]), 0.5);
+        assert_eq!(frustum.test_sphere(&inside), Intersection::Inside);
+        assert_eq!(frustum.test_sphere(&straddling), Intersection::Intersecting);
+        assert_eq!(frustum.test_sphere(&outside), Intersection::Outside);
+    }
+
+    #[test]
+    fn boxes_combine_and_transform() {
+        let a = Aabb::from_points(&[make_vec3(&[0.0, 0.0, 0.0]), make_vec3(&[1.0, 2.0, -1.0])]).unwrap();
+        assert_eq!(a, Aabb::new(&make_vec3(&[0.0, 0.0, -1.0]), &make_vec3(&[1.0, 2.0, 0.0])));
+        let b = Aabb::from_center_extents(&make_vec3(&[3.0, 0.0, 0.0]), &make_vec3(&[0.5, 0.5, 0.5]));
+        let both = a.union(&b);
+        assert!(both.contains_aabb(&a) && both.contains_aabb(&b));
+        assert!(!a.intersects(&b) && both.intersects(&b));
+        assert_eq!(a.surface_area(), 2.0 * (2.0 + 2.0 + 1.0));
+
+        // a quarter turn about z swaps the x and y extents
+        let turned = rotate(&translation(&make_vec3(&[10.0, 0.0, 0.0])), std::f32::consts::FRAC_PI_2, &make_vec3(&[0.0, 0.0, 1.0]));
+        let t = a.transformed(&turned);
+        assert!(distance(&t.center(), &make_vec3(&[9.0, 0.5, -0.5])) < 1e-5);
+        assert!(distance(&t.extents(), &make_vec3(&[1.0, 0.5, 0.5])) < 1e-5);
+        assert_eq!(Aabb::from_points(&[]), None);
+    }
+
+    #[test]
+    fn ray_hits_box_in_front_only() {
+        let aabb = Aabb::from_center_extents(&make_vec3(&[0.0, 0.0, -5.0]), &make_vec3(&[1.0, 1.0, 1.0]));
+        let ray = Ray::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]));
+        let t = ray.intersect_aabb(&aabb).unwrap();
+        assert!((t - 4.0).abs() < 1e-5);
+        let away = Ray::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, 1.0]));
+        assert_eq!(away.intersect_aabb(&aabb), None);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::render_gl::capture;
+use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::state;
+use gl::types::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+
+// picks the headless backend when set: "egl" or "osmesa"
+pub const BACKEND_VAR: &str = "GAME_HEADLESS";
+
+// GL contexts without a window or display server, for rendering on build
+// servers. both backends are loaded at runtime, so the binaries don't link
+// against them and machines without Mesa can still run everything else.
+// with Mesa and no GPU, LIBGL_ALWAYS_SOFTWARE=1 forces llvmpipe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadlessBackend {
+    // EGL_MESA_platform_surfaceless / EGL_KHR_surfaceless_context
+    Egl,
+    // Mesa's off screen renderer, rendering into client memory
+    OsMesa
+}
+
+impl HeadlessBackend {
+    pub fn parse(name: &str) -> Result<HeadlessBackend, String> {
+        match name.trim().to_lowercase().as_str() {
+            "egl" => Ok(HeadlessBackend::Egl),
+            "osmesa" => Ok(HeadlessBackend::OsMesa),
+            other => Err(format!("unknown headless backend '{}', expected egl or osmesa", other))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeadlessBackend::Egl => "egl",
+            HeadlessBackend::OsMesa => "osmesa"
+        }
+    }
+
+    // the backends to try in order: only the requested one if the
+    // variable is set, otherwise EGL first since it is the faster of the two
+    pub fn candidates(requested: Option<&str>) -> Result<Vec<HeadlessBackend>, String> {
+        match requested {
+            Some(name) if !name.trim().is_empty() => Ok(vec![HeadlessBackend::parse(name)?]),
+            _ => Ok(vec![HeadlessBackend::Egl, HeadlessBackend::OsMesa])
+        }
+    }
+}
+
+// a current GL 3.3 core context. GL function pointers are process wide and
+// the context is current on the creating thread only, so keep one per
+// thread and don't mix it with a window
+pub struct HeadlessContext {
+    backend: HeadlessBackend,
+    library: Library,
+    state: ContextState
+}
+
+enum ContextState {
+    Egl { display: *mut c_void, context: *mut c_void },
+    // OSMesa needs a colour buffer to make the context current even though
+    // everything is drawn into framebuffer objects
+    OsMesa { context: *mut c_void, _buffer: Vec<u8> }
+}
+
+impl HeadlessContext {
+    // uses the backend named by GAME_HEADLESS, or the first one that works
+    pub fn create() -> Result<HeadlessContext, String> {
+        let requested = std::env::var(BACKEND_VAR).ok();
+        let mut errors = Vec::new();
+        for backend in HeadlessBackend::candidates(requested.as_deref())? {
+            match HeadlessContext::new(backend) {
+                Ok(context) => return Ok(context),
+                Err(e) => errors.push(format!("{}: {}", backend.name(), e))
+            }
+        }
+        Err(format!("no headless GL context available ({})", errors.join("; ")))
+    }
+
+    // the context for a test that renders, or None to skip it on machines
+    // without one. when GAME_HEADLESS or CI is set a context is expected,
+    // so failing to get one fails the test instead of passing it unrendered
+    pub fn for_test(test: &str) -> Option<HeadlessContext> {
+        match HeadlessContext::create() {
+            Ok(context) => Some(context),
+            Err(e) if std::env::var_os(BACKEND_VAR).is_some() || std::env::var_os("CI").is_some() => {
+                panic!("{}: {}", test, e)
+            }
+            Err(e) => {
+                eprintln!("skipping {}: {}", test, e);
+                None
+            }
+        }
+    }
+
+    pub fn new(backend: HeadlessBackend) -> Result<HeadlessContext, String> {
+        let context = match backend {
+            HeadlessBackend::Egl => create_egl()?,
+            HeadlessBackend::OsMesa => create_osmesa()?
+        };
+        context.load_gl();
+        // a new context starts from the defaults
+        state::reset();
+        let version = unsafe { gl::GetString(gl::VERSION) };
+        if version.is_null() {
+            return Err(format!("{} context has no GL", backend.name()));
+        }
+        Ok(context)
+    }
+
+    pub fn backend(&self) -> HeadlessBackend {
+        self.backend
+    }
+
+    // e.g "4.5 (Core Profile) Mesa 23.2.1", handy in test logs
+    pub fn version(&self) -> String {
+        gl_string(gl::VERSION)
+    }
+
+    pub fn renderer(&self) -> String {
+        gl_string(gl::RENDERER)
+    }
+
+    fn load_gl(&self) {
+        let proc_address = match self.backend {
+            HeadlessBackend::Egl => "eglGetProcAddress",
+            HeadlessBackend::OsMesa => "OSMesaGetProcAddress"
+        };
+        let get_proc: Option<extern "C" fn(*const c_char) -> *const c_void> =
+            unsafe { self.library.function(proc_address) };
+        let get_proc = match get_proc {
+            Some(f) => f,
+            None => return
+        };
+        // EGL only has to return core functions with EGL_KHR_get_all_proc_addresses,
+        // so fall back to the symbols libGL exports
+        let libgl = Library::open(&["libGL.so.1", "libOpenGL.so.0"]).ok();
+        gl::load_with(|name| {
+            let cname = CString::new(name).unwrap();
+            let mut address = get_proc(cname.as_ptr());
+            if address.is_null() {
+                if let Some(ref libgl) = libgl {
+                    address = libgl.symbol(name) as *const c_void;
+                }
+            }
+            address
+        });
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        unsafe {
+            match self.state {
+                ContextState::Egl { display, context } => {
+                    let make_current: Option<EglMakeCurrent> = self.library.function("eglMakeCurrent");
+                    let destroy: Option<extern "C" fn(*mut c_void, *mut c_void) -> c_uint> =
+                        self.library.function("eglDestroyContext");
+                    let terminate: Option<extern "C" fn(*mut c_void) -> c_uint> = self.library.function("eglTerminate");
+                    if let (Some(make_current), Some(destroy), Some(terminate)) = (make_current, destroy, terminate) {
+                        make_current(display, ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+                        destroy(display, context);
+                        terminate(display);
+                    }
+                }
+                ContextState::OsMesa { context, .. } => {
+                    let destroy: Option<extern "C" fn(*mut c_void)> = self.library.function("OSMesaDestroyContext");
+                    if let Some(destroy) = destroy {
+                        destroy(context);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn gl_string(name: GLenum) -> String {
+    unsafe {
+        let s = gl::GetString(name);
+        if s.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(s as *const c_char).to_string_lossy().into_owned()
+        }
+    }
+}
+
+const EGL_NONE: i32 = 0x3038;
+const EGL_SURFACE_TYPE: i32 = 0x3033;
+const EGL_PBUFFER_BIT: i32 = 0x0001;
+const EGL_RENDERABLE_TYPE: i32 = 0x3040;
+const EGL_OPENGL_BIT: i32 = 0x0008;
+const EGL_RED_SIZE: i32 = 0x3024;
+const EGL_GREEN_SIZE: i32 = 0x3023;
+const EGL_BLUE_SIZE: i32 = 0x3022;
+const EGL_OPENGL_API: c_uint = 0x30A2;
+const EGL_CONTEXT_MAJOR_VERSION: i32 = 0x3098;
+const EGL_CONTEXT_MINOR_VERSION: i32 = 0x30FB;
+const EGL_CONTEXT_OPENGL_PROFILE_MASK: i32 = 0x30FD;
+const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: i32 = 0x0001;
+const EGL_PLATFORM_SURFACELESS_MESA: c_uint = 0x31DD;
+
+type EglMakeCurrent = extern "C" fn(*mut c_void, *mut c_void, *mut c_void, *mut c_void) -> c_uint;
+
+fn create_egl() -> Result<HeadlessContext, String> {
+    let library = Library::open(&["libEGL.so.1", "libEGL.so"])?;
+    unsafe {
+        let get_proc: extern "C" fn(*const c_char) -> *const c_void = library.require("eglGetProcAddress")?;
+        let get_display: extern "C" fn(*mut c_void) -> *mut c_void = library.require("eglGetDisplay")?;
+        let initialize: extern "C" fn(*mut c_void, *mut i32, *mut i32) -> c_uint = library.require("eglInitialize")?;
+        let bind_api: extern "C" fn(c_uint) -> c_uint = library.require("eglBindAPI")?;
+        let choose_config: extern "C" fn(*mut c_void, *const i32, *mut *mut c_void, i32, *mut i32) -> c_uint =
+            library.require("eglChooseConfig")?;
+        let create_context: extern "C" fn(*mut c_void, *mut c_void, *mut c_void, *const i32) -> *mut c_void =
+            library.require("eglCreateContext")?;
+        let make_current: EglMakeCurrent = library.require("eglMakeCurrent")?;
+
+        // the surfaceless platform needs neither a GPU nor a display server.
+        // without it, the default display still works on most Mesa builds
+        let name = CString::new("eglGetPlatformDisplayEXT").unwrap();
+        let platform_display = get_proc(name.as_ptr());
+        let mut display = ptr::null_mut();
+        if !platform_display.is_null() {
+            let platform_display: extern "C" fn(c_uint, *mut c_void, *const i32) -> *mut c_void =
+                std::mem::transmute(platform_display);
+            display = platform_display(EGL_PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null());
+        }
+        if display.is_null() {
+            display = get_display(ptr::null_mut());
+        }
+        if display.is_null() {
+            return Err("no EGL display".to_string());
+        }
+        let (mut major, mut minor) = (0, 0);
+        if initialize(display, &mut major, &mut minor) == 0 {
+            return Err("eglInitialize failed".to_string());
+        }
+        if bind_api(EGL_OPENGL_API) == 0 {
+            return Err("desktop OpenGL is not supported by this EGL".to_string());
+        }
+
+        let config_attributes = [
+            EGL_SURFACE_TYPE, EGL_PBUFFER_BIT,
+            EGL_RENDERABLE_TYPE, EGL_OPENGL_BIT,
+            EGL_RED_SIZE, 8, EGL_GREEN_SIZE, 8, EGL_BLUE_SIZE, 8,
+            EGL_NONE
+        ];
+        let mut config = ptr::null_mut();
+        let mut count = 0;
+        if choose_config(display, config_attributes.as_ptr(), &mut config, 1, &mut count) == 0 || count == 0 {
+            return Err("no EGL config supports desktop OpenGL".to_string());
+        }
+
+        let context_attributes = [
+            EGL_CONTEXT_MAJOR_VERSION, 3,
+            EGL_CONTEXT_MINOR_VERSION, 3,
+            EGL_CONTEXT_OPENGL_PROFILE_MASK, EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            EGL_NONE
+        ];
+        let context = create_context(display, config, ptr::null_mut(), context_attributes.as_ptr());
+        if context.is_null() {
+            return Err("unable to create a GL 3.3 core EGL context".to_string());
+        }
+        // no surface at all, everything is drawn into framebuffer objects
+        if make_current(display, ptr::null_mut(), ptr::null_mut(), context) == 0 {
+            return Err("surfaceless contexts are not supported".to_string());
+        }
+        Ok(HeadlessContext {
+            backend: HeadlessBackend::Egl,
+            library,
+            state: ContextState::Egl { display, context }
+        })
+    }
+}
+
+const OSMESA_FORMAT: c_int = 0x22;
+const OSMESA_RGBA: c_int = 0x1908;
+const OSMESA_DEPTH_BITS: c_int = 0x30;
+const OSMESA_STENCIL_BITS: c_int = 0x31;
+const OSMESA_PROFILE: c_int = 0x33;
+const OSMESA_CORE_PROFILE: c_int = 0x34;
+const OSMESA_CONTEXT_MAJOR_VERSION: c_int = 0x36;
+const OSMESA_CONTEXT_MINOR_VERSION: c_int = 0x37;
+
+fn create_osmesa() -> Result<HeadlessContext, String> {
+    let library = Library::open(&["libOSMesa.so.8", "libOSMesa.so.6", "libOSMesa.so"])?;
+    unsafe {
+        let create_context: extern "C" fn(*const c_int, *mut c_void) -> *mut c_void =
+            library.require("OSMesaCreateContextAttribs")?;
+        let make_current: extern "C" fn(*mut c_void, *mut c_void, GLenum, c_int, c_int) -> u8 =
+            library.require("OSMesaMakeCurrent")?;
+
+        let attributes = [
+            OSMESA_FORMAT, OSMESA_RGBA,
+            OSMESA_DEPTH_BITS, 24,
+            OSMESA_STENCIL_BITS, 8,
+            OSMESA_PROFILE, OSMESA_CORE_PROFILE,
+            OSMESA_CONTEXT_MAJOR_VERSION, 3,
+            OSMESA_CONTEXT_MINOR_VERSION, 3,
+            0
+        ];
+        let context = create_context(attributes.as_ptr(), ptr::null_mut());
+        if context.is_null() {
+            return Err("unable to create a GL 3.3 core OSMesa context".to_string());
+        }
+        let mut buffer = vec![0u8; 4];
+        if make_current(context, buffer.as_mut_ptr() as *mut c_void, gl::UNSIGNED_BYTE, 1, 1) == 0 {
+            return Err("OSMesaMakeCurrent failed".to_string());
+        }
+        Ok(HeadlessContext {
+            backend: HeadlessBackend::OsMesa,
+            library,
+            state: ContextState::OsMesa { context, _buffer: buffer }
+        })
+    }
+}
+
+// an RGBA8 colour target with a depth buffer, the usual thing to point a
+// demo at when running headless
+pub fn offscreen_target(width: u32, height: u32) -> Result<Framebuffer, String> {
+    Ok(Framebuffer::new(FramebufferDesc::new(width, height)
+        .color(ColorFormat::Rgba8)
+        .depth_renderbuffer(DepthFormat::Depth24Stencil8))?)
+}
+
+// RGBA8 pixels of a colour attachment, bottom row first as GL stores them
+pub fn read_pixels(framebuffer: &Framebuffer, index: usize) -> Vec<u8> {
+    capture::read_pixels(capture::Source::Attachment(framebuffer, index))
+}
+
+#[cfg(unix)]
+const RTLD_NOW: c_int = 2;
+
+#[cfg(unix)]
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+}
+
+// a shared library opened with dlopen
+struct Library {
+    handle: *mut c_void
+}
+
+impl Library {
+    // opens the first of the given names that loads
+    #[cfg(unix)]
+    fn open(names: &[&str]) -> Result<Library, String> {
+        for name in names {
+            let cname = CString::new(*name).unwrap();
+            let handle = unsafe { dlopen(cname.as_ptr(), RTLD_NOW) };
+            if !handle.is_null() {
+                return Ok(Library { handle });
+            }
+        }
+        Err(format!("unable to load {}", names.join(" or ")))
+    }
+
+    #[cfg(not(unix))]
+    fn open(names: &[&str]) -> Result<Library, String> {
+        Err(format!("unable to load {}: headless rendering needs a unix dynamic loader", names.join(" or ")))
+    }
+
+    #[cfg(unix)]
+    fn symbol(&self, name: &str) -> *mut c_void {
+        let cname = CString::new(name).unwrap();
+        unsafe { dlsym(self.handle, cname.as_ptr()) }
+    }
+
+    #[cfg(not(unix))]
+    fn symbol(&self, _name: &str) -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    // F has to be the extern "C" fn type of the symbol
+    unsafe fn function<F: Copy>(&self, name: &str) -> Option<F> {
+        let address = self.symbol(name);
+        if address.is_null() {
+            None
+        } else {
+            Some(std::mem::transmute_copy(&address))
+        }
+    }
+
+    unsafe fn require<F: Copy>(&self, name: &str) -> Result<F, String> {
+        self.function(name).ok_or_else(|| format!("missing {}", name))
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            dlclose(self.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_backend_names() {
+        assert_eq!(HeadlessBackend::parse("egl"), Ok(HeadlessBackend::Egl));
+        assert_eq!(HeadlessBackend::parse(" OSMesa "), Ok(HeadlessBackend::OsMesa));
+        assert!(HeadlessBackend::parse("glx").is_err());
+        for backend in [HeadlessBackend::Egl, HeadlessBackend::OsMesa].iter() {
+            assert_eq!(HeadlessBackend::parse(backend.name()), Ok(*backend));
+        }
+    }
+
+    #[test]
+    fn requested_backend_is_the_only_candidate() {
+        assert_eq!(HeadlessBackend::candidates(Some("osmesa")).unwrap(), vec![HeadlessBackend::OsMesa]);
+        assert_eq!(HeadlessBackend::candidates(None).unwrap(),
+                   vec![HeadlessBackend::Egl, HeadlessBackend::OsMesa]);
+        assert_eq!(HeadlessBackend::candidates(Some("")).unwrap().len(), 2);
+        assert!(HeadlessBackend::candidates(Some("wgl")).is_err());
+    }
+}
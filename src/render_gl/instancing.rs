@@ -0,0 +1,308 @@
+use crate::glm::*;
+use crate::render_gl::debug;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::Program;
+use gl::types::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// per instance attributes, read by shaders/instancing.glsl: the model
+// matrix takes four locations (one per column), then a colour and four
+// floats the shader can use however it likes. meshes use the locations
+// below these for their vertices
+pub const INSTANCE_LOCATION: GLuint = 8;
+pub const INSTANCE_ATTRIBUTES: GLuint = 6;
+// floats per instance
+pub const INSTANCE_STRIDE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceData {
+    pub model: Mat4,
+    pub color: Vec4,
+    pub custom: Vec4
+}
+
+impl InstanceData {
+    // white, custom data zeroed
+    pub fn new(model: &Mat4) -> InstanceData {
+        InstanceData { model: *model, color: make_vec4(&[1.0, 1.0, 1.0, 1.0]), custom: make_vec4(&[0.0; 4]) }
+    }
+
+    pub fn color(mut self, color: &Vec4) -> InstanceData {
+        self.color = *color;
+        self
+    }
+
+    pub fn custom(mut self, custom: &Vec4) -> InstanceData {
+        self.custom = *custom;
+        self
+    }
+
+    fn write(&self, out: &mut Vec<f32>) {
+        out.extend_from_slice(self.model.as_slice());
+        out.extend_from_slice(self.color.as_slice());
+        out.extend_from_slice(self.custom.as_slice());
+    }
+}
+
+// tells instancing.glsl whether the model matrix comes from the instance
+// attributes or the `model` uniform. stays set on the program, so code
+// drawing with the same program both ways has to set it every time
+pub fn set_instanced(program: &Program, instanced: bool) {
+    program.set_uniform_1i("instanced", instanced as i32);
+}
+
+// meshes remember which buffer their instance attributes point at, and
+// buffer names get reused, so each buffer gets a number of its own
+static NEXT_SERIAL: AtomicUsize = AtomicUsize::new(1);
+
+// a vertex buffer of InstanceData, grown as needed
+pub struct InstanceBuffer {
+    vbo: GLuint,
+    serial: usize,
+    len: usize,
+    capacity: usize,
+    data: Vec<f32>
+}
+
+impl Default for InstanceBuffer {
+    fn default() -> InstanceBuffer {
+        InstanceBuffer::new()
+    }
+}
+
+impl InstanceBuffer {
+    pub fn new() -> InstanceBuffer {
+        let mut vbo: GLuint = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut vbo);
+        }
+        InstanceBuffer { vbo: vbo, serial: NEXT_SERIAL.fetch_add(1, Ordering::Relaxed), len: 0, capacity: 0, data: Vec::new() }
+    }
+
+    pub fn with_instances(instances: &[InstanceData]) -> InstanceBuffer {
+        let mut buffer = InstanceBuffer::new();
+        buffer.upload(instances);
+        buffer
+    }
+
+    pub fn id(&self) -> GLuint {
+        self.vbo
+    }
+
+    pub fn serial(&self) -> usize {
+        self.serial
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn set_label(&self, label: &str) {
+        debug::label_buffer(self.vbo, label);
+    }
+
+    // replaces the contents. the storage is only reallocated when it has
+    // to grow, otherwise the old contents are orphaned and overwritten
+    pub fn upload(&mut self, instances: &[InstanceData]) {
+        self.data.clear();
+        for instance in instances {
+            instance.write(&mut self.data);
+        }
+        self.len = instances.len();
+        let size = (self.data.len() * std::mem::size_of::<f32>()) as GLsizeiptr;
+        unsafe {
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            if self.len > self.capacity {
+                self.capacity = self.len.next_power_of_two();
+            }
+            if self.capacity > 0 {
+                let capacity = (self.capacity * INSTANCE_STRIDE * std::mem::size_of::<f32>()) as GLsizeiptr;
+                gl::BufferData(gl::ARRAY_BUFFER, capacity, std::ptr::null(), gl::DYNAMIC_DRAW);
+            }
+            if size > 0 {
+                gl::BufferSubData(gl::ARRAY_BUFFER, 0, size, self.data.as_ptr() as *const GLvoid);
+            }
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+        }
+    }
+}
+
+impl Drop for InstanceBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
+struct Batch<'a, M> {
+    mesh: &'a Mesh,
+    material: &'a M,
+    instances: Vec<InstanceData>
+}
+
+// collects the draws of a frame and merges those that share a mesh and a
+// material into one instanced draw. both are compared by address, so
+// draws only merge when they use the very same Mesh and material, not
+// copies of them. `M` is whatever the setup callback needs to bind a
+// material, e.g a material::Material or a Program
+pub struct InstanceBatcher<'a, M> {
+    batches: Vec<Batch<'a, M>>,
+    instances: usize
+}
+
+impl<'a, M> Default for InstanceBatcher<'a, M> {
+    fn default() -> InstanceBatcher<'a, M> {
+        InstanceBatcher::new()
+    }
+}
+
+impl<'a, M> InstanceBatcher<'a, M> {
+    pub fn new() -> InstanceBatcher<'a, M> {
+        InstanceBatcher { batches: Vec::new(), instances: 0 }
+    }
+
+    pub fn push(&mut self, mesh: &'a Mesh, material: &'a M, instance: InstanceData) {
+        self.instances += 1;
+        let existing = self.batches.iter_mut()
+            .find(|b| std::ptr::eq(b.mesh, mesh) && std::ptr::eq(b.material, material));
+        match existing {
+            Some(batch) => batch.instances.push(instance),
+            None => self.batches.push(Batch { mesh: mesh, material: material, instances: vec![instance] })
+        }
+    }
+
+    // draw calls draw() will make, one per batch
+    pub fn draw_calls(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn instances(&self) -> usize {
+        self.instances
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.batches.clear();
+        self.instances = 0;
+    }
+
+    // every instance, batch after batch in the order the batches were
+    // started. this is the layout upload() writes
+    pub fn instance_data(&self) -> Vec<InstanceData> {
+        self.batches.iter().flat_map(|b| b.instances.iter().cloned()).collect()
+    }
+
+    pub fn upload(&self, buffer: &mut InstanceBuffer) {
+        buffer.upload(&self.instance_data());
+    }
+
+    // draws each batch with one instanced call from `buffer`, which has to
+    // hold what upload() put there. uploading once allows several passes,
+    // e.g shadow maps and the main view. `setup` is called whenever the
+    // material changes and has to bind it, including
+    // set_instanced(program, true)
+    pub fn draw(&self, buffer: &InstanceBuffer, setup: &mut dyn FnMut(&M)) {
+        let mut first = 0;
+        let mut previous: Option<&M> = None;
+        for batch in &self.batches {
+            let same = match previous {
+                Some(p) => std::ptr::eq(p, batch.material),
+                None => false
+            };
+            if !same {
+                setup(batch.material);
+                previous = Some(batch.material);
+            }
+            batch.mesh.draw_instances(buffer, first, batch.instances.len());
+            first += batch.instances.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::mock::MockGl;
+    use crate::render_gl::{shader_lib, Shader};
+    use std::ffi::CString;
+
+    fn program() -> Program {
+        let source = |s: &str| CString::new(s).unwrap();
+        Program::from_shaders(&[
+            Shader::from_vert_source(&source("#version 330 core\nvoid main() {}")).unwrap(),
+            Shader::from_frag_source(&source("#version 330 core\nvoid main() {}")).unwrap()
+        ]).unwrap()
+    }
+
+    fn at(x: f32) -> InstanceData {
+        InstanceData::new(&translate(&Mat4::identity(), &make_vec3(&[x, 0.0, 0.0])))
+    }
+
+    #[test]
+    fn instance_layout() {
+        let mut data = Vec::new();
+        at(3.0).color(&make_vec4(&[0.1, 0.2, 0.3, 0.4])).custom(&make_vec4(&[5.0, 6.0, 7.0, 8.0])).write(&mut data);
+        assert_eq!(data.len(), INSTANCE_STRIDE);
+        // column major, so the translation is the fourth column
+        assert_eq!(&data[12..16], &[3.0, 0.0, 0.0, 1.0]);
+        assert_eq!(&data[16..24], &[0.1, 0.2, 0.3, 0.4, 5.0, 6.0, 7.0, 8.0]);
+
+        let source = shader_lib::library_source("instancing.glsl").unwrap();
+        assert!(source.contains(&format!("layout (location = {}) in mat4 instanceModel", INSTANCE_LOCATION)));
+        assert!(source.contains(&format!("layout (location = {}) in vec4 instanceData", INSTANCE_LOCATION + INSTANCE_ATTRIBUTES - 1)));
+    }
+
+    #[test]
+    fn draws_sharing_mesh_and_material_are_merged() {
+        let gl = MockGl::install();
+        let (cube, sphere) = (Mesh::cube(), Mesh::sphere(8, 4));
+        let (wood, metal) = (program(), program());
+        let mut batcher = InstanceBatcher::new();
+        batcher.push(&cube, &wood, at(0.0));
+        batcher.push(&cube, &metal, at(1.0));
+        batcher.push(&sphere, &wood, at(2.0));
+        batcher.push(&cube, &wood, at(3.0));
+        batcher.push(&cube, &metal, at(4.0));
+        assert_eq!((batcher.draw_calls(), batcher.instances()), (3, 5));
+        // grouped by batch, each in submission order
+        let xs: Vec<f32> = batcher.instance_data().iter().map(|i| i.model[(0, 3)]).collect();
+        assert_eq!(xs, vec![0.0, 3.0, 1.0, 4.0, 2.0]);
+
+        let mut buffer = InstanceBuffer::new();
+        batcher.upload(&mut buffer);
+        let mut bound = Vec::new();
+        batcher.draw(&buffer, &mut |material: &Program| {
+            material.set_used();
+            set_instanced(material, true);
+            bound.push(material.id());
+        });
+        assert_eq!(bound, vec![wood.id(), metal.id(), wood.id()]);
+        let state = gl.state();
+        assert_eq!(state.draws.len(), 3);
+        assert_eq!(state.draws[0].count, cube.count());
+        assert_eq!(state.draws[0].instances, 2);
+        assert_eq!(state.draws[2].instances, 1);
+        assert_eq!(state.buffer_sizes[&buffer.id()], (8 * INSTANCE_STRIDE * 4) as isize);
+        assert!(state.errors.is_empty(), "{:?}", state.errors);
+    }
+
+    #[test]
+    fn buffers_only_grow() {
+        let gl = MockGl::install();
+        let mut buffer = InstanceBuffer::with_instances(&[at(0.0), at(1.0), at(2.0)]);
+        assert_eq!(gl.state().buffer_sizes[&buffer.id()], (4 * INSTANCE_STRIDE * 4) as isize);
+        buffer.upload(&[at(0.0)]);
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(gl.state().buffer_sizes[&buffer.id()], (4 * INSTANCE_STRIDE * 4) as isize);
+        let other = InstanceBuffer::new();
+        assert_ne!(other.serial(), buffer.serial());
+    }
+}
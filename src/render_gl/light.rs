@@ -0,0 +1,326 @@
+use crate::glm::*;
+use crate::render_gl::{to_radians, Program};
+use crate::render_gl::shadow;
+use crate::render_gl::ssao;
+
+// keep these in sync with the #defines in shaders/lights.glsl
+pub const MAX_DIR_LIGHTS: usize = 4;
+pub const MAX_POINT_LIGHTS: usize = 16;
+pub const MAX_SPOT_LIGHTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32
+}
+
+// (range, linear, quadratic). constant is always 1.0
+// Source: http://wiki.ogre3d.org/tiki-index.php?page=-Point+Light+Attenuation
+const ATTENUATION_TABLE: &[(f32, f32, f32)] = &[
+    (7.0, 0.7, 1.8),
+    (13.0, 0.35, 0.44),
+    (20.0, 0.22, 0.20),
+    (32.0, 0.14, 0.07),
+    (50.0, 0.09, 0.032),
+    (65.0, 0.07, 0.017),
+    (100.0, 0.045, 0.0075),
+    (160.0, 0.027, 0.0028),
+    (200.0, 0.022, 0.0019),
+    (325.0, 0.014, 0.0007),
+    (600.0, 0.007, 0.0002),
+    (3250.0, 0.0014, 0.000007),
+];
+
+impl Attenuation {
+    pub fn new(constant: f32, linear: f32, quadratic: f32) -> Attenuation {
+        Attenuation { constant: constant, linear: linear, quadratic: quadratic }
+    }
+
+    // picks the smallest preset that still reaches `range` world units
+    pub fn for_range(range: f32) -> Attenuation {
+        let (_, linear, quadratic) = ATTENUATION_TABLE.iter()
+            .find(|(r, _, _)| *r >= range)
+            .unwrap_or(&ATTENUATION_TABLE[ATTENUATION_TABLE.len() - 1]);
+        Attenuation::new(1.0, *linear, *quadratic)
+    }
+
+    // the range of the preset this is, so for_range(r).range() is r
+    // rounded up to a preset. None for any other coefficients
+    pub fn range(&self) -> Option<f32> {
+        ATTENUATION_TABLE.iter()
+            .find(|(_, linear, quadratic)| *self == Attenuation::new(1.0, *linear, *quadratic))
+            .map(|(range, _, _)| *range)
+    }
+
+    pub fn at(&self, distance: f32) -> f32 {
+        1.0 / (self.constant + self.linear * distance + self.quadratic * distance * distance)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectionalLight {
+    // direction the light travels in, i.e from the light towards the scene
+    pub direction: Vec3,
+    pub ambient: Vec3,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub cast_shadows: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub ambient: Vec3,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub attenuation: Attenuation,
+    pub cast_shadows: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub ambient: Vec3,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub attenuation: Attenuation,
+    // cone angles in degrees. full intensity inside inner, none outside outer
+    pub inner_cutoff: f32,
+    pub outer_cutoff: f32,
+    pub cast_shadows: bool
+}
+
+impl DirectionalLight {
+    pub fn new(direction: &Vec3, color: &Vec3) -> DirectionalLight {
+        DirectionalLight {
+            direction: normalize(direction),
+            ambient: color * 0.1,
+            diffuse: color.clone(),
+            specular: color.clone(),
+            cast_shadows: false
+        }
+    }
+}
+
+impl PointLight {
+    pub fn new(position: &Vec3, color: &Vec3, range: f32) -> PointLight {
+        PointLight {
+            position: position.clone(),
+            ambient: color * 0.05,
+            diffuse: color.clone(),
+            specular: color.clone(),
+            attenuation: Attenuation::for_range(range),
+            cast_shadows: false
+        }
+    }
+}
+
+impl SpotLight {
+    pub fn new(position: &Vec3, direction: &Vec3, color: &Vec3, range: f32) -> SpotLight {
+        SpotLight {
+            position: position.clone(),
+            direction: normalize(direction),
+            ambient: make_vec3(&[0.0, 0.0, 0.0]),
+            diffuse: color.clone(),
+            specular: color.clone(),
+            attenuation: Attenuation::for_range(range),
+            inner_cutoff: 12.5,
+            outer_cutoff: 17.5,
+            cast_shadows: false
+        }
+    }
+}
+
+// all the lights affecting a draw. uploaded to the uniform arrays declared
+// in shaders/lights.glsl; lights beyond the MAX_* limits are ignored
+pub struct LightSet {
+    pub directional: Vec<DirectionalLight>,
+    pub point: Vec<PointLight>,
+    pub spot: Vec<SpotLight>
+}
+
+impl LightSet {
+    pub fn new() -> LightSet {
+        LightSet { directional: Vec::new(), point: Vec::new(), spot: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.directional.clear();
+        self.point.clear();
+        self.spot.clear();
+    }
+
+    pub fn num_directional(&self) -> usize {
+        self.directional.len().min(MAX_DIR_LIGHTS)
+    }
+
+    pub fn num_point(&self) -> usize {
+        self.point.len().min(MAX_POINT_LIGHTS)
+    }
+
+    pub fn num_spot(&self) -> usize {
+        self.spot.len().min(MAX_SPOT_LIGHTS)
+    }
+
+    // there is one shadow map per kind of light, so these find the only
+    // shadow caster of each kind. see check_shadow_casters
+    pub fn directional_shadow_caster(&self) -> Option<usize> {
+        self.directional.iter().take(MAX_DIR_LIGHTS).position(|l| l.cast_shadows)
+    }
+
+    pub fn point_shadow_caster(&self) -> Option<usize> {
+        self.point.iter().take(MAX_POINT_LIGHTS).position(|l| l.cast_shadows)
+    }
+
+    pub fn spot_shadow_caster(&self) -> Option<usize> {
+        self.spot.iter().take(MAX_SPOT_LIGHTS).position(|l| l.cast_shadows)
+    }
+
+    // errors when more than one light of a kind casts shadows, or when the
+    // caster is past the lights the shaders see. ShadowRenderer::render
+    // fails with this rather than leaving lights without their shadows
+    pub fn check_shadow_casters(&self) -> Result<(), String> {
+        let directional: Vec<bool> = self.directional.iter().map(|l| l.cast_shadows).collect();
+        let point: Vec<bool> = self.point.iter().map(|l| l.cast_shadows).collect();
+        let spot: Vec<bool> = self.spot.iter().map(|l| l.cast_shadows).collect();
+        check_casters("directional", &directional, MAX_DIR_LIGHTS)?;
+        check_casters("point", &point, MAX_POINT_LIGHTS)?;
+        check_casters("spot", &spot, MAX_SPOT_LIGHTS)
+    }
+
+    // the program must be in use
+    pub fn apply(&self, program: &Program) {
+        program.set_uniform_1i("numDirLights", self.num_directional() as i32);
+        program.set_uniform_1i("numPointLights", self.num_point() as i32);
+        program.set_uniform_1i("numSpotLights", self.num_spot() as i32);
+
+        let index = |i: Option<usize>| i.map(|i| i as i32).unwrap_or(-1);
+        program.set_uniform_1i("dirShadowLight", index(self.directional_shadow_caster()));
+        program.set_uniform_1i("pointShadowLight", index(self.point_shadow_caster()));
+        program.set_uniform_1i("spotShadowLight", index(self.spot_shadow_caster()));
+        set_sampler_units(program);
+
+        for (i, light) in self.directional.iter().take(MAX_DIR_LIGHTS).enumerate() {
+            let name = |field: &str| format!("dirLights[{}].{}", i, field);
+            program.set_uniform_vec3(&name("direction"), &light.direction);
+            program.set_uniform_vec3(&name("ambient"), &light.ambient);
+            program.set_uniform_vec3(&name("diffuse"), &light.diffuse);
+            program.set_uniform_vec3(&name("specular"), &light.specular);
+        }
+        for (i, light) in self.point.iter().take(MAX_POINT_LIGHTS).enumerate() {
+            let name = |field: &str| format!("pointLights[{}].{}", i, field);
+            program.set_uniform_vec3(&name("position"), &light.position);
+            program.set_uniform_vec3(&name("ambient"), &light.ambient);
+            program.set_uniform_vec3(&name("diffuse"), &light.diffuse);
+            program.set_uniform_vec3(&name("specular"), &light.specular);
+            program.set_uniform_1f(&name("constant"), light.attenuation.constant);
+            program.set_uniform_1f(&name("linear"), light.attenuation.linear);
+            program.set_uniform_1f(&name("quadratic"), light.attenuation.quadratic);
+        }
+        for (i, light) in self.spot.iter().take(MAX_SPOT_LIGHTS).enumerate() {
+            let name = |field: &str| format!("spotLights[{}].{}", i, field);
+            program.set_uniform_vec3(&name("position"), &light.position);
+            program.set_uniform_vec3(&name("direction"), &light.direction);
+            program.set_uniform_vec3(&name("ambient"), &light.ambient);
+            program.set_uniform_vec3(&name("diffuse"), &light.diffuse);
+            program.set_uniform_vec3(&name("specular"), &light.specular);
+            program.set_uniform_1f(&name("constant"), light.attenuation.constant);
+            program.set_uniform_1f(&name("linear"), light.attenuation.linear);
+            program.set_uniform_1f(&name("quadratic"), light.attenuation.quadratic);
+            program.set_uniform_1f(&name("innerCutoff"), to_radians(light.inner_cutoff).cos());
+            program.set_uniform_1f(&name("outerCutoff"), to_radians(light.outer_cutoff).cos());
+        }
+    }
+}
+
+fn check_casters(kind: &str, casts_shadows: &[bool], max: usize) -> Result<(), String> {
+    let casters: Vec<usize> = (0..casts_shadows.len()).filter(|&i| casts_shadows[i]).collect();
+    if casters.len() > 1 {
+        return Err(format!("{} {} lights cast shadows, only one light of each kind can", casters.len(), kind));
+    }
+    match casters.first() {
+        Some(&i) if i >= max => Err(format!("{} light {} casts shadows, but only the first {} are lit", kind, i, max)),
+        _ => Ok(())
+    }
+}
+
+// the shadow and SSAO samplers declared by lights.glsl always live on their
+// own units, even when no map is bound, so they never clash with material
+// textures on unit 0. the program must be in use
+pub fn set_sampler_units(program: &Program) {
+    program.set_uniform_1i("dirShadowMap", shadow::DIR_SHADOW_UNIT as i32);
+    program.set_uniform_1i("spotShadowMap", shadow::SPOT_SHADOW_UNIT as i32);
+    program.set_uniform_1i("pointShadowMap", shadow::POINT_SHADOW_UNIT as i32);
+    program.set_uniform_1i("ssaoMap", ssao::SSAO_UNIT as i32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::shader_lib;
+
+    #[test]
+    fn attenuation_presets_cover_the_range() {
+        assert_eq!(Attenuation::for_range(50.0), Attenuation::new(1.0, 0.09, 0.032));
+        assert_eq!(Attenuation::for_range(51.0), Attenuation::new(1.0, 0.07, 0.017));
+        assert_eq!(Attenuation::for_range(1.0), Attenuation::new(1.0, 0.7, 1.8));
+        assert_eq!(Attenuation::for_range(1e6), Attenuation::new(1.0, 0.0014, 0.000007));
+        // every preset leaves only a few percent of the light at its range
+        for (range, _, _) in ATTENUATION_TABLE {
+            assert!(Attenuation::for_range(*range).at(*range) < 0.05);
+        }
+    }
+
+    #[test]
+    fn only_presets_have_a_range() {
+        assert_eq!(Attenuation::for_range(40.0).range(), Some(50.0));
+        assert_eq!(Attenuation::new(1.0, 0.09, 0.03).range(), None);
+        assert_eq!(Attenuation::new(1.0, std::f32::NAN, 0.032).range(), None);
+    }
+
+    #[test]
+    fn light_counts_are_capped() {
+        let mut lights = LightSet::new();
+        for i in 0..(MAX_POINT_LIGHTS + 3) {
+            lights.point.push(PointLight::new(&make_vec3(&[i as f32, 0.0, 0.0]), &make_vec3(&[1.0, 1.0, 1.0]), 20.0));
+        }
+        assert_eq!(lights.num_point(), MAX_POINT_LIGHTS);
+        assert_eq!(lights.num_spot(), 0);
+    }
+
+    #[test]
+    fn one_shadow_caster_per_kind() {
+        let mut lights = LightSet::new();
+        let white = make_vec3(&[1.0, 1.0, 1.0]);
+        for i in 0..3 {
+            let mut light = PointLight::new(&make_vec3(&[i as f32, 0.0, 0.0]), &white, 20.0);
+            light.cast_shadows = i > 0;
+            lights.point.push(light);
+        }
+        assert!(lights.check_shadow_casters().is_err());
+        lights.point[2].cast_shadows = false;
+        assert_eq!(lights.check_shadow_casters(), Ok(()));
+        assert_eq!(lights.point_shadow_caster(), Some(1));
+        assert_eq!(lights.spot_shadow_caster(), None);
+
+        // a caster past the lights the shaders see would get no map either
+        let mut lights = LightSet::new();
+        for i in 0..=MAX_SPOT_LIGHTS {
+            let mut light = SpotLight::new(&white, &white, &white, 20.0);
+            light.cast_shadows = i == MAX_SPOT_LIGHTS;
+            lights.spot.push(light);
+        }
+        assert!(lights.check_shadow_casters().is_err());
+        assert_eq!(lights.spot_shadow_caster(), None);
+    }
+
+    #[test]
+    fn limits_match_the_shader_library() {
+        let source = shader_lib::library_source("lights.glsl").unwrap();
+        assert!(source.contains(&format!("#define MAX_DIR_LIGHTS {}", MAX_DIR_LIGHTS)));
+        assert!(source.contains(&format!("#define MAX_POINT_LIGHTS {}", MAX_POINT_LIGHTS)));
+        assert!(source.contains(&format!("#define MAX_SPOT_LIGHTS {}", MAX_SPOT_LIGHTS)));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::glm::*;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::state;
+use crate::render_gl::texture::Texture;
+use crate::render_gl::transparency;
+use crate::render_gl::{load_texture, shader_lib, Program, Shader};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub enum MaterialParam {
+    Float(f32),
+    Int(i32),
+    Vec3(Vec3),
+    Vec4(Vec4),
+    Mat4(Mat4),
+    Texture(Rc<Texture>)
+}
+
+impl PartialEq for MaterialParam {
+    fn eq(&self, other: &MaterialParam) -> bool {
+        match (self, other) {
+            (MaterialParam::Float(a), MaterialParam::Float(b)) => a == b,
+            (MaterialParam::Int(a), MaterialParam::Int(b)) => a == b,
+            (MaterialParam::Vec3(a), MaterialParam::Vec3(b)) => a == b,
+            (MaterialParam::Vec4(a), MaterialParam::Vec4(b)) => a == b,
+            (MaterialParam::Mat4(a), MaterialParam::Mat4(b)) => a == b,
+            (MaterialParam::Texture(a), MaterialParam::Texture(b)) => a.get_id() == b.get_id(),
+            _ => false
+        }
+    }
+}
+
+// uniform name -> value. defaults are shared between all instances of a
+// material, each instance only stores what it overrides
+#[derive(Debug, Clone)]
+pub struct ParamMap {
+    defaults: Rc<BTreeMap<String, MaterialParam>>,
+    overrides: BTreeMap<String, MaterialParam>
+}
+
+impl ParamMap {
+    pub fn new(defaults: BTreeMap<String, MaterialParam>) -> ParamMap {
+        ParamMap { defaults: Rc::new(defaults), overrides: BTreeMap::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MaterialParam> {
+        self.overrides.get(name).or_else(|| self.defaults.get(name))
+    }
+
+    pub fn set(&mut self, name: &str, value: MaterialParam) {
+        self.overrides.insert(name.to_string(), value);
+    }
+
+    // drops an override so the default shows through again
+    pub fn reset(&mut self, name: &str) {
+        self.overrides.remove(name);
+    }
+
+    pub fn is_overridden(&self, name: &str) -> bool {
+        self.overrides.contains_key(name)
+    }
+
+    // every parameter, sorted by name, with overrides applied
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a MaterialParam)> + 'a {
+        self.defaults.keys()
+            .chain(self.overrides.keys().filter(move |k| !self.defaults.contains_key(*k)))
+            .map(move |k| (k.as_str(), self.get(k).unwrap()))
+    }
+
+    // the effective values of this map become the defaults of the instance
+    pub fn instance(&self) -> ParamMap {
+        if self.overrides.is_empty() {
+            return ParamMap { defaults: self.defaults.clone(), overrides: BTreeMap::new() };
+        }
+        let merged = self.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        ParamMap::new(merged)
+    }
+
+    // texture parameters in the order they are assigned to texture units
+    pub fn texture_slots(&self) -> Vec<(&str, &Rc<Texture>)> {
+        self.iter().filter_map(|(name, value)| match value {
+            MaterialParam::Texture(t) => Some((name, t)),
+            _ => None
+        }).collect()
+    }
+
+    // parameters whose value differs from `previous` (or that it doesn't have)
+    pub fn changed_since<'a>(&'a self, previous: &ParamMap) -> Vec<(&'a str, &'a MaterialParam)> {
+        self.iter().filter(|(name, value)| previous.get(name) != Some(*value)).collect()
+    }
+}
+
+// how a material's surfaces combine with what is already drawn. Alpha and
+// Additive objects go through a transparency::TransparentQueue, sorted back
+// to front; WeightedBlended ones are composited order independently, which
+// also works where they intersect (see transparency.rs)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Opaque,
+    Alpha,
+    Additive,
+    WeightedBlended
+}
+
+impl BlendMode {
+    pub fn is_transparent(&self) -> bool {
+        *self != BlendMode::Opaque
+    }
+
+    pub fn pipeline(&self) -> PipelineState {
+        match self {
+            BlendMode::Opaque => PipelineState::opaque(),
+            BlendMode::Alpha => PipelineState::alpha_blended(),
+            BlendMode::Additive => PipelineState::additive(),
+            BlendMode::WeightedBlended => transparency::accumulation_pipeline()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Material {
+    program: Rc<Program>,
+    pub params: ParamMap,
+    pub blend: BlendMode
+}
+
+impl Material {
+    pub fn new(program: Rc<Program>, defaults: BTreeMap<String, MaterialParam>) -> Material {
+        Material { program: program, params: ParamMap::new(defaults), blend: BlendMode::Opaque }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn shares_program(&self, other: &Material) -> bool {
+        Rc::ptr_eq(&self.program, &other.program)
+    }
+
+    // a copy that shares the program and textures; overrides set on the
+    // instance don't affect this material
+    pub fn instance(&self) -> Material {
+        Material { program: self.program.clone(), params: self.params.instance(), blend: self.blend }
+    }
+
+    pub fn set(&mut self, name: &str, value: MaterialParam) -> &mut Material {
+        self.params.set(name, value);
+        self
+    }
+
+    // binds the program and uploads the parameters. if `previous` is the
+    // material that was applied last, only what differs from it is touched
+    pub fn apply(&self, previous: Option<&Material>) {
+        match previous {
+            Some(p) if self.shares_program(p) => {
+                let slots = self.params.texture_slots();
+                let same_slots = slots.iter().map(|(n, _)| *n)
+                    .eq(p.params.texture_slots().iter().map(|(n, _)| *n));
+                for (name, value) in self.params.changed_since(&p.params) {
+                    match value {
+                        MaterialParam::Texture(_) if same_slots => {
+                            let unit = slots.iter().position(|(n, _)| *n == name).unwrap();
+                            bind_texture(unit, value);
+                        }
+                        MaterialParam::Texture(_) => {}
+                        _ => self.upload(name, value)
+                    }
+                }
+                if !same_slots {
+                    self.upload_textures();
+                }
+            }
+            _ => {
+                self.program.set_used();
+                for (name, value) in self.params.iter() {
+                    if let MaterialParam::Texture(_) = value {
+                        continue;
+                    }
+                    self.upload(name, value);
+                }
+                self.upload_textures();
+            }
+        }
+    }
+
+    fn upload_textures(&self) {
+        for (unit, (name, texture)) in self.params.texture_slots().into_iter().enumerate() {
+            bind_texture(unit, &MaterialParam::Texture(texture.clone()));
+            self.program.set_uniform_1i(name, unit as i32);
+        }
+    }
+
+    fn upload(&self, name: &str, value: &MaterialParam) {
+        match value {
+            MaterialParam::Float(v) => self.program.set_uniform_1f(name, *v),
+            MaterialParam::Int(v) => self.program.set_uniform_1i(name, *v),
+            MaterialParam::Vec3(v) => self.program.set_uniform_vec3(name, v),
+            MaterialParam::Vec4(v) => self.program.set_uniform_vec4(name, v),
+            MaterialParam::Mat4(v) => self.program.set_uniform_mat4(name, v),
+            MaterialParam::Texture(_) => None
+        };
+    }
+
+    // see MaterialDesc for the file format
+    pub fn load(path: &str) -> Result<Material, String> {
+        MaterialDesc::load(path)?.build()
+    }
+}
+
+fn bind_texture(unit: usize, value: &MaterialParam) {
+    if let MaterialParam::Texture(t) = value {
+        state::active_texture(gl::TEXTURE0 + unit as u32);
+        state::bind_texture(gl::TEXTURE_2D, t.get_id());
+    }
+}
+
+// a material description file looks like
+//
+// {
+//   "vertex": "../src/bin/lightingmap.vert",
+//   "fragment": "../src/bin/lightingmap.frag",
+//   "blend": "alpha",
+//   "params": {
+//     "material.shininess": 32.0,
+//     "light.ambient": [0.2, 0.2, 0.2],
+//     "material.diffuse": { "texture": "container2.png" }
+//   }
+// }
+//
+// blend is one of opaque (the default), alpha, additive or weighted_blended.
+// numbers are always floats. arrays of 3, 4 and 16 floats become vec3,
+// vec4 and (column major) mat4. relative paths start from the directory
+// the file is in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialDesc {
+    pub vertex: String,
+    pub fragment: String,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamDesc>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParamDesc {
+    Float(f32),
+    Vector(Vec<f32>),
+    Texture { texture: String }
+}
+
+impl MaterialDesc {
+    pub fn from_json(source: &str) -> Result<MaterialDesc, String> {
+        serde_json::from_str(source).map_err(|e| e.to_string())
+    }
+
+    // reads a material file, with its paths made relative to the working
+    // directory
+    pub fn load(path: &str) -> Result<MaterialDesc, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let desc = MaterialDesc::from_json(&source).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Ok(desc.map_paths(&|p| dir.join(p).to_string_lossy().into_owned()))
+    }
+
+    // the same material with every shader and texture path passed through `f`
+    pub fn map_paths(&self, f: &dyn Fn(&str) -> String) -> MaterialDesc {
+        let mut desc = self.clone();
+        desc.vertex = f(&self.vertex);
+        desc.fragment = f(&self.fragment);
+        for param in desc.params.values_mut() {
+            if let ParamDesc::Texture { texture } = param {
+                *texture = f(texture);
+            }
+        }
+        desc
+    }
+
+    // converts everything except textures, which need a GL context
+    pub fn value_params(&self) -> Result<BTreeMap<String, MaterialParam>, String> {
+        let mut params = BTreeMap::new();
+        for (name, param) in self.params.iter() {
+            let value = match param {
+                ParamDesc::Float(v) => MaterialParam::Float(*v),
+                ParamDesc::Vector(v) if v.len() == 3 => MaterialParam::Vec3(make_vec3(v)),
+                ParamDesc::Vector(v) if v.len() == 4 => MaterialParam::Vec4(make_vec4(v)),
+                ParamDesc::Vector(v) if v.len() == 16 => MaterialParam::Mat4(make_mat4(v)),
+                ParamDesc::Vector(v) => {
+                    return Err(format!("{}: expected 3, 4 or 16 floats, got {}", name, v.len()));
+                }
+                ParamDesc::Texture { .. } => continue
+            };
+            params.insert(name.clone(), value);
+        }
+        Ok(params)
+    }
+
+    pub fn build(&self) -> Result<Material, String> {
+        self.build_cached(&mut BTreeMap::new())
+    }
+
+    // like build, but textures already in `textures` (by path) are shared
+    // instead of loaded again, and the ones it loads are added
+    pub fn build_cached(&self, textures: &mut BTreeMap<String, Rc<Texture>>) -> Result<Material, String> {
+        let read = |path: &String| fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e));
+        let vert = Shader::from_vert_source(&shader_lib::preprocess(&read(&self.vertex)?)?)?;
+        let frag = Shader::from_frag_source(&shader_lib::preprocess(&read(&self.fragment)?)?)?;
+        let program = Program::from_shaders(&[vert, frag])?;
+
+        let mut params = self.value_params()?;
+        for (name, param) in self.params.iter() {
+            if let ParamDesc::Texture { texture } = param {
+                if !textures.contains_key(texture) {
+                    let id = load_texture(texture)?;
+                    textures.insert(texture.clone(), Rc::new(Texture::owned(id, texture)));
+                }
+                params.insert(name.clone(), MaterialParam::Texture(textures[texture].clone()));
+            }
+        }
+        let mut material = Material::new(Rc::new(program), params);
+        material.blend = self.blend;
+        Ok(material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> BTreeMap<String, MaterialParam> {
+        let mut params = BTreeMap::new();
+        params.insert("material.shininess".to_string(), MaterialParam::Float(32.0));
+        params.insert("material.specular".to_string(), MaterialParam::Texture(Rc::new(Texture::new(2, &"spec.png".to_string()))));
+        params.insert("material.diffuse".to_string(), MaterialParam::Texture(Rc::new(Texture::new(1, &"diffuse.png".to_string()))));
+        params
+    }
+
+    #[test]
+    fn overrides_shadow_defaults() {
+        let mut params = ParamMap::new(defaults());
+        params.set("material.shininess", MaterialParam::Float(64.0));
+        params.set("tint", MaterialParam::Vec3(make_vec3(&[1.0, 0.0, 0.0])));
+        assert_eq!(params.get("material.shininess"), Some(&MaterialParam::Float(64.0)));
+        let names: Vec<&str> = params.iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["material.diffuse", "material.shininess", "material.specular", "tint"]);
+        params.reset("material.shininess");
+        assert_eq!(params.get("material.shininess"), Some(&MaterialParam::Float(32.0)));
+    }
+
+    #[test]
+    fn instances_do_not_leak_into_the_parent() {
+        let mut parent = ParamMap::new(defaults());
+        parent.set("material.shininess", MaterialParam::Float(8.0));
+        let mut child = parent.instance();
+        assert_eq!(child.get("material.shininess"), Some(&MaterialParam::Float(8.0)));
+        assert!(!child.is_overridden("material.shininess"));
+        child.set("material.shininess", MaterialParam::Float(128.0));
+        assert_eq!(parent.get("material.shininess"), Some(&MaterialParam::Float(8.0)));
+    }
+
+    #[test]
+    fn only_changed_params_are_reported() {
+        let base = ParamMap::new(defaults());
+        let mut other = base.instance();
+        assert!(other.changed_since(&base).is_empty());
+        other.set("material.shininess", MaterialParam::Float(16.0));
+        other.set("material.diffuse", MaterialParam::Texture(Rc::new(Texture::new(1, &"same id".to_string()))));
+        let changed: Vec<&str> = other.changed_since(&base).iter().map(|(n, _)| *n).collect();
+        assert_eq!(changed, vec!["material.shininess"]);
+    }
+
+    #[test]
+    fn texture_units_follow_name_order() {
+        let params = ParamMap::new(defaults());
+        let slots: Vec<u32> = params.texture_slots().iter().map(|(_, t)| t.get_id()).collect();
+        assert_eq!(slots, vec![1, 2]);
+    }
+
+    #[test]
+    fn parses_a_description() {
+        let desc = MaterialDesc::from_json(r#"{
+            "vertex": "a.vert",
+            "fragment": "a.frag",
+            "params": {
+                "material.shininess": 32.0,
+                "count": 3,
+                "light.ambient": [0.2, 0.2, 0.2],
+                "material.diffuse": { "texture": "container2.png" }
+            }
+        }"#).unwrap();
+        assert_eq!(desc.blend, BlendMode::Opaque);
+        assert_eq!(desc.params["material.diffuse"], ParamDesc::Texture { texture: "container2.png".to_string() });
+        let params = desc.value_params().unwrap();
+        assert_eq!(params["material.shininess"], MaterialParam::Float(32.0));
+        assert_eq!(params["count"], MaterialParam::Float(3.0));
+        assert_eq!(params["light.ambient"], MaterialParam::Vec3(make_vec3(&[0.2, 0.2, 0.2])));
+        assert!(!params.contains_key("material.diffuse"));
+
+        let bad = MaterialDesc::from_json(r#"{"vertex": "a", "fragment": "b", "params": {"x": [1.0, 2.0]}}"#).unwrap();
+        assert!(bad.value_params().is_err());
+    }
+
+    #[test]
+    fn missing_textures_are_an_error() {
+        // the image is read before any GL call, so this needs no context
+        let err = load_texture(&"no/such/image.png".to_string()).unwrap_err();
+        assert!(err.contains("no/such/image.png"), "{}", err);
+    }
+
+    #[test]
+    fn paths_start_from_the_material_file() {
+        let desc = MaterialDesc::load("materials/container.json").unwrap();
+        assert_eq!(desc.vertex, "materials/../src/bin/lightingmap.vert");
+        assert!(Path::new(&desc.vertex).exists());
+        assert_eq!(desc.params["material.diffuse"], ParamDesc::Texture { texture: "materials/../container2.png".to_string() });
+    }
+
+    #[test]
+    fn blend_modes() {
+        let desc = MaterialDesc::from_json(r#"{"vertex": "a", "fragment": "b", "blend": "weighted_blended"}"#).unwrap();
+        assert_eq!(desc.blend, BlendMode::WeightedBlended);
+        assert!(MaterialDesc::from_json(r#"{"vertex": "a", "fragment": "b", "blend": "glass"}"#).is_err());
+        assert!(!BlendMode::Opaque.is_transparent());
+        assert_eq!(BlendMode::Alpha.pipeline(), PipelineState::alpha_blended());
+        assert!(!BlendMode::WeightedBlended.pipeline().depth.write);
+    }
+}
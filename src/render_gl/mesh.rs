@@ -0,0 +1,247 @@
+use crate::glm::*;
+use crate::render_gl::debug;
+use crate::render_gl::frustum::Aabb;
+use crate::render_gl::instancing::{InstanceBuffer, INSTANCE_ATTRIBUTES, INSTANCE_LOCATION, INSTANCE_STRIDE};
+use crate::render_gl::obj;
+use crate::render_gl::sphere;
+use crate::render_gl::state;
+use gl::types::*;
+use std::cell::Cell;
+use std::fs;
+
+// interleaved position (3), normal (3), uv (2) for the 36 vertices of a unit cube
+pub const CUBE_VERTICES: [f32; 288] = [
+    -0.5, -0.5, -0.5, 0.0, 0.0, -1.0, 0.0, 0.0,
+    0.5, 0.5, -0.5, 0.0, 0.0, -1.0, 1.0, 1.0,
+    0.5, -0.5, -0.5, 0.0, 0.0, -1.0, 1.0, 0.0,
+    0.5, 0.5, -0.5, 0.0, 0.0, -1.0, 1.0, 1.0,
+    -0.5, -0.5, -0.5, 0.0, 0.0, -1.0, 0.0, 0.0,
+    -0.5, 0.5, -0.5, 0.0, 0.0, -1.0, 0.0, 1.0,
+    -0.5, -0.5, 0.5, 0.0, 0.0, 1.0, 0.0, 0.0,
+    0.5, -0.5, 0.5, 0.0, 0.0, 1.0, 1.0, 0.0,
+    0.5, 0.5, 0.5, 0.0, 0.0, 1.0, 1.0, 1.0,
+    0.5, 0.5, 0.5, 0.0, 0.0, 1.0, 1.0, 1.0,
+    -0.5, 0.5, 0.5, 0.0, 0.0, 1.0, 0.0, 1.0,
+    -0.5, -0.5, 0.5, 0.0, 0.0, 1.0, 0.0, 0.0,
+    -0.5, 0.5, 0.5, -1.0, 0.0, 0.0, 1.0, 0.0,
+    -0.5, 0.5, -0.5, -1.0, 0.0, 0.0, 1.0, 1.0,
+    -0.5, -0.5, -0.5, -1.0, 0.0, 0.0, 0.0, 1.0,
+    -0.5, -0.5, -0.5, -1.0, 0.0, 0.0, 0.0, 1.0,
+    -0.5, -0.5, 0.5, -1.0, 0.0, 0.0, 0.0, 0.0,
+    -0.5, 0.5, 0.5, -1.0, 0.0, 0.0, 1.0, 0.0,
+    0.5, 0.5, 0.5, 1.0, 0.0, 0.0, 1.0, 0.0,
+    0.5, -0.5, -0.5, 1.0, 0.0, 0.0, 0.0, 1.0,
+    0.5, 0.5, -0.5, 1.0, 0.0, 0.0, 1.0, 1.0,
+    0.5, -0.5, -0.5, 1.0, 0.0, 0.0, 0.0, 1.0,
+    0.5, 0.5, 0.5, 1.0, 0.0, 0.0, 1.0, 0.0,
+    0.5, -0.5, 0.5, 1.0, 0.0, 0.0, 0.0, 0.0,
+    -0.5, -0.5, -0.5, 0.0, -1.0, 0.0, 0.0, 1.0,
+    0.5, -0.5, -0.5, 0.0, -1.0, 0.0, 1.0, 1.0,
+    0.5, -0.5, 0.5, 0.0, -1.0, 0.0, 1.0, 0.0,
+    0.5, -0.5, 0.5, 0.0, -1.0, 0.0, 1.0, 0.0,
+    -0.5, -0.5, 0.5, 0.0, -1.0, 0.0, 0.0, 0.0,
+    -0.5, -0.5, -0.5, 0.0, -1.0, 0.0, 0.0, 1.0,
+    -0.5, 0.5, -0.5, 0.0, 1.0, 0.0, 0.0, 1.0,
+    0.5, 0.5, 0.5, 0.0, 1.0, 0.0, 1.0, 0.0,
+    0.5, 0.5, -0.5, 0.0, 1.0, 0.0, 1.0, 1.0,
+    0.5, 0.5, 0.5, 0.0, 1.0, 0.0, 1.0, 0.0,
+    -0.5, 0.5, -0.5, 0.0, 1.0, 0.0, 0.0, 1.0,
+    -0.5, 0.5, 0.5, 0.0, 1.0, 0.0, 0.0, 0.0,
+];
+
+// a VAO with its vertex (and optionally index) buffer. `layout` lists the
+// number of floats of each attribute, in attribute location order
+pub struct Mesh {
+    vao: GLuint,
+    vbo: GLuint,
+    ebo: GLuint,
+    count: i32,
+    mode: GLenum,
+    // of the positions, in the mesh's own space
+    bounds: Aabb,
+    // (serial, first instance) of the InstanceBuffer the instance
+    // attributes point at
+    instances: Cell<Option<(usize, usize)>>
+}
+
+impl Mesh {
+    pub fn new(vertices: &[f32], layout: &[i32], indices: Option<&[u32]>) -> Mesh {
+        let stride: i32 = layout.iter().sum();
+        let mut vao: GLuint = 0;
+        let mut vbo: GLuint = 0;
+        let mut ebo: GLuint = 0;
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::GenBuffers(1, &mut vbo);
+            state::bind_vertex_array(vao);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                (vertices.len() * std::mem::size_of::<f32>()) as GLsizeiptr,
+                vertices.as_ptr() as *const GLvoid,
+                gl::STATIC_DRAW);
+            let mut offset = 0;
+            for (location, size) in layout.iter().enumerate() {
+                gl::EnableVertexAttribArray(location as GLuint);
+                gl::VertexAttribPointer(
+                    location as GLuint,
+                    *size,
+                    gl::FLOAT,
+                    gl::FALSE,
+                    stride * std::mem::size_of::<f32>() as GLint,
+                    (offset * std::mem::size_of::<f32>()) as *const GLvoid);
+                offset += *size as usize;
+            }
+            if let Some(indices) = indices {
+                gl::GenBuffers(1, &mut ebo);
+                gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
+                gl::BufferData(
+                    gl::ELEMENT_ARRAY_BUFFER,
+                    (indices.len() * std::mem::size_of::<u32>()) as GLsizeiptr,
+                    indices.as_ptr() as *const GLvoid,
+                    gl::STATIC_DRAW);
+            }
+            state::bind_vertex_array(0);
+        }
+        let count = match indices {
+            Some(indices) => indices.len() as i32,
+            None => vertices.len() as i32 / stride
+        };
+        // the first attribute is the position; 2d ones lie at z = 0
+        let positions: Vec<Vec3> = vertices.chunks(stride as usize)
+            .map(|v| match layout[0] {
+                2 => make_vec3(&[v[0], v[1], 0.0]),
+                _ => make_vec3(&v[..3])
+            })
+            .collect();
+        let bounds = Aabb::from_points(&positions)
+            .unwrap_or_else(|| Aabb::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, 0.0])));
+        Mesh { vao: vao, vbo: vbo, ebo: ebo, count: count, mode: gl::TRIANGLES, bounds: bounds, instances: Cell::new(None) }
+    }
+
+    // position, normal, uv
+    pub fn cube() -> Mesh {
+        Mesh::new(&CUBE_VERTICES, &[3, 3, 2], None)
+    }
+
+    // unit radius. position, normal, uv
+    pub fn sphere(sectors: u32, stacks: u32) -> Mesh {
+        let (vertices, indices) = sphere::generate_uv_sphere(sectors, stacks, 1.0);
+        Mesh::new(&vertices, &[3, 3, 2], Some(&indices))
+    }
+
+    // position, normal, uv. see obj::parse_obj for what is supported
+    pub fn load_obj(path: &str) -> Result<Mesh, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let (vertices, indices) = obj::parse_obj(&source).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Mesh::new(&vertices, &[3, 3, 2], Some(&indices)))
+    }
+
+    // covers the whole of NDC, for full screen passes. position (xy), uv
+    pub fn quad() -> Mesh {
+        let vertices: [f32; 16] = [
+            -1.0, -1.0, 0.0, 0.0,
+            1.0, -1.0, 1.0, 0.0,
+            1.0, 1.0, 1.0, 1.0,
+            -1.0, 1.0, 0.0, 1.0];
+        let indices: [u32; 6] = [0, 1, 2, 2, 3, 0];
+        Mesh::new(&vertices, &[2, 2], Some(&indices))
+    }
+
+    // names the vertex array and its buffers for capture tools
+    pub fn set_label(&self, label: &str) {
+        debug::label(gl::VERTEX_ARRAY, self.vao, label);
+        debug::label_buffer(self.vbo, &format!("{} vertices", label));
+        debug::label_buffer(self.ebo, &format!("{} indices", label));
+    }
+
+    pub fn vao(&self) -> GLuint {
+        self.vao
+    }
+
+    pub fn vertex_buffer(&self) -> GLuint {
+        self.vbo
+    }
+
+    // vertices, or indices for indexed meshes
+    pub fn count(&self) -> i32 {
+        self.count
+    }
+
+    pub fn bounds(&self) -> &Aabb {
+        &self.bounds
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.ebo != 0
+    }
+
+    pub fn draw(&self) {
+        unsafe {
+            state::bind_vertex_array(self.vao);
+            if self.is_indexed() {
+                crate::gl_check!(gl::DrawElements(self.mode, self.count, gl::UNSIGNED_INT, std::ptr::null()));
+            } else {
+                crate::gl_check!(gl::DrawArrays(self.mode, 0, self.count));
+            }
+            state::bind_vertex_array(0);
+        }
+    }
+
+    // draws `count` instances. the per instance attributes have to be set
+    // up already, e.g with bind_instances
+    pub fn draw_instanced(&self, count: i32) {
+        unsafe {
+            state::bind_vertex_array(self.vao);
+            if self.is_indexed() {
+                crate::gl_check!(gl::DrawElementsInstanced(self.mode, self.count, gl::UNSIGNED_INT, std::ptr::null(), count));
+            } else {
+                crate::gl_check!(gl::DrawArraysInstanced(self.mode, 0, self.count, count));
+            }
+            state::bind_vertex_array(0);
+        }
+    }
+
+    // points the instance attributes (see instancing.rs) at `buffer`,
+    // starting with instance `first`. does nothing if they already do
+    pub fn bind_instances(&self, buffer: &InstanceBuffer, first: usize) {
+        let key = Some((buffer.serial(), first));
+        if self.instances.get() == key {
+            return;
+        }
+        self.instances.set(key);
+        let size = std::mem::size_of::<f32>();
+        let base = first * INSTANCE_STRIDE * size;
+        unsafe {
+            state::bind_vertex_array(self.vao);
+            gl::BindBuffer(gl::ARRAY_BUFFER, buffer.id());
+            for i in 0..INSTANCE_ATTRIBUTES {
+                let location = INSTANCE_LOCATION + i;
+                gl::EnableVertexAttribArray(location);
+                gl::VertexAttribPointer(location, 4, gl::FLOAT, gl::FALSE, (INSTANCE_STRIDE * size) as GLint,
+                                        (base + i as usize * 4 * size) as *const GLvoid);
+                gl::VertexAttribDivisor(location, 1);
+            }
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            state::bind_vertex_array(0);
+        }
+    }
+
+    // instances first..first + count of `buffer`
+    pub fn draw_instances(&self, buffer: &InstanceBuffer, first: usize, count: usize) {
+        self.bind_instances(buffer, first);
+        self.draw_instanced(count as i32);
+    }
+}
+
+impl Drop for Mesh {
+    fn drop(&mut self) {
+        unsafe {
+            if self.ebo != 0 {
+                gl::DeleteBuffers(1, &self.ebo);
+            }
+            gl::DeleteBuffers(1, &self.vbo);
+            state::delete_vertex_array(self.vao);
+        }
+    }
+}
@@ -0,0 +1,1098 @@
+use crate::render_gl::debug;
+use crate::render_gl::state;
+use gl::types::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+
+// a fake GL for unit tests. installing it points the gl crate's function
+// table at recording stand-ins, so render code runs unchanged without a
+// context and the test can look at what it did afterwards:
+//
+// let gl = MockGl::install();
+// let framebuffer = Framebuffer::new(desc).unwrap();
+// drop(framebuffer);
+// assert!(gl.state().textures.is_empty());
+//
+// functions it doesn't stand in for are left unloaded and panic when called
+
+// the function table is global, so tests that load real or fake functions
+// into it have to take turns. tests using HeadlessContext hold this too
+static GL_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn lock_gl() -> MutexGuard<'static, ()> {
+    // a failed test mustn't take every later one down with it
+    GL_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UniformValue {
+    Int(i32),
+    Float(f32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+    Mat4([f32; 16])
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawCall {
+    pub mode: GLenum,
+    pub count: i32,
+    pub indexed: bool,
+    // 1 unless the draw was instanced
+    pub instances: i32,
+    pub program: GLuint,
+    pub vertex_array: GLuint,
+    pub framebuffer: GLuint,
+    // texture bound to each (unit, target) at the time of the draw
+    pub textures: BTreeMap<(u32, GLenum), GLuint>,
+    // the query deciding the draw inside BeginConditionalRender
+    pub condition: Option<GLuint>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MockState {
+    next_name: GLuint,
+    // objects that were created and not deleted yet
+    pub textures: BTreeSet<GLuint>,
+    pub buffers: BTreeSet<GLuint>,
+    pub vertex_arrays: BTreeSet<GLuint>,
+    pub framebuffers: BTreeSet<GLuint>,
+    pub renderbuffers: BTreeSet<GLuint>,
+    pub shaders: BTreeSet<GLuint>,
+    pub programs: BTreeSet<GLuint>,
+    pub queries: BTreeSet<GLuint>,
+    // how many of each kind were ever created, keyed like `live`
+    pub created: BTreeMap<&'static str, usize>,
+
+    pub active_unit: u32,
+    pub bound_textures: BTreeMap<(u32, GLenum), GLuint>,
+    pub bound_buffers: BTreeMap<GLenum, GLuint>,
+    pub vertex_array: GLuint,
+    pub draw_framebuffer: GLuint,
+    pub read_framebuffer: GLuint,
+    pub renderbuffer: GLuint,
+    pub program: GLuint,
+    pub capabilities: BTreeSet<GLenum>,
+    pub viewport: [GLint; 4],
+    pub blend_func: (GLenum, GLenum),
+    pub blend_func_alpha: (GLenum, GLenum),
+    pub depth_func: GLenum,
+    pub depth_mask: bool,
+    pub cull_face: GLenum,
+    pub front_face: GLenum,
+    pub polygon_mode: GLenum,
+    pub color_mask: [bool; 4],
+    // calls that set the state above, what the state cache saves
+    pub state_calls: usize,
+
+    // (target, query) of each BeginQuery, in order
+    pub queries_run: Vec<(GLenum, GLuint)>,
+    pub active_query: Option<GLuint>,
+    pub conditional: Option<GLuint>,
+    // what queries ended from now on report, None for a result that
+    // never becomes available. see MockGl::set_query_samples
+    pub query_samples: Option<GLuint>,
+    query_results: BTreeMap<GLuint, Option<GLuint>>,
+
+    // (width, height, internal format) of each allocated texture
+    pub texture_sizes: BTreeMap<GLuint, (i32, i32, GLenum)>,
+    pub buffer_sizes: BTreeMap<GLuint, isize>,
+    // the last value uploaded to each (program, uniform)
+    pub uniforms: BTreeMap<(GLuint, String), UniformValue>,
+    pub labels: BTreeMap<(GLenum, GLuint), String>,
+    pub groups: Vec<String>,
+    pub draws: Vec<DrawCall>,
+    // calls real GL would reject, e.g binding a deleted texture
+    pub errors: Vec<String>,
+
+    // location handed out by GetUniformLocation -> (program, name)
+    locations: Vec<(GLuint, String)>,
+    shader_sources: BTreeMap<GLuint, String>,
+    attached: BTreeMap<GLuint, BTreeSet<GLuint>>,
+    info_logs: BTreeMap<GLuint, String>
+}
+
+impl MockState {
+    // live object names of a kind: "texture", "buffer", "vertex array",
+    // "framebuffer", "renderbuffer", "shader", "program" or "query"
+    pub fn live(&self, kind: &str) -> &BTreeSet<GLuint> {
+        match kind {
+            "query" => &self.queries,
+            "texture" => &self.textures,
+            "buffer" => &self.buffers,
+            "vertex array" => &self.vertex_arrays,
+            "framebuffer" => &self.framebuffers,
+            "renderbuffer" => &self.renderbuffers,
+            "shader" => &self.shaders,
+            "program" => &self.programs,
+            _ => panic!("unknown object kind {}", kind)
+        }
+    }
+
+    pub fn created(&self, kind: &str) -> usize {
+        self.created.get(kind).cloned().unwrap_or(0)
+    }
+
+    // everything still alive, e.g ["texture 3", "buffer 7"]
+    pub fn leaks(&self) -> Vec<String> {
+        let mut leaks = Vec::new();
+        for kind in KINDS.iter() {
+            for name in self.live(kind) {
+                leaks.push(format!("{} {}", kind, name));
+            }
+        }
+        leaks
+    }
+
+    pub fn uniform(&self, program: GLuint, name: &str) -> Option<&UniformValue> {
+        self.uniforms.get(&(program, name.to_string()))
+    }
+
+    pub fn bound_texture(&self, unit: u32, target: GLenum) -> GLuint {
+        self.bound_textures.get(&(unit, target)).cloned().unwrap_or(0)
+    }
+
+    pub fn label(&self, identifier: GLenum, name: GLuint) -> Option<&str> {
+        self.labels.get(&(identifier, name)).map(|l| l.as_str())
+    }
+
+    fn create(&mut self, kind: &'static str) -> GLuint {
+        self.next_name += 1;
+        let name = self.next_name;
+        self.live_mut(kind).insert(name);
+        *self.created.entry(kind).or_insert(0) += 1;
+        name
+    }
+
+    fn delete(&mut self, kind: &'static str, name: GLuint) {
+        // like GL, deleting 0 or a name that was never created is ignored
+        if self.live_mut(kind).remove(&name) {
+            self.labels.retain(|(_, n), _| *n != name);
+        }
+    }
+
+    fn live_mut(&mut self, kind: &str) -> &mut BTreeSet<GLuint> {
+        match kind {
+            "query" => &mut self.queries,
+            "texture" => &mut self.textures,
+            "buffer" => &mut self.buffers,
+            "vertex array" => &mut self.vertex_arrays,
+            "framebuffer" => &mut self.framebuffers,
+            "renderbuffer" => &mut self.renderbuffers,
+            "shader" => &mut self.shaders,
+            "program" => &mut self.programs,
+            _ => panic!("unknown object kind {}", kind)
+        }
+    }
+
+    // core profiles only accept names from Gen*/Create*
+    fn check_live(&mut self, kind: &'static str, name: GLuint, call: &str) -> bool {
+        if name == 0 || self.live(kind).contains(&name) {
+            true
+        } else {
+            self.errors.push(format!("{}: {} {} doesn't exist", call, kind, name));
+            false
+        }
+    }
+
+    fn set_uniform(&mut self, location: GLint, value: UniformValue) {
+        if location == -1 {
+            return;
+        }
+        let (program, name) = match self.locations.get(location as usize) {
+            Some(entry) => entry.clone(),
+            None => {
+                self.errors.push(format!("uniform location {} was never queried", location));
+                return;
+            }
+        };
+        if program != self.program {
+            self.errors.push(format!("uniform {} of program {} set while program {} is in use",
+                                     name, program, self.program));
+            return;
+        }
+        self.uniforms.insert((program, name), value);
+    }
+
+    fn draw(&mut self, mode: GLenum, count: i32, indexed: bool, instances: i32) {
+        if self.program == 0 {
+            self.errors.push("draw without a program".to_string());
+        }
+        let unit_textures = self.bound_textures.iter()
+            .filter(|(_, texture)| **texture != 0)
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        self.draws.push(DrawCall {
+            mode: mode,
+            count: count,
+            indexed: indexed,
+            instances: instances,
+            program: self.program,
+            vertex_array: self.vertex_array,
+            framebuffer: self.draw_framebuffer,
+            textures: unit_textures,
+            condition: self.conditional
+        });
+    }
+}
+
+const KINDS: [&str; 8] = ["texture", "buffer", "vertex array", "framebuffer", "renderbuffer", "shader", "program", "query"];
+
+thread_local! {
+    static STATE: RefCell<MockState> = RefCell::new(MockState::default());
+}
+
+fn with<R, F: FnOnce(&mut MockState) -> R>(f: F) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+// holds the GL lock for as long as it is alive. state is per thread, so
+// render code has to run on the thread that installed the mock
+pub struct MockGl {
+    _lock: MutexGuard<'static, ()>
+}
+
+impl MockGl {
+    pub fn install() -> MockGl {
+        let lock = lock_gl();
+        with(|state| *state = MockState::default());
+        load_shims();
+        debug::forget_support();
+        state::reset();
+        MockGl { _lock: lock }
+    }
+
+    // a copy of everything recorded so far
+    pub fn state(&self) -> MockState {
+        with(|state| state.clone())
+    }
+
+    pub fn clear_draws(&self) {
+        with(|state| state.draws.clear());
+    }
+
+    // samples passed that queries ended after this call report
+    pub fn set_query_samples(&self, samples: Option<GLuint>) {
+        with(|state| state.query_samples = samples);
+    }
+}
+
+impl Drop for MockGl {
+    fn drop(&mut self) {
+        gl::load_with(|_| ptr::null());
+        debug::forget_support();
+        state::reset();
+    }
+}
+
+unsafe fn text(chars: *const GLchar, length: GLsizei) -> String {
+    if chars.is_null() {
+        return String::new();
+    }
+    if length < 0 {
+        CStr::from_ptr(chars).to_string_lossy().into_owned()
+    } else {
+        String::from_utf8_lossy(slice::from_raw_parts(chars as *const u8, length as usize)).into_owned()
+    }
+}
+
+// copies a log or label out the way glGet*InfoLog does, truncated and
+// nul terminated
+unsafe fn write_text(source: &str, size: GLsizei, length: *mut GLsizei, out: *mut GLchar) {
+    let count = source.len().min((size.max(1) - 1) as usize);
+    if !out.is_null() && size > 0 {
+        ptr::copy_nonoverlapping(source.as_ptr(), out as *mut u8, count);
+        *out.add(count) = 0;
+    }
+    if !length.is_null() {
+        *length = count as GLsizei;
+    }
+}
+
+unsafe fn generate(kind: &'static str, n: GLsizei, names: *mut GLuint) {
+    for i in 0..n.max(0) as usize {
+        *names.add(i) = with(|state| state.create(kind));
+    }
+}
+
+unsafe fn remove(kind: &'static str, n: GLsizei, names: *const GLuint) {
+    for i in 0..n.max(0) as usize {
+        let name = *names.add(i);
+        with(|state| state.delete(kind, name));
+    }
+}
+
+unsafe fn floats<'a>(values: *const GLfloat, count: usize) -> &'a [f32] {
+    slice::from_raw_parts(values, count)
+}
+
+extern "system" fn gen_textures(n: GLsizei, names: *mut GLuint) { unsafe { generate("texture", n, names) } }
+extern "system" fn delete_textures(n: GLsizei, names: *const GLuint) { unsafe { remove("texture", n, names) } }
+extern "system" fn gen_buffers(n: GLsizei, names: *mut GLuint) { unsafe { generate("buffer", n, names) } }
+extern "system" fn delete_buffers(n: GLsizei, names: *const GLuint) { unsafe { remove("buffer", n, names) } }
+extern "system" fn gen_vertex_arrays(n: GLsizei, names: *mut GLuint) { unsafe { generate("vertex array", n, names) } }
+extern "system" fn delete_vertex_arrays(n: GLsizei, names: *const GLuint) { unsafe { remove("vertex array", n, names) } }
+extern "system" fn gen_framebuffers(n: GLsizei, names: *mut GLuint) { unsafe { generate("framebuffer", n, names) } }
+extern "system" fn delete_framebuffers(n: GLsizei, names: *const GLuint) { unsafe { remove("framebuffer", n, names) } }
+extern "system" fn gen_renderbuffers(n: GLsizei, names: *mut GLuint) { unsafe { generate("renderbuffer", n, names) } }
+extern "system" fn delete_renderbuffers(n: GLsizei, names: *const GLuint) { unsafe { remove("renderbuffer", n, names) } }
+
+extern "system" fn active_texture(unit: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.active_unit = unit - gl::TEXTURE0;
+    });
+}
+
+extern "system" fn bind_texture(target: GLenum, texture: GLuint) {
+    with(|state| {
+        state.state_calls += 1;
+        if state.check_live("texture", texture, "BindTexture") {
+            let unit = state.active_unit;
+            state.bound_textures.insert((unit, target), texture);
+        }
+    });
+}
+
+fn allocate_texture(target: GLenum, width: GLsizei, height: GLsizei, format: GLenum) {
+    with(|state| {
+        let texture = state.bound_texture(state.active_unit, target);
+        if texture == 0 {
+            state.errors.push("TexImage2D without a bound texture".to_string());
+        } else {
+            state.texture_sizes.insert(texture, (width, height, format));
+        }
+    });
+}
+
+extern "system" fn tex_image_2d(target: GLenum, _level: GLint, internal_format: GLint, width: GLsizei, height: GLsizei,
+                                _border: GLint, _format: GLenum, _kind: GLenum, _pixels: *const c_void) {
+    allocate_texture(target, width, height, internal_format as GLenum);
+}
+
+extern "system" fn tex_image_2d_multisample(target: GLenum, _samples: GLsizei, internal_format: GLenum,
+                                            width: GLsizei, height: GLsizei, _fixed: GLboolean) {
+    allocate_texture(target, width, height, internal_format);
+}
+
+extern "system" fn tex_parameteri(_target: GLenum, _name: GLenum, _value: GLint) {}
+extern "system" fn tex_parameterfv(_target: GLenum, _name: GLenum, _values: *const GLfloat) {}
+extern "system" fn generate_mipmap(_target: GLenum) {}
+extern "system" fn pixel_storei(_name: GLenum, _value: GLint) {}
+
+extern "system" fn bind_buffer(target: GLenum, buffer: GLuint) {
+    with(|state| {
+        if state.check_live("buffer", buffer, "BindBuffer") {
+            state.bound_buffers.insert(target, buffer);
+        }
+    });
+}
+
+extern "system" fn buffer_data(target: GLenum, size: GLsizeiptr, _data: *const c_void, _usage: GLenum) {
+    with(|state| {
+        let buffer = state.bound_buffers.get(&target).cloned().unwrap_or(0);
+        if buffer == 0 {
+            state.errors.push("BufferData without a bound buffer".to_string());
+        } else {
+            state.buffer_sizes.insert(buffer, size);
+        }
+    });
+}
+
+extern "system" fn buffer_sub_data(target: GLenum, offset: GLintptr, size: GLsizeiptr, _data: *const c_void) {
+    with(|state| {
+        let buffer = state.bound_buffers.get(&target).cloned().unwrap_or(0);
+        let allocated = state.buffer_sizes.get(&buffer).cloned().unwrap_or(0);
+        if buffer == 0 {
+            state.errors.push("BufferSubData without a bound buffer".to_string());
+        } else if offset + size > allocated {
+            state.errors.push(format!("BufferSubData writes {} bytes past the end of buffer {}", offset + size - allocated, buffer));
+        }
+    });
+}
+
+extern "system" fn bind_vertex_array(vertex_array: GLuint) {
+    with(|state| {
+        state.state_calls += 1;
+        if state.check_live("vertex array", vertex_array, "BindVertexArray") {
+            state.vertex_array = vertex_array;
+        }
+    });
+}
+
+extern "system" fn enable_vertex_attrib_array(_index: GLuint) {}
+extern "system" fn vertex_attrib_pointer(_index: GLuint, _size: GLint, _kind: GLenum, _normalized: GLboolean,
+                                         _stride: GLsizei, _offset: *const c_void) {}
+extern "system" fn vertex_attrib_divisor(_index: GLuint, _divisor: GLuint) {}
+
+extern "system" fn bind_framebuffer(target: GLenum, framebuffer: GLuint) {
+    with(|state| {
+        if !state.check_live("framebuffer", framebuffer, "BindFramebuffer") {
+            return;
+        }
+        if target == gl::FRAMEBUFFER || target == gl::DRAW_FRAMEBUFFER {
+            state.draw_framebuffer = framebuffer;
+        }
+        if target == gl::FRAMEBUFFER || target == gl::READ_FRAMEBUFFER {
+            state.read_framebuffer = framebuffer;
+        }
+    });
+}
+
+extern "system" fn bind_renderbuffer(_target: GLenum, renderbuffer: GLuint) {
+    with(|state| {
+        if state.check_live("renderbuffer", renderbuffer, "BindRenderbuffer") {
+            state.renderbuffer = renderbuffer;
+        }
+    });
+}
+
+extern "system" fn framebuffer_texture_2d(_target: GLenum, _attachment: GLenum, _tex_target: GLenum, texture: GLuint, _level: GLint) {
+    with(|state| { state.check_live("texture", texture, "FramebufferTexture2D"); });
+}
+
+extern "system" fn framebuffer_renderbuffer(_target: GLenum, _attachment: GLenum, _rb_target: GLenum, renderbuffer: GLuint) {
+    with(|state| { state.check_live("renderbuffer", renderbuffer, "FramebufferRenderbuffer"); });
+}
+
+extern "system" fn renderbuffer_storage(_target: GLenum, _format: GLenum, _width: GLsizei, _height: GLsizei) {}
+extern "system" fn renderbuffer_storage_multisample(_target: GLenum, _samples: GLsizei, _format: GLenum,
+                                                    _width: GLsizei, _height: GLsizei) {}
+extern "system" fn check_framebuffer_status(_target: GLenum) -> GLenum { gl::FRAMEBUFFER_COMPLETE }
+extern "system" fn draw_buffers(_n: GLsizei, _buffers: *const GLenum) {}
+extern "system" fn draw_buffer(_buffer: GLenum) {}
+extern "system" fn read_buffer(_buffer: GLenum) {}
+extern "system" fn blit_framebuffer(_x0: GLint, _y0: GLint, _x1: GLint, _y1: GLint, _dx0: GLint, _dy0: GLint,
+                                    _dx1: GLint, _dy1: GLint, _mask: GLbitfield, _filter: GLenum) {}
+
+extern "system" fn create_shader(_kind: GLenum) -> GLuint {
+    with(|state| state.create("shader"))
+}
+
+extern "system" fn shader_source(shader: GLuint, count: GLsizei, strings: *const *const GLchar, lengths: *const GLint) {
+    let mut source = String::new();
+    for i in 0..count.max(0) as usize {
+        unsafe {
+            let length = if lengths.is_null() { -1 } else { *lengths.add(i) };
+            source.push_str(&text(*strings.add(i), length));
+        }
+    }
+    with(|state| { state.shader_sources.insert(shader, source); });
+}
+
+// a source with an #error directive fails, like it would on a driver
+extern "system" fn compile_shader(shader: GLuint) {
+    with(|state| {
+        let source = state.shader_sources.get(&shader).cloned().unwrap_or_default();
+        match source.lines().position(|l| l.trim_start().starts_with("#error")) {
+            Some(line) => {
+                let log = format!("0:{}: error: {}", line + 1, source.lines().nth(line).unwrap().trim());
+                state.info_logs.insert(shader, log);
+            }
+            None => { state.info_logs.remove(&shader); }
+        }
+    });
+}
+
+extern "system" fn get_shaderiv(shader: GLuint, name: GLenum, value: *mut GLint) {
+    let result = with(|state| {
+        let log = state.info_logs.get(&shader);
+        match name {
+            gl::COMPILE_STATUS => log.is_none() as GLint,
+            gl::INFO_LOG_LENGTH => log.map(|l| l.len() as GLint + 1).unwrap_or(0),
+            _ => 0
+        }
+    });
+    unsafe { *value = result; }
+}
+
+extern "system" fn get_shader_info_log(shader: GLuint, size: GLsizei, length: *mut GLsizei, log: *mut GLchar) {
+    let text = with(|state| state.info_logs.get(&shader).cloned().unwrap_or_default());
+    unsafe { write_text(&text, size, length, log) }
+}
+
+extern "system" fn delete_shader(shader: GLuint) {
+    with(|state| state.delete("shader", shader));
+}
+
+extern "system" fn create_program() -> GLuint {
+    with(|state| state.create("program"))
+}
+
+extern "system" fn attach_shader(program: GLuint, shader: GLuint) {
+    with(|state| {
+        if state.check_live("shader", shader, "AttachShader") {
+            state.attached.entry(program).or_default().insert(shader);
+        }
+    });
+}
+
+extern "system" fn detach_shader(program: GLuint, shader: GLuint) {
+    with(|state| {
+        if let Some(shaders) = state.attached.get_mut(&program) {
+            shaders.remove(&shader);
+        }
+    });
+}
+
+// linking fails when an attached shader didn't compile
+extern "system" fn link_program(program: GLuint) {
+    with(|state| {
+        let failed = state.attached.get(&program).into_iter().flatten()
+            .find(|shader| state.info_logs.contains_key(shader))
+            .cloned();
+        match failed {
+            Some(shader) => {
+                let log = format!("error: shader {} failed to compile", shader);
+                state.info_logs.insert(program, log);
+            }
+            None => { state.info_logs.remove(&program); }
+        }
+    });
+}
+
+extern "system" fn get_programiv(program: GLuint, name: GLenum, value: *mut GLint) {
+    let result = with(|state| {
+        let log = state.info_logs.get(&program);
+        match name {
+            gl::LINK_STATUS => log.is_none() as GLint,
+            gl::INFO_LOG_LENGTH => log.map(|l| l.len() as GLint + 1).unwrap_or(0),
+            _ => 0
+        }
+    });
+    unsafe { *value = result; }
+}
+
+extern "system" fn get_program_info_log(program: GLuint, size: GLsizei, length: *mut GLsizei, log: *mut GLchar) {
+    let text = with(|state| state.info_logs.get(&program).cloned().unwrap_or_default());
+    unsafe { write_text(&text, size, length, log) }
+}
+
+extern "system" fn use_program(program: GLuint) {
+    with(|state| {
+        state.state_calls += 1;
+        if state.check_live("program", program, "UseProgram") {
+            state.program = program;
+        }
+    });
+}
+
+extern "system" fn delete_program(program: GLuint) {
+    with(|state| {
+        state.delete("program", program);
+        state.attached.remove(&program);
+    });
+}
+
+// every uniform is active; each (program, name) gets its own location
+extern "system" fn get_uniform_location(program: GLuint, name: *const GLchar) -> GLint {
+    let name = unsafe { text(name, -1) };
+    with(|state| {
+        if !state.check_live("program", program, "GetUniformLocation") || program == 0 {
+            return -1;
+        }
+        let key = (program, name);
+        match state.locations.iter().position(|l| *l == key) {
+            Some(location) => location as GLint,
+            None => {
+                state.locations.push(key);
+                state.locations.len() as GLint - 1
+            }
+        }
+    })
+}
+
+extern "system" fn uniform_1i(location: GLint, v: GLint) {
+    with(|state| state.set_uniform(location, UniformValue::Int(v)));
+}
+
+extern "system" fn uniform_1f(location: GLint, v: GLfloat) {
+    with(|state| state.set_uniform(location, UniformValue::Float(v)));
+}
+
+extern "system" fn uniform_2fv(location: GLint, _count: GLsizei, v: *const GLfloat) {
+    let v = unsafe { floats(v, 2) };
+    with(|state| state.set_uniform(location, UniformValue::Vec2([v[0], v[1]])));
+}
+
+extern "system" fn uniform_3fv(location: GLint, _count: GLsizei, v: *const GLfloat) {
+    let v = unsafe { floats(v, 3) };
+    with(|state| state.set_uniform(location, UniformValue::Vec3([v[0], v[1], v[2]])));
+}
+
+extern "system" fn uniform_4fv(location: GLint, _count: GLsizei, v: *const GLfloat) {
+    let v = unsafe { floats(v, 4) };
+    with(|state| state.set_uniform(location, UniformValue::Vec4([v[0], v[1], v[2], v[3]])));
+}
+
+extern "system" fn uniform_matrix_4fv(location: GLint, _count: GLsizei, _transpose: GLboolean, v: *const GLfloat) {
+    let mut m = [0.0; 16];
+    m.copy_from_slice(unsafe { floats(v, 16) });
+    with(|state| state.set_uniform(location, UniformValue::Mat4(m)));
+}
+
+extern "system" fn draw_arrays(mode: GLenum, _first: GLint, count: GLsizei) {
+    with(|state| state.draw(mode, count, false, 1));
+}
+
+extern "system" fn draw_elements(mode: GLenum, count: GLsizei, _kind: GLenum, _indices: *const c_void) {
+    with(|state| state.draw(mode, count, true, 1));
+}
+
+extern "system" fn draw_arrays_instanced(mode: GLenum, _first: GLint, count: GLsizei, instances: GLsizei) {
+    with(|state| state.draw(mode, count, false, instances));
+}
+
+extern "system" fn draw_elements_instanced(mode: GLenum, count: GLsizei, _kind: GLenum, _indices: *const c_void,
+                                           instances: GLsizei) {
+    with(|state| state.draw(mode, count, true, instances));
+}
+
+extern "system" fn enable(capability: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.capabilities.insert(capability);
+    });
+}
+
+extern "system" fn disable(capability: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.capabilities.remove(&capability);
+    });
+}
+
+extern "system" fn viewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    with(|state| {
+        state.state_calls += 1;
+        state.viewport = [x, y, width, height];
+    });
+}
+
+extern "system" fn blend_func(source: GLenum, destination: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.blend_func = (source, destination);
+        state.blend_func_alpha = (source, destination);
+    });
+}
+
+extern "system" fn blend_func_separate(source: GLenum, destination: GLenum, source_alpha: GLenum, destination_alpha: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.blend_func = (source, destination);
+        state.blend_func_alpha = (source_alpha, destination_alpha);
+    });
+}
+
+extern "system" fn blend_equation(_mode: GLenum) {
+    with(|state| state.state_calls += 1);
+}
+
+extern "system" fn depth_func(func: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.depth_func = func;
+    });
+}
+
+extern "system" fn depth_mask(write: GLboolean) {
+    with(|state| {
+        state.state_calls += 1;
+        state.depth_mask = write == gl::TRUE;
+    });
+}
+
+extern "system" fn cull_face(mode: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.cull_face = mode;
+    });
+}
+
+extern "system" fn front_face(mode: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.front_face = mode;
+    });
+}
+
+extern "system" fn polygon_mode(_face: GLenum, mode: GLenum) {
+    with(|state| {
+        state.state_calls += 1;
+        state.polygon_mode = mode;
+    });
+}
+
+extern "system" fn color_mask(red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
+    with(|state| {
+        state.state_calls += 1;
+        state.color_mask = [red == gl::TRUE, green == gl::TRUE, blue == gl::TRUE, alpha == gl::TRUE];
+    });
+}
+
+extern "system" fn stencil_func(_func: GLenum, _reference: GLint, _mask: GLuint) {
+    with(|state| state.state_calls += 1);
+}
+
+extern "system" fn stencil_op(_stencil_fail: GLenum, _depth_fail: GLenum, _pass: GLenum) {
+    with(|state| state.state_calls += 1);
+}
+
+extern "system" fn stencil_mask(_mask: GLuint) {
+    with(|state| state.state_calls += 1);
+}
+
+extern "system" fn clear(_mask: GLbitfield) {}
+extern "system" fn clear_color(_r: GLfloat, _g: GLfloat, _b: GLfloat, _a: GLfloat) {}
+
+// reports a 4.3 context, so KHR_debug paths are taken
+extern "system" fn get_integerv(name: GLenum, value: *mut GLint) {
+    if name == gl::VIEWPORT {
+        let viewport = with(|state| state.viewport);
+        unsafe { ptr::copy_nonoverlapping(viewport.as_ptr(), value, 4) }
+        return;
+    }
+    let result = with(|state| match name {
+        gl::MAJOR_VERSION => 4,
+        gl::MINOR_VERSION => 3,
+        gl::DEBUG_GROUP_STACK_DEPTH => state.groups.len() as GLint + 1,
+        gl::CURRENT_PROGRAM => state.program as GLint,
+        gl::ACTIVE_TEXTURE => (gl::TEXTURE0 + state.active_unit) as GLint,
+        gl::DRAW_FRAMEBUFFER_BINDING => state.draw_framebuffer as GLint,
+        gl::READ_FRAMEBUFFER_BINDING => state.read_framebuffer as GLint,
+        gl::VERTEX_ARRAY_BINDING => state.vertex_array as GLint,
+        gl::TEXTURE_BINDING_2D => state.bound_texture(state.active_unit, gl::TEXTURE_2D) as GLint,
+        _ => 0
+    });
+    unsafe { *value = result; }
+}
+
+extern "system" fn get_error() -> GLenum { gl::NO_ERROR }
+
+extern "system" fn object_label(identifier: GLenum, name: GLuint, length: GLsizei, label: *const GLchar) {
+    let label = unsafe { text(label, length) };
+    with(|state| { state.labels.insert((identifier, name), label); });
+}
+
+extern "system" fn get_object_label(identifier: GLenum, name: GLuint, size: GLsizei, length: *mut GLsizei, label: *mut GLchar) {
+    let text = with(|state| state.label(identifier, name).unwrap_or("").to_string());
+    unsafe { write_text(&text, size, length, label) }
+}
+
+extern "system" fn push_debug_group(_source: GLenum, _id: GLuint, length: GLsizei, message: *const GLchar) {
+    let message = unsafe { text(message, length) };
+    with(|state| state.groups.push(message));
+}
+
+extern "system" fn pop_debug_group() {
+    with(|state| {
+        if state.groups.pop().is_none() {
+            state.errors.push("PopDebugGroup with no group pushed".to_string());
+        }
+    });
+}
+
+extern "system" fn debug_message_callback(_callback: GLDEBUGPROC, _user: *const c_void) {}
+extern "system" fn debug_message_control(_source: GLenum, _kind: GLenum, _severity: GLenum, _count: GLsizei,
+                                         _ids: *const GLuint, _enabled: GLboolean) {}
+
+extern "system" fn gen_queries(n: GLsizei, names: *mut GLuint) { unsafe { generate("query", n, names) } }
+extern "system" fn delete_queries(n: GLsizei, names: *const GLuint) { unsafe { remove("query", n, names) } }
+
+extern "system" fn begin_query(target: GLenum, query: GLuint) {
+    with(|state| {
+        if !state.check_live("query", query, "BeginQuery") {
+            return;
+        }
+        if let Some(active) = state.active_query {
+            state.errors.push(format!("BeginQuery({}) while query {} is active", query, active));
+        }
+        state.active_query = Some(query);
+        state.queries_run.push((target, query));
+    });
+}
+
+extern "system" fn end_query(_target: GLenum) {
+    with(|state| match state.active_query.take() {
+        Some(query) => {
+            let samples = state.query_samples;
+            state.query_results.insert(query, samples);
+        }
+        None => state.errors.push("EndQuery with no active query".to_string())
+    });
+}
+
+fn query_result(query: GLuint, name: GLenum) -> GLuint {
+    with(|state| {
+        let result = state.query_results.get(&query).cloned().unwrap_or_else(|| {
+            state.errors.push(format!("result of query {} which never ran", query));
+            None
+        });
+        match name {
+            gl::QUERY_RESULT_AVAILABLE => result.is_some() as GLuint,
+            _ => {
+                if result.is_none() {
+                    state.errors.push(format!("waited on query {} which never finishes", query));
+                }
+                result.unwrap_or(0)
+            }
+        }
+    })
+}
+
+extern "system" fn get_query_objectiv(query: GLuint, name: GLenum, value: *mut GLint) {
+    let result = query_result(query, name);
+    unsafe { *value = result as GLint; }
+}
+
+extern "system" fn get_query_objectuiv(query: GLuint, name: GLenum, value: *mut GLuint) {
+    let result = query_result(query, name);
+    unsafe { *value = result; }
+}
+
+extern "system" fn begin_conditional_render(query: GLuint, _mode: GLenum) {
+    with(|state| {
+        if state.check_live("query", query, "BeginConditionalRender") {
+            state.conditional = Some(query);
+        }
+    });
+}
+
+extern "system" fn end_conditional_render() {
+    with(|state| {
+        if state.conditional.take().is_none() {
+            state.errors.push("EndConditionalRender outside conditional rendering".to_string());
+        }
+    });
+}
+
+macro_rules! shim {
+    ($($function:ident => $shim:expr),* $(,)*) => {
+        $(gl::$function::load_with(|_| $shim as *const c_void);)*
+    }
+}
+
+fn load_shims() {
+    // start from nothing so a call the mock doesn't know about panics
+    // instead of reaching a driver
+    gl::load_with(|_| ptr::null());
+    shim! {
+        GenTextures => gen_textures,
+        DeleteTextures => delete_textures,
+        ActiveTexture => active_texture,
+        BindTexture => bind_texture,
+        TexImage2D => tex_image_2d,
+        TexImage2DMultisample => tex_image_2d_multisample,
+        TexParameteri => tex_parameteri,
+        TexParameterfv => tex_parameterfv,
+        GenerateMipmap => generate_mipmap,
+        PixelStorei => pixel_storei,
+        GenBuffers => gen_buffers,
+        DeleteBuffers => delete_buffers,
+        BindBuffer => bind_buffer,
+        BufferData => buffer_data,
+        BufferSubData => buffer_sub_data,
+        GenVertexArrays => gen_vertex_arrays,
+        DeleteVertexArrays => delete_vertex_arrays,
+        BindVertexArray => bind_vertex_array,
+        EnableVertexAttribArray => enable_vertex_attrib_array,
+        VertexAttribPointer => vertex_attrib_pointer,
+        VertexAttribDivisor => vertex_attrib_divisor,
+        GenFramebuffers => gen_framebuffers,
+        DeleteFramebuffers => delete_framebuffers,
+        BindFramebuffer => bind_framebuffer,
+        FramebufferTexture2D => framebuffer_texture_2d,
+        FramebufferRenderbuffer => framebuffer_renderbuffer,
+        CheckFramebufferStatus => check_framebuffer_status,
+        DrawBuffers => draw_buffers,
+        DrawBuffer => draw_buffer,
+        ReadBuffer => read_buffer,
+        BlitFramebuffer => blit_framebuffer,
+        GenRenderbuffers => gen_renderbuffers,
+        DeleteRenderbuffers => delete_renderbuffers,
+        BindRenderbuffer => bind_renderbuffer,
+        RenderbufferStorage => renderbuffer_storage,
+        RenderbufferStorageMultisample => renderbuffer_storage_multisample,
+        CreateShader => create_shader,
+        ShaderSource => shader_source,
+        CompileShader => compile_shader,
+        GetShaderiv => get_shaderiv,
+        GetShaderInfoLog => get_shader_info_log,
+        DeleteShader => delete_shader,
+        CreateProgram => create_program,
+        AttachShader => attach_shader,
+        DetachShader => detach_shader,
+        LinkProgram => link_program,
+        GetProgramiv => get_programiv,
+        GetProgramInfoLog => get_program_info_log,
+        UseProgram => use_program,
+        DeleteProgram => delete_program,
+        GetUniformLocation => get_uniform_location,
+        Uniform1i => uniform_1i,
+        Uniform1f => uniform_1f,
+        Uniform2fv => uniform_2fv,
+        Uniform3fv => uniform_3fv,
+        Uniform4fv => uniform_4fv,
+        UniformMatrix4fv => uniform_matrix_4fv,
+        DrawArrays => draw_arrays,
+        DrawElements => draw_elements,
+        DrawArraysInstanced => draw_arrays_instanced,
+        DrawElementsInstanced => draw_elements_instanced,
+        Enable => enable,
+        Disable => disable,
+        Viewport => viewport,
+        BlendFunc => blend_func,
+        BlendFuncSeparate => blend_func_separate,
+        BlendEquation => blend_equation,
+        DepthFunc => depth_func,
+        DepthMask => depth_mask,
+        CullFace => cull_face,
+        FrontFace => front_face,
+        PolygonMode => polygon_mode,
+        ColorMask => color_mask,
+        StencilFunc => stencil_func,
+        StencilOp => stencil_op,
+        StencilMask => stencil_mask,
+        Clear => clear,
+        ClearColor => clear_color,
+        GetIntegerv => get_integerv,
+        GetError => get_error,
+        ObjectLabel => object_label,
+        GetObjectLabel => get_object_label,
+        PushDebugGroup => push_debug_group,
+        PopDebugGroup => pop_debug_group,
+        DebugMessageCallback => debug_message_callback,
+        DebugMessageControl => debug_message_control,
+        GenQueries => gen_queries,
+        DeleteQueries => delete_queries,
+        BeginQuery => begin_query,
+        EndQuery => end_query,
+        GetQueryObjectiv => get_query_objectiv,
+        GetQueryObjectuiv => get_query_objectuiv,
+        BeginConditionalRender => begin_conditional_render,
+        EndConditionalRender => end_conditional_render,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+    use crate::render_gl::material::{Material, MaterialParam};
+    use crate::render_gl::mesh::Mesh;
+    use crate::render_gl::{Program, Shader};
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+    use std::rc::Rc;
+
+    fn program(fragment: &str) -> Result<Program, String> {
+        let vert = Shader::from_vert_source(&CString::new("#version 330 core\nvoid main() {}").unwrap())?;
+        let frag = Shader::from_frag_source(&CString::new(fragment).unwrap())?;
+        Program::from_shaders(&[vert, frag])
+    }
+
+    #[test]
+    fn framebuffers_delete_what_they_create() {
+        let gl = MockGl::install();
+        {
+            let desc = FramebufferDesc::new(64, 32)
+                .color(ColorFormat::Rgba16F)
+                .color(ColorFormat::Rgba8)
+                .depth_texture(DepthFormat::Depth24);
+            let mut framebuffer = Framebuffer::new(desc).unwrap();
+            let state = gl.state();
+            assert_eq!(state.textures.len(), 3);
+            assert_eq!(state.texture_sizes[&framebuffer.color_texture(0)], (64, 32, gl::RGBA16F));
+            framebuffer.resize(128, 64).unwrap();
+            assert_eq!(gl.state().created("texture"), 6);
+        }
+        let state = gl.state();
+        assert!(state.leaks().is_empty(), "leaked {:?}", state.leaks());
+        assert!(state.errors.is_empty(), "{:?}", state.errors);
+    }
+
+    #[test]
+    fn set_textures_binds_units_to_samplers() {
+        let dir = std::env::temp_dir().join(format!("mock_gl_textures_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("white.png");
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 255, 255, 255])).save(&path).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let gl = MockGl::install();
+        {
+            let mut program = program("#version 330 core\nvoid main() {}").unwrap();
+            let diffuse = program.program_load_texture(&"diffuse".to_string(), &path).unwrap();
+            let specular = program.program_load_texture(&"specular".to_string(), &path).unwrap();
+            program.set_used();
+            program.set_textures();
+
+            let state = gl.state();
+            assert_eq!(state.bound_texture(0, gl::TEXTURE_2D), diffuse);
+            assert_eq!(state.bound_texture(1, gl::TEXTURE_2D), specular);
+            assert_eq!(state.uniform(program.id(), "diffuse"), Some(&UniformValue::Int(0)));
+            assert_eq!(state.uniform(program.id(), "specular"), Some(&UniformValue::Int(1)));
+            assert_eq!(state.texture_sizes[&diffuse], (2, 2, gl::RGBA8));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        let state = gl.state();
+        assert!(state.leaks().is_empty(), "leaked {:?}", state.leaks());
+        assert!(state.errors.is_empty(), "{:?}", state.errors);
+    }
+
+    #[test]
+    fn compile_errors_are_reported() {
+        let gl = MockGl::install();
+        let error = program("#version 330 core\n#error not today\nvoid main() {}").err().unwrap();
+        assert!(error.contains("0:2: error: #error not today"), "{}", error);
+        assert!(gl.state().leaks().is_empty());
+    }
+
+    #[test]
+    fn uniforms_and_draws_are_recorded() {
+        let gl = MockGl::install();
+        let program = Rc::new(program("#version 330 core\nvoid main() {}").unwrap());
+        let mesh = Mesh::quad();
+        mesh.set_label("quad");
+
+        let mut params = BTreeMap::new();
+        params.insert("tint".to_string(), MaterialParam::Vec3(glm::vec3(1.0, 0.5, 0.0)));
+        params.insert("model".to_string(), MaterialParam::Mat4(glm::identity()));
+        let material = Material::new(program.clone(), params);
+        material.apply(None);
+        mesh.draw();
+
+        let state = gl.state();
+        assert_eq!(state.uniform(program.id(), "tint"), Some(&UniformValue::Vec3([1.0, 0.5, 0.0])));
+        match state.uniform(program.id(), "model") {
+            Some(UniformValue::Mat4(m)) => assert_eq!(m[0], 1.0),
+            other => panic!("model is {:?}", other)
+        }
+        assert_eq!(state.draws.len(), 1);
+        assert_eq!(state.draws[0].vertex_array, mesh.vao());
+        assert_eq!(state.draws[0].program, program.id());
+        assert_eq!((state.draws[0].count, state.draws[0].indexed), (6, true));
+        assert_eq!(state.label(gl::VERTEX_ARRAY, mesh.vao()), Some("quad"));
+        // unbound after drawing
+        assert_eq!(state.vertex_array, 0);
+    }
+
+    #[test]
+    fn uniforms_of_another_program_are_errors() {
+        let gl = MockGl::install();
+        let a = program("#version 330 core\nvoid main() {}").unwrap();
+        let b = program("#version 330 core\nvoid main() {}").unwrap();
+        a.set_used();
+        b.set_uniform_1f("exposure", 1.0);
+        let state = gl.state();
+        assert_eq!(state.errors.len(), 1);
+        assert!(state.uniform(b.id(), "exposure").is_none());
+    }
+}
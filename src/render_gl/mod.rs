@@ -8,7 +8,33 @@ use gl::types::*;
 
 pub mod texture;
 pub mod camera;
+pub mod camera_path;
 pub mod sphere;
+pub mod obj;
+pub mod frustum;
+pub mod bvh;
+pub mod occlusion;
+pub mod light;
+pub mod material;
+pub mod shader_lib;
+pub mod shadow;
+pub mod mesh;
+pub mod pbr;
+pub mod framebuffer;
+pub mod postprocess;
+pub mod deferred;
+pub mod ssao;
+pub mod headless;
+pub mod capture;
+pub mod debug;
+pub mod profiler;
+pub mod mock;
+pub mod state;
+pub mod pipeline;
+pub mod transparency;
+pub mod instancing;
+pub mod scene;
+pub mod scene_file;
 
 use crate::render_gl::texture::Texture;
 pub struct Shader {
@@ -36,6 +62,10 @@ impl Shader {
     pub fn from_frag_source(source: &CStr) -> Result<Shader, String> {
         Shader::from_source(source, gl::FRAGMENT_SHADER)
     }
+
+    pub fn set_label(&self, label: &str) {
+        debug::label(gl::SHADER, self.id, label);
+    }
 }
 
 impl Drop for Shader {
@@ -73,6 +103,7 @@ pub fn shader_from_source(source: &CStr, kind: gl::types::GLuint) -> Result<gl::
                 len,
                 ptr::null_mut(),
                 error.as_ptr() as * mut gl::types::GLchar);
+            gl::DeleteShader(id);
         }
         return Err(error.to_string_lossy().into_owned());
 
@@ -100,7 +131,7 @@ impl Program {
             unsafe { gl::AttachShader(program_id, shader.id());}
         }
 
-        unsafe { gl::LinkProgram(program_id); }
+        unsafe { crate::gl_check!(gl::LinkProgram(program_id)); }
 
 
         let mut success: GLint = 1;
@@ -121,6 +152,7 @@ impl Program {
                     ptr::null_mut(),
                     error.as_ptr() as *mut gl::types::GLchar
                 );
+                gl::DeleteProgram(program_id);
             }
             return Err(error.to_string_lossy().into_owned());
         }
@@ -134,6 +166,11 @@ impl Program {
         self.id
     }
 
+    // shows up in capture tools and driver messages instead of the id
+    pub fn set_label(&self, label: &str) {
+        debug::label(gl::PROGRAM, self.id, label);
+    }
+
     pub fn location(&self, name: &str) -> Option<i32> {
 
         let mat_name = CString::new(name).unwrap();
@@ -157,7 +194,7 @@ impl Program {
             None
         } else {
             unsafe {
-                gl::UniformMatrix4fv(mat_loc, 1, gl::FALSE, mat.as_slice().as_ptr());
+                crate::gl_check!(gl::UniformMatrix4fv(mat_loc, 1, gl::FALSE, mat.as_slice().as_ptr()));
             }
             Some(mat_loc)
         }
@@ -174,7 +211,23 @@ impl Program {
             None
         } else {
             unsafe {
-                gl::Uniform1f(loc, v as gl::types::GLfloat);
+                crate::gl_check!(gl::Uniform1f(loc, v as gl::types::GLfloat));
+            }
+            Some(loc)
+        }
+    }
+
+    pub fn set_uniform_1i(&self, name: &str, v: i32) -> Option<i32> {
+        let cname = CString::new(name).unwrap();
+        let loc;
+        unsafe {
+            loc = gl::GetUniformLocation(self.id(), cname.as_ptr() as *const i8);
+        }
+        if loc == -1 {
+            None
+        } else {
+            unsafe {
+                crate::gl_check!(gl::Uniform1i(loc, v as gl::types::GLint));
             }
             Some(loc)
         }
@@ -192,7 +245,25 @@ impl Program {
             None
         } else {
             unsafe {
-                gl::Uniform4fv(vec_loc, 1, glm::value_ptr(vec).as_ptr());
+                crate::gl_check!(gl::Uniform4fv(vec_loc, 1, glm::value_ptr(vec).as_ptr()));
+                Some(vec_loc)
+            }
+        }
+    }
+
+    pub fn set_uniform_vec2(&self, vec_name: &str, vec: &glm::Vec2) -> Option<i32> {
+
+        let vec_name = CString::new(vec_name).unwrap();
+        let vec_loc;
+        unsafe {
+            vec_loc = gl::GetUniformLocation(self.id(), vec_name.as_ptr() as *const i8);
+        }
+
+        if vec_loc == -1 {
+            None
+        } else {
+            unsafe {
+                crate::gl_check!(gl::Uniform2fv(vec_loc, 1, glm::value_ptr(vec).as_ptr()));
                 Some(vec_loc)
             }
         }
@@ -210,7 +281,7 @@ impl Program {
             None
         } else {
             unsafe {
-                gl::Uniform3fv(vec_loc, 1, glm::value_ptr(vec).as_ptr());
+                crate::gl_check!(gl::Uniform3fv(vec_loc, 1, glm::value_ptr(vec).as_ptr()));
                 Some(vec_loc)
             }
         }
@@ -219,55 +290,23 @@ impl Program {
 
     // right now, I am supporting only one texture. Need to figure out how to add multiple textures
     pub fn program_load_texture(&mut self, name: &String, image_path: &String) -> Option<u32> {
-        let border_colors: Vec<f32> = vec![0.0, 1.0, 0.0, 1.0];
-        let mut tex: u32 = 0;
-            unsafe {
-                gl::GenTextures(1, &mut tex);
-                gl::BindTexture(gl::TEXTURE_2D, tex);
-                gl::TexParameterfv(gl::TEXTURE_2D, gl::TEXTURE_BORDER_COLOR, border_colors.as_ptr());
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);    // set texture wrapping to GL_REPEAT (default wrapping method)
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, 0);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
-            }
-            let (image_type, image_data, width, height) = load_image(image_path).unwrap();
-            let image_bytes = &image_data[..];
-            let (pixel_type, channels) = if image_type == ImageType::RGB {
-                (gl::RGB8, gl::RGB)
-            }else {
-                    (gl::RGBA8, gl::RGBA)
-            };
-            unsafe {
-                gl::TexImage2D(gl::TEXTURE_2D,
-                               0,
-                               pixel_type as gl::types::GLint,
-                               width as i32,
-                               height as i32,
-                               0,
-                               channels,
-                               gl::UNSIGNED_BYTE,
-                               image_bytes.as_ptr() as *const gl::types::GLvoid);
-                gl::GenerateMipmap(gl::TEXTURE_2D);
-                gl::BindTexture(gl::TEXTURE_2D, 0);
-            }
-            self.textures.push(Texture::new(tex, name));
-            return Some(tex);
+        let tex = load_texture(image_path).unwrap();
+        self.textures.push(Texture::owned(tex, name));
+        return Some(tex);
     }
 
     pub fn set_textures(&self) {
         for i in 0..self.textures.len() {
             unsafe {
-                gl::ActiveTexture(gl::TEXTURE0 + i as u32);
+                state::active_texture(gl::TEXTURE0 + i as u32);
                 let tex_id = self.textures[i].get_id();
                 let tex_name = self.textures[i].get_name();
-                gl::BindTexture(gl::TEXTURE_2D, tex_id);
+                state::bind_texture(gl::TEXTURE_2D, tex_id);
                 let texture_location = gl::GetUniformLocation(
                     self.id(),
                     CString::new(tex_name.into_bytes()).unwrap().as_ptr());
 
-                gl::Uniform1i(texture_location, i as i32);
+                crate::gl_check!(gl::Uniform1i(texture_location, i as i32));
             }
 
         }
@@ -276,21 +315,23 @@ impl Program {
 
 
     pub fn set_used(&self) {
-        unsafe {
-            gl::UseProgram(self.id);
-        }
+        state::use_program(self.id);
     }
 }
 impl Drop for Program {
     fn drop(&mut self) {
-        unsafe {
-            gl::DeleteProgram(self.id);
-        }
+        state::delete_program(self.id);
     }
 }
 
+// a missing or unreadable file is an error, not a panic: material and
+// scene files name images that may not be there
+fn open_image(filename: &String) -> Result<image::DynamicImage, String> {
+    image::open(filename).map_err(|e| format!("unable to load image {}: {}", filename, e))
+}
+
 fn load_image(filename: &String) -> Result<(ImageType, Vec<u8>, u32, u32), String> {
-    let k = image::open(filename).unwrap();
+    let k = open_image(filename)?;
     match k  {
         image::DynamicImage::ImageRgb8(ref _im) => {
             let data = k.as_rgb8().unwrap();
@@ -306,15 +347,53 @@ fn load_image(filename: &String) -> Result<(ImageType, Vec<u8>, u32, u32), Strin
     }
 }
 
+// uploads an RGB/RGBA image as a mipmapped, repeating 2D texture
+pub fn load_texture(image_path: &String) -> Result<GLuint, String> {
+    let border_colors: Vec<f32> = vec![0.0, 1.0, 0.0, 1.0];
+    let mut tex: u32 = 0;
+    let (image_type, image_data, width, height) = load_image(image_path)?;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_2D, tex);
+        gl::TexParameterfv(gl::TEXTURE_2D, gl::TEXTURE_BORDER_COLOR, border_colors.as_ptr());
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);    // set texture wrapping to GL_REPEAT (default wrapping method)
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, 0);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
+    }
+    let image_bytes = &image_data[..];
+    let (pixel_type, channels) = if image_type == ImageType::RGB {
+        (gl::RGB8, gl::RGB)
+    }else {
+            (gl::RGBA8, gl::RGBA)
+    };
+    unsafe {
+        crate::gl_check!(gl::TexImage2D(gl::TEXTURE_2D,
+                                        0,
+                                        pixel_type as gl::types::GLint,
+                                        width as i32,
+                                        height as i32,
+                                        0,
+                                        channels,
+                                        gl::UNSIGNED_BYTE,
+                                        image_bytes.as_ptr() as *const gl::types::GLvoid));
+        crate::gl_check!(gl::GenerateMipmap(gl::TEXTURE_2D));
+        state::bind_texture(gl::TEXTURE_2D, 0);
+    }
+    Ok(tex)
+}
+
 pub fn load_png_image(filename: &String) -> Result<(Vec<u8>, u32, u32), String> {
-    let img = image::open(filename).unwrap();
-    let data  = img.as_rgba8().unwrap();
+    let img = open_image(filename)?;
+    let data  = img.as_rgba8().ok_or_else(|| format!("{}: not an RGBA image", filename))?;
     Ok((data.clone().into_raw(), data.width(), data.height()))
 }
 
 pub fn load_jpeg_image(filename: &String) -> Result<(Vec<u8>, u32, u32), String> {
-    let img = image::open(filename).unwrap();
-    let data  = img.as_rgb8().unwrap();
+    let img = open_image(filename)?;
+    let data  = img.as_rgb8().ok_or_else(|| format!("{}: not an RGB image", filename))?;
     if data.len() == 0 {
         return Err(String::from(format!("{}: empty jpeg image",filename)))
     }
@@ -324,11 +403,11 @@ pub fn load_jpeg_image(filename: &String) -> Result<(Vec<u8>, u32, u32), String>
 
 pub fn load_cube_vertices() -> Vec<f32> {
     vec![-0.5, -0.5, -0.5, 0.0, 0.0,
-         0.5, -0.5, -0.5, 1.0, 0.0,
          0.5, 0.5, -0.5, 1.0, 1.0,
+         0.5, -0.5, -0.5, 1.0, 0.0,
          0.5, 0.5, -0.5, 1.0, 1.0,
-         -0.5, 0.5, -0.5, 0.0, 1.0,
          -0.5, -0.5, -0.5, 0.0, 0.0,
+         -0.5, 0.5, -0.5, 0.0, 1.0,
          -0.5, -0.5, 0.5, 0.0, 0.0,
          0.5, -0.5, 0.5, 1.0, 0.0,
          0.5, 0.5, 0.5, 1.0, 1.0,
@@ -342,11 +421,11 @@ pub fn load_cube_vertices() -> Vec<f32> {
          -0.5, -0.5, 0.5, 0.0, 0.0,
          -0.5, 0.5, 0.5, 1.0, 0.0,
          0.5, 0.5, 0.5, 1.0, 0.0,
-         0.5, 0.5, -0.5, 1.0, 1.0,
          0.5, -0.5, -0.5, 0.0, 1.0,
+         0.5, 0.5, -0.5, 1.0, 1.0,
          0.5, -0.5, -0.5, 0.0, 1.0,
-         0.5, -0.5, 0.5, 0.0, 0.0,
          0.5, 0.5, 0.5, 1.0, 0.0,
+         0.5, -0.5, 0.5, 0.0, 0.0,
          -0.5, -0.5, -0.5, 0.0, 1.0,
          0.5, -0.5, -0.5, 1.0, 1.0,
          0.5, -0.5, 0.5, 1.0, 0.0,
@@ -354,11 +433,11 @@ pub fn load_cube_vertices() -> Vec<f32> {
          -0.5, -0.5, 0.5, 0.0, 0.0,
          -0.5, -0.5, -0.5, 0.0, 1.0,
          -0.5, 0.5, -0.5, 0.0, 1.0,
-         0.5, 0.5, -0.5, 1.0, 1.0,
          0.5, 0.5, 0.5, 1.0, 0.0,
+         0.5, 0.5, -0.5, 1.0, 1.0,
          0.5, 0.5, 0.5, 1.0, 0.0,
-         -0.5, 0.5, 0.5, 0.0, 0.0,
-         -0.5, 0.5, -0.5, 0.0, 1.0]
+         -0.5, 0.5, -0.5, 0.0, 1.0,
+         -0.5, 0.5, 0.5, 0.0, 0.0]
 }
 
 pub fn set_texture(filename: &String) -> GLuint {
@@ -378,7 +457,7 @@ pub fn set_texture(filename: &String) -> GLuint {
         gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
         gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_BASE_LEVEL, 0);
         gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
-        gl::BindTexture(gl::TEXTURE_2D, k);
+        state::bind_texture(gl::TEXTURE_2D, k);
         gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB8 as gl::types::GLint, width as i32, height as i32, 0, gl::RGB, gl::UNSIGNED_BYTE, img_bytes.as_ptr() as *const gl::types::GLvoid);
         gl::GenerateMipmap(gl::TEXTURE_2D);
     }
@@ -0,0 +1,134 @@
+use crate::glm::*;
+use std::collections::HashMap;
+
+// reads a wavefront .obj into the layout Mesh::cube() uses: interleaved
+// position (3), normal (3), uv (2), plus indices. polygons are split into
+// fans. faces without normals get flat ones and missing uvs are zero.
+// materials, groups and smoothing groups are ignored
+pub fn parse_obj(source: &str) -> Result<(Vec<f32>, Vec<u32>), String> {
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut vertices: Vec<f32> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    // (position, uv, normal) -> index, for corners that have a normal
+    let mut seen: HashMap<(usize, Option<usize>, usize), u32> = HashMap::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let error = |message: &str| format!("line {}: {}", number + 1, message);
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let floats = |words: std::str::SplitWhitespace| -> Result<Vec<f32>, String> {
+            words.map(|w| w.parse::<f32>().map_err(|_| error(&format!("bad number '{}'", w)))).collect()
+        };
+        match keyword {
+            "v" | "vn" => {
+                let v = floats(words)?;
+                if v.len() < 3 {
+                    return Err(error("expected 3 coordinates"));
+                }
+                let v = make_vec3(&v[..3]);
+                if keyword == "v" { positions.push(v) } else { normals.push(v) }
+            }
+            "vt" => {
+                let v = floats(words)?;
+                if v.len() < 2 {
+                    return Err(error("expected 2 coordinates"));
+                }
+                uvs.push(make_vec2(&v[..2]));
+            }
+            "f" => {
+                let mut corners = Vec::new();
+                for word in words {
+                    corners.push(parse_corner(word, positions.len(), uvs.len(), normals.len()).map_err(|e| error(&e))?);
+                }
+                if corners.len() < 3 {
+                    return Err(error("a face needs at least 3 vertices"));
+                }
+                let flat = {
+                    let (a, b, c) = (positions[corners[0].0], positions[corners[1].0], positions[corners[2].0]);
+                    let n = (b - a).cross(&(c - a));
+                    if n.norm() > 0.0 { normalize(&n) } else { n }
+                };
+                let mut face = Vec::new();
+                for (position, uv, normal) in corners {
+                    let key = normal.map(|n| (position, uv, n));
+                    if let Some(index) = key.and_then(|k| seen.get(&k)) {
+                        face.push(*index);
+                        continue;
+                    }
+                    let index = (vertices.len() / 8) as u32;
+                    let n = normal.map(|n| normals[n]).unwrap_or(flat);
+                    let t = uv.map(|t| uvs[t]).unwrap_or_else(|| make_vec2(&[0.0, 0.0]));
+                    vertices.extend_from_slice(positions[position].as_slice());
+                    vertices.extend_from_slice(n.as_slice());
+                    vertices.extend_from_slice(t.as_slice());
+                    if let Some(k) = key {
+                        seen.insert(k, index);
+                    }
+                    face.push(index);
+                }
+                for i in 1..face.len() - 1 {
+                    indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    if indices.is_empty() {
+        return Err("no faces".to_string());
+    }
+    Ok((vertices, indices))
+}
+
+// "v", "v/vt", "v//vn" or "v/vt/vn", 1 based, negative counting back from
+// the last one read
+fn parse_corner(word: &str, positions: usize, uvs: usize, normals: usize) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let resolve = |part: Option<&str>, count: usize| -> Result<Option<usize>, String> {
+        let part = match part {
+            Some(p) if !p.is_empty() => p,
+            _ => return Ok(None)
+        };
+        let i: i64 = part.parse().map_err(|_| format!("bad index '{}'", part))?;
+        let index = if i < 0 { count as i64 + i } else { i - 1 };
+        if index < 0 || index >= count as i64 {
+            return Err(format!("index {} out of range", i));
+        }
+        Ok(Some(index as usize))
+    };
+    let mut parts = word.split('/');
+    let position = resolve(parts.next(), positions)?.ok_or_else(|| format!("bad face vertex '{}'", word))?;
+    Ok((position, resolve(parts.next(), uvs)?, resolve(parts.next(), normals)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quads_become_triangle_fans() {
+        let source = "# a unit square facing +z
+            v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0
+            vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1
+            vn 0 0 1
+            f 1/1/1 2/2/1 3/3/1 4/4/1
+            f 1/1/1 3/3/1 -1/-1/-1";
+        let (vertices, indices) = parse_obj(source).unwrap();
+        // the second face reuses the first face's corners
+        assert_eq!(vertices.len(), 4 * 8);
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3, 0, 2, 3]);
+        assert_eq!(&vertices[16..24], &[1.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn missing_normals_are_flat() {
+        let (vertices, indices) = parse_obj("v 0 0 0\nv 0 0 1\nv 1 0 0\nf 1 2 3").unwrap();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(&vertices[3..8], &[0.0, 1.0, 0.0, 0.0, 0.0]);
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").unwrap_err().contains("line 2"));
+        assert!(parse_obj("v 0 0 0").is_err());
+    }
+}
@@ -0,0 +1,587 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use crate::render_gl::frustum::Aabb;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::{CullMode, PipelineState};
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+
+// how objects that survived frustum culling are checked for being hidden
+// behind others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcclusionMode {
+    Off,
+    // hardware queries, see OcclusionQueries
+    Queries,
+    // a depth pyramid drawn on the CPU, see HiZBuffer
+    HiZ
+}
+
+impl OcclusionMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OcclusionMode::Off => "off",
+            OcclusionMode::Queries => "queries",
+            OcclusionMode::HiZ => "hi-z"
+        }
+    }
+
+    pub fn next(self) -> OcclusionMode {
+        match self {
+            OcclusionMode::Off => OcclusionMode::Queries,
+            OcclusionMode::Queries => OcclusionMode::HiZ,
+            OcclusionMode::HiZ => OcclusionMode::Off
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OcclusionStats {
+    // objects checked this frame
+    pub tested: usize,
+    // of those, the ones found hidden
+    pub occluded: usize,
+    // queries issued this frame
+    pub queries: usize,
+    // earlier queries whose results weren't back at the start of the frame
+    pub waiting: usize,
+    // hidden objects left to conditional rendering rather than skipped
+    pub conditional: usize
+}
+
+struct QueryState {
+    query: GLuint,
+    visible: bool,
+    // issued and not read back yet
+    pending: bool,
+    // the frame a visible object is queried again
+    next_check: u64
+}
+
+// GL_ANY_SAMPLES_PASSED queries per object. results are read a frame or
+// more late, when they are ready, and the last one is used until then. a
+// frame goes:
+//
+// queries.begin_frame();
+// for objects where queries.is_visible(key): queries.draw_visible(key, draw)
+// then for the others: queries.draw_occluded(key, bounds, camera, draw)
+//
+// visible objects are drawn first and fill the depth buffer; every
+// `recheck_interval` frames their draw doubles as their query. hidden ones
+// draw their bounding box against that depth instead. with conditional
+// rendering the object is drawn right after its box and the GPU drops the
+// draw if the box was hidden, so objects coming into view don't appear a
+// frame late. without it they are skipped until a query finds them
+pub struct OcclusionQueries<K> {
+    pub recheck_interval: u64,
+    states: BTreeMap<K, QueryState>,
+    program: Program,
+    cube: Mesh,
+    conditional: bool,
+    frame: u64,
+    stats: OcclusionStats
+}
+
+impl<K: Ord + Copy> OcclusionQueries<K> {
+    pub fn supported() -> bool {
+        gl::GenQueries::is_loaded() && gl::BeginQuery::is_loaded()
+    }
+
+    pub fn new() -> Result<OcclusionQueries<K>, String> {
+        let program = Program::from_shaders(&[
+            Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/occlusion_box.vert"))?)?,
+            // only depth testing matters, like for the shadow maps
+            Shader::from_frag_source(&CString::new(include_str!("shaders/shadow_depth.frag")).unwrap())?
+        ])?;
+        program.set_label("occlusion box");
+        let cube = Mesh::cube();
+        cube.set_label("occlusion box");
+        Ok(OcclusionQueries {
+            recheck_interval: 4,
+            states: BTreeMap::new(),
+            program: program,
+            cube: cube,
+            conditional: gl::BeginConditionalRender::is_loaded(),
+            frame: 0,
+            stats: OcclusionStats::default()
+        })
+    }
+
+    pub fn conditional_render(&self) -> bool {
+        self.conditional
+    }
+
+    // turning it on only works where GL has it
+    pub fn set_conditional_render(&mut self, enabled: bool) {
+        self.conditional = enabled && gl::BeginConditionalRender::is_loaded();
+    }
+
+    // reads the results that are ready, without waiting for the others
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.stats = OcclusionStats::default();
+        for state in self.states.values_mut().filter(|s| s.pending) {
+            let mut available: GLint = 0;
+            unsafe {
+                gl::GetQueryObjectiv(state.query, gl::QUERY_RESULT_AVAILABLE, &mut available);
+            }
+            if available == 0 {
+                self.stats.waiting += 1;
+                continue;
+            }
+            let mut samples: GLuint = 0;
+            unsafe {
+                gl::GetQueryObjectuiv(state.query, gl::QUERY_RESULT, &mut samples);
+            }
+            state.visible = samples != 0;
+            state.pending = false;
+        }
+    }
+
+    // what the last finished query found. objects never queried are visible
+    pub fn is_visible(&self, key: K) -> bool {
+        self.states.get(&key).map(|s| s.visible).unwrap_or(true)
+    }
+
+    fn state(&mut self, key: K) -> &mut QueryState {
+        self.states.entry(key).or_insert_with(|| {
+            let mut query = 0;
+            unsafe {
+                gl::GenQueries(1, &mut query);
+            }
+            QueryState { query: query, visible: true, pending: false, next_check: 0 }
+        })
+    }
+
+    // draws an object the last query found visible, inside a query when it
+    // is due for another one
+    pub fn draw_visible(&mut self, key: K, draw: &mut dyn FnMut()) {
+        self.stats.tested += 1;
+        let (frame, interval) = (self.frame, self.recheck_interval.max(1));
+        let state = self.state(key);
+        if state.pending || frame < state.next_check {
+            draw();
+            return;
+        }
+        unsafe {
+            gl::BeginQuery(gl::ANY_SAMPLES_PASSED, state.query);
+        }
+        draw();
+        unsafe {
+            gl::EndQuery(gl::ANY_SAMPLES_PASSED);
+        }
+        state.pending = true;
+        state.next_check = frame + interval;
+        self.stats.queries += 1;
+    }
+
+    // tests the bounding box of an object the last query found hidden and
+    // runs `draw` under conditional rendering where there is any. the box
+    // is drawn with its own program, so `draw` has to bind its own;
+    // PipelineState::opaque() is applied again afterwards
+    pub fn draw_occluded(&mut self, key: K, bounds: &Aabb, camera: &Camera, draw: &mut dyn FnMut()) {
+        self.stats.tested += 1;
+        // from inside the box, the near plane clips its faces and walls
+        // between them and the camera may hide them but not the object
+        if bounds.expanded(2.0 * camera.near()).contains(&camera.position()) {
+            self.state(key).visible = true;
+            draw();
+            return;
+        }
+        self.stats.occluded += 1;
+        let (query, pending) = {
+            let state = self.state(key);
+            (state.query, state.pending)
+        };
+        // a box query still in flight is used rather than doubled up
+        if !pending {
+            self.draw_box(query, bounds, camera);
+            self.state(key).pending = true;
+            self.stats.queries += 1;
+        }
+        if self.conditional {
+            unsafe {
+                gl::BeginConditionalRender(query, gl::QUERY_WAIT);
+            }
+            draw();
+            unsafe {
+                gl::EndConditionalRender();
+            }
+            self.stats.conditional += 1;
+        }
+    }
+
+    fn draw_box(&self, query: GLuint, bounds: &Aabb, camera: &Camera) {
+        PipelineState::opaque().depth_write(false).color_mask([false; 4]).cull(CullMode::None).apply();
+        self.program.set_used();
+        self.program.set_uniform_mat4("viewProjection", &camera.view_projection());
+        let model = translation(&bounds.center()) * scaling(&(bounds.extents() * 2.0));
+        self.program.set_uniform_mat4("model", &model);
+        unsafe {
+            gl::BeginQuery(gl::ANY_SAMPLES_PASSED, query);
+        }
+        self.cube.draw();
+        unsafe {
+            gl::EndQuery(gl::ANY_SAMPLES_PASSED);
+        }
+        PipelineState::opaque().apply();
+    }
+
+    // deletes the queries of objects `keep` returns false for
+    pub fn retain(&mut self, keep: &dyn Fn(K) -> bool) {
+        let mut dropped = Vec::new();
+        self.states.retain(|key, state| {
+            let keep = keep(*key);
+            if !keep {
+                dropped.push(state.query);
+            }
+            keep
+        });
+        if !dropped.is_empty() {
+            unsafe {
+                gl::DeleteQueries(dropped.len() as GLsizei, dropped.as_ptr());
+            }
+        }
+    }
+
+    // the counters of the current frame
+    pub fn stats(&self) -> OcclusionStats {
+        self.stats
+    }
+}
+
+impl<K> Drop for OcclusionQueries<K> {
+    fn drop(&mut self) {
+        let queries: Vec<GLuint> = self.states.values().map(|s| s.query).collect();
+        if !queries.is_empty() {
+            unsafe {
+                gl::DeleteQueries(queries.len() as GLsizei, queries.as_ptr());
+            }
+        }
+    }
+}
+
+struct Level {
+    width: usize,
+    height: usize,
+    depth: Vec<f32>
+}
+
+// a small depth buffer drawn on the CPU, for when queries aren't available
+// or a frame of latency is too much. occluders are rasterised at pixel
+// centers, then each level of the pyramid keeps the farthest depth of 2x2
+// texels of the one below. a box is hidden when its nearest point is
+// behind every texel it covers on the first level where that is at most
+// 2x2 texels. occluders have to be solid: anything behind the pixels they
+// cover counts as hidden
+pub struct HiZBuffer {
+    view_projection: Mat4,
+    // window depth, 0 at the near plane and 1 at the far one
+    levels: Vec<Level>
+}
+
+impl HiZBuffer {
+    pub fn new(width: usize, height: usize) -> HiZBuffer {
+        let (mut width, mut height) = (width.max(1), height.max(1));
+        let mut levels = Vec::new();
+        loop {
+            levels.push(Level { width: width, height: height, depth: vec![1.0; width * height] });
+            if width == 1 && height == 1 {
+                break;
+            }
+            width = width.div_ceil(2);
+            height = height.div_ceil(2);
+        }
+        HiZBuffer { view_projection: Mat4::identity(), levels: levels }
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // the depth at texel (x, y) of a level, e.g for drawing it as a debug view
+    pub fn depth(&self, level: usize, x: usize, y: usize) -> f32 {
+        let level = &self.levels[level];
+        level.depth[y * level.width + x]
+    }
+
+    // starts a frame seen through `view_projection`, with nothing drawn
+    pub fn clear(&mut self, view_projection: &Mat4) {
+        self.view_projection = *view_projection;
+        for level in self.levels.iter_mut() {
+            for depth in level.depth.iter_mut() {
+                *depth = 1.0;
+            }
+        }
+    }
+
+    pub fn add_triangle(&mut self, a: &Vec3, b: &Vec3, c: &Vec3) {
+        let clip = |p: &Vec3| self.view_projection * make_vec4(&[p.x, p.y, p.z, 1.0]);
+        let polygon = clip_near(&[clip(a), clip(b), clip(c)]);
+        if polygon.len() < 3 {
+            return;
+        }
+        let screen: Vec<Vec3> = polygon.iter().map(|p| self.to_window(p)).collect();
+        for i in 1..screen.len() - 1 {
+            self.fill(&screen[0], &screen[i], &screen[i + 1]);
+        }
+    }
+
+    // `aabb` in the space `model` maps to the world, e.g a wall's mesh
+    // bounds and its world matrix. rotated boxes stay tight this way
+    pub fn add_box(&mut self, aabb: &Aabb, model: &Mat4) {
+        // corner indices around each face, see Aabb::corners
+        const FACES: [[usize; 4]; 6] = [[0, 1, 3, 2], [4, 5, 7, 6], [0, 1, 5, 4], [2, 3, 7, 6], [0, 2, 6, 4], [1, 3, 7, 5]];
+        let corners: Vec<Vec3> = aabb.corners().iter()
+            .map(|c| (model * make_vec4(&[c.x, c.y, c.z, 1.0])).xyz())
+            .collect();
+        for face in FACES.iter() {
+            let [a, b, c, d] = *face;
+            self.add_triangle(&corners[a], &corners[b], &corners[c]);
+            self.add_triangle(&corners[a], &corners[c], &corners[d]);
+        }
+    }
+
+    // fills in the coarser levels. call once the occluders are added
+    pub fn finish(&mut self) {
+        for i in 1..self.levels.len() {
+            let (finer, coarser) = self.levels.split_at_mut(i);
+            let (fine, coarse) = (&finer[i - 1], &mut coarser[0]);
+            for y in 0..coarse.height {
+                for x in 0..coarse.width {
+                    let mut farthest: f32 = 0.0;
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                        let fx = (2 * x + dx).min(fine.width - 1);
+                        let fy = (2 * y + dy).min(fine.height - 1);
+                        farthest = farthest.max(fine.depth[fy * fine.width + fx]);
+                    }
+                    coarse.depth[y * coarse.width + x] = farthest;
+                }
+            }
+        }
+    }
+
+    // whether any of the box might be seen past the occluders. boxes
+    // reaching through the near plane or off screen count as visible;
+    // frustum culling deals with the latter
+    pub fn is_visible(&self, aabb: &Aabb) -> bool {
+        let mut min = make_vec3(&[f32::MAX, f32::MAX, f32::MAX]);
+        let mut max = make_vec3(&[f32::MIN, f32::MIN, f32::MIN]);
+        for corner in aabb.corners().iter() {
+            let clip = self.view_projection * make_vec4(&[corner.x, corner.y, corner.z, 1.0]);
+            if clip.z + clip.w < 0.0 || clip.w <= 0.0 {
+                return true;
+            }
+            let window = self.to_window(&clip);
+            min = min.inf(&window);
+            max = max.sup(&window);
+        }
+        let (width, height) = (self.width() as f32, self.height() as f32);
+        let (x0, y0) = (min.x.floor().max(0.0) as usize, min.y.floor().max(0.0) as usize);
+        let (x1, y1) = (max.x.ceil().min(width) as usize, max.y.ceil().min(height) as usize);
+        if x0 >= x1 || y0 >= y1 {
+            return true;
+        }
+        // inclusive texel ranges from here on
+        let (mut x0, mut y0, mut x1, mut y1) = (x0, y0, x1 - 1, y1 - 1);
+        let mut level = 0;
+        while level + 1 < self.levels.len() && (x1 - x0 > 1 || y1 - y0 > 1) {
+            level += 1;
+            x0 /= 2;
+            y0 /= 2;
+            x1 /= 2;
+            y1 /= 2;
+        }
+        let level = &self.levels[level];
+        (y0..=y1).any(|y| (x0..=x1).any(|x| min.z <= level.depth[y * level.width + x]))
+    }
+
+    fn to_window(&self, clip: &Vec4) -> Vec3 {
+        let ndc = clip.xyz() / clip.w;
+        make_vec3(&[(ndc.x * 0.5 + 0.5) * self.width() as f32,
+                    (ndc.y * 0.5 + 0.5) * self.height() as f32,
+                    ndc.z * 0.5 + 0.5])
+    }
+
+    // keeps the nearest depth at the pixel centers the triangle covers,
+    // whichever way it winds
+    fn fill(&mut self, a: &Vec3, b: &Vec3, c: &Vec3) {
+        let edge = |from: &Vec3, to: &Vec3, x: f32, y: f32| (to.x - from.x) * (y - from.y) - (to.y - from.y) * (x - from.x);
+        let area = edge(a, b, c.x, c.y);
+        if area.abs() < 1e-9 {
+            return;
+        }
+        let level = &mut self.levels[0];
+        let x0 = a.x.min(b.x).min(c.x).floor().max(0.0) as usize;
+        let y0 = a.y.min(b.y).min(c.y).floor().max(0.0) as usize;
+        let x1 = a.x.max(b.x).max(c.x).ceil().min(level.width as f32) as usize;
+        let y1 = a.y.max(b.y).max(c.y).ceil().min(level.height as f32) as usize;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let (wa, wb, wc) = (edge(b, c, px, py) / area, edge(c, a, px, py) / area, edge(a, b, px, py) / area);
+                if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                    continue;
+                }
+                let depth = &mut level.depth[y * level.width + x];
+                *depth = depth.min(wa * a.z + wb * b.z + wc * c.z);
+            }
+        }
+    }
+}
+
+// cuts off what lies in front of the near plane (z < -w in GL clip space)
+fn clip_near(polygon: &[Vec4]) -> Vec<Vec4> {
+    let distance = |p: &Vec4| p.z + p.w;
+    let mut clipped = Vec::new();
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        let (da, db) = (distance(a), distance(b));
+        if da >= 0.0 {
+            clipped.push(*a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            clipped.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+    clipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::mock::MockGl;
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 1.0);
+        camera.set_perspective(60.0, 1.0, 0.1, 100.0);
+        camera
+    }
+
+    fn cube(x: f32, y: f32, z: f32, size: f32) -> Aabb {
+        Aabb::from_center_extents(&make_vec3(&[x, y, z]), &make_vec3(&[size, size, size]))
+    }
+
+    #[test]
+    fn walls_hide_what_is_behind_them() {
+        let mut hiz = HiZBuffer::new(64, 48);
+        assert_eq!(hiz.levels(), 7);
+        hiz.clear(&camera().view_projection());
+        // a 4 x 4 wall 5 units ahead, turned a little around y
+        let wall = Aabb::new(&make_vec3(&[-2.0, -2.0, -0.1]), &make_vec3(&[2.0, 2.0, 0.1]));
+        let model = translation(&make_vec3(&[0.0, 0.0, -5.0])) * rotation(0.2, &make_vec3(&[0.0, 1.0, 0.0]));
+        hiz.add_box(&wall, &model);
+        hiz.finish();
+        assert!(hiz.depth(0, 32, 24) < 1.0);
+        assert_eq!(hiz.depth(0, 0, 0), 1.0);
+        // coarser levels hold the farthest depth below them
+        assert_eq!(hiz.depth(hiz.levels() - 1, 0, 0), 1.0);
+
+        assert!(!hiz.is_visible(&cube(0.0, 0.0, -10.0, 0.5)));
+        assert!(!hiz.is_visible(&cube(0.5, 0.5, -30.0, 2.0)));
+        assert!(hiz.is_visible(&cube(0.0, 0.0, -3.0, 0.5)), "in front of the wall");
+        assert!(hiz.is_visible(&cube(5.0, 0.0, -10.0, 0.5)), "beside the wall");
+        assert!(hiz.is_visible(&cube(0.0, 0.0, -10.0, 3.0)), "sticking out around it");
+        assert!(hiz.is_visible(&cube(0.0, 0.0, 0.0, 0.5)), "around the camera");
+
+        hiz.clear(&camera().view_projection());
+        hiz.finish();
+        assert!(hiz.is_visible(&cube(0.0, 0.0, -10.0, 0.5)));
+    }
+
+    #[test]
+    fn occluders_are_clipped_at_the_near_plane() {
+        let mut hiz = HiZBuffer::new(32, 32);
+        hiz.clear(&camera().view_projection());
+        // a floor running from behind the camera into the distance
+        let floor = Aabb::new(&make_vec3(&[-50.0, -1.2, -50.0]), &make_vec3(&[50.0, -1.0, 50.0]));
+        hiz.add_box(&floor, &Mat4::identity());
+        hiz.finish();
+        assert_eq!(hiz.depth(0, 16, 31), 1.0, "the sky stays empty");
+        assert!(hiz.depth(0, 16, 0) < 1.0);
+        assert!(!hiz.is_visible(&cube(0.0, -3.0, -8.0, 0.5)), "under the floor");
+        assert!(hiz.is_visible(&cube(0.0, 0.0, -8.0, 0.5)));
+    }
+
+    #[test]
+    fn queries_reuse_the_last_result() {
+        let gl = MockGl::install();
+        let camera = camera();
+        let ball = Mesh::sphere(8, 4);
+        let mut queries: OcclusionQueries<u32> = OcclusionQueries::new().unwrap();
+        let bounds = cube(0.0, 0.0, -10.0, 1.0);
+        let frame = |queries: &mut OcclusionQueries<u32>| {
+            gl.clear_draws();
+            queries.begin_frame();
+            // the box program stands in for the ball's material
+            queries.program.set_used();
+            if queries.is_visible(7) {
+                queries.draw_visible(7, &mut || ball.draw());
+            } else {
+                queries.draw_occluded(7, &bounds, &camera, &mut || ball.draw());
+            }
+            queries.stats()
+        };
+
+        // the first draw is queried, and found hidden
+        gl.set_query_samples(Some(0));
+        assert_eq!(frame(&mut queries).queries, 1);
+        assert_eq!(gl.state().queries_run.len(), 1);
+        // then the box is drawn and decides the ball's draw on the GPU
+        let stats = frame(&mut queries);
+        assert_eq!((stats.occluded, stats.queries, stats.conditional), (1, 1, 1));
+        let state = gl.state();
+        let (target, query) = state.queries_run[1];
+        assert_eq!(target, gl::ANY_SAMPLES_PASSED);
+        assert_eq!(state.draws.len(), 2);
+        assert_eq!((state.draws[0].count, state.draws[0].condition), (36, None));
+        assert_eq!((state.draws[1].count, state.draws[1].condition), (ball.count(), Some(query)));
+        assert_eq!(state.color_mask, [true; 4], "the box pass restores the pipeline");
+
+        // a result that isn't back keeps the last one
+        gl.set_query_samples(None);
+        frame(&mut queries);
+        gl.set_query_samples(Some(4));
+        assert_eq!(frame(&mut queries).waiting, 1);
+        assert!(!queries.is_visible(7));
+
+        // without conditional rendering hidden objects aren't drawn
+        queries.set_conditional_render(false);
+        assert_eq!(frame(&mut queries).conditional, 0);
+        assert!(gl.state().draws.is_empty());
+        assert!(gl.state().errors.is_empty(), "{:?}", gl.state().errors);
+
+        queries.retain(&|key| key != 7);
+        assert!(gl.state().queries.is_empty());
+    }
+
+    #[test]
+    fn visible_objects_are_queried_every_few_frames() {
+        let gl = MockGl::install();
+        gl.set_query_samples(Some(10));
+        let mut queries: OcclusionQueries<u32> = OcclusionQueries::new().unwrap();
+        queries.recheck_interval = 3;
+        let mut issued = Vec::new();
+        for _ in 0..7 {
+            queries.begin_frame();
+            queries.draw_visible(1, &mut || ());
+            queries.draw_visible(2, &mut || ());
+            issued.push(queries.stats().queries);
+        }
+        assert_eq!(issued, vec![2, 0, 0, 2, 0, 0, 2]);
+        assert_eq!(gl.state().queries.len(), 2);
+        drop(queries);
+        assert!(gl.state().leaks().iter().all(|l| !l.starts_with("query")));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::glm::*;
+use crate::render_gl::debug::{self, DebugGroup};
+use crate::render_gl::material::{Material, MaterialParam};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::shadow::point_light_matrices;
+use crate::render_gl::state;
+use crate::render_gl::texture::Texture;
+use crate::render_gl::{load_image, shader_lib, ImageType, Program, Shader};
+use gl::types::*;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+// texture units reserved for image based lighting, below the shadow units
+pub const IRRADIANCE_UNIT: u32 = 10;
+pub const PREFILTER_UNIT: u32 = 11;
+pub const BRDF_LUT_UNIT: u32 = 12;
+
+pub fn program() -> Result<Program, String> {
+    Program::from_shaders(&[
+        Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/pbr.vert"))?)?,
+        Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/pbr.frag"))?)?
+    ])
+}
+
+// metallic-roughness parameters for shaders/pbr.frag. each factor is
+// multiplied with its map when one is set
+#[derive(Debug, Clone)]
+pub struct PbrMaterial {
+    pub albedo: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ao: f32,
+    pub emissive: Vec3,
+    pub albedo_map: Option<Rc<Texture>>,
+    pub metallic_map: Option<Rc<Texture>>,
+    pub roughness_map: Option<Rc<Texture>>,
+    pub ao_map: Option<Rc<Texture>>,
+    // tangent space, perturbed with screen space derivatives so meshes need no tangents
+    pub normal_map: Option<Rc<Texture>>,
+    pub emissive_map: Option<Rc<Texture>>
+}
+
+impl Default for PbrMaterial {
+    fn default() -> PbrMaterial {
+        PbrMaterial {
+            albedo: make_vec3(&[1.0, 1.0, 1.0]),
+            metallic: 0.0,
+            roughness: 0.5,
+            ao: 1.0,
+            emissive: make_vec3(&[0.0, 0.0, 0.0]),
+            albedo_map: None,
+            metallic_map: None,
+            roughness_map: None,
+            ao_map: None,
+            normal_map: None,
+            emissive_map: None
+        }
+    }
+}
+
+impl PbrMaterial {
+    pub fn new(albedo: &Vec3, metallic: f32, roughness: f32) -> PbrMaterial {
+        PbrMaterial { albedo: albedo.clone(), metallic: metallic, roughness: roughness, ..PbrMaterial::default() }
+    }
+
+    // the uniforms of the `material` struct in shaders/pbr.frag
+    pub fn params(&self) -> BTreeMap<String, MaterialParam> {
+        let mut params = BTreeMap::new();
+        let mut insert = |name: &str, value: MaterialParam| {
+            params.insert(format!("material.{}", name), value);
+        };
+        insert("albedo", MaterialParam::Vec3(self.albedo.clone()));
+        insert("metallic", MaterialParam::Float(self.metallic));
+        insert("roughness", MaterialParam::Float(self.roughness));
+        insert("ao", MaterialParam::Float(self.ao));
+        insert("emissive", MaterialParam::Vec3(self.emissive.clone()));
+        let maps = [
+            ("albedoMap", "hasAlbedoMap", &self.albedo_map),
+            ("metallicMap", "hasMetallicMap", &self.metallic_map),
+            ("roughnessMap", "hasRoughnessMap", &self.roughness_map),
+            ("aoMap", "hasAoMap", &self.ao_map),
+            ("normalMap", "hasNormalMap", &self.normal_map),
+            ("emissiveMap", "hasEmissiveMap", &self.emissive_map),
+        ];
+        for (map, flag, texture) in maps.iter() {
+            insert(flag, MaterialParam::Int(texture.is_some() as i32));
+            if let Some(texture) = texture {
+                insert(map, MaterialParam::Texture(texture.clone()));
+            }
+        }
+        params
+    }
+
+    pub fn material(&self, program: Rc<Program>) -> Material {
+        Material::new(program, self.params())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IblSettings {
+    pub irradiance_size: u32,
+    pub prefilter_size: u32,
+    // mip levels of the prefiltered map, roughness 0 to 1
+    pub prefilter_levels: u32,
+    pub brdf_lut_size: u32
+}
+
+impl Default for IblSettings {
+    fn default() -> IblSettings {
+        IblSettings { irradiance_size: 32, prefilter_size: 128, prefilter_levels: 5, brdf_lut_size: 512 }
+    }
+}
+
+// roughness the prefiltered map is convolved with at `level`
+pub fn prefilter_roughness(level: u32, levels: u32) -> f32 {
+    if levels <= 1 {
+        return 0.0;
+    }
+    level as f32 / (levels - 1) as f32
+}
+
+pub fn mip_size(size: u32, level: u32) -> u32 {
+    (size >> level).max(1)
+}
+
+// the textures read by the image based lighting in shaders/pbr.frag
+pub struct IblMaps {
+    pub irradiance: GLuint,
+    pub prefilter: GLuint,
+    pub brdf_lut: GLuint,
+    pub prefilter_levels: u32
+}
+
+impl IblMaps {
+    // convolves `environment`, a cube map of `environment_size` texels per
+    // face, in offscreen passes. mipmaps are generated for the environment
+    // since the prefilter pass samples its lower levels
+    pub fn generate(environment: GLuint, environment_size: u32, settings: &IblSettings) -> Result<IblMaps, String> {
+        let _group = DebugGroup::new("ibl precompute");
+        let irradiance_program = capture_program(include_str!("shaders/irradiance.frag"))?;
+        let prefilter_program = capture_program(include_str!("shaders/prefilter.frag"))?;
+        let brdf_program = Program::from_shaders(&[
+            Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/fullscreen.vert"))?)?,
+            Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/brdf.frag"))?)?
+        ])?;
+        let cube = Mesh::cube();
+        let quad = Mesh::quad();
+        let views = point_light_matrices(&make_vec3(&[0.0, 0.0, 0.0]), 0.1, 10.0);
+
+        let viewport = state::current_viewport();
+        let mut fbo: GLuint = 0;
+        PipelineState::fullscreen().apply();
+        unsafe {
+            state::enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+            state::bind_texture(gl::TEXTURE_CUBE_MAP, environment);
+            gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+            gl::GenerateMipmap(gl::TEXTURE_CUBE_MAP);
+            gl::GenFramebuffers(1, &mut fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+        }
+        let maps = IblMaps {
+            irradiance: create_cubemap(settings.irradiance_size, 1),
+            prefilter: create_cubemap(settings.prefilter_size, settings.prefilter_levels),
+            brdf_lut: create_brdf_lut(settings.brdf_lut_size),
+            prefilter_levels: settings.prefilter_levels
+        };
+        debug::label_texture(maps.irradiance, "ibl irradiance");
+        debug::label_texture(maps.prefilter, "ibl prefilter");
+        debug::label_texture(maps.brdf_lut, "ibl brdf lut");
+
+        let result: Result<(), String> = (|| {
+            irradiance_program.set_used();
+            irradiance_program.set_uniform_1i("environmentMap", 0);
+            bind_environment(environment);
+            render_faces(maps.irradiance, 0, settings.irradiance_size, &views, &irradiance_program, &cube)?;
+
+            prefilter_program.set_used();
+            prefilter_program.set_uniform_1i("environmentMap", 0);
+            prefilter_program.set_uniform_1f("environmentSize", environment_size as f32);
+            bind_environment(environment);
+            for level in 0..settings.prefilter_levels {
+                prefilter_program.set_uniform_1f("roughness", prefilter_roughness(level, settings.prefilter_levels));
+                render_faces(maps.prefilter, level, mip_size(settings.prefilter_size, level), &views, &prefilter_program, &cube)?;
+            }
+
+            brdf_program.set_used();
+            unsafe {
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, maps.brdf_lut, 0);
+                state::viewport(0, 0, settings.brdf_lut_size as GLsizei, settings.brdf_lut_size as GLsizei);
+            }
+            check_framebuffer()?;
+            quad.draw();
+            Ok(())
+        })();
+
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::DeleteFramebuffers(1, &fbo);
+            state::viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+        }
+        PipelineState::opaque().apply();
+        result.map(|_| maps)
+    }
+}
+
+impl Drop for IblMaps {
+    fn drop(&mut self) {
+        let textures = [self.irradiance, self.prefilter, self.brdf_lut];
+        state::delete_textures(&textures);
+    }
+}
+
+// binds the maps to their reserved units. without maps the shader falls
+// back to a constant ambient term. the program must be in use
+pub fn apply_ibl(program: &Program, maps: Option<&IblMaps>) {
+    // the samplers always point at their own units so a samplerCube never
+    // shares a unit with the sampler2D material maps
+    program.set_uniform_1i("irradianceMap", IRRADIANCE_UNIT as i32);
+    program.set_uniform_1i("prefilterMap", PREFILTER_UNIT as i32);
+    program.set_uniform_1i("brdfLUT", BRDF_LUT_UNIT as i32);
+    program.set_uniform_1i("useIbl", maps.is_some() as i32);
+    if let Some(maps) = maps {
+        program.set_uniform_1f("prefilterLevels", maps.prefilter_levels as f32);
+        state::active_texture(gl::TEXTURE0 + IRRADIANCE_UNIT);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, maps.irradiance);
+        state::active_texture(gl::TEXTURE0 + PREFILTER_UNIT);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, maps.prefilter);
+        state::active_texture(gl::TEXTURE0 + BRDF_LUT_UNIT);
+        state::bind_texture(gl::TEXTURE_2D, maps.brdf_lut);
+        state::active_texture(gl::TEXTURE0);
+    }
+}
+
+// faces in +X, -X, +Y, -Y, +Z, -Z order, all the same size
+pub fn load_cubemap(faces: &[String]) -> Result<GLuint, String> {
+    if faces.len() != 6 {
+        return Err(format!("a cube map needs 6 faces, got {}", faces.len()));
+    }
+    let mut tex: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, tex);
+    }
+    for (i, face) in faces.iter().enumerate() {
+        let (image_type, data, width, height) = match load_image(face) {
+            Ok(image) => image,
+            Err(e) => {
+                state::delete_texture(tex);
+                return Err(e);
+            }
+        };
+        let (internal, format) = if image_type == ImageType::RGB { (gl::RGB8, gl::RGB) } else { (gl::RGBA8, gl::RGBA) };
+        unsafe {
+            gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as GLenum, 0, internal as GLint,
+                           width as GLsizei, height as GLsizei, 0, format, gl::UNSIGNED_BYTE,
+                           data.as_ptr() as *const GLvoid);
+        }
+    }
+    unsafe {
+        set_cubemap_parameters(false);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+    Ok(tex)
+}
+
+fn capture_program(fragment: &str) -> Result<Program, String> {
+    Program::from_shaders(&[
+        Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/cubemap.vert"))?)?,
+        Shader::from_frag_source(&shader_lib::preprocess(fragment)?)?
+    ])
+}
+
+// the cube map must be bound
+unsafe fn set_cubemap_parameters(mipmapped: bool) {
+    let min = if mipmapped { gl::LINEAR_MIPMAP_LINEAR } else { gl::LINEAR };
+    gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, min as i32);
+    gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+    gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+    gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+}
+
+fn create_cubemap(size: u32, levels: u32) -> GLuint {
+    let mut tex: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, tex);
+        for level in 0..levels {
+            let s = mip_size(size, level) as GLsizei;
+            for face in 0..6 {
+                gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + face, level as GLint, gl::RGB16F as GLint,
+                               s, s, 0, gl::RGB, gl::FLOAT, std::ptr::null());
+            }
+        }
+        set_cubemap_parameters(levels > 1);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAX_LEVEL, levels as i32 - 1);
+        state::bind_texture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+    tex
+}
+
+fn create_brdf_lut(size: u32) -> GLuint {
+    let mut tex: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+        state::bind_texture(gl::TEXTURE_2D, tex);
+        gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RG16F as GLint, size as GLsizei, size as GLsizei, 0,
+                       gl::RG, gl::FLOAT, std::ptr::null());
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        state::bind_texture(gl::TEXTURE_2D, 0);
+    }
+    tex
+}
+
+fn bind_environment(environment: GLuint) {
+    state::active_texture(gl::TEXTURE0);
+    state::bind_texture(gl::TEXTURE_CUBE_MAP, environment);
+}
+
+// draws the inside of a cube into each face of `target` at mip `level`
+fn render_faces(target: GLuint, level: u32, size: u32, views: &[Mat4], program: &Program, cube: &Mesh) -> Result<(), String> {
+    state::viewport(0, 0, size as GLsizei, size as GLsizei);
+    for (face, view_projection) in views.iter().enumerate() {
+        unsafe {
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0,
+                                     gl::TEXTURE_CUBE_MAP_POSITIVE_X + face as GLenum, target, level as GLint);
+        }
+        check_framebuffer()?;
+        program.set_uniform_mat4("viewProjection", view_projection);
+        unsafe {
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+        cube.draw();
+    }
+    Ok(())
+}
+
+fn check_framebuffer() -> Result<(), String> {
+    let status = unsafe { gl::CheckFramebufferStatus(gl::FRAMEBUFFER) };
+    if status != gl::FRAMEBUFFER_COMPLETE {
+        return Err(format!("IBL capture framebuffer incomplete: 0x{:x}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::shadow;
+
+    #[test]
+    fn prefilter_levels_span_the_roughness_range() {
+        let levels = IblSettings::default().prefilter_levels;
+        assert_eq!(prefilter_roughness(0, levels), 0.0);
+        assert_eq!(prefilter_roughness(2, levels), 0.5);
+        assert_eq!(prefilter_roughness(levels - 1, levels), 1.0);
+        assert_eq!(prefilter_roughness(0, 1), 0.0);
+        assert_eq!(mip_size(128, 4), 8);
+        assert_eq!(mip_size(2, 5), 1);
+    }
+
+    #[test]
+    fn missing_maps_clear_their_flags() {
+        let params = PbrMaterial::new(&make_vec3(&[1.0, 0.0, 0.0]), 1.0, 0.2).params();
+        assert_eq!(params.get("material.metallic"), Some(&MaterialParam::Float(1.0)));
+        assert_eq!(params.get("material.hasAlbedoMap"), Some(&MaterialParam::Int(0)));
+        assert_eq!(params.get("material.hasNormalMap"), Some(&MaterialParam::Int(0)));
+        assert!(params.get("material.albedoMap").is_none());
+    }
+
+    #[test]
+    fn maps_set_their_flags() {
+        let mut pbr = PbrMaterial::default();
+        pbr.normal_map = Some(Rc::new(Texture::new(3, &String::from("normal"))));
+        let params = pbr.params();
+        assert_eq!(params.get("material.hasNormalMap"), Some(&MaterialParam::Int(1)));
+        assert!(params.contains_key("material.normalMap"));
+    }
+
+    #[test]
+    fn ibl_units_are_reserved() {
+        let units = [IRRADIANCE_UNIT, PREFILTER_UNIT, BRDF_LUT_UNIT];
+        for unit in units.iter() {
+            assert!(*unit < shadow::DIR_SHADOW_UNIT);
+        }
+    }
+}
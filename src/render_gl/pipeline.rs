@@ -0,0 +1,445 @@
+use crate::render_gl::state::{self, StateCache};
+use gl::types::*;
+
+// fixed function state for a draw, set as a whole with apply() instead of
+// a trail of enables and disables. goes through the state cache, so only
+// what differs from the last pipeline reaches GL:
+//
+// PipelineState::alpha_blended().apply();
+// glass.draw();
+//
+// the presets are starting points, change them with the builder methods,
+// e.g PipelineState::opaque().cull(CullMode::None). glClear honours the
+// depth and colour masks, so apply an opaque pipeline before clearing
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha
+}
+
+impl BlendFactor {
+    pub fn to_gl(&self) -> GLenum {
+        match self {
+            BlendFactor::Zero => gl::ZERO,
+            BlendFactor::One => gl::ONE,
+            BlendFactor::SrcColor => gl::SRC_COLOR,
+            BlendFactor::OneMinusSrcColor => gl::ONE_MINUS_SRC_COLOR,
+            BlendFactor::DstColor => gl::DST_COLOR,
+            BlendFactor::OneMinusDstColor => gl::ONE_MINUS_DST_COLOR,
+            BlendFactor::SrcAlpha => gl::SRC_ALPHA,
+            BlendFactor::OneMinusSrcAlpha => gl::ONE_MINUS_SRC_ALPHA,
+            BlendFactor::DstAlpha => gl::DST_ALPHA,
+            BlendFactor::OneMinusDstAlpha => gl::ONE_MINUS_DST_ALPHA
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOp {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max
+}
+
+impl BlendOp {
+    pub fn to_gl(&self) -> GLenum {
+        match self {
+            BlendOp::Add => gl::FUNC_ADD,
+            BlendOp::Subtract => gl::FUNC_SUBTRACT,
+            BlendOp::ReverseSubtract => gl::FUNC_REVERSE_SUBTRACT,
+            BlendOp::Min => gl::MIN,
+            BlendOp::Max => gl::MAX
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always
+}
+
+impl CompareFunc {
+    pub fn to_gl(&self) -> GLenum {
+        match self {
+            CompareFunc::Never => gl::NEVER,
+            CompareFunc::Less => gl::LESS,
+            CompareFunc::Equal => gl::EQUAL,
+            CompareFunc::LessEqual => gl::LEQUAL,
+            CompareFunc::Greater => gl::GREATER,
+            CompareFunc::NotEqual => gl::NOTEQUAL,
+            CompareFunc::GreaterEqual => gl::GEQUAL,
+            CompareFunc::Always => gl::ALWAYS
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    Front,
+    Back
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    Increment,
+    IncrementWrap,
+    Decrement,
+    DecrementWrap,
+    Invert
+}
+
+impl StencilOp {
+    pub fn to_gl(&self) -> GLenum {
+        match self {
+            StencilOp::Keep => gl::KEEP,
+            StencilOp::Zero => gl::ZERO,
+            StencilOp::Replace => gl::REPLACE,
+            StencilOp::Increment => gl::INCR,
+            StencilOp::IncrementWrap => gl::INCR_WRAP,
+            StencilOp::Decrement => gl::DECR,
+            StencilOp::DecrementWrap => gl::DECR_WRAP,
+            StencilOp::Invert => gl::INVERT
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolygonMode {
+    Fill,
+    Line,
+    Point
+}
+
+impl PolygonMode {
+    pub fn to_gl(&self) -> GLenum {
+        match self {
+            PolygonMode::Fill => gl::FILL,
+            PolygonMode::Line => gl::LINE,
+            PolygonMode::Point => gl::POINT
+        }
+    }
+}
+
+// result = src * source colour <op> dst * colour in the target. alpha
+// uses its own pair of factors, the same as rgb unless separate_alpha is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlendState {
+    pub op: BlendOp,
+    pub src: BlendFactor,
+    pub dst: BlendFactor,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor
+}
+
+impl BlendState {
+    pub fn new(op: BlendOp, src: BlendFactor, dst: BlendFactor) -> BlendState {
+        BlendState { op: op, src: src, dst: dst, src_alpha: src, dst_alpha: dst }
+    }
+
+    pub fn separate_alpha(mut self, src: BlendFactor, dst: BlendFactor) -> BlendState {
+        self.src_alpha = src;
+        self.dst_alpha = dst;
+        self
+    }
+
+    // non premultiplied alpha
+    pub fn alpha() -> BlendState {
+        BlendState::new(BlendOp::Add, BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha)
+    }
+
+    pub fn additive() -> BlendState {
+        BlendState::new(BlendOp::Add, BlendFactor::One, BlendFactor::One)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthState {
+    pub test: bool,
+    pub write: bool,
+    pub func: CompareFunc
+}
+
+// the same for front and back faces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StencilState {
+    pub func: CompareFunc,
+    pub reference: i32,
+    pub read_mask: u32,
+    pub write_mask: u32,
+    pub stencil_fail: StencilOp,
+    pub depth_fail: StencilOp,
+    pub pass: StencilOp
+}
+
+impl StencilState {
+    // writes `reference` wherever something is drawn
+    pub fn write(reference: i32) -> StencilState {
+        StencilState {
+            func: CompareFunc::Always,
+            reference: reference,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            stencil_fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Replace
+        }
+    }
+
+    // draws only where the stencil buffer holds `reference`
+    pub fn equal(reference: i32) -> StencilState {
+        StencilState {
+            func: CompareFunc::Equal,
+            reference: reference,
+            read_mask: 0xff,
+            write_mask: 0,
+            stencil_fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineState {
+    pub blend: Option<BlendState>,
+    pub depth: DepthState,
+    pub cull: CullMode,
+    pub front_face: FrontFace,
+    pub stencil: Option<StencilState>,
+    pub polygon_mode: PolygonMode,
+    pub color_mask: [bool; 4]
+}
+
+impl Default for PipelineState {
+    fn default() -> PipelineState {
+        PipelineState::opaque()
+    }
+}
+
+impl PipelineState {
+    // depth tested and written, back faces culled, no blending
+    pub fn opaque() -> PipelineState {
+        PipelineState {
+            blend: None,
+            depth: DepthState { test: true, write: true, func: CompareFunc::Less },
+            cull: CullMode::Back,
+            front_face: FrontFace::CounterClockwise,
+            stencil: None,
+            polygon_mode: PolygonMode::Fill,
+            color_mask: [true; 4]
+        }
+    }
+
+    // tested against the opaque depth but not written, so surfaces behind
+    // still blend in; both sides are drawn. sort back to front
+    pub fn alpha_blended() -> PipelineState {
+        PipelineState::opaque()
+            .blend(Some(BlendState::alpha()))
+            .depth_write(false)
+            .cull(CullMode::None)
+    }
+
+    // light volumes, particles, glow. order doesn't matter
+    pub fn additive() -> PipelineState {
+        PipelineState::opaque()
+            .blend(Some(BlendState::additive()))
+            .depth_write(false)
+            .cull(CullMode::None)
+    }
+
+    pub fn wireframe() -> PipelineState {
+        PipelineState::opaque()
+            .polygon_mode(PolygonMode::Line)
+            .cull(CullMode::None)
+    }
+
+    // full screen passes: no depth, no culling, no blending
+    pub fn fullscreen() -> PipelineState {
+        PipelineState::opaque()
+            .depth(DepthState { test: false, write: false, func: CompareFunc::Always })
+            .cull(CullMode::None)
+    }
+
+    pub fn blend(mut self, blend: Option<BlendState>) -> PipelineState {
+        self.blend = blend;
+        self
+    }
+
+    pub fn depth(mut self, depth: DepthState) -> PipelineState {
+        self.depth = depth;
+        self
+    }
+
+    pub fn depth_test(mut self, test: bool) -> PipelineState {
+        self.depth.test = test;
+        self
+    }
+
+    pub fn depth_write(mut self, write: bool) -> PipelineState {
+        self.depth.write = write;
+        self
+    }
+
+    pub fn depth_func(mut self, func: CompareFunc) -> PipelineState {
+        self.depth.func = func;
+        self
+    }
+
+    pub fn cull(mut self, cull: CullMode) -> PipelineState {
+        self.cull = cull;
+        self
+    }
+
+    pub fn front_face(mut self, front_face: FrontFace) -> PipelineState {
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn stencil(mut self, stencil: Option<StencilState>) -> PipelineState {
+        self.stencil = stencil;
+        self
+    }
+
+    pub fn polygon_mode(mut self, mode: PolygonMode) -> PipelineState {
+        self.polygon_mode = mode;
+        self
+    }
+
+    pub fn color_mask(mut self, mask: [bool; 4]) -> PipelineState {
+        self.color_mask = mask;
+        self
+    }
+
+    pub fn apply(&self) {
+        state::with(|cache| self.apply_to(cache));
+    }
+
+    pub fn apply_to(&self, cache: &mut StateCache) {
+        let boolean = |b: bool| if b { gl::TRUE } else { gl::FALSE };
+        match self.blend {
+            Some(blend) => {
+                cache.enable(gl::BLEND);
+                cache.blend_equation(blend.op.to_gl());
+                cache.blend_func_separate(blend.src.to_gl(), blend.dst.to_gl(),
+                                          blend.src_alpha.to_gl(), blend.dst_alpha.to_gl());
+            }
+            None => cache.disable(gl::BLEND)
+        }
+
+        cache.set_capability(gl::DEPTH_TEST, self.depth.test);
+        cache.depth_mask(boolean(self.depth.write));
+        cache.depth_func(self.depth.func.to_gl());
+
+        match self.cull {
+            CullMode::None => cache.disable(gl::CULL_FACE),
+            CullMode::Front | CullMode::Back => {
+                cache.enable(gl::CULL_FACE);
+                cache.cull_face(if self.cull == CullMode::Front { gl::FRONT } else { gl::BACK });
+            }
+        }
+        cache.front_face(match self.front_face {
+            FrontFace::CounterClockwise => gl::CCW,
+            FrontFace::Clockwise => gl::CW
+        });
+
+        match self.stencil {
+            Some(stencil) => {
+                cache.enable(gl::STENCIL_TEST);
+                cache.stencil_func(stencil.func.to_gl(), stencil.reference, stencil.read_mask);
+                cache.stencil_op(stencil.stencil_fail.to_gl(), stencil.depth_fail.to_gl(), stencil.pass.to_gl());
+                cache.stencil_mask(stencil.write_mask);
+            }
+            None => cache.disable(gl::STENCIL_TEST)
+        }
+
+        cache.polygon_mode(self.polygon_mode.to_gl());
+        let mask = self.color_mask;
+        cache.color_mask(boolean(mask[0]), boolean(mask[1]), boolean(mask[2]), boolean(mask[3]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::mock::MockGl;
+
+    #[test]
+    fn presets() {
+        let opaque = PipelineState::opaque();
+        assert!(opaque.blend.is_none() && opaque.depth.write);
+        let alpha = PipelineState::alpha_blended();
+        assert_eq!(alpha.blend, Some(BlendState::alpha()));
+        assert!(alpha.depth.test && !alpha.depth.write);
+        assert_eq!(PipelineState::additive().blend.unwrap().dst, BlendFactor::One);
+        assert_eq!(PipelineState::wireframe().polygon_mode, PolygonMode::Line);
+        assert!(!PipelineState::fullscreen().depth.test);
+        assert_eq!(PipelineState::default(), opaque);
+    }
+
+    #[test]
+    fn presets_reach_gl() {
+        let gl = MockGl::install();
+        let mut cache = StateCache::new();
+        PipelineState::opaque().apply_to(&mut cache);
+        let state = gl.state();
+        assert!(state.capabilities.contains(&gl::DEPTH_TEST));
+        assert!(state.capabilities.contains(&gl::CULL_FACE));
+        assert!(!state.capabilities.contains(&gl::BLEND));
+        assert!(state.depth_mask);
+        assert_eq!((state.cull_face, state.front_face, state.polygon_mode), (gl::BACK, gl::CCW, gl::FILL));
+
+        PipelineState::alpha_blended().apply_to(&mut cache);
+        let state = gl.state();
+        assert!(state.capabilities.contains(&gl::BLEND));
+        assert!(!state.capabilities.contains(&gl::CULL_FACE));
+        assert!(!state.depth_mask);
+        assert_eq!(state.blend_func, (gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA));
+
+        PipelineState::wireframe().color_mask([true, true, true, false]).apply_to(&mut cache);
+        let state = gl.state();
+        assert_eq!(state.polygon_mode, gl::LINE);
+        assert_eq!(state.color_mask, [true, true, true, false]);
+    }
+
+    #[test]
+    fn only_differences_are_issued() {
+        let gl = MockGl::install();
+        let mut cache = StateCache::new();
+        PipelineState::opaque().apply_to(&mut cache);
+        let calls = gl.state().state_calls;
+        PipelineState::opaque().apply_to(&mut cache);
+        assert_eq!(gl.state().state_calls, calls);
+
+        // blend on, its equation and factors, depth writes off, culling off
+        PipelineState::additive().apply_to(&mut cache);
+        assert_eq!(gl.state().state_calls, calls + 5);
+    }
+}
@@ -0,0 +1,564 @@
+use crate::glm::*;
+use crate::render_gl::debug::{self, DebugGroup};
+use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::{BlendState, PipelineState};
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapper {
+    None,
+    Reinhard,
+    Aces,
+    Filmic
+}
+
+impl ToneMapper {
+    // the toneMapper uniform in shaders/post_tonemap.frag
+    pub fn shader_index(&self) -> i32 {
+        match self {
+            ToneMapper::None => 0,
+            ToneMapper::Reinhard => 1,
+            ToneMapper::Aces => 2,
+            ToneMapper::Filmic => 3
+        }
+    }
+
+    // for cycling through them with a key
+    pub fn next(&self) -> ToneMapper {
+        match self {
+            ToneMapper::None => ToneMapper::Reinhard,
+            ToneMapper::Reinhard => ToneMapper::Aces,
+            ToneMapper::Aces => ToneMapper::Filmic,
+            ToneMapper::Filmic => ToneMapper::None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostSettings {
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    // 1.0 leaves the output linear
+    pub gamma: f32,
+    pub bloom: bool,
+    // brightness above which pixels bloom
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+    pub bloom_levels: usize,
+    pub fxaa: bool,
+    pub vignette: bool,
+    pub vignette_strength: f32,
+    pub vignette_radius: f32,
+    // only applies when a LUT is set
+    pub color_grading: bool,
+    pub lut_strength: f32
+}
+
+impl Default for PostSettings {
+    fn default() -> PostSettings {
+        PostSettings {
+            exposure: 1.0,
+            tone_mapper: ToneMapper::Aces,
+            gamma: 2.2,
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.05,
+            bloom_levels: 5,
+            fxaa: true,
+            vignette: false,
+            vignette_strength: 0.5,
+            vignette_radius: 0.75,
+            color_grading: true,
+            lut_strength: 1.0
+        }
+    }
+}
+
+// one full screen pass of the chain. bloom isn't a stage of its own, it is
+// built from the scene beforehand and added in by the tone mapping pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    ToneMap,
+    ColorGrading,
+    Fxaa,
+    Vignette,
+    Custom(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Scene,
+    PingPong(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    PingPong(usize),
+    Output
+}
+
+// a user supplied fragment shader. it gets the previous pass as `image`
+// (unit 0), `texelSize` and `TexCoords` from shaders/fullscreen.vert
+pub struct CustomPass {
+    pub name: String,
+    pub enabled: bool,
+    program: Program
+}
+
+// the order the enabled passes run in. grading, FXAA and the rest work on
+// tone mapped, gamma encoded colours
+pub fn plan(settings: &PostSettings, has_lut: bool, custom: &[CustomPass]) -> Vec<Stage> {
+    let mut stages = vec![Stage::ToneMap];
+    if settings.color_grading && has_lut {
+        stages.push(Stage::ColorGrading);
+    }
+    if settings.fxaa {
+        stages.push(Stage::Fxaa);
+    }
+    if settings.vignette {
+        stages.push(Stage::Vignette);
+    }
+    for (i, pass) in custom.iter().enumerate() {
+        if pass.enabled {
+            stages.push(Stage::Custom(i));
+        }
+    }
+    stages
+}
+
+// where each of `count` stages reads from and renders to. the first reads
+// the scene, the last writes the output and the rest alternate between the
+// two ping-pong targets
+pub fn route(count: usize) -> Vec<(Source, Target)> {
+    (0..count).map(|i| {
+        let source = if i == 0 { Source::Scene } else { Source::PingPong((i - 1) % 2) };
+        let target = if i + 1 == count { Target::Output } else { Target::PingPong(i % 2) };
+        (source, target)
+    }).collect()
+}
+
+// each bloom level is half the size of the one before, starting at half resolution
+pub fn bloom_sizes(width: u32, height: u32, levels: usize) -> Vec<(u32, u32)> {
+    (1..levels as u32 + 1).map(|i| ((width >> i).max(1), (height >> i).max(1))).collect()
+}
+
+// a colour grading lookup table, sampled with the graded colour as coordinates
+pub struct Lut3D {
+    texture: GLuint,
+    size: u32
+}
+
+impl Lut3D {
+    // `data` is size^3 RGB triples with red changing fastest, as in .cube files
+    pub fn new(size: u32, data: &[f32]) -> Result<Lut3D, String> {
+        let expected = (size * size * size * 3) as usize;
+        if data.len() != expected {
+            return Err(format!("LUT of size {} needs {} values, got {}", size, expected, data.len()));
+        }
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            state::bind_texture(gl::TEXTURE_3D, texture);
+            gl::TexImage3D(gl::TEXTURE_3D, 0, gl::RGB16F as GLint, size as GLsizei, size as GLsizei, size as GLsizei,
+                           0, gl::RGB, gl::FLOAT, data.as_ptr() as *const GLvoid);
+            gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+            state::bind_texture(gl::TEXTURE_3D, 0);
+        }
+        debug::label_texture(texture, "color grading lut");
+        Ok(Lut3D { texture: texture, size: size })
+    }
+
+    pub fn identity(size: u32) -> Lut3D {
+        Lut3D::new(size, &identity_lut(size)).unwrap()
+    }
+
+    // an Adobe/Resolve .cube file
+    pub fn load(path: &str) -> Result<Lut3D, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let (size, data) = parse_cube(&source)?;
+        Lut3D::new(size, &data)
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+impl Drop for Lut3D {
+    fn drop(&mut self) {
+        state::delete_texture(self.texture);
+    }
+}
+
+pub fn identity_lut(size: u32) -> Vec<f32> {
+    let max = (size.max(2) - 1) as f32;
+    let mut data = Vec::with_capacity((size * size * size * 3) as usize);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                data.extend_from_slice(&[r as f32 / max, g as f32 / max, b as f32 / max]);
+            }
+        }
+    }
+    data
+}
+
+// returns the LUT_3D_SIZE and the table. only 3D LUTs with the default
+// 0..1 domain are supported
+pub fn parse_cube(source: &str) -> Result<(u32, Vec<f32>), String> {
+    let mut size: Option<u32> = None;
+    let mut data = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap();
+        match first {
+            "LUT_3D_SIZE" => {
+                let value = words.next().ok_or_else(|| format!("line {}: LUT_3D_SIZE without a size", number + 1))?;
+                size = Some(value.parse().map_err(|_| format!("line {}: bad LUT_3D_SIZE {}", number + 1, value))?);
+            }
+            "LUT_1D_SIZE" => return Err(String::from("1D LUTs are not supported")),
+            "TITLE" | "DOMAIN_MIN" | "DOMAIN_MAX" => {}
+            _ => {
+                for word in line.split_whitespace() {
+                    data.push(word.parse::<f32>().map_err(|_| format!("line {}: bad value {}", number + 1, word))?);
+                }
+            }
+        }
+    }
+    let size = size.ok_or_else(|| String::from("missing LUT_3D_SIZE"))?;
+    if data.len() != (size * size * size * 3) as usize {
+        return Err(format!("LUT_3D_SIZE {} needs {} entries, found {}", size, size * size * size, data.len() / 3));
+    }
+    Ok((size, data))
+}
+
+// HDR scene target -> bloom -> tone mapping -> colour grading -> FXAA ->
+// vignette -> custom passes -> output. render the scene between
+// begin_scene and finish
+pub struct PostProcessor {
+    pub settings: PostSettings,
+    scene: Framebuffer,
+    ping_pong: Vec<Framebuffer>,
+    bloom_mips: Vec<Framebuffer>,
+    quad: Mesh,
+    tonemap_program: Program,
+    bloom_down_program: Program,
+    bloom_up_program: Program,
+    lut_program: Program,
+    fxaa_program: Program,
+    vignette_program: Program,
+    lut: Option<Lut3D>,
+    custom: Vec<CustomPass>
+}
+
+impl PostProcessor {
+    pub fn new(width: u32, height: u32, settings: PostSettings) -> Result<PostProcessor, String> {
+        let mut scene = Framebuffer::new(FramebufferDesc::new(width, height)
+            .color(ColorFormat::Rgba16F)
+            .depth_renderbuffer(DepthFormat::Depth24Stencil8))?;
+        scene.set_label("post scene");
+        // the chain after tone mapping is LDR, but float targets keep custom passes flexible
+        let mut ping_pong = vec![
+            Framebuffer::new(FramebufferDesc::new(width, height).color(ColorFormat::Rgba16F))?,
+            Framebuffer::new(FramebufferDesc::new(width, height).color(ColorFormat::Rgba16F))?
+        ];
+        for (i, target) in ping_pong.iter_mut().enumerate() {
+            target.set_label(&format!("post ping-pong {}", i));
+        }
+        let bloom_mips = create_bloom_mips(width, height, settings.bloom_levels)?;
+        Ok(PostProcessor {
+            settings: settings,
+            scene: scene,
+            ping_pong: ping_pong,
+            bloom_mips: bloom_mips,
+            quad: Mesh::quad(),
+            tonemap_program: fullscreen_program("tonemap", include_str!("shaders/post_tonemap.frag"))?,
+            bloom_down_program: fullscreen_program("bloom down", include_str!("shaders/post_bloom_down.frag"))?,
+            bloom_up_program: fullscreen_program("bloom up", include_str!("shaders/post_bloom_up.frag"))?,
+            lut_program: fullscreen_program("lut", include_str!("shaders/post_lut.frag"))?,
+            fxaa_program: fullscreen_program("fxaa", include_str!("shaders/post_fxaa.frag"))?,
+            vignette_program: fullscreen_program("vignette", include_str!("shaders/post_vignette.frag"))?,
+            lut: None,
+            custom: Vec::new()
+        })
+    }
+
+    // the HDR target the scene is drawn into
+    pub fn scene(&self) -> &Framebuffer {
+        &self.scene
+    }
+
+    pub fn begin_scene(&self) {
+        self.scene.begin();
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        if self.scene.resize(width, height)? {
+            for target in self.ping_pong.iter_mut() {
+                target.resize(width, height)?;
+            }
+            self.bloom_mips = create_bloom_mips(width, height, self.settings.bloom_levels)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_lut(&mut self, lut: Option<Lut3D>) {
+        self.lut = lut;
+    }
+
+    // appended after the built in passes, in the order they are added
+    pub fn add_pass(&mut self, name: &str, fragment_source: &str) -> Result<(), String> {
+        let program = fullscreen_program(name, fragment_source)?;
+        self.custom.push(CustomPass { name: name.to_string(), enabled: true, program: program });
+        Ok(())
+    }
+
+    pub fn remove_pass(&mut self, name: &str) {
+        self.custom.retain(|p| p.name != name);
+    }
+
+    // returns false if there is no such pass
+    pub fn set_pass_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.custom.iter_mut().find(|p| p.name == name) {
+            Some(pass) => {
+                pass.enabled = enabled;
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn stages(&self) -> Vec<Stage> {
+        plan(&self.settings, self.lut.is_some(), &self.custom)
+    }
+
+    // runs the chain on the scene target and writes the result into
+    // `output`, or the default framebuffer when None
+    pub fn finish(&mut self, output: Option<&Framebuffer>) {
+        let _group = DebugGroup::new("post-processing");
+        if self.settings.bloom_levels != self.bloom_mips.len() {
+            if let Ok(mips) = create_bloom_mips(self.scene.width(), self.scene.height(), self.settings.bloom_levels) {
+                self.bloom_mips = mips;
+            }
+        }
+        let viewport = state::current_viewport();
+        PipelineState::fullscreen().apply();
+        let bloom = self.settings.bloom && !self.bloom_mips.is_empty();
+        if bloom {
+            self.render_bloom();
+        }
+
+        let stages = self.stages();
+        for (stage, (source, target)) in stages.iter().zip(route(stages.len())) {
+            let source = match source {
+                Source::Scene => self.scene.color_texture(0),
+                Source::PingPong(i) => self.ping_pong[i].color_texture(0)
+            };
+            match target {
+                Target::PingPong(i) => self.ping_pong[i].begin(),
+                Target::Output => match output {
+                    Some(framebuffer) => framebuffer.begin(),
+                    None => unsafe {
+                        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                        state::viewport(0, 0, self.scene.width() as GLsizei, self.scene.height() as GLsizei);
+                    }
+                }
+            }
+            let _stage_group = DebugGroup::new(&match *stage {
+                Stage::Custom(i) => self.custom[i].name.clone(),
+                _ => format!("{:?}", stage)
+            });
+            let program = self.prepare(*stage, bloom);
+            program.set_uniform_1i("image", 0);
+            program.set_uniform_vec2("texelSize", &texel_size(self.scene.width(), self.scene.height()));
+            bind_texture(0, gl::TEXTURE_2D, source);
+            self.quad.draw();
+        }
+
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            state::viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+        }
+        PipelineState::opaque().apply();
+    }
+
+    // uses the program for `stage` and sets everything but the input image
+    fn prepare(&self, stage: Stage, bloom: bool) -> &Program {
+        let s = &self.settings;
+        match stage {
+            Stage::ToneMap => {
+                let p = &self.tonemap_program;
+                p.set_used();
+                p.set_uniform_1f("exposure", s.exposure);
+                p.set_uniform_1f("gamma", s.gamma);
+                p.set_uniform_1i("toneMapper", s.tone_mapper.shader_index());
+                p.set_uniform_1i("useBloom", bloom as i32);
+                p.set_uniform_1f("bloomIntensity", s.bloom_intensity);
+                p.set_uniform_1i("bloom", 1);
+                if bloom {
+                    bind_texture(1, gl::TEXTURE_2D, self.bloom_mips[0].color_texture(0));
+                }
+                p
+            }
+            Stage::ColorGrading => {
+                let p = &self.lut_program;
+                let lut = self.lut.as_ref().unwrap();
+                p.set_used();
+                p.set_uniform_1i("lut", 1);
+                p.set_uniform_1f("lutSize", lut.size() as f32);
+                p.set_uniform_1f("lutStrength", s.lut_strength);
+                bind_texture(1, gl::TEXTURE_3D, lut.texture());
+                p
+            }
+            Stage::Fxaa => {
+                self.fxaa_program.set_used();
+                &self.fxaa_program
+            }
+            Stage::Vignette => {
+                let p = &self.vignette_program;
+                p.set_used();
+                p.set_uniform_1f("vignetteStrength", s.vignette_strength);
+                p.set_uniform_1f("vignetteRadius", s.vignette_radius);
+                p.set_uniform_1f("aspect", self.scene.width() as f32 / self.scene.height() as f32);
+                p
+            }
+            Stage::Custom(i) => {
+                let p = &self.custom[i].program;
+                p.set_used();
+                p
+            }
+        }
+    }
+
+    // progressively downsamples the bright parts of the scene, then blurs
+    // back up, accumulating every level into the first mip
+    fn render_bloom(&self) {
+        let _group = DebugGroup::new("bloom");
+        let down = &self.bloom_down_program;
+        down.set_used();
+        down.set_uniform_1i("image", 0);
+        down.set_uniform_1f("threshold", self.settings.bloom_threshold);
+        let mut source = &self.scene;
+        for (i, mip) in self.bloom_mips.iter().enumerate() {
+            mip.begin();
+            down.set_uniform_1i("prefilter", (i == 0) as i32);
+            down.set_uniform_vec2("texelSize", &texel_size(source.width(), source.height()));
+            bind_texture(0, gl::TEXTURE_2D, source.color_texture(0));
+            self.quad.draw();
+            source = mip;
+        }
+
+        let up = &self.bloom_up_program;
+        up.set_used();
+        up.set_uniform_1i("image", 0);
+        PipelineState::fullscreen().blend(Some(BlendState::additive())).apply();
+        for i in (1..self.bloom_mips.len()).rev() {
+            let source = &self.bloom_mips[i];
+            let target = &self.bloom_mips[i - 1];
+            target.bind();
+            state::viewport(0, 0, target.width() as GLsizei, target.height() as GLsizei);
+            up.set_uniform_1f("filterRadius", 1.0 / source.width() as f32);
+            bind_texture(0, gl::TEXTURE_2D, source.color_texture(0));
+            self.quad.draw();
+        }
+        PipelineState::fullscreen().apply();
+    }
+}
+
+fn texel_size(width: u32, height: u32) -> Vec2 {
+    make_vec2(&[1.0 / width as f32, 1.0 / height as f32])
+}
+
+fn fullscreen_program(name: &str, fragment_source: &str) -> Result<Program, String> {
+    let program = Program::from_shaders(&[
+        Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/fullscreen.vert"))?)?,
+        Shader::from_frag_source(&shader_lib::preprocess(fragment_source)?)?
+    ])?;
+    program.set_label(&format!("post {}", name));
+    Ok(program)
+}
+
+fn create_bloom_mips(width: u32, height: u32, levels: usize) -> Result<Vec<Framebuffer>, String> {
+    let mut mips = Vec::with_capacity(levels);
+    for (w, h) in bloom_sizes(width, height, levels) {
+        let mut mip = Framebuffer::new(FramebufferDesc::new(w, h).color(ColorFormat::R11G11B10F))?;
+        mip.set_label(&format!("bloom mip {}", mips.len()));
+        mips.push(mip);
+    }
+    Ok(mips)
+}
+
+fn bind_texture(unit: u32, target: GLenum, texture: GLuint) {
+    state::active_texture(gl::TEXTURE0 + unit);
+    state::bind_texture(target, texture);
+    state::active_texture(gl::TEXTURE0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_passes_are_skipped() {
+        let mut settings = PostSettings::default();
+        assert_eq!(plan(&settings, false, &[]), vec![Stage::ToneMap, Stage::Fxaa]);
+        assert_eq!(plan(&settings, true, &[]), vec![Stage::ToneMap, Stage::ColorGrading, Stage::Fxaa]);
+        settings.fxaa = false;
+        settings.vignette = true;
+        assert_eq!(plan(&settings, false, &[]), vec![Stage::ToneMap, Stage::Vignette]);
+    }
+
+    #[test]
+    fn passes_ping_pong_between_targets() {
+        assert_eq!(route(1), vec![(Source::Scene, Target::Output)]);
+        assert_eq!(route(4), vec![
+            (Source::Scene, Target::PingPong(0)),
+            (Source::PingPong(0), Target::PingPong(1)),
+            (Source::PingPong(1), Target::PingPong(0)),
+            (Source::PingPong(0), Target::Output)
+        ]);
+    }
+
+    #[test]
+    fn bloom_levels_halve() {
+        assert_eq!(bloom_sizes(800, 600, 3), vec![(400, 300), (200, 150), (100, 75)]);
+        assert_eq!(bloom_sizes(4, 2, 3), vec![(2, 1), (1, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn tone_mappers_cycle() {
+        let mut mapper = ToneMapper::None;
+        for _ in 0..4 {
+            mapper = mapper.next();
+        }
+        assert_eq!(mapper, ToneMapper::None);
+    }
+
+    #[test]
+    fn parses_cube_luts() {
+        let source = "# identity\nTITLE \"id\"\nLUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\n\
+                      0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+        let (size, data) = parse_cube(source).unwrap();
+        assert_eq!(size, 2);
+        assert_eq!(data, identity_lut(2));
+        assert!(parse_cube("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(parse_cube("LUT_1D_SIZE 16\n").is_err());
+        assert!(parse_cube("0 0 0\n").is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use gl::types::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+// samples kept per scope for the rolling averages, two seconds at 60 fps
+pub const DEFAULT_WINDOW: usize = 120;
+
+// a fixed window of recent samples in milliseconds
+#[derive(Clone, Debug)]
+pub struct Rolling {
+    samples: Vec<f32>,
+    next: usize,
+    capacity: usize
+}
+
+impl Rolling {
+    pub fn new(capacity: usize) -> Rolling {
+        Rolling { samples: Vec::with_capacity(capacity), next: 0, capacity: capacity.max(1) }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn average(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().sum::<f32>() / self.samples.len() as f32
+    }
+
+    pub fn min(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().cloned().fold(f32::INFINITY, f32::min)
+    }
+
+    pub fn max(&self) -> f32 {
+        self.samples.iter().cloned().fold(0.0, f32::max)
+    }
+}
+
+// frame times in fixed size buckets, the last one collecting everything slower
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bucket_ms: f32,
+    counts: Vec<u32>,
+    total: u32
+}
+
+impl Histogram {
+    pub fn new(bucket_ms: f32, buckets: usize) -> Histogram {
+        Histogram { bucket_ms: bucket_ms, counts: vec![0; buckets.max(1)], total: 0 }
+    }
+
+    pub fn record(&mut self, ms: f32) {
+        let last = self.counts.len() - 1;
+        let bucket = ((ms.max(0.0) / self.bucket_ms) as usize).min(last);
+        self.counts[bucket] += 1;
+        self.total += 1;
+    }
+
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn bucket_ms(&self) -> f32 {
+        self.bucket_ms
+    }
+
+    // upper edge of the bucket the p-th percentile (0..1) falls in
+    pub fn percentile(&self, p: f32) -> f32 {
+        let wanted = (p.clamp(0.0, 1.0) * self.total as f32).ceil().max(1.0) as u32;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return (i + 1) as f32 * self.bucket_ms;
+            }
+        }
+        self.counts.len() as f32 * self.bucket_ms
+    }
+
+    // one line per non empty bucket, e.g "16-17 ms  ####### 42"
+    pub fn to_text(&self, width: usize) -> String {
+        let largest = self.counts.iter().cloned().max().unwrap_or(0).max(1);
+        let mut out = String::new();
+        let last = self.counts.len() - 1;
+        for (i, count) in self.counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let low = i as f32 * self.bucket_ms;
+            let range = if i == last { format!("{}+ ms", low) } else { format!("{}-{} ms", low, low + self.bucket_ms) };
+            let bar = "#".repeat((*count as usize * width).div_ceil(largest as usize));
+            out.push_str(&format!("{:>12}  {} {}\n", range, bar, count));
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScopeStats {
+    pub cpu: Rolling,
+    // empty for CPU only scopes
+    pub gpu: Rolling
+}
+
+// one complete event in the Chrome trace event format, times in microseconds.
+// Source: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: String,
+    pub ph: String,
+    pub ts: f64,
+    pub dur: f64,
+    pub pid: u32,
+    pub tid: u32
+}
+
+// the CPU timeline and the GPU one show up as two threads
+const CPU_TRACK: u32 = 1;
+const GPU_TRACK: u32 = 2;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceFile<'a> {
+    trace_events: &'a [TraceEvent],
+    display_time_unit: &'a str
+}
+
+// loads in chrome://tracing, Perfetto or speedscope
+pub fn chrome_trace_json(events: &[TraceEvent]) -> Result<String, String> {
+    serde_json::to_string(&TraceFile { trace_events: events, display_time_unit: "ms" }).map_err(|e| e.to_string())
+}
+
+struct Scope {
+    // names of the enclosing scopes and this one, joined by '/'
+    path: String,
+    name: String,
+    start_us: f64,
+    duration_us: f64,
+    gpu_query: Option<usize>
+}
+
+// GL_TIME_ELAPSED queries reused every other frame. results are read a
+// frame late, when they are almost always ready, so reading never stalls
+struct QueryPool {
+    queries: Vec<GLuint>,
+    used: usize
+}
+
+impl QueryPool {
+    fn new() -> QueryPool {
+        QueryPool { queries: Vec::new(), used: 0 }
+    }
+
+    fn acquire(&mut self) -> usize {
+        if self.used == self.queries.len() {
+            let mut query = 0;
+            unsafe {
+                gl::GenQueries(1, &mut query);
+            }
+            self.queries.push(query);
+        }
+        self.used += 1;
+        self.used - 1
+    }
+
+    // nanoseconds, or None if the GPU isn't done yet
+    fn result(&self, slot: usize) -> Option<u64> {
+        let query = self.queries[slot];
+        let mut available: GLint = 0;
+        unsafe {
+            gl::GetQueryObjectiv(query, gl::QUERY_RESULT_AVAILABLE, &mut available);
+        }
+        if available == 0 {
+            return None;
+        }
+        let mut elapsed: GLuint64 = 0;
+        unsafe {
+            gl::GetQueryObjectui64v(query, gl::QUERY_RESULT, &mut elapsed);
+        }
+        Some(elapsed)
+    }
+}
+
+impl Drop for QueryPool {
+    fn drop(&mut self) {
+        if !self.queries.is_empty() {
+            unsafe {
+                gl::DeleteQueries(self.queries.len() as GLsizei, self.queries.as_ptr());
+            }
+        }
+    }
+}
+
+// times nested CPU scopes, and GPU scopes through timer queries, per frame:
+//
+// profiler.begin_frame();
+// profiler.begin_gpu("shadows");
+// ...
+// profiler.end();
+// profiler.end_frame();
+//
+// GL can only run one GL_TIME_ELAPSED query at a time, so a GPU scope
+// opened inside another one is only timed on the CPU. GPU results arrive
+// one frame late
+pub struct Profiler {
+    epoch: Instant,
+    frame: u64,
+    frame_start_us: f64,
+    scopes: Vec<Scope>,
+    open: Vec<usize>,
+    gpu_open: bool,
+    gpu_enabled: bool,
+    pools: [QueryPool; 2],
+    // last frame's scopes, waiting for their GPU results
+    in_flight: Vec<Scope>,
+    stats: BTreeMap<String, ScopeStats>,
+    // values counted this frame, and their averages over past frames
+    frame_counters: BTreeMap<String, f32>,
+    counters: BTreeMap<String, Rolling>,
+    frame_times: Rolling,
+    histogram: Histogram,
+    window: usize,
+    trace: Option<Vec<TraceEvent>>
+}
+
+impl Profiler {
+    // `gpu` needs a current context; without it GPU scopes are CPU only
+    pub fn new(gpu: bool) -> Profiler {
+        Profiler {
+            epoch: Instant::now(),
+            frame: 0,
+            frame_start_us: 0.0,
+            scopes: Vec::new(),
+            open: Vec::new(),
+            gpu_open: false,
+            gpu_enabled: gpu,
+            pools: [QueryPool::new(), QueryPool::new()],
+            in_flight: Vec::new(),
+            stats: BTreeMap::new(),
+            frame_counters: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            frame_times: Rolling::new(DEFAULT_WINDOW),
+            histogram: Histogram::new(1.0, 50),
+            window: DEFAULT_WINDOW,
+            trace: None
+        }
+    }
+
+    fn now_us(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64() * 1e6
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.scopes.clear();
+        self.open.clear();
+        self.pools[(self.frame % 2) as usize].used = 0;
+        self.frame_start_us = self.now_us();
+    }
+
+    pub fn begin(&mut self, name: &str) {
+        self.open_scope(name, false);
+    }
+
+    // a scope timed on both the CPU and the GPU
+    pub fn begin_gpu(&mut self, name: &str) {
+        self.open_scope(name, true);
+    }
+
+    fn open_scope(&mut self, name: &str, gpu: bool) {
+        let path = match self.open.last() {
+            Some(parent) => format!("{}/{}", self.scopes[*parent].path, name),
+            None => name.to_string()
+        };
+        let gpu_query = if gpu && self.gpu_enabled && !self.gpu_open {
+            let pool = &mut self.pools[(self.frame % 2) as usize];
+            let slot = pool.acquire();
+            unsafe {
+                gl::BeginQuery(gl::TIME_ELAPSED, pool.queries[slot]);
+            }
+            self.gpu_open = true;
+            Some(slot)
+        } else {
+            None
+        };
+        self.open.push(self.scopes.len());
+        self.scopes.push(Scope {
+            path: path,
+            name: name.to_string(),
+            start_us: self.now_us(),
+            duration_us: 0.0,
+            gpu_query: gpu_query
+        });
+    }
+
+    pub fn end(&mut self) {
+        let index = match self.open.pop() {
+            Some(index) => index,
+            None => return
+        };
+        let now = self.now_us();
+        let scope = &mut self.scopes[index];
+        scope.duration_us = now - scope.start_us;
+        if scope.gpu_query.is_some() {
+            unsafe {
+                gl::EndQuery(gl::TIME_ELAPSED);
+            }
+            self.gpu_open = false;
+        }
+    }
+
+    // adds `value` to this frame's count of `name`, e.g objects drawn or
+    // culled. frames that don't count it leave its average alone
+    pub fn count(&mut self, name: &str, value: f32) {
+        *self.frame_counters.entry(name.to_string()).or_insert(0.0) += value;
+    }
+
+    // runs `f` inside a CPU scope
+    pub fn scope<R>(&mut self, name: &str, f: impl FnOnce(&mut Profiler) -> R) -> R {
+        self.begin(name);
+        let result = f(self);
+        self.end();
+        result
+    }
+
+    pub fn end_frame(&mut self) {
+        while !self.open.is_empty() {
+            self.end();
+        }
+        let now = self.now_us();
+        let frame_ms = ((now - self.frame_start_us) / 1000.0) as f32;
+        self.frame_times.push(frame_ms);
+        self.histogram.record(frame_ms);
+
+        let window = self.window;
+        for scope in &self.scopes {
+            self.stats.entry(scope.path.clone())
+                .or_insert_with(|| ScopeStats { cpu: Rolling::new(window), gpu: Rolling::new(window) })
+                .cpu.push((scope.duration_us / 1000.0) as f32);
+        }
+        for (name, value) in std::mem::take(&mut self.frame_counters) {
+            self.counters.entry(name).or_insert_with(|| Rolling::new(window)).push(value);
+        }
+        if let Some(ref mut trace) = self.trace {
+            trace.push(TraceEvent {
+                name: format!("frame {}", self.frame),
+                cat: "frame".to_string(),
+                ph: "X".to_string(),
+                ts: self.frame_start_us,
+                dur: now - self.frame_start_us,
+                pid: 1,
+                tid: CPU_TRACK
+            });
+            for scope in &self.scopes {
+                trace.push(TraceEvent {
+                    name: scope.name.clone(),
+                    cat: "cpu".to_string(),
+                    ph: "X".to_string(),
+                    ts: scope.start_us,
+                    dur: scope.duration_us,
+                    pid: 1,
+                    tid: CPU_TRACK
+                });
+            }
+        }
+
+        self.collect_gpu();
+        self.in_flight = std::mem::take(&mut self.scopes);
+        self.frame += 1;
+    }
+
+    // reads the previous frame's queries. ones still running are dropped
+    // rather than waited for
+    fn collect_gpu(&mut self) {
+        let pool = &self.pools[((self.frame + 1) % 2) as usize];
+        for scope in &self.in_flight {
+            let slot = match scope.gpu_query {
+                Some(slot) => slot,
+                None => continue
+            };
+            let nanoseconds = match pool.result(slot) {
+                Some(ns) => ns,
+                None => continue
+            };
+            let ms = nanoseconds as f32 / 1e6;
+            if let Some(stats) = self.stats.get_mut(&scope.path) {
+                stats.gpu.push(ms);
+            }
+            // the GPU runs behind the CPU, so its scopes are drawn starting
+            // where the CPU recorded them
+            if let Some(ref mut trace) = self.trace {
+                trace.push(TraceEvent {
+                    name: scope.name.clone(),
+                    cat: "gpu".to_string(),
+                    ph: "X".to_string(),
+                    ts: scope.start_us,
+                    dur: nanoseconds as f64 / 1000.0,
+                    pid: 1,
+                    tid: GPU_TRACK
+                });
+            }
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // scopes by path, e.g "render/shadows"
+    pub fn stats(&self) -> &BTreeMap<String, ScopeStats> {
+        &self.stats
+    }
+
+    pub fn counters(&self) -> &BTreeMap<String, Rolling> {
+        &self.counters
+    }
+
+    pub fn frame_times(&self) -> &Rolling {
+        &self.frame_times
+    }
+
+    pub fn histogram(&self) -> &Histogram {
+        &self.histogram
+    }
+
+    pub fn start_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    // the events recorded since start_trace, see chrome_trace_json
+    pub fn stop_trace(&mut self) -> Vec<TraceEvent> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    // averages per scope, indented by nesting, and the frame time percentiles
+    pub fn summary(&self) -> String {
+        let mut out = format!("frame {:.2} ms avg, {:.2} max, p50 < {} ms, p99 < {} ms\n",
+                              self.frame_times.average(), self.frame_times.max(),
+                              self.histogram.percentile(0.5), self.histogram.percentile(0.99));
+        for (path, stats) in &self.stats {
+            let depth = path.matches('/').count();
+            let name = path.rsplit('/').next().unwrap_or(path);
+            out.push_str(&format!("{}{:<24} cpu {:7.3} ms", "  ".repeat(depth + 1), name, stats.cpu.average()));
+            if !stats.gpu.is_empty() {
+                out.push_str(&format!("  gpu {:7.3} ms", stats.gpu.average()));
+            }
+            out.push('\n');
+        }
+        for (name, counter) in &self.counters {
+            out.push_str(&format!("  {:<24} {:9.1} avg\n", name, counter.average()));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_window_forgets_old_samples() {
+        let mut rolling = Rolling::new(3);
+        assert_eq!(rolling.average(), 0.0);
+        for sample in [1.0, 2.0, 3.0, 10.0].iter() {
+            rolling.push(*sample);
+        }
+        assert_eq!(rolling.len(), 3);
+        assert_eq!(rolling.average(), 5.0);
+        assert_eq!(rolling.min(), 2.0);
+        assert_eq!(rolling.max(), 10.0);
+    }
+
+    #[test]
+    fn histogram_buckets_and_percentiles() {
+        let mut histogram = Histogram::new(1.0, 4);
+        for ms in [0.5, 1.5, 1.7, 2.2, 40.0].iter() {
+            histogram.record(*ms);
+        }
+        assert_eq!(histogram.counts(), &[1, 2, 1, 1]);
+        assert_eq!(histogram.total(), 5);
+        assert_eq!(histogram.percentile(0.5), 2.0);
+        assert_eq!(histogram.percentile(1.0), 4.0);
+        assert!(histogram.to_text(10).contains("3+ ms"));
+    }
+
+    #[test]
+    fn nested_cpu_scopes_are_named_by_path() {
+        let mut profiler = Profiler::new(false);
+        profiler.start_trace();
+        for _ in 0..2 {
+            profiler.begin_frame();
+            profiler.scope("render", |p| {
+                p.begin_gpu("shadows");
+                p.end();
+                p.scope("post", |_| ());
+            });
+            // left open, closed by end_frame
+            profiler.begin("swap");
+            profiler.end_frame();
+        }
+        let paths: Vec<&String> = profiler.stats().keys().collect();
+        assert_eq!(paths, vec!["render", "render/post", "render/shadows", "swap"]);
+        assert_eq!(profiler.stats()["render"].cpu.len(), 2);
+        assert!(profiler.stats()["render/shadows"].gpu.is_empty());
+        assert_eq!(profiler.frame_times().len(), 2);
+        assert!(profiler.summary().contains("    shadows"));
+        assert!(profiler.counters().is_empty());
+
+        let events = profiler.stop_trace();
+        assert_eq!(events.len(), 10);
+        let render = events.iter().find(|e| e.name == "render").unwrap();
+        let post = events.iter().find(|e| e.name == "post").unwrap();
+        assert!(post.ts >= render.ts && post.ts + post.dur <= render.ts + render.dur);
+        assert!(!profiler.is_tracing());
+    }
+
+    #[test]
+    fn counters_are_summed_per_frame() {
+        let mut profiler = Profiler::new(false);
+        profiler.begin_frame();
+        profiler.count("drawn", 3.0);
+        profiler.count("drawn", 2.0);
+        profiler.end_frame();
+        profiler.begin_frame();
+        profiler.count("drawn", 1.0);
+        profiler.count("culled", 4.0);
+        profiler.end_frame();
+        assert_eq!(profiler.counters()["drawn"].average(), 3.0);
+        assert_eq!(profiler.counters()["culled"].len(), 1);
+        assert!(profiler.summary().contains("culled"));
+    }
+
+    #[test]
+    fn gpu_scopes_are_read_a_frame_late() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let _context = match crate::render_gl::headless::HeadlessContext::for_test("gpu timing") {
+            Some(context) => context,
+            None => return
+        };
+        let mut profiler = Profiler::new(true);
+        for frame in 0..3 {
+            profiler.begin_frame();
+            profiler.begin_gpu("clear");
+            // nested GPU scopes are only timed on the CPU
+            profiler.begin_gpu("inner");
+            unsafe {
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+            }
+            profiler.end();
+            profiler.end();
+            unsafe {
+                gl::Finish();
+            }
+            profiler.end_frame();
+            assert_eq!(profiler.stats()["clear"].gpu.len(), frame);
+        }
+        assert!(profiler.stats()["clear/inner"].gpu.is_empty());
+    }
+
+    #[test]
+    fn trace_json_uses_the_chrome_field_names() {
+        let events = vec![TraceEvent {
+            name: "shadows".to_string(),
+            cat: "gpu".to_string(),
+            ph: "X".to_string(),
+            ts: 10.0,
+            dur: 2.5,
+            pid: 1,
+            tid: GPU_TRACK
+        }];
+        let json: serde_json::Value = serde_json::from_str(&chrome_trace_json(&events).unwrap()).unwrap();
+        assert_eq!(json["displayTimeUnit"], "ms");
+        assert_eq!(json["traceEvents"][0]["ph"], "X");
+        assert_eq!(json["traceEvents"][0]["dur"], 2.5);
+        assert_eq!(json["traceEvents"][0]["tid"], 2);
+    }
+}
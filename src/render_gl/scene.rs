@@ -0,0 +1,563 @@
+use crate::glm::*;
+use crate::render_gl::bvh::{Bvh, CullStats, ProxyId};
+use crate::render_gl::camera::Camera;
+use crate::render_gl::frustum::{Aabb, Frustum};
+use crate::render_gl::instancing::{InstanceBatcher, InstanceData};
+use crate::render_gl::light::{DirectionalLight, LightSet, PointLight, SpotLight};
+use crate::render_gl::material::Material;
+use crate::render_gl::mesh::Mesh;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+// a transform relative to the parent node: scale first, then rotation,
+// then translation, the order glTF uses
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Qua<f32>,
+    pub scale: Vec3
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: make_vec3(&[0.0, 0.0, 0.0]),
+            rotation: quat_identity(),
+            scale: make_vec3(&[1.0, 1.0, 1.0])
+        }
+    }
+
+    pub fn from_translation(translation: &Vec3) -> Transform {
+        Transform::identity().translated(translation)
+    }
+
+    // splits a matrix without shear or projection back into its parts.
+    // negative scales come back as a rotation with positive scale when
+    // an even number of axes are flipped, the result is the same matrix
+    pub fn from_matrix(m: &Mat4) -> Transform {
+        let column = |i: usize| make_vec3(&[m[(0, i)], m[(1, i)], m[(2, i)]]);
+        let mut scale = make_vec3(&[length(&column(0)), length(&column(1)), length(&column(2))]);
+        if determinant(&mat4_to_mat3(m)) < 0.0 {
+            scale.x = -scale.x;
+        }
+        let rotation = Mat3::from_columns(&[column(0) / scale.x, column(1) / scale.y, column(2) / scale.z]);
+        Transform {
+            translation: column(3),
+            rotation: quat_normalize(&mat3_to_quat(&rotation)),
+            scale: scale
+        }
+    }
+
+    pub fn translated(mut self, translation: &Vec3) -> Transform {
+        self.translation = *translation;
+        self
+    }
+
+    pub fn rotated(mut self, rotation: &Qua<f32>) -> Transform {
+        self.rotation = quat_normalize(rotation);
+        self
+    }
+
+    // angle in degrees
+    pub fn rotated_about(self, axis: &Vec3, angle: f32) -> Transform {
+        self.rotated(&quat_angle_axis(angle.to_radians(), &normalize(axis)))
+    }
+
+    pub fn scaled(mut self, scale: &Vec3) -> Transform {
+        self.scale = *scale;
+        self
+    }
+
+    pub fn uniformly_scaled(self, scale: f32) -> Transform {
+        self.scaled(&make_vec3(&[scale, scale, scale]))
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        let rotation_scale = quat_to_mat4(&self.rotation) * scaling(&self.scale);
+        translation(&self.translation) * rotation_scale
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+// lights attached to a node are given in the node's space. a point light
+// at the origin sits wherever the node is, and directions turn with it
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeLight {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight)
+}
+
+// the projection of a camera node. it looks down the node's -z axis with
+// +y up, like a glTF camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeCamera {
+    // vertical field of view in degrees
+    pub fov: f32,
+    pub near: f32,
+    pub far: f32
+}
+
+pub struct Node {
+    pub name: String,
+    pub mesh: Option<Rc<Mesh>>,
+    pub material: Option<Rc<Material>>,
+    pub light: Option<NodeLight>,
+    pub camera: Option<NodeCamera>,
+    // the mesh is solid and fills its bounds, like a wall or a crate, so
+    // it can hide other nodes from the CPU occlusion test. see HiZBuffer
+    pub occluder: bool,
+    transform: Transform,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    // world matrix, valid while dirty is false. a dirty node's children
+    // are always dirty too, so a clean node's ancestors are all clean
+    world: Cell<Mat4>,
+    dirty: Cell<bool>
+}
+
+impl Node {
+    fn new(name: &str, transform: Transform, parent: Option<NodeId>) -> Node {
+        Node {
+            name: name.to_string(),
+            mesh: None,
+            material: None,
+            light: None,
+            camera: None,
+            occluder: false,
+            transform: transform,
+            parent: parent,
+            children: Vec::new(),
+            world: Cell::new(Mat4::identity()),
+            dirty: Cell::new(true)
+        }
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+}
+
+// a hierarchy of nodes. transforms only change through the scene so it
+// can mark the world matrices below them as stale; they are recomputed
+// the next time they are asked for. removed nodes leave a hole, so ids of
+// the others stay valid
+pub struct Scene {
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene { nodes: Vec::new(), roots: Vec::new() }
+    }
+
+    pub fn add(&mut self, name: &str, transform: Transform) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Node::new(name, transform, None)));
+        self.roots.push(id);
+        id
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, name: &str, transform: Transform) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Node::new(name, transform, Some(parent))));
+        self.node_mut(parent).children.push(id);
+        id
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        matches!(self.nodes.get(id.0), Some(Some(_)))
+    }
+
+    // panics on ids of removed nodes, like indexing a Vec out of bounds
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0].as_ref().expect("node was removed from the scene")
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0].as_mut().expect("node was removed from the scene")
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.iter().filter(|n| n.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    // the first node with this name, depth first
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        let mut found = None;
+        self.traverse(&mut |id, node, _| {
+            if found.is_none() && node.name == name {
+                found = Some(id);
+            }
+        });
+        found
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) {
+        self.node_mut(id).transform = transform;
+        self.invalidate(id);
+    }
+
+    // moves `id` under `parent`, or to the top level with None. it keeps
+    // its local transform, so it moves along with its new parent
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> Result<(), String> {
+        if let Some(parent) = parent {
+            let mut ancestor = Some(parent);
+            while let Some(a) = ancestor {
+                if a == id {
+                    return Err(format!("node '{}' can't become a child of itself or its descendants", self.node(id).name));
+                }
+                ancestor = self.node(a).parent;
+            }
+        }
+        match self.node(id).parent {
+            Some(old) => self.node_mut(old).children.retain(|c| *c != id),
+            None => self.roots.retain(|r| *r != id)
+        }
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id)
+        }
+        self.node_mut(id).parent = parent;
+        self.invalidate(id);
+        Ok(())
+    }
+
+    // removes the node and everything below it
+    pub fn remove(&mut self, id: NodeId) {
+        match self.node(id).parent {
+            Some(parent) => self.node_mut(parent).children.retain(|c| *c != id),
+            None => self.roots.retain(|r| *r != id)
+        }
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            if let Some(node) = self.nodes[id.0].take() {
+                pending.extend(node.children);
+            }
+        }
+    }
+
+    fn invalidate(&self, id: NodeId) {
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            let node = self.node(id);
+            // anything below an already dirty node is dirty as well
+            if !node.dirty.replace(true) {
+                pending.extend_from_slice(&node.children);
+            }
+        }
+    }
+
+    pub fn world_matrix(&self, id: NodeId) -> Mat4 {
+        let node = self.node(id);
+        if node.dirty.get() {
+            let local = node.transform.matrix();
+            let world = match node.parent {
+                Some(parent) => self.world_matrix(parent) * local,
+                None => local
+            };
+            node.world.set(world);
+            node.dirty.set(false);
+        }
+        node.world.get()
+    }
+
+    pub fn world_position(&self, id: NodeId) -> Vec3 {
+        let world = self.world_matrix(id);
+        make_vec3(&[world[(0, 3)], world[(1, 3)], world[(2, 3)]])
+    }
+
+    // the node's mesh bounds in world space, None without a mesh
+    pub fn world_bounds(&self, id: NodeId) -> Option<Aabb> {
+        let mesh = self.node(id).mesh.as_ref()?;
+        Some(mesh.bounds().transformed(&self.world_matrix(id)))
+    }
+
+    // visits every node depth first, parents before their children, with
+    // its world matrix
+    pub fn traverse<'a>(&'a self, visit: &mut dyn FnMut(NodeId, &'a Node, &Mat4)) {
+        let mut pending: Vec<NodeId> = self.roots.iter().rev().cloned().collect();
+        while let Some(id) = pending.pop() {
+            let node = self.node(id);
+            visit(id, node, &self.world_matrix(id));
+            pending.extend(node.children.iter().rev());
+        }
+    }
+
+    // queues every node that has both a mesh and a material. nodes sharing
+    // them are drawn together, so shared meshes and materials should be the
+    // same Rc rather than copies
+    pub fn submit<'a>(&'a self, batcher: &mut InstanceBatcher<'a, Material>) {
+        self.traverse(&mut |_, node, world| {
+            if let (Some(mesh), Some(material)) = (&node.mesh, &node.material) {
+                batcher.push(mesh, material, InstanceData::new(world));
+            }
+        });
+    }
+
+    // the lights of all nodes, moved into world space
+    pub fn lights(&self) -> LightSet {
+        let mut lights = LightSet::new();
+        self.traverse(&mut |_, node, world| {
+            let point = |p: &Vec3| (world * make_vec4(&[p.x, p.y, p.z, 1.0])).xyz();
+            let direction = |d: &Vec3| normalize(&(world * make_vec4(&[d.x, d.y, d.z, 0.0])).xyz());
+            match &node.light {
+                Some(NodeLight::Directional(light)) => {
+                    let mut light = light.clone();
+                    light.direction = direction(&light.direction);
+                    lights.directional.push(light);
+                }
+                Some(NodeLight::Point(light)) => {
+                    let mut light = light.clone();
+                    light.position = point(&light.position);
+                    lights.point.push(light);
+                }
+                Some(NodeLight::Spot(light)) => {
+                    let mut light = light.clone();
+                    light.position = point(&light.position);
+                    light.direction = direction(&light.direction);
+                    lights.spot.push(light);
+                }
+                None => {}
+            }
+        });
+        lights
+    }
+
+    // points `camera` the way the camera node looks, keeping its aspect
+    pub fn apply_camera(&self, id: NodeId, camera: &mut Camera) -> Result<(), String> {
+        let node = self.node(id);
+        let projection = node.camera.ok_or_else(|| format!("node '{}' has no camera", node.name))?;
+        let world = self.world_matrix(id);
+        let axis = |v: [f32; 4]| normalize(&(world * make_vec4(&v)).xyz());
+        camera.set_pose(&self.world_position(id), &axis([0.0, 0.0, -1.0, 0.0]), &axis([0.0, 1.0, 0.0, 0.0]));
+        let aspect = camera.aspect();
+        camera.set_perspective(projection.fov, aspect, projection.near, projection.far);
+        Ok(())
+    }
+}
+
+// keeps a Bvh of the scene's mesh nodes in step with the scene. sync()
+// after moving things: nodes that stayed put cost a box comparison, moved
+// ones are updated in the tree, added and removed ones are inserted and
+// dropped
+pub struct SceneCuller {
+    bvh: Bvh<NodeId>,
+    proxies: BTreeMap<NodeId, ProxyId>,
+    stats: CullStats
+}
+
+impl SceneCuller {
+    // inserting one by one and then sorting the whole tree gives a tree
+    // as good as Bvh::build while keeping track of the proxies. nodes can
+    // move, so they keep the enlarged boxes insert gives them
+    pub fn new(scene: &Scene) -> SceneCuller {
+        let mut culler = SceneCuller { bvh: Bvh::new(), proxies: BTreeMap::new(), stats: CullStats::default() };
+        culler.sync(scene);
+        culler.bvh.rebuild();
+        culler
+    }
+
+    pub fn sync(&mut self, scene: &Scene) {
+        let mut seen = BTreeSet::new();
+        let bvh = &mut self.bvh;
+        let proxies = &mut self.proxies;
+        scene.traverse(&mut |id, _, _| {
+            let aabb = match scene.world_bounds(id) {
+                Some(aabb) => aabb,
+                None => return
+            };
+            seen.insert(id);
+            match proxies.get(&id) {
+                Some(proxy) => {
+                    if bvh.aabb(*proxy) != Some(&aabb) {
+                        bvh.update(*proxy, &aabb);
+                    }
+                }
+                None => {
+                    proxies.insert(id, bvh.insert(&aabb, id));
+                }
+            }
+        });
+        let gone: Vec<NodeId> = proxies.keys().filter(|id| !seen.contains(id)).cloned().collect();
+        for id in gone {
+            bvh.remove(proxies.remove(&id).unwrap());
+        }
+    }
+
+    // the mesh nodes at least partly inside `frustum`
+    pub fn cull(&mut self, frustum: &Frustum) -> BTreeSet<NodeId> {
+        let mut visible = BTreeSet::new();
+        self.stats = self.bvh.cull(frustum, &mut |_, id| {
+            visible.insert(*id);
+        });
+        visible
+    }
+
+    // the counters of the last cull()
+    pub fn stats(&self) -> CullStats {
+        self.stats
+    }
+
+    pub fn bvh(&self) -> &Bvh<NodeId> {
+        &self.bvh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::mock::MockGl;
+
+    fn close(a: &Mat4, b: &Mat4) -> bool {
+        (a - b).iter().all(|d| d.abs() < 1e-5)
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> Transform {
+        Transform::from_translation(&make_vec3(&[x, y, z]))
+    }
+
+    #[test]
+    fn transforms_scale_then_rotate_then_translate() {
+        let t = at(1.0, 2.0, 3.0).rotated_about(&make_vec3(&[0.0, 1.0, 0.0]), 90.0).uniformly_scaled(2.0);
+        let p = t.matrix() * make_vec4(&[1.0, 0.0, 0.0, 1.0]);
+        // scaled to 2 along x, turned onto -z, then moved. the scale must
+        // not touch the translation
+        assert!(distance(&p.xyz(), &make_vec3(&[1.0, 2.0, 1.0])) < 1e-5, "{:?}", p);
+        assert!(close(&Transform::from_matrix(&t.matrix()).matrix(), &t.matrix()));
+
+        let flipped = at(0.0, 1.0, 0.0).scaled(&make_vec3(&[-1.0, 2.0, 3.0]));
+        assert!(close(&Transform::from_matrix(&flipped.matrix()).matrix(), &flipped.matrix()));
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let mut scene = Scene::new();
+        let table = scene.add("table", at(5.0, 0.0, 0.0));
+        let cup = scene.add_child(table, "cup", at(0.0, 1.0, 0.0));
+        let handle = scene.add_child(cup, "handle", at(0.5, 0.0, 0.0));
+        assert_eq!(scene.world_position(handle), make_vec3(&[5.5, 1.0, 0.0]));
+
+        scene.set_transform(table, at(0.0, 0.0, -2.0).uniformly_scaled(2.0));
+        assert_eq!(scene.world_position(handle), make_vec3(&[1.0, 2.0, -2.0]));
+        assert_eq!(scene.find("cup"), Some(cup));
+
+        // reparenting keeps the local transform
+        scene.set_parent(cup, None).unwrap();
+        assert_eq!(scene.world_position(handle), make_vec3(&[0.5, 1.0, 0.0]));
+        assert_eq!(scene.roots(), &[table, cup]);
+        assert!(scene.set_parent(cup, Some(handle)).is_err());
+
+        scene.remove(cup);
+        assert!(!scene.contains(handle));
+        assert_eq!(scene.len(), 1);
+    }
+
+    #[test]
+    fn only_moved_subtrees_are_recomputed() {
+        let mut scene = Scene::new();
+        let a = scene.add("a", at(1.0, 0.0, 0.0));
+        let b = scene.add_child(a, "b", at(1.0, 0.0, 0.0));
+        let c = scene.add("c", at(0.0, 1.0, 0.0));
+        scene.traverse(&mut |_, _, _| {});
+        assert!([a, b, c].iter().all(|id| !scene.node(*id).dirty.get()));
+
+        scene.set_transform(a, at(2.0, 0.0, 0.0));
+        assert!(scene.node(a).dirty.get() && scene.node(b).dirty.get());
+        assert!(!scene.node(c).dirty.get());
+        assert_eq!(scene.world_position(b), make_vec3(&[3.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn lights_and_cameras_move_with_their_nodes() {
+        let mut scene = Scene::new();
+        let rig = scene.add("rig", at(0.0, 3.0, 0.0).rotated_about(&make_vec3(&[0.0, 1.0, 0.0]), 90.0));
+        let lamp = scene.add_child(rig, "lamp", at(0.0, 0.0, -2.0));
+        let white = make_vec3(&[1.0, 1.0, 1.0]);
+        scene.node_mut(lamp).light = Some(NodeLight::Spot(SpotLight::new(
+            &make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]), &white, 20.0)));
+        scene.node_mut(rig).camera = Some(NodeCamera { fov: 60.0, near: 0.5, far: 50.0 });
+
+        let lights = scene.lights();
+        assert_eq!(lights.spot.len(), 1);
+        assert!(distance(&lights.spot[0].position, &make_vec3(&[-2.0, 3.0, 0.0])) < 1e-5);
+        assert!(distance(&lights.spot[0].direction, &make_vec3(&[-1.0, 0.0, 0.0])) < 1e-5);
+
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 1.0);
+        scene.apply_camera(rig, &mut camera).unwrap();
+        assert!(distance(&camera.front(), &make_vec3(&[-1.0, 0.0, 0.0])) < 1e-5);
+        assert_eq!((camera.fov(), camera.near(), camera.far()), (60.0, 0.5, 50.0));
+        assert!(scene.apply_camera(lamp, &mut camera).is_err());
+    }
+
+    #[test]
+    fn culler_follows_moved_added_and_removed_nodes() {
+        let _gl = MockGl::install();
+        let cube = Rc::new(Mesh::cube());
+        let mut scene = Scene::new();
+        let add = |scene: &mut Scene, name: &str, transform: Transform| {
+            let id = scene.add(name, transform);
+            scene.node_mut(id).mesh = Some(cube.clone());
+            id
+        };
+        let ahead = add(&mut scene, "ahead", at(0.0, 0.0, -5.0));
+        let behind = add(&mut scene, "behind", at(0.0, 0.0, 5.0));
+        let child = scene.add_child(behind, "child", at(0.0, 0.0, 1.0));
+        scene.node_mut(child).mesh = Some(cube.clone());
+        scene.add("empty", at(0.0, 0.0, -5.0));
+
+        let mut camera = Camera::new(&make_vec3(&[0.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                     &make_vec3(&[0.0, 1.0, 0.0]), 1.0);
+        let mut culler = SceneCuller::new(&scene);
+        assert_eq!(culler.cull(&camera.frustum()), [ahead].iter().cloned().collect());
+        // the root, "ahead" inside the frustum and the subtree of the two
+        // behind the camera, which is dropped whole
+        assert_eq!(culler.bvh().depth(), 3);
+        assert_eq!(culler.stats(), CullStats { objects: 3, visible: 1, culled: 2, tested: 3 });
+
+        // moving a parent moves the bounds of its children
+        scene.set_transform(behind, at(0.0, 0.0, -10.0));
+        scene.remove(ahead);
+        let added = add(&mut scene, "added", at(50.0, 0.0, -5.0));
+        culler.sync(&scene);
+        assert_eq!(culler.cull(&camera.frustum()), [behind, child].iter().cloned().collect());
+        assert_eq!((culler.stats().objects, culler.stats().culled), (3, 1));
+
+        camera.set_pose(&make_vec3(&[50.0, 0.0, 0.0]), &make_vec3(&[0.0, 0.0, -1.0]), &make_vec3(&[0.0, 1.0, 0.0]));
+        assert_eq!(culler.cull(&camera.frustum()), [added].iter().cloned().collect());
+        assert_eq!(scene.world_bounds(added), Some(Aabb::new(&make_vec3(&[49.5, -0.5, -5.5]), &make_vec3(&[50.5, 0.5, -4.5]))));
+    }
+}
@@ -0,0 +1,887 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use crate::render_gl::framebuffer::Framebuffer;
+use crate::render_gl::light::{Attenuation, DirectionalLight, LightSet, PointLight, SpotLight};
+use crate::render_gl::material::{Material, MaterialDesc};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::occlusion::{HiZBuffer, OcclusionMode, OcclusionQueries, OcclusionStats};
+use crate::render_gl::pbr;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::scene::{NodeCamera, NodeId, NodeLight, Scene, SceneCuller, Transform};
+use crate::render_gl::shadow::ShadowRenderer;
+use crate::render_gl::ssao;
+use crate::render_gl::texture::Texture;
+use crate::render_gl::transparency::{self, TransparentQueue};
+use crate::render_gl::Program;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+// the CPU depth pyramid for OcclusionMode::HiZ. small, only large
+// occluders matter and the test is conservative
+const HIZ_WIDTH: usize = 256;
+const HIZ_HEIGHT: usize = 128;
+
+// a scene file, RON or JSON. in JSON:
+//
+// {
+//   "camera": "eye",
+//   "meshes": { "box": "cube", "ball": { "sphere": { "sectors": 32, "stacks": 16 } },
+//               "teapot": { "file": "models/teapot.obj" } },
+//   "materials": { "wood": "../materials/container.json", "red": { "vertex": ..., "fragment": ... } },
+//   "nodes": [
+//     { "name": "table", "transform": { "translation": [0.0, -1.0, 0.0] }, "mesh": "box", "material": "wood",
+//       "children": [ { "name": "lamp", "light": { "point": { "color": [1.0, 0.8, 0.6], "range": 20.0 } } } ] },
+//     { "name": "eye", "transform": { "translation": [0.0, 0.0, 5.0] }, "camera": { "fov": 45.0 } }
+//   ]
+// }
+//
+// materials are either a path to a material file or the contents of one,
+// see MaterialDesc; that is also where textures are named. textures used
+// by several materials are loaded once. relative paths start from the
+// directory the file is in. rotations are quaternions as [x, y, z, w].
+// the camera is the named node, or the first camera node.
+// `"occluder": true` marks solid nodes, see Node::occluder
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SceneDesc {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    #[serde(default)]
+    pub meshes: BTreeMap<String, MeshDesc>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialRef>,
+    #[serde(default)]
+    pub nodes: Vec<NodeDesc>,
+    // where relative paths start from. load sets it to the file's
+    // directory, otherwise it is the working directory
+    #[serde(skip)]
+    pub dir: PathBuf
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeshDesc {
+    Cube,
+    Sphere { sectors: u32, stacks: u32 },
+    // a wavefront .obj
+    File(String)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum MaterialRef {
+    File(String),
+    Inline(MaterialDesc)
+}
+
+// by hand rather than untagged, which buffers the value first and then
+// can't read RON's bare enum variants like `blend: alpha`
+impl<'de> Deserialize<'de> for MaterialRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MaterialRef, D::Error> {
+        struct RefVisitor;
+
+        impl<'de> Visitor<'de> for RefVisitor {
+            type Value = MaterialRef;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a material file or a material")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<MaterialRef, E> {
+                Ok(MaterialRef::File(path.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<MaterialRef, A::Error> {
+                MaterialDesc::deserialize(de::value::MapAccessDeserializer::new(map)).map(MaterialRef::Inline)
+            }
+        }
+
+        deserializer.deserialize_any(RefVisitor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeDesc {
+    pub name: String,
+    #[serde(default)]
+    pub transform: TransformDesc,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub light: Option<LightDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraDesc>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub occluder: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<NodeDesc>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransformDesc {
+    #[serde(default = "zero")]
+    pub translation: [f32; 3],
+    #[serde(default = "no_rotation")]
+    pub rotation: [f32; 4],
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3]
+}
+
+fn zero() -> [f32; 3] { [0.0; 3] }
+fn no_rotation() -> [f32; 4] { [0.0, 0.0, 0.0, 1.0] }
+fn unit_scale() -> [f32; 3] { [1.0; 3] }
+fn is_false(b: &bool) -> bool { !*b }
+
+impl Default for TransformDesc {
+    fn default() -> TransformDesc {
+        TransformDesc { translation: zero(), rotation: no_rotation(), scale: unit_scale() }
+    }
+}
+
+impl TransformDesc {
+    pub fn from_transform(t: &Transform) -> TransformDesc {
+        let r = t.rotation.coords;
+        TransformDesc {
+            translation: [t.translation.x, t.translation.y, t.translation.z],
+            rotation: [r.x, r.y, r.z, r.w],
+            scale: [t.scale.x, t.scale.y, t.scale.z]
+        }
+    }
+
+    pub fn to_transform(&self) -> Transform {
+        let [x, y, z, w] = self.rotation;
+        Transform::from_translation(&make_vec3(&self.translation))
+            .rotated(&Qua::new(w, x, y, z))
+            .scaled(&make_vec3(&self.scale))
+    }
+}
+
+// positions come from the node, directions are in the node's space
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightDesc {
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+        #[serde(default)]
+        cast_shadows: bool
+    },
+    Point {
+        color: [f32; 3],
+        range: f32,
+        #[serde(default)]
+        cast_shadows: bool
+    },
+    Spot {
+        direction: [f32; 3],
+        color: [f32; 3],
+        range: f32,
+        #[serde(default = "inner_cutoff")]
+        inner_cutoff: f32,
+        #[serde(default = "outer_cutoff")]
+        outer_cutoff: f32,
+        #[serde(default)]
+        cast_shadows: bool
+    }
+}
+
+fn inner_cutoff() -> f32 { 12.5 }
+fn outer_cutoff() -> f32 { 17.5 }
+
+impl LightDesc {
+    // the color is the diffuse one. a light whose ambient and specular
+    // colours aren't the ones to_light derives from it, or whose
+    // attenuation isn't one of the Attenuation::for_range presets, can't
+    // be written down and is an error
+    pub fn from_light(light: &NodeLight) -> Result<LightDesc, String> {
+        let array = |v: &Vec3| [v.x, v.y, v.z];
+        let range = |attenuation: &Attenuation| attenuation.range()
+            .ok_or_else(|| format!("{:?} isn't one of the attenuation presets", attenuation));
+        let colors = |(ambient, specular): (&Vec3, &Vec3), expected: (&Vec3, &Vec3)| {
+            if (ambient, specular) == expected {
+                Ok(())
+            } else {
+                Err("the ambient and specular colours have to be the ones its colour gives".to_string())
+            }
+        };
+        match light {
+            NodeLight::Directional(l) => {
+                let derived = DirectionalLight::new(&l.direction, &l.diffuse);
+                colors((&l.ambient, &l.specular), (&derived.ambient, &derived.specular))?;
+                Ok(LightDesc::Directional {
+                    direction: array(&l.direction),
+                    color: array(&l.diffuse),
+                    cast_shadows: l.cast_shadows
+                })
+            }
+            NodeLight::Point(l) => {
+                let derived = PointLight::new(&l.position, &l.diffuse, 0.0);
+                colors((&l.ambient, &l.specular), (&derived.ambient, &derived.specular))?;
+                Ok(LightDesc::Point {
+                    color: array(&l.diffuse),
+                    range: range(&l.attenuation)?,
+                    cast_shadows: l.cast_shadows
+                })
+            }
+            NodeLight::Spot(l) => {
+                let derived = SpotLight::new(&l.position, &l.direction, &l.diffuse, 0.0);
+                colors((&l.ambient, &l.specular), (&derived.ambient, &derived.specular))?;
+                Ok(LightDesc::Spot {
+                    direction: array(&l.direction),
+                    color: array(&l.diffuse),
+                    range: range(&l.attenuation)?,
+                    inner_cutoff: l.inner_cutoff,
+                    outer_cutoff: l.outer_cutoff,
+                    cast_shadows: l.cast_shadows
+                })
+            }
+        }
+    }
+
+    pub fn to_light(&self) -> NodeLight {
+        let origin = make_vec3(&[0.0, 0.0, 0.0]);
+        match self {
+            LightDesc::Directional { direction, color, cast_shadows } => {
+                let mut light = DirectionalLight::new(&make_vec3(direction), &make_vec3(color));
+                light.cast_shadows = *cast_shadows;
+                NodeLight::Directional(light)
+            }
+            LightDesc::Point { color, range, cast_shadows } => {
+                let mut light = PointLight::new(&origin, &make_vec3(color), *range);
+                light.cast_shadows = *cast_shadows;
+                NodeLight::Point(light)
+            }
+            LightDesc::Spot { direction, color, range, inner_cutoff, outer_cutoff, cast_shadows } => {
+                let mut light = SpotLight::new(&origin, &make_vec3(direction), &make_vec3(color), *range);
+                light.inner_cutoff = *inner_cutoff;
+                light.outer_cutoff = *outer_cutoff;
+                light.cast_shadows = *cast_shadows;
+                NodeLight::Spot(light)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CameraDesc {
+    // vertical, in degrees
+    #[serde(default = "fov")]
+    pub fov: f32,
+    #[serde(default = "near")]
+    pub near: f32,
+    #[serde(default = "far")]
+    pub far: f32
+}
+
+fn fov() -> f32 { 45.0 }
+fn near() -> f32 { 0.1 }
+fn far() -> f32 { 100.0 }
+
+impl SceneDesc {
+    pub fn from_json(source: &str) -> Result<SceneDesc, String> {
+        serde_json::from_str(source).map_err(|e| e.to_string())
+    }
+
+    pub fn from_ron(source: &str) -> Result<SceneDesc, String> {
+        ron::de::from_str(source).map_err(|e| e.to_string())
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
+    pub fn to_ron(&self) -> Result<String, String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(|e| e.to_string())
+    }
+
+    // the format follows the extension: .ron, anything else is JSON
+    pub fn load(path: &str) -> Result<SceneDesc, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let desc = if is_ron(path) { SceneDesc::from_ron(&source) } else { SceneDesc::from_json(&source) };
+        let mut desc = desc.map_err(|e| format!("{}: {}", path, e))?;
+        desc.dir = parent_dir(path);
+        Ok(desc)
+    }
+
+    // relative paths are rewritten to start from the new file's directory
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let dir = parent_dir(path);
+        let desc = self.map_paths(&|p| relocate(&self.dir, &dir, p));
+        let source = if is_ron(path) { desc.to_ron()? } else { desc.to_json()? };
+        fs::write(path, source).map_err(|e| format!("unable to write {}: {}", path, e))
+    }
+
+    // the same scene with every mesh, material, shader and texture path
+    // passed through `f`
+    pub fn map_paths(&self, f: &dyn Fn(&str) -> String) -> SceneDesc {
+        let mut desc = self.clone();
+        for mesh in desc.meshes.values_mut() {
+            if let MeshDesc::File(path) = mesh {
+                *path = f(path);
+            }
+        }
+        for material in desc.materials.values_mut() {
+            *material = match material {
+                MaterialRef::File(path) => MaterialRef::File(f(path)),
+                MaterialRef::Inline(inline) => MaterialRef::Inline(inline.map_paths(f))
+            };
+        }
+        desc
+    }
+
+    // every node with its parent's index, parents first
+    fn flatten(&self) -> Vec<(Option<usize>, &NodeDesc)> {
+        let mut nodes = Vec::new();
+        let mut pending: Vec<(Option<usize>, &NodeDesc)> = self.nodes.iter().rev().map(|n| (None, n)).collect();
+        while let Some((parent, node)) = pending.pop() {
+            let index = nodes.len();
+            nodes.push((parent, node));
+            pending.extend(node.children.iter().rev().map(|c| (Some(index), c)));
+        }
+        nodes
+    }
+
+    // checks that everything the nodes refer to exists, without touching GL
+    pub fn validate(&self) -> Result<(), String> {
+        for (_, node) in self.flatten() {
+            if let Some(mesh) = &node.mesh {
+                if !self.meshes.contains_key(mesh) {
+                    return Err(format!("node '{}' uses the unknown mesh '{}'", node.name, mesh));
+                }
+            }
+            if let Some(material) = &node.material {
+                if !self.materials.contains_key(material) {
+                    return Err(format!("node '{}' uses the unknown material '{}'", node.name, material));
+                }
+            }
+        }
+        if let Some(camera) = &self.camera {
+            let found = self.flatten().iter().any(|(_, n)| n.name == *camera && n.camera.is_some());
+            if !found {
+                return Err(format!("no camera node named '{}'", camera));
+            }
+        }
+        Ok(())
+    }
+
+    // builds the scene graph without any meshes or materials
+    fn build_nodes(&self) -> (Scene, Vec<NodeId>) {
+        let mut scene = Scene::new();
+        let mut ids: Vec<NodeId> = Vec::new();
+        for (parent, desc) in self.flatten() {
+            let transform = desc.transform.to_transform();
+            let id = match parent {
+                Some(p) => scene.add_child(ids[p], &desc.name, transform),
+                None => scene.add(&desc.name, transform)
+            };
+            let node = scene.node_mut(id);
+            node.light = desc.light.as_ref().map(|l| l.to_light());
+            node.camera = desc.camera.map(|c| NodeCamera { fov: c.fov, near: c.near, far: c.far });
+            node.occluder = desc.occluder;
+            ids.push(id);
+        }
+        (scene, ids)
+    }
+
+    // loads the meshes, materials and textures and builds the scene graph.
+    // needs a current context
+    pub fn build(&self) -> Result<LoadedScene, String> {
+        self.validate()?;
+        let files = self.map_paths(&|p| self.dir.join(p).to_string_lossy().into_owned());
+        let mut meshes = BTreeMap::new();
+        for (name, desc) in files.meshes.iter() {
+            let mesh = match desc {
+                MeshDesc::Cube => Mesh::cube(),
+                MeshDesc::Sphere { sectors, stacks } => Mesh::sphere(*sectors, *stacks),
+                MeshDesc::File(path) => Mesh::load_obj(path)?
+            };
+            mesh.set_label(name);
+            meshes.insert(name.clone(), Rc::new(mesh));
+        }
+        let mut textures: BTreeMap<String, Rc<Texture>> = BTreeMap::new();
+        let mut materials = BTreeMap::new();
+        for (name, reference) in files.materials.iter() {
+            let desc = match reference {
+                MaterialRef::File(path) => MaterialDesc::load(path)?,
+                MaterialRef::Inline(desc) => desc.clone()
+            };
+            let material = desc.build_cached(&mut textures).map_err(|e| format!("material '{}': {}", name, e))?;
+            materials.insert(name.clone(), Rc::new(material));
+        }
+
+        let (mut scene, ids) = self.build_nodes();
+        for (id, (_, desc)) in ids.iter().zip(self.flatten()) {
+            let node = scene.node_mut(*id);
+            node.mesh = desc.mesh.as_ref().map(|m| meshes[m].clone());
+            node.material = desc.material.as_ref().map(|m| materials[m].clone());
+        }
+        let camera = match &self.camera {
+            Some(name) => scene.find(name),
+            None => ids.iter().cloned().find(|id| scene.node(*id).camera.is_some())
+        };
+        let culler = SceneCuller::new(&scene);
+        let queries = if OcclusionQueries::<NodeId>::supported() { Some(OcclusionQueries::new()?) } else { None };
+        Ok(LoadedScene {
+            desc: self.clone(),
+            scene: scene,
+            camera: camera,
+            meshes: meshes,
+            materials: materials,
+            culler: culler,
+            occlusion: if queries.is_some() { OcclusionMode::Queries } else { OcclusionMode::HiZ },
+            queries: queries,
+            hiz: HiZBuffer::new(HIZ_WIDTH, HIZ_HEIGHT),
+            occlusion_stats: OcclusionStats::default()
+        })
+    }
+}
+
+fn is_ron(path: &str) -> bool {
+    Path::new(path).extension().and_then(|e| e.to_str()) == Some("ron")
+}
+
+fn name_of<T>(named: &BTreeMap<String, Rc<T>>, value: &Rc<T>) -> Option<String> {
+    named.iter().find(|(_, v)| Rc::ptr_eq(v, value)).map(|(name, _)| name.clone())
+}
+
+fn parent_dir(path: &str) -> PathBuf {
+    Path::new(path).parent().map(|p| p.to_path_buf()).unwrap_or_default()
+}
+
+// `path`, relative to `from`, made relative to `to` instead
+fn relocate(from: &Path, to: &Path, path: &str) -> String {
+    if from == to || Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    let target = absolute(&from.join(path));
+    let base = absolute(to);
+    let common = target.iter().zip(base.iter()).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..base.iter().count() {
+        relative.push("..");
+    }
+    for part in target.iter().skip(common) {
+        relative.push(part);
+    }
+    relative.to_string_lossy().into_owned()
+}
+
+// without touching the file system, so it works for files that aren't
+// written yet
+fn absolute(path: &Path) -> PathBuf {
+    let mut absolute = std::env::current_dir().unwrap_or_default();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            other => absolute.push(other)
+        }
+    }
+    absolute
+}
+
+// a scene file turned into GL objects
+pub struct LoadedScene {
+    // what it was built from, for the meshes and materials when saving
+    desc: SceneDesc,
+    pub scene: Scene,
+    // the node the view starts from, if the file has a camera
+    pub camera: Option<NodeId>,
+    pub meshes: BTreeMap<String, Rc<Mesh>>,
+    pub materials: BTreeMap<String, Rc<Material>>,
+    // what draw() last culled is in culler.stats()
+    pub culler: SceneCuller,
+    // how draw() finds nodes hidden behind others. Queries is the default
+    // where GL has them, HiZ needs nodes marked as occluders
+    pub occlusion: OcclusionMode,
+    queries: Option<OcclusionQueries<NodeId>>,
+    hiz: HiZBuffer,
+    occlusion_stats: OcclusionStats
+}
+
+impl LoadedScene {
+    pub fn load(path: &str) -> Result<LoadedScene, String> {
+        SceneDesc::load(path)?.build()
+    }
+
+    // the scene as it is now, e.g after moving or adding nodes. nodes can
+    // only use meshes and materials from the file; material instances and
+    // meshes made in code have no description to write
+    pub fn to_desc(&self) -> Result<SceneDesc, String> {
+        let nodes = self.scene.roots().iter().map(|id| self.node_desc(*id)).collect::<Result<Vec<_>, _>>()?;
+        let camera = match self.camera {
+            Some(id) if self.scene.contains(id) => Some(self.scene.node(id).name.clone()),
+            _ => None
+        };
+        Ok(SceneDesc {
+            camera: camera,
+            meshes: self.desc.meshes.clone(),
+            materials: self.desc.materials.clone(),
+            nodes: nodes,
+            dir: self.desc.dir.clone()
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        self.to_desc()?.save(path)
+    }
+
+    fn node_desc(&self, id: NodeId) -> Result<NodeDesc, String> {
+        let node = self.scene.node(id);
+        let mesh = match &node.mesh {
+            Some(mesh) => Some(name_of(&self.meshes, mesh)
+                .filter(|name| self.desc.meshes.contains_key(name))
+                .ok_or_else(|| format!("node '{}' has a mesh that isn't in the scene file", node.name))?),
+            None => None
+        };
+        let material = match &node.material {
+            Some(material) => Some(name_of(&self.materials, material)
+                .filter(|name| self.desc.materials.contains_key(name))
+                .ok_or_else(|| format!("node '{}' has a material that isn't in the scene file", node.name))?),
+            None => None
+        };
+        let light = match &node.light {
+            Some(light) => Some(LightDesc::from_light(light).map_err(|e| format!("node '{}' has a light that can't be saved: {}", node.name, e))?),
+            None => None
+        };
+        let children = node.children().iter().map(|c| self.node_desc(*c)).collect::<Result<Vec<_>, _>>()?;
+        Ok(NodeDesc {
+            name: node.name.clone(),
+            transform: TransformDesc::from_transform(node.transform()),
+            mesh: mesh,
+            material: material,
+            light: light,
+            camera: node.camera.map(|c| CameraDesc { fov: c.fov, near: c.near, far: c.far }),
+            occluder: node.occluder,
+            children: children
+        })
+    }
+
+    // draws every mesh with `program`, which only gets its `model`
+    // uniform set. for depth only passes like ShadowRenderer::render
+    pub fn draw_geometry(&self, program: &Program) {
+        self.scene.traverse(&mut |_, node, world| {
+            if let Some(mesh) = &node.mesh {
+                program.set_uniform_mat4("model", world);
+                mesh.draw();
+            }
+        });
+    }
+
+    // forward shades the nodes that have a mesh and a material and are in
+    // view, opaque ones first, then transparent ones back to front.
+    // materials get the usual view, perspective, model and viewPos uniforms
+    // and the lights
+    pub fn draw(&mut self, camera: &Camera, lights: &LightSet, shadows: Option<&ShadowRenderer>, target: Option<&Framebuffer>) {
+        let setup = |program: &Program| {
+            program.set_uniform_mat4("view", &camera.look_at());
+            program.set_uniform_mat4("perspective", &camera.projection());
+            program.set_uniform_vec3("viewPos", &camera.position());
+            lights.apply(program);
+            if let Some(shadows) = shadows {
+                shadows.apply(program);
+            }
+            ssao::apply_ssao(program, None);
+            pbr::apply_ibl(program, None);
+        };
+
+        self.culler.sync(&self.scene);
+        let mut visible = self.culler.cull(&camera.frustum());
+        self.occlusion_stats = OcclusionStats::default();
+        // without queries the CPU test stands in for them
+        let mode = match (self.occlusion, &self.queries) {
+            (OcclusionMode::Queries, None) => OcclusionMode::HiZ,
+            (mode, _) => mode
+        };
+        if mode == OcclusionMode::HiZ {
+            self.hide_occluded(camera, &mut visible);
+        }
+        let mut queries = match mode {
+            OcclusionMode::Queries => self.queries.as_mut(),
+            _ => None
+        };
+        let scene = &self.scene;
+        if let Some(queries) = queries.as_mut() {
+            queries.retain(&|id| scene.contains(id));
+            queries.begin_frame();
+        }
+
+        PipelineState::opaque().apply();
+        let mut queue = TransparentQueue::new();
+        let mut hidden = Vec::new();
+        let mut previous: Option<&Material> = None;
+        scene.traverse(&mut |id, node, world| {
+            let (mesh, material): (&Mesh, &Material) = match (&node.mesh, &node.material) {
+                (Some(mesh), Some(material)) if visible.contains(&id) => (mesh, material),
+                _ => return
+            };
+            if material.blend.is_transparent() {
+                queue.push(&scene.world_position(id), material.blend, (mesh, material, *world));
+                return;
+            }
+            // what was hidden last frame is tested against everything else
+            if let Some(queries) = queries.as_ref() {
+                if !queries.is_visible(id) {
+                    hidden.push((id, mesh, material, *world));
+                    return;
+                }
+            }
+            let switched = match previous {
+                Some(p) => !p.shares_program(material),
+                None => true
+            };
+            material.apply(previous);
+            if switched {
+                setup(material.program());
+            }
+            material.program().set_uniform_mat4("model", world);
+            match queries.as_mut() {
+                Some(queries) => queries.draw_visible(id, &mut || mesh.draw()),
+                None => mesh.draw()
+            }
+            previous = Some(material);
+        });
+        if let Some(queries) = queries {
+            for (id, mesh, material, world) in hidden {
+                queries.draw_occluded(id, &mesh.bounds().transformed(&world), camera, &mut || {
+                    material.apply(None);
+                    setup(material.program());
+                    material.program().set_uniform_mat4("model", &world);
+                    mesh.draw();
+                });
+            }
+            self.occlusion_stats = queries.stats();
+        }
+
+        queue.draw(&camera.position(), None, target, &mut |(mesh, material, world), mode| {
+            material.apply(None);
+            setup(material.program());
+            transparency::set_blend_mode(material.program(), mode);
+            material.program().set_uniform_mat4("model", world);
+            mesh.draw();
+        });
+    }
+
+    // draws the occluder nodes in view into the CPU depth pyramid and
+    // drops the other nodes it hides. occluders are always kept, each
+    // would hide itself
+    fn hide_occluded(&mut self, camera: &Camera, visible: &mut BTreeSet<NodeId>) {
+        let scene = &self.scene;
+        let hiz = &mut self.hiz;
+        hiz.clear(&camera.view_projection());
+        for id in visible.iter() {
+            let node = scene.node(*id);
+            if let (true, Some(mesh)) = (node.occluder, &node.mesh) {
+                hiz.add_box(mesh.bounds(), &scene.world_matrix(*id));
+            }
+        }
+        hiz.finish();
+        let mut stats = OcclusionStats::default();
+        visible.retain(|id| {
+            if scene.node(*id).occluder {
+                return true;
+            }
+            stats.tested += 1;
+            let seen = scene.world_bounds(*id).map(|bounds| hiz.is_visible(&bounds)).unwrap_or(true);
+            if !seen {
+                stats.occluded += 1;
+            }
+            seen
+        });
+        self.occlusion_stats = stats;
+    }
+
+    // what draw() last found hidden
+    pub fn occlusion_stats(&self) -> OcclusionStats {
+        self.occlusion_stats
+    }
+
+    // points `camera` from the scene's camera node, if it has one
+    pub fn apply_camera(&self, camera: &mut Camera) -> Result<(), String> {
+        match self.camera {
+            Some(id) => self.scene.apply_camera(id, camera),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::light::Attenuation;
+    use crate::render_gl::material::{BlendMode, ParamDesc};
+    use crate::render_gl::mock::MockGl;
+
+    const SCENE: &str = r#"{
+        "camera": "eye",
+        "meshes": { "box": "cube", "ball": { "sphere": { "sectors": 8, "stacks": 4 } } },
+        "materials": {
+            "red": {
+                "vertex": "src/render_gl/shaders/pbr.vert",
+                "fragment": "src/render_gl/shaders/pbr.frag",
+                "params": { "material.albedo": [0.8, 0.1, 0.1], "material.roughness": 0.5 }
+            }
+        },
+        "nodes": [
+            { "name": "table", "transform": { "translation": [0.0, -1.0, 0.0], "scale": [2.0, 0.1, 2.0] },
+              "mesh": "box", "material": "red",
+              "children": [
+                  { "name": "ball", "transform": { "translation": [0.0, 10.0, 0.0] }, "mesh": "ball", "material": "red" },
+                  { "name": "lamp", "transform": { "translation": [0.0, 20.0, 0.0] },
+                    "light": { "point": { "color": [1.0, 0.9, 0.8], "range": 20.0 } } }
+              ] },
+            { "name": "eye", "transform": { "translation": [0.0, 0.0, 5.0] }, "camera": { "fov": 50.0 } }
+        ]
+    }"#;
+
+    #[test]
+    fn round_trips_through_json_and_ron() {
+        let desc = SceneDesc::from_json(SCENE).unwrap();
+        assert_eq!(desc.nodes[0].children.len(), 2);
+        assert_eq!(desc.nodes[0].transform.rotation, [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(desc.meshes["box"], MeshDesc::Cube);
+        assert_eq!(desc.nodes[1].camera, Some(CameraDesc { fov: 50.0, near: 0.1, far: 100.0 }));
+        assert!(desc.validate().is_ok());
+
+        let mut desc = desc;
+        if let Some(MaterialRef::Inline(red)) = desc.materials.get_mut("red") {
+            red.blend = BlendMode::Alpha;
+            red.params.insert("material.albedoMap".to_string(), ParamDesc::Texture { texture: "brick.png".to_string() });
+        }
+        desc.materials.insert("wood".to_string(), MaterialRef::File("materials/container.json".to_string()));
+        desc.nodes[0].occluder = true;
+        assert_eq!(SceneDesc::from_json(&desc.to_json().unwrap()).unwrap(), desc);
+        assert_eq!(SceneDesc::from_ron(&desc.to_ron().unwrap()).unwrap(), desc);
+
+        let t = Transform::from_translation(&make_vec3(&[1.0, 2.0, 3.0])).rotated_about(&make_vec3(&[0.0, 1.0, 0.0]), 30.0);
+        let back = TransformDesc::from_transform(&t).to_transform();
+        assert!((back.matrix() - t.matrix()).iter().all(|d| d.abs() < 1e-6));
+    }
+
+    #[test]
+    fn example_scenes_are_valid() {
+        for entry in fs::read_dir("scenes").unwrap() {
+            let path = entry.unwrap().path();
+            let path = path.to_str().unwrap();
+            let desc = SceneDesc::load(path).unwrap_or_else(|e| panic!("{}", e));
+            desc.validate().unwrap_or_else(|e| panic!("{}", e));
+            // from the file's directory, whatever the working directory is.
+            // textures aren't part of the repository
+            desc.map_paths(&|p| {
+                let file = desc.dir.join(p);
+                assert!(p.ends_with(".png") || file.exists(), "{} doesn't exist", file.display());
+                p.to_string()
+            });
+        }
+    }
+
+    #[test]
+    fn saving_elsewhere_keeps_paths_working() {
+        assert_eq!(relocate(Path::new("scenes"), Path::new(""), "../materials/crate.json"), "materials/crate.json");
+        assert_eq!(relocate(Path::new(""), Path::new("scenes"), "materials/crate.json"), "../materials/crate.json");
+        assert_eq!(relocate(Path::new("scenes"), Path::new("scenes/old"), "a.obj"), "../a.obj");
+        assert_eq!(relocate(Path::new("scenes"), Path::new("scenes"), "./a.obj"), "./a.obj");
+        assert_eq!(relocate(Path::new("scenes"), Path::new("other"), "/models/a.obj"), "/models/a.obj");
+    }
+
+    #[test]
+    fn broken_references_are_reported() {
+        let mut desc = SceneDesc::from_json(SCENE).unwrap();
+        desc.nodes[0].children[0].material = Some("blue".to_string());
+        assert_eq!(desc.validate().unwrap_err(), "node 'ball' uses the unknown material 'blue'");
+        desc.nodes[0].children[0].material = None;
+        desc.camera = Some("table".to_string());
+        assert!(desc.validate().is_err());
+    }
+
+    #[test]
+    fn edited_scenes_save_and_load_again() {
+        let _gl = MockGl::install();
+        let mut loaded = SceneDesc::from_json(SCENE).unwrap().build().unwrap();
+        let ball = loaded.scene.find("ball").unwrap();
+        let moved = Transform::from_translation(&make_vec3(&[1.0, 2.0, 3.0])).uniformly_scaled(0.5);
+        loaded.scene.set_transform(ball, moved.clone());
+        let eye = loaded.scene.find("eye").unwrap();
+        let added = loaded.scene.add_child(eye, "torch", Transform::from_translation(&make_vec3(&[0.0, 0.0, -1.0])));
+        {
+            let node = loaded.scene.node_mut(added);
+            node.mesh = Some(loaded.meshes["box"].clone());
+            node.material = Some(loaded.materials["red"].clone());
+            node.occluder = true;
+            let desc = LightDesc::Spot {
+                direction: [0.0, 0.0, -1.0], color: [1.0, 0.5, 0.0], range: 32.0,
+                inner_cutoff: 10.0, outer_cutoff: 15.0, cast_shadows: true
+            };
+            node.light = Some(desc.to_light());
+        }
+        let lamp = loaded.scene.find("lamp").unwrap();
+        loaded.scene.remove(lamp);
+
+        let path = std::env::temp_dir().join(format!("edited_scene_{}.ron", std::process::id()));
+        let path = path.to_str().unwrap();
+        loaded.save(path).unwrap();
+        let reloaded = LoadedScene::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(reloaded.to_desc().unwrap().nodes, loaded.to_desc().unwrap().nodes);
+
+        let scene = &reloaded.scene;
+        assert_eq!(scene.len(), 4);
+        assert!(scene.find("lamp").is_none());
+        let ball = scene.find("ball").unwrap();
+        assert!((scene.node(ball).transform().matrix() - moved.matrix()).iter().all(|d| d.abs() < 1e-6));
+        let torch = scene.find("torch").unwrap();
+        assert_eq!(scene.node(torch).parent(), scene.find("eye"));
+        assert!(Rc::ptr_eq(scene.node(torch).mesh.as_ref().unwrap(), &reloaded.meshes["box"]));
+        assert!(scene.node(torch).occluder);
+        match &scene.node(torch).light {
+            Some(NodeLight::Spot(light)) => {
+                assert_eq!(light.attenuation, Attenuation::for_range(32.0));
+                assert_eq!((light.inner_cutoff, light.outer_cutoff, light.cast_shadows), (10.0, 15.0, true));
+            }
+            other => panic!("expected a spot light, got {:?}", other)
+        }
+        assert_eq!(reloaded.camera, scene.find("eye"));
+
+        // instances have overrides the file can't describe
+        let instance = Rc::new(loaded.materials["red"].instance());
+        loaded.scene.node_mut(ball).material = Some(instance);
+        assert_eq!(loaded.to_desc().unwrap_err(), "node 'ball' has a material that isn't in the scene file");
+        loaded.scene.node_mut(ball).material = Some(loaded.materials["red"].clone());
+
+        // and so do lights with their own ambient colour or attenuation
+        let torch = loaded.scene.find("torch").unwrap();
+        if let Some(NodeLight::Spot(light)) = &mut loaded.scene.node_mut(torch).light {
+            light.ambient = make_vec3(&[0.1, 0.1, 0.1]);
+        }
+        assert!(loaded.to_desc().unwrap_err().starts_with("node 'torch' has a light that can't be saved"));
+        if let Some(NodeLight::Spot(light)) = &mut loaded.scene.node_mut(torch).light {
+            light.ambient = make_vec3(&[0.0, 0.0, 0.0]);
+            light.attenuation = Attenuation::new(1.0, 0.1, 0.05);
+        }
+        assert!(loaded.to_desc().unwrap_err().contains("isn't one of the attenuation presets"));
+    }
+
+    #[test]
+    fn builds_the_node_hierarchy() {
+        let _gl = MockGl::install();
+        let loaded = SceneDesc::from_json(SCENE).unwrap().build().unwrap();
+        let scene = &loaded.scene;
+        assert_eq!(scene.len(), 4);
+        let ball = scene.find("ball").unwrap();
+        // the table's scale applies to its children as well
+        assert_eq!(scene.world_position(ball), make_vec3(&[0.0, 0.0, 0.0]));
+        assert!(Rc::ptr_eq(scene.node(ball).material.as_ref().unwrap(), &loaded.materials["red"]));
+        assert_eq!(loaded.camera, scene.find("eye"));
+        let lights = scene.lights();
+        assert_eq!(lights.point.len(), 1);
+        assert_eq!(lights.point[0].position, make_vec3(&[0.0, 1.0, 0.0]));
+    }
+}
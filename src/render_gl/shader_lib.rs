@@ -0,0 +1,69 @@
+use std::ffi::CString;
+
+// GLSL 330 has no #include, so shared snippets are pasted in before the
+// source is handed to the driver. Each library file is included once per
+// shader even if several files pull it in.
+pub const LIBRARY: &[(&str, &str)] = &[
+    ("lights.glsl", include_str!("shaders/lights.glsl")),
+    ("shadows.glsl", include_str!("shaders/shadows.glsl")),
+    ("pbr.glsl", include_str!("shaders/pbr.glsl")),
+    ("oit.glsl", include_str!("shaders/oit.glsl")),
+    ("instancing.glsl", include_str!("shaders/instancing.glsl")),
+];
+
+pub fn library_source(name: &str) -> Option<&'static str> {
+    LIBRARY.iter().find(|(n, _)| *n == name).map(|(_, source)| *source)
+}
+
+// replaces every `#include "name"` line with the library source called `name`
+pub fn expand_includes(source: &str) -> Result<String, String> {
+    let mut included: Vec<String> = Vec::new();
+    expand(source, &mut included)
+}
+
+fn expand(source: &str, included: &mut Vec<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(source.len());
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("#include") {
+            let name = trimmed["#include".len()..].trim().trim_matches('"');
+            if included.iter().any(|n| n == name) {
+                continue;
+            }
+            let lib = library_source(name)
+                .ok_or_else(|| format!("unknown shader include \"{}\"", name))?;
+            included.push(name.to_string());
+            out.push_str(&expand(lib, included)?);
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+// convenience for Shader::from_*_source, which take a CStr
+pub fn preprocess(source: &str) -> Result<CString, String> {
+    let expanded = expand_includes(source)?;
+    CString::new(expanded).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_are_expanded_once() {
+        let source = "#version 330 core\n#include \"lights.glsl\"\n#include \"lights.glsl\"\nvoid main() {}\n";
+        let expanded = expand_includes(source).unwrap();
+        assert!(expanded.starts_with("#version 330 core\n"));
+        assert_eq!(expanded.matches("struct PointLight").count(), 1);
+        assert!(!expanded.contains("#include"));
+        assert!(expanded.ends_with("void main() {}\n"));
+    }
+
+    #[test]
+    fn unknown_includes_are_an_error() {
+        assert!(expand_includes("#include \"nope.glsl\"").is_err());
+    }
+}
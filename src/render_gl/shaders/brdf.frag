@@ -0,0 +1,39 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec2 FragColor;
+
+#include "pbr.glsl"
+
+const uint sampleCount = 1024u;
+
+// split sum approximation, second half: scale and bias to F0 as a function
+// of N.V (x) and roughness (y)
+vec2 integrateBRDF(float NdotV, float roughness)
+{
+	vec3 V = vec3(sqrt(1.0 - NdotV * NdotV), 0.0, NdotV);
+	vec3 N = vec3(0.0, 0.0, 1.0);
+	float A = 0.0;
+	float B = 0.0;
+	for (uint i = 0u; i < sampleCount; i++) {
+		vec2 Xi = hammersley(i, sampleCount);
+		vec3 H = importanceSampleGGX(Xi, N, roughness);
+		vec3 L = normalize(2.0 * dot(V, H) * H - V);
+		float NdotL = max(L.z, 0.0);
+		float NdotH = max(H.z, 0.0);
+		float VdotH = max(dot(V, H), 0.0);
+		if (NdotL > 0.0) {
+			float G = geometrySmithIBL(N, V, L, roughness);
+			float G_Vis = (G * VdotH) / (NdotH * NdotV);
+			float Fc = pow(1.0 - VdotH, 5.0);
+			A += (1.0 - Fc) * G_Vis;
+			B += Fc * G_Vis;
+		}
+	}
+	return vec2(A, B) / float(sampleCount);
+}
+
+void main()
+{
+	FragColor = integrateBRDF(TexCoords.x, TexCoords.y);
+}
@@ -0,0 +1,12 @@
+#version 330 core
+layout (location = 0) in vec3 aPosition;
+
+uniform mat4 viewProjection;
+
+out vec3 LocalPos;
+
+void main()
+{
+    LocalPos = aPosition;
+    gl_Position = viewProjection * vec4(aPosition, 1.0);
+}
@@ -0,0 +1,42 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+#include "lights.glsl"
+
+uniform sampler2D gPosition;
+uniform sampler2D gNormal;
+uniform sampler2D gAlbedoSpec;
+uniform vec3 viewPos;
+
+// directional and spot lights. point lights are added by the light volumes
+void main()
+{
+	vec4 normalShininess = texture(gNormal, TexCoords);
+	// nothing was drawn here
+	if (normalShininess.xyz == vec3(0.0))
+		discard;
+	vec3 fragPos = texture(gPosition, TexCoords).xyz;
+	vec3 normal = normalize(normalShininess.xyz);
+	vec4 albedoSpec = texture(gAlbedoSpec, TexCoords);
+	vec3 albedo = albedoSpec.rgb;
+	vec3 specularColor = vec3(albedoSpec.a);
+	float shininess = normalShininess.w;
+	vec3 viewDir = normalize(viewPos - fragPos);
+
+	vec3 result = vec3(0.0);
+	for (int i = 0; i < numDirLights; i++) {
+		float shadow = 1.0;
+		if (i == dirShadowLight)
+			shadow = dirShadow(fragPos, normal, normalize(-dirLights[i].direction));
+		result += calcDirLight(dirLights[i], shadow, normal, viewDir, albedo, specularColor, shininess);
+	}
+	for (int i = 0; i < numSpotLights; i++) {
+		float shadow = 1.0;
+		if (i == spotShadowLight)
+			shadow = spotShadow(fragPos, normal, normalize(spotLights[i].position - fragPos));
+		result += calcSpotLight(spotLights[i], shadow, fragPos, normal, viewDir, albedo, specularColor, shininess);
+	}
+	FragColor = vec4(result, 1.0);
+}
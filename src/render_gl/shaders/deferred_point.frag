@@ -0,0 +1,42 @@
+#version 330 core
+
+out vec4 FragColor;
+
+flat in vec3 LightPosition;
+flat in float LightRadius;
+flat in vec3 LightDiffuse;
+flat in vec3 LightSpecular;
+flat in vec3 LightAttenuation;
+flat in vec3 LightAmbient;
+
+#include "lights.glsl"
+
+uniform sampler2D gPosition;
+uniform sampler2D gNormal;
+uniform sampler2D gAlbedoSpec;
+uniform vec3 viewPos;
+uniform vec2 screenSize;
+
+// one point light, shading the G-buffer texels its volume covers
+void main()
+{
+	vec2 uv = gl_FragCoord.xy / screenSize;
+	vec4 normalShininess = texture(gNormal, uv);
+	if (normalShininess.xyz == vec3(0.0))
+		discard;
+	vec3 fragPos = texture(gPosition, uv).xyz;
+	float dist = length(LightPosition - fragPos);
+	if (dist > LightRadius)
+		discard;
+
+	vec3 normal = normalize(normalShininess.xyz);
+	vec4 albedoSpec = texture(gAlbedoSpec, uv);
+	vec3 lightDir = (LightPosition - fragPos) / dist;
+	vec3 viewDir = normalize(viewPos - fragPos);
+	float attenuation = attenuate(LightAttenuation.x, LightAttenuation.y, LightAttenuation.z, dist);
+	// fade out towards the edge of the volume so it doesn't end in a hard circle
+	float window = clamp(1.0 - pow(dist / LightRadius, 4.0), 0.0, 1.0);
+	vec3 lit = shade(lightDir, LightAmbient, LightDiffuse, LightSpecular, 1.0,
+	                 normal, viewDir, albedoSpec.rgb, vec3(albedoSpec.a), normalShininess.w);
+	FragColor = vec4(lit * attenuation * window * window, 1.0);
+}
@@ -0,0 +1,31 @@
+#version 330 core
+
+layout (location = 0) in vec3 aPosition;
+// per instance, see deferred::volume_instances
+layout (location = 2) in vec3 lightPosition;
+layout (location = 3) in float lightRadius;
+layout (location = 4) in vec3 lightDiffuse;
+layout (location = 5) in vec3 lightSpecular;
+layout (location = 6) in vec3 lightAttenuation;
+layout (location = 7) in vec3 lightAmbient;
+
+flat out vec3 LightPosition;
+flat out float LightRadius;
+flat out vec3 LightDiffuse;
+flat out vec3 LightSpecular;
+flat out vec3 LightAttenuation;
+flat out vec3 LightAmbient;
+
+uniform mat4 view;
+uniform mat4 perspective;
+
+void main()
+{
+	LightPosition = lightPosition;
+	LightRadius = lightRadius;
+	LightDiffuse = lightDiffuse;
+	LightSpecular = lightSpecular;
+	LightAttenuation = lightAttenuation;
+	LightAmbient = lightAmbient;
+	gl_Position = perspective * view * vec4(aPosition * lightRadius + lightPosition, 1.0);
+}
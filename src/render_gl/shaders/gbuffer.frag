@@ -0,0 +1,28 @@
+#version 330 core
+
+layout (location = 0) out vec4 gPosition;
+layout (location = 1) out vec4 gNormal;
+layout (location = 2) out vec4 gAlbedoSpec;
+
+in vec3 FragPos;
+in vec3 Normal;
+in vec2 TexCoords;
+
+struct Material {
+	sampler2D diffuse;
+	sampler2D specular;
+	float shininess;
+};
+
+uniform Material material;
+uniform mat4 view;
+
+// position.w is the view space depth, normal.w the shininess and
+// albedoSpec.a the specular intensity
+void main()
+{
+	gPosition = vec4(FragPos, -(view * vec4(FragPos, 1.0)).z);
+	gNormal = vec4(normalize(Normal), material.shininess);
+	gAlbedoSpec.rgb = texture(material.diffuse, TexCoords).rgb;
+	gAlbedoSpec.a = texture(material.specular, TexCoords).r;
+}
@@ -0,0 +1,19 @@
+// per instance attributes, see render_gl/instancing.rs for the layout
+layout (location = 8) in mat4 instanceModel;
+layout (location = 12) in vec4 instanceColor;
+layout (location = 13) in vec4 instanceData;
+
+uniform mat4 model;
+// set while drawing from an instance buffer, otherwise `model` is used
+uniform bool instanced;
+
+mat4 modelMatrix()
+{
+	return instanced ? instanceModel : model;
+}
+
+// white unless instanced
+vec4 modelColor()
+{
+	return instanced ? instanceColor : vec4(1.0);
+}
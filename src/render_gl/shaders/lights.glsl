@@ -0,0 +1,131 @@
+// keep the limits in sync with MAX_*_LIGHTS in render_gl/light.rs
+#define MAX_DIR_LIGHTS 4
+#define MAX_POINT_LIGHTS 16
+#define MAX_SPOT_LIGHTS 8
+
+#include "shadows.glsl"
+
+struct DirLight {
+	vec3 direction;
+	vec3 ambient;
+	vec3 diffuse;
+	vec3 specular;
+};
+
+struct PointLight {
+	vec3 position;
+	vec3 ambient;
+	vec3 diffuse;
+	vec3 specular;
+	float constant;
+	float linear;
+	float quadratic;
+};
+
+struct SpotLight {
+	vec3 position;
+	vec3 direction;
+	vec3 ambient;
+	vec3 diffuse;
+	vec3 specular;
+	float constant;
+	float linear;
+	float quadratic;
+	// cosines of the cone angles
+	float innerCutoff;
+	float outerCutoff;
+};
+
+uniform DirLight dirLights[MAX_DIR_LIGHTS];
+uniform PointLight pointLights[MAX_POINT_LIGHTS];
+uniform SpotLight spotLights[MAX_SPOT_LIGHTS];
+uniform int numDirLights;
+uniform int numPointLights;
+uniform int numSpotLights;
+
+// screen space ambient occlusion, see render_gl/ssao.rs
+uniform sampler2D ssaoMap;
+uniform int useSsao;
+
+// 1.0 where nothing occludes the ambient light, or when SSAO is off
+float ambientOcclusion()
+{
+	if (useSsao == 0)
+		return 1.0;
+	return texture(ssaoMap, gl_FragCoord.xy / vec2(textureSize(ssaoMap, 0))).r;
+}
+
+float attenuate(float constant, float linear, float quadratic, float distance)
+{
+	return 1.0 / (constant + linear * distance + quadratic * (distance * distance));
+}
+
+// lightDir points from the fragment to the light, viewDir from the fragment to the eye.
+// shadow only darkens the diffuse and specular terms, ambient occlusion only the ambient one
+vec3 shade(vec3 lightDir, vec3 ambient, vec3 diffuse, vec3 specular, float shadow,
+           vec3 normal, vec3 viewDir, vec3 albedo, vec3 specularColor, float shininess)
+{
+	float diff = max(dot(normal, lightDir), 0.0);
+	vec3 reflectDir = reflect(-lightDir, normal);
+	float spec = pow(max(dot(viewDir, reflectDir), 0.0), shininess);
+	return ambient * albedo * ambientOcclusion() + shadow * (diffuse * diff * albedo + specular * spec * specularColor);
+}
+
+vec3 calcDirLight(DirLight light, float shadow, vec3 normal, vec3 viewDir,
+                  vec3 albedo, vec3 specularColor, float shininess)
+{
+	vec3 lightDir = normalize(-light.direction);
+	return shade(lightDir, light.ambient, light.diffuse, light.specular, shadow,
+	             normal, viewDir, albedo, specularColor, shininess);
+}
+
+vec3 calcPointLight(PointLight light, float shadow, vec3 fragPos, vec3 normal, vec3 viewDir,
+                    vec3 albedo, vec3 specularColor, float shininess)
+{
+	vec3 lightDir = normalize(light.position - fragPos);
+	float attenuation = attenuate(light.constant, light.linear, light.quadratic,
+	                              length(light.position - fragPos));
+	return attenuation * shade(lightDir, light.ambient, light.diffuse, light.specular, shadow,
+	                           normal, viewDir, albedo, specularColor, shininess);
+}
+
+vec3 calcSpotLight(SpotLight light, float shadow, vec3 fragPos, vec3 normal, vec3 viewDir,
+                   vec3 albedo, vec3 specularColor, float shininess)
+{
+	vec3 lightDir = normalize(light.position - fragPos);
+	float attenuation = attenuate(light.constant, light.linear, light.quadratic,
+	                              length(light.position - fragPos));
+	float theta = dot(lightDir, normalize(-light.direction));
+	float epsilon = light.innerCutoff - light.outerCutoff;
+	float intensity = clamp((theta - light.outerCutoff) / epsilon, 0.0, 1.0);
+	// the ambient term is not limited to the cone
+	vec3 ambient = light.ambient * albedo * ambientOcclusion();
+	vec3 lit = shade(lightDir, vec3(0.0), light.diffuse, light.specular, shadow,
+	                 normal, viewDir, albedo, specularColor, shininess);
+	return attenuation * (ambient + intensity * lit);
+}
+
+vec3 calcLights(vec3 fragPos, vec3 normal, vec3 viewDir, vec3 albedo, vec3 specularColor, float shininess)
+{
+	vec3 result = vec3(0.0);
+	for (int i = 0; i < numDirLights; i++) {
+		float shadow = 1.0;
+		if (i == dirShadowLight)
+			shadow = dirShadow(fragPos, normal, normalize(-dirLights[i].direction));
+		result += calcDirLight(dirLights[i], shadow, normal, viewDir, albedo, specularColor, shininess);
+	}
+	for (int i = 0; i < numPointLights; i++) {
+		float shadow = 1.0;
+		if (i == pointShadowLight)
+			shadow = pointShadow(fragPos, pointLights[i].position, normal,
+			                     normalize(pointLights[i].position - fragPos));
+		result += calcPointLight(pointLights[i], shadow, fragPos, normal, viewDir, albedo, specularColor, shininess);
+	}
+	for (int i = 0; i < numSpotLights; i++) {
+		float shadow = 1.0;
+		if (i == spotShadowLight)
+			shadow = spotShadow(fragPos, normal, normalize(spotLights[i].position - fragPos));
+		result += calcSpotLight(spotLights[i], shadow, fragPos, normal, viewDir, albedo, specularColor, shininess);
+	}
+	return result;
+}
@@ -0,0 +1,31 @@
+// output for transparent surfaces, see render_gl/transparency.rs. call
+// writeTransparent() instead of writing FragColor yourself. sorted passes
+// only have one colour target, so AccumulatedAlpha is dropped there
+layout (location = 0) out vec4 FragColor;
+layout (location = 1) out float AccumulatedAlpha;
+
+// set while drawing into the weighted blended accumulation targets
+uniform bool weightedBlended;
+
+// favours surfaces that are close and opaque. gl_FragCoord.z is used as
+// is, so the weights depend on the near and far planes
+// Source: McGuire & Bavoil, Weighted Blended Order-Independent Transparency (2013)
+float transparencyWeight(float alpha)
+{
+	float depth = 1.0 - gl_FragCoord.z * 0.9;
+	return clamp(pow(min(1.0, alpha * 10.0) + 0.01, 3.0) * 1e8 * depth * depth * depth, 1e-2, 3e3);
+}
+
+// `color` is not premultiplied
+void writeTransparent(vec4 color)
+{
+	if (weightedBlended) {
+		float weight = transparencyWeight(color.a);
+		// rgb adds up, alpha multiplies into how much of the background is left
+		FragColor = vec4(color.rgb * color.a * weight, color.a);
+		AccumulatedAlpha = color.a * weight;
+	} else {
+		FragColor = color;
+		AccumulatedAlpha = 0.0;
+	}
+}
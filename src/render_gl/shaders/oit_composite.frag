@@ -0,0 +1,19 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D accumulation;
+uniform sampler2D accumulatedAlpha;
+
+void main()
+{
+	vec4 accum = texture(accumulation, TexCoords);
+	// the part of the background still visible
+	float revealage = accum.a;
+	if (revealage >= 1.0) {
+		discard;
+	}
+	vec3 average = accum.rgb / max(texture(accumulatedAlpha, TexCoords).r, 1e-5);
+	FragColor = vec4(average, revealage);
+}
@@ -0,0 +1,137 @@
+#version 330 core
+
+out vec4 FragColor;
+
+in vec3 FragPos;
+in vec3 Normal;
+in vec2 TexCoords;
+
+#include "lights.glsl"
+#include "pbr.glsl"
+
+// factors multiply the maps; a map that isn't used reads as white
+struct PbrMaterial {
+	vec3 albedo;
+	float metallic;
+	float roughness;
+	float ao;
+	vec3 emissive;
+	sampler2D albedoMap;
+	sampler2D metallicMap;
+	sampler2D roughnessMap;
+	sampler2D aoMap;
+	sampler2D normalMap;
+	sampler2D emissiveMap;
+	int hasAlbedoMap;
+	int hasMetallicMap;
+	int hasRoughnessMap;
+	int hasAoMap;
+	int hasNormalMap;
+	int hasEmissiveMap;
+};
+
+uniform PbrMaterial material;
+uniform vec3 viewPos;
+
+uniform int useIbl;
+uniform samplerCube irradianceMap;
+uniform samplerCube prefilterMap;
+uniform sampler2D brdfLUT;
+uniform float prefilterLevels;
+
+// perturbs the normal without a tangent attribute, using screen space derivatives
+// Source: http://www.thetenthplanet.de/archives/1180
+vec3 perturbNormal(vec3 N)
+{
+	vec3 tangentNormal = texture(material.normalMap, TexCoords).xyz * 2.0 - 1.0;
+	vec3 dp1 = dFdx(FragPos);
+	vec3 dp2 = dFdy(FragPos);
+	vec2 duv1 = dFdx(TexCoords);
+	vec2 duv2 = dFdy(TexCoords);
+	vec3 T = normalize(dp1 * duv2.t - dp2 * duv1.t);
+	vec3 B = -normalize(cross(N, T));
+	return normalize(mat3(T, B, N) * tangentNormal);
+}
+
+vec3 radiance(vec3 N, vec3 V, vec3 L, vec3 lightColor, vec3 albedo, float metallic, float roughness, vec3 F0)
+{
+	vec3 H = normalize(V + L);
+	float NDF = distributionGGX(N, H, roughness);
+	float G = geometrySmith(N, V, L, roughness);
+	vec3 F = fresnelSchlick(max(dot(H, V), 0.0), F0);
+
+	vec3 specular = (NDF * G * F) / (4.0 * max(dot(N, V), 0.0) * max(dot(N, L), 0.0) + 0.0001);
+	// metals have no diffuse
+	vec3 kD = (vec3(1.0) - F) * (1.0 - metallic);
+	float NdotL = max(dot(N, L), 0.0);
+	return (kD * albedo / PI + specular) * lightColor * NdotL;
+}
+
+void main()
+{
+	vec3 albedo = material.albedo;
+	if (material.hasAlbedoMap != 0)
+		albedo *= pow(texture(material.albedoMap, TexCoords).rgb, vec3(2.2));
+	float metallic = material.metallic;
+	if (material.hasMetallicMap != 0)
+		metallic *= texture(material.metallicMap, TexCoords).r;
+	float roughness = material.roughness;
+	if (material.hasRoughnessMap != 0)
+		roughness *= texture(material.roughnessMap, TexCoords).r;
+	float ao = material.ao;
+	if (material.hasAoMap != 0)
+		ao *= texture(material.aoMap, TexCoords).r;
+	vec3 emissive = material.emissive;
+	if (material.hasEmissiveMap != 0)
+		emissive *= texture(material.emissiveMap, TexCoords).rgb;
+
+	vec3 N = normalize(Normal);
+	if (material.hasNormalMap != 0)
+		N = perturbNormal(N);
+	vec3 V = normalize(viewPos - FragPos);
+	vec3 R = reflect(-V, N);
+
+	// dielectrics reflect ~4% head on, metals tint the reflection with albedo
+	vec3 F0 = mix(vec3(0.04), albedo, metallic);
+
+	vec3 Lo = vec3(0.0);
+	for (int i = 0; i < numDirLights; i++) {
+		vec3 L = normalize(-dirLights[i].direction);
+		float shadow = i == dirShadowLight ? dirShadow(FragPos, N, L) : 1.0;
+		Lo += shadow * radiance(N, V, L, dirLights[i].diffuse, albedo, metallic, roughness, F0);
+	}
+	for (int i = 0; i < numPointLights; i++) {
+		vec3 L = normalize(pointLights[i].position - FragPos);
+		float attenuation = attenuate(pointLights[i].constant, pointLights[i].linear, pointLights[i].quadratic,
+		                              length(pointLights[i].position - FragPos));
+		float shadow = i == pointShadowLight ? pointShadow(FragPos, pointLights[i].position, N, L) : 1.0;
+		Lo += shadow * attenuation * radiance(N, V, L, pointLights[i].diffuse, albedo, metallic, roughness, F0);
+	}
+	for (int i = 0; i < numSpotLights; i++) {
+		vec3 L = normalize(spotLights[i].position - FragPos);
+		float attenuation = attenuate(spotLights[i].constant, spotLights[i].linear, spotLights[i].quadratic,
+		                              length(spotLights[i].position - FragPos));
+		float theta = dot(L, normalize(-spotLights[i].direction));
+		float epsilon = spotLights[i].innerCutoff - spotLights[i].outerCutoff;
+		float intensity = clamp((theta - spotLights[i].outerCutoff) / epsilon, 0.0, 1.0);
+		float shadow = i == spotShadowLight ? spotShadow(FragPos, N, L) : 1.0;
+		Lo += shadow * intensity * attenuation * radiance(N, V, L, spotLights[i].diffuse, albedo, metallic, roughness, F0);
+	}
+
+	vec3 ambient = vec3(0.03) * albedo * ao;
+	if (useIbl != 0) {
+		float NdotV = max(dot(N, V), 0.0);
+		vec3 F = fresnelSchlickRoughness(NdotV, F0, roughness);
+		vec3 kD = (1.0 - F) * (1.0 - metallic);
+		vec3 diffuse = texture(irradianceMap, N).rgb * albedo;
+		vec3 prefiltered = textureLod(prefilterMap, R, roughness * (prefilterLevels - 1.0)).rgb;
+		vec2 brdf = texture(brdfLUT, vec2(NdotV, roughness)).rg;
+		vec3 specular = prefiltered * (F * brdf.x + brdf.y);
+		ambient = (kD * diffuse + specular) * ao;
+	}
+
+	ambient *= ambientOcclusion();
+
+	// output is linear; tone mapping and gamma happen later in the frame
+	FragColor = vec4(ambient + Lo + emissive, 1.0);
+}
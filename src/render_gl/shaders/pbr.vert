@@ -0,0 +1,24 @@
+#version 330 core
+
+layout (location=0) in vec3 aPosition;
+layout (location=1) in vec3 normal;
+layout (location=2) in vec2 texCoords;
+
+out vec2 TexCoords;
+out vec3 Normal;
+out vec3 FragPos;
+
+#include "instancing.glsl"
+
+uniform mat4 view;
+uniform mat4 perspective;
+
+void main()
+{
+    mat4 world = modelMatrix();
+    FragPos = vec3(world * vec4(aPosition, 1.0));
+    Normal = mat3(transpose(inverse(world))) * normal;
+    TexCoords = texCoords;
+
+    gl_Position = perspective * view * vec4(FragPos, 1.0);
+}
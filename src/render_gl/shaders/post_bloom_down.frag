@@ -0,0 +1,41 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec3 FragColor;
+
+uniform sampler2D image;
+uniform vec2 texelSize;
+// only the first downsample keeps what is brighter than the threshold
+uniform int prefilter;
+uniform float threshold;
+
+// 13 tap downsample, Jimenez 2014 "Next Generation Post Processing in Call of Duty"
+void main()
+{
+	float x = texelSize.x;
+	float y = texelSize.y;
+	vec3 a = texture(image, TexCoords + vec2(-2.0 * x, 2.0 * y)).rgb;
+	vec3 b = texture(image, TexCoords + vec2(0.0, 2.0 * y)).rgb;
+	vec3 c = texture(image, TexCoords + vec2(2.0 * x, 2.0 * y)).rgb;
+	vec3 d = texture(image, TexCoords + vec2(-2.0 * x, 0.0)).rgb;
+	vec3 e = texture(image, TexCoords).rgb;
+	vec3 f = texture(image, TexCoords + vec2(2.0 * x, 0.0)).rgb;
+	vec3 g = texture(image, TexCoords + vec2(-2.0 * x, -2.0 * y)).rgb;
+	vec3 h = texture(image, TexCoords + vec2(0.0, -2.0 * y)).rgb;
+	vec3 i = texture(image, TexCoords + vec2(2.0 * x, -2.0 * y)).rgb;
+	vec3 j = texture(image, TexCoords + vec2(-x, y)).rgb;
+	vec3 k = texture(image, TexCoords + vec2(x, y)).rgb;
+	vec3 l = texture(image, TexCoords + vec2(-x, -y)).rgb;
+	vec3 m = texture(image, TexCoords + vec2(x, -y)).rgb;
+
+	vec3 color = e * 0.125;
+	color += (a + c + g + i) * 0.03125;
+	color += (b + d + f + h) * 0.0625;
+	color += (j + k + l + m) * 0.125;
+
+	if (prefilter != 0) {
+		float brightness = max(color.r, max(color.g, color.b));
+		color *= max(brightness - threshold, 0.0) / max(brightness, 0.0001);
+	}
+	FragColor = max(color, vec3(0.0001));
+}
@@ -0,0 +1,28 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec3 FragColor;
+
+uniform sampler2D image;
+uniform float filterRadius;
+
+// 3x3 tent filter, added onto the next larger mip with additive blending
+void main()
+{
+	float x = filterRadius;
+	float y = filterRadius;
+	vec3 a = texture(image, TexCoords + vec2(-x, y)).rgb;
+	vec3 b = texture(image, TexCoords + vec2(0.0, y)).rgb;
+	vec3 c = texture(image, TexCoords + vec2(x, y)).rgb;
+	vec3 d = texture(image, TexCoords + vec2(-x, 0.0)).rgb;
+	vec3 e = texture(image, TexCoords).rgb;
+	vec3 f = texture(image, TexCoords + vec2(x, 0.0)).rgb;
+	vec3 g = texture(image, TexCoords + vec2(-x, -y)).rgb;
+	vec3 h = texture(image, TexCoords + vec2(0.0, -y)).rgb;
+	vec3 i = texture(image, TexCoords + vec2(x, -y)).rgb;
+
+	vec3 color = e * 4.0;
+	color += (b + d + f + h) * 2.0;
+	color += (a + c + g + i);
+	FragColor = color / 16.0;
+}
@@ -0,0 +1,46 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D image;
+uniform vec2 texelSize;
+
+const float FXAA_SPAN_MAX = 8.0;
+const float FXAA_REDUCE_MUL = 1.0 / 8.0;
+const float FXAA_REDUCE_MIN = 1.0 / 128.0;
+
+float luma(vec3 color)
+{
+	return dot(color, vec3(0.299, 0.587, 0.114));
+}
+
+// FXAA 2 style: blur along the local edge direction. expects gamma encoded input
+void main()
+{
+	vec3 rgbNW = texture(image, TexCoords + vec2(-1.0, -1.0) * texelSize).rgb;
+	vec3 rgbNE = texture(image, TexCoords + vec2(1.0, -1.0) * texelSize).rgb;
+	vec3 rgbSW = texture(image, TexCoords + vec2(-1.0, 1.0) * texelSize).rgb;
+	vec3 rgbSE = texture(image, TexCoords + vec2(1.0, 1.0) * texelSize).rgb;
+	vec3 rgbM = texture(image, TexCoords).rgb;
+
+	float lumaNW = luma(rgbNW);
+	float lumaNE = luma(rgbNE);
+	float lumaSW = luma(rgbSW);
+	float lumaSE = luma(rgbSE);
+	float lumaM = luma(rgbM);
+	float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+	float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+	vec2 dir = vec2(-((lumaNW + lumaNE) - (lumaSW + lumaSE)), (lumaNW + lumaSW) - (lumaNE + lumaSE));
+	float dirReduce = max((lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+	float rcpDirMin = 1.0 / (min(abs(dir.x), abs(dir.y)) + dirReduce);
+	dir = clamp(dir * rcpDirMin, vec2(-FXAA_SPAN_MAX), vec2(FXAA_SPAN_MAX)) * texelSize;
+
+	vec3 rgbA = 0.5 * (texture(image, TexCoords + dir * (1.0 / 3.0 - 0.5)).rgb +
+	                   texture(image, TexCoords + dir * (2.0 / 3.0 - 0.5)).rgb);
+	vec3 rgbB = rgbA * 0.5 + 0.25 * (texture(image, TexCoords - dir * 0.5).rgb +
+	                                 texture(image, TexCoords + dir * 0.5).rgb);
+	float lumaB = luma(rgbB);
+	FragColor = vec4((lumaB < lumaMin || lumaB > lumaMax) ? rgbA : rgbB, 1.0);
+}
@@ -0,0 +1,18 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D image;
+uniform sampler3D lut;
+uniform float lutSize;
+uniform float lutStrength;
+
+void main()
+{
+	vec3 color = clamp(texture(image, TexCoords).rgb, 0.0, 1.0);
+	// sample texel centres so the ends of the LUT map onto 0 and 1
+	vec3 coord = color * ((lutSize - 1.0) / lutSize) + 0.5 / lutSize;
+	vec3 graded = texture(lut, coord).rgb;
+	FragColor = vec4(mix(color, graded, lutStrength), 1.0);
+}
@@ -0,0 +1,65 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D image;
+uniform sampler2D bloom;
+uniform int useBloom;
+uniform float bloomIntensity;
+uniform float exposure;
+uniform float gamma;
+// 0 none, 1 Reinhard, 2 ACES, 3 filmic. see ToneMapper
+uniform int toneMapper;
+
+vec3 reinhard(vec3 x)
+{
+	return x / (1.0 + x);
+}
+
+// Source: https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+vec3 aces(vec3 x)
+{
+	const float a = 2.51;
+	const float b = 0.03;
+	const float c = 2.43;
+	const float d = 0.59;
+	const float e = 0.14;
+	return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+// Uncharted 2. Source: http://filmicworlds.com/blog/filmic-tonemapping-operators/
+vec3 uncharted2(vec3 x)
+{
+	const float A = 0.15;
+	const float B = 0.50;
+	const float C = 0.10;
+	const float D = 0.20;
+	const float E = 0.02;
+	const float F = 0.30;
+	return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 filmic(vec3 x)
+{
+	const float W = 11.2;
+	return uncharted2(2.0 * x) / uncharted2(vec3(W));
+}
+
+void main()
+{
+	vec3 hdr = texture(image, TexCoords).rgb;
+	if (useBloom != 0)
+		hdr += texture(bloom, TexCoords).rgb * bloomIntensity;
+	hdr *= exposure;
+
+	vec3 mapped = hdr;
+	if (toneMapper == 1)
+		mapped = reinhard(hdr);
+	else if (toneMapper == 2)
+		mapped = aces(hdr);
+	else if (toneMapper == 3)
+		mapped = filmic(hdr);
+
+	FragColor = vec4(pow(mapped, vec3(1.0 / gamma)), 1.0);
+}
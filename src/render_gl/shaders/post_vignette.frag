@@ -0,0 +1,18 @@
+#version 330 core
+
+in vec2 TexCoords;
+out vec4 FragColor;
+
+uniform sampler2D image;
+uniform float vignetteStrength;
+// distance from the centre, in half screens, where darkening starts
+uniform float vignetteRadius;
+uniform float aspect;
+
+void main()
+{
+	vec3 color = texture(image, TexCoords).rgb;
+	vec2 offset = (TexCoords - 0.5) * vec2(aspect, 1.0) * 2.0;
+	float falloff = smoothstep(vignetteRadius, vignetteRadius + 0.75, length(offset));
+	FragColor = vec4(color * (1.0 - falloff * vignetteStrength), 1.0);
+}
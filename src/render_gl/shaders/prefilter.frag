@@ -0,0 +1,42 @@
+#version 330 core
+
+in vec3 LocalPos;
+out vec4 FragColor;
+
+#include "pbr.glsl"
+
+uniform samplerCube environmentMap;
+uniform float roughness;
+uniform float environmentSize;
+const uint sampleCount = 1024u;
+
+// split sum approximation, first half: the environment convolved with the
+// GGX lobe for this mip's roughness, assuming N = V = R
+void main()
+{
+	vec3 N = normalize(LocalPos);
+	vec3 V = N;
+
+	vec3 prefiltered = vec3(0.0);
+	float totalWeight = 0.0;
+	for (uint i = 0u; i < sampleCount; i++) {
+		vec2 Xi = hammersley(i, sampleCount);
+		vec3 H = importanceSampleGGX(Xi, N, roughness);
+		vec3 L = normalize(2.0 * dot(V, H) * H - V);
+		float NdotL = max(dot(N, L), 0.0);
+		if (NdotL > 0.0) {
+			// sample a blurrier mip of the source where the pdf is low, to avoid fireflies
+			float D = distributionGGX(N, H, roughness);
+			float NdotH = max(dot(N, H), 0.0);
+			float HdotV = max(dot(H, V), 0.0);
+			float pdf = D * NdotH / (4.0 * HdotV) + 0.0001;
+			float saTexel = 4.0 * PI / (6.0 * environmentSize * environmentSize);
+			float saSample = 1.0 / (float(sampleCount) * pdf + 0.0001);
+			float mip = roughness == 0.0 ? 0.0 : 0.5 * log2(saSample / saTexel);
+
+			prefiltered += textureLod(environmentMap, L, mip).rgb * NdotL;
+			totalWeight += NdotL;
+		}
+	}
+	FragColor = vec4(prefiltered / totalWeight, 1.0);
+}
@@ -0,0 +1,6 @@
+#version 330 core
+
+// depth is written by the rasteriser, nothing to do here
+void main()
+{
+}
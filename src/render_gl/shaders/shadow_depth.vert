@@ -0,0 +1,15 @@
+#version 330 core
+layout (location = 0) in vec3 aPosition;
+
+#include "instancing.glsl"
+
+uniform mat4 lightSpace;
+
+out vec3 FragPos;
+
+void main()
+{
+    vec4 world = modelMatrix() * vec4(aPosition, 1.0);
+    FragPos = world.xyz;
+    gl_Position = lightSpace * world;
+}
@@ -0,0 +1,12 @@
+#version 330 core
+
+in vec3 FragPos;
+
+uniform vec3 lightPos;
+uniform float farPlane;
+
+// cube shadow maps store the linear distance to the light, scaled to [0, 1]
+void main()
+{
+    gl_FragDepth = length(FragPos - lightPos) / farPlane;
+}
@@ -0,0 +1,116 @@
+// keep MAX_CASCADES in sync with render_gl/shadow.rs
+#define MAX_CASCADES 4
+
+// each kind of light has (at most) one shadow caster. the *ShadowLight
+// uniforms hold its index into the light arrays, or -1
+uniform sampler2DArray dirShadowMap;
+uniform mat4 dirLightSpace[MAX_CASCADES];
+// view space distance at which each cascade ends
+uniform float cascadeEnds[MAX_CASCADES];
+uniform int numCascades;
+uniform int dirShadowLight;
+
+uniform sampler2D spotShadowMap;
+uniform mat4 spotLightSpace;
+uniform int spotShadowLight;
+
+uniform samplerCube pointShadowMap;
+uniform float pointShadowFar;
+uniform int pointShadowLight;
+
+uniform mat4 shadowView;
+uniform float shadowBias;
+uniform float shadowSlopeBias;
+uniform int pcfRadius;
+
+// slope scaled: the more a surface faces away from the light, the more its
+// depth changes across one shadow map texel, so the depth stored for the
+// texel can be that much further from the point being shaded
+float shadowBiasFor(vec3 normal, vec3 lightDir)
+{
+	return max(shadowSlopeBias * (1.0 - dot(normal, lightDir)), shadowBias);
+}
+
+// the fraction of the (2 * pcfRadius + 1)^2 samples around projCoords that are lit
+float pcf2D(sampler2D map, vec3 projCoords, float bias)
+{
+	vec2 texel = 1.0 / vec2(textureSize(map, 0));
+	float lit = 0.0;
+	for (int x = -pcfRadius; x <= pcfRadius; x++) {
+		for (int y = -pcfRadius; y <= pcfRadius; y++) {
+			float closest = texture(map, projCoords.xy + vec2(x, y) * texel).r;
+			lit += projCoords.z - bias > closest ? 0.0 : 1.0;
+		}
+	}
+	float samples = float((2 * pcfRadius + 1) * (2 * pcfRadius + 1));
+	return lit / samples;
+}
+
+float pcfLayer(sampler2DArray map, int layer, vec3 projCoords, float bias)
+{
+	vec2 texel = 1.0 / vec2(textureSize(map, 0).xy);
+	float lit = 0.0;
+	for (int x = -pcfRadius; x <= pcfRadius; x++) {
+		for (int y = -pcfRadius; y <= pcfRadius; y++) {
+			float closest = texture(map, vec3(projCoords.xy + vec2(x, y) * texel, float(layer))).r;
+			lit += projCoords.z - bias > closest ? 0.0 : 1.0;
+		}
+	}
+	float samples = float((2 * pcfRadius + 1) * (2 * pcfRadius + 1));
+	return lit / samples;
+}
+
+vec3 toShadowCoords(mat4 lightSpace, vec3 fragPos)
+{
+	vec4 clip = lightSpace * vec4(fragPos, 1.0);
+	return (clip.xyz / clip.w) * 0.5 + 0.5;
+}
+
+// 1.0 is fully lit, 0.0 fully in shadow
+float dirShadow(vec3 fragPos, vec3 normal, vec3 lightDir)
+{
+	float depth = -(shadowView * vec4(fragPos, 1.0)).z;
+	int cascade = numCascades - 1;
+	for (int i = 0; i < numCascades; i++) {
+		if (depth < cascadeEnds[i]) {
+			cascade = i;
+			break;
+		}
+	}
+	vec3 projCoords = toShadowCoords(dirLightSpace[cascade], fragPos);
+	if (projCoords.z > 1.0)
+		return 1.0;
+	// further cascades cover more world space per texel, so need less bias
+	float bias = shadowBiasFor(normal, lightDir) / float(cascade + 1);
+	return pcfLayer(dirShadowMap, cascade, projCoords, bias);
+}
+
+float spotShadow(vec3 fragPos, vec3 normal, vec3 lightDir)
+{
+	vec3 projCoords = toShadowCoords(spotLightSpace, fragPos);
+	if (projCoords.z > 1.0)
+		return 1.0;
+	return pcf2D(spotShadowMap, projCoords, shadowBiasFor(normal, lightDir));
+}
+
+float pointShadow(vec3 fragPos, vec3 lightPos, vec3 normal, vec3 lightDir)
+{
+	vec3 toFrag = fragPos - lightPos;
+	float current = length(toFrag) / pointShadowFar;
+	float bias = shadowBiasFor(normal, lightDir);
+	// sample a small cube of directions around toFrag
+	float radius = (1.0 + current) * 0.01 * float(pcfRadius);
+	float lit = 0.0;
+	float samples = 0.0;
+	for (int x = -pcfRadius; x <= pcfRadius; x++) {
+		for (int y = -pcfRadius; y <= pcfRadius; y++) {
+			for (int z = -pcfRadius; z <= pcfRadius; z++) {
+				vec3 offset = vec3(x, y, z) * radius;
+				float closest = texture(pointShadowMap, toFrag + offset * length(toFrag)).r;
+				lit += current - bias > closest ? 0.0 : 1.0;
+				samples += 1.0;
+			}
+		}
+	}
+	return lit / samples;
+}
@@ -0,0 +1,51 @@
+#version 330 core
+
+in vec2 TexCoords;
+out float FragColor;
+
+// keep in sync with MAX_KERNEL_SIZE in render_gl/ssao.rs
+#define MAX_KERNEL_SIZE 64
+
+uniform sampler2D gPosition;
+uniform sampler2D gNormal;
+uniform sampler2D noiseTexture;
+
+uniform vec3 samples[MAX_KERNEL_SIZE];
+uniform int kernelSize;
+uniform float radius;
+uniform float bias;
+uniform float power;
+uniform vec2 noiseScale;
+uniform mat4 view;
+uniform mat4 projection;
+
+// Source: https://learnopengl.com/Advanced-Lighting/SSAO
+void main()
+{
+	vec4 normalShininess = texture(gNormal, TexCoords);
+	if (normalShininess.xyz == vec3(0.0)) {
+		FragColor = 1.0;
+		return;
+	}
+	// the G-buffer is in world space, occlusion is computed in view space
+	vec3 fragPos = (view * vec4(texture(gPosition, TexCoords).xyz, 1.0)).xyz;
+	vec3 normal = normalize(mat3(view) * normalShininess.xyz);
+	vec3 randomVec = normalize(texture(noiseTexture, TexCoords * noiseScale).xyz);
+
+	// Gram-Schmidt, so the kernel is rotated by the noise around the normal
+	vec3 tangent = normalize(randomVec - normal * dot(randomVec, normal));
+	vec3 bitangent = cross(normal, tangent);
+	mat3 TBN = mat3(tangent, bitangent, normal);
+
+	float occlusion = 0.0;
+	for (int i = 0; i < kernelSize; i++) {
+		vec3 samplePos = fragPos + TBN * samples[i] * radius;
+		vec4 offset = projection * vec4(samplePos, 1.0);
+		offset.xy = (offset.xy / offset.w) * 0.5 + 0.5;
+		// gPosition.w holds the positive view depth
+		float sampleDepth = -texture(gPosition, offset.xy).w;
+		float rangeCheck = smoothstep(0.0, 1.0, radius / abs(fragPos.z - sampleDepth));
+		occlusion += (sampleDepth >= samplePos.z + bias ? 1.0 : 0.0) * rangeCheck;
+	}
+	FragColor = pow(1.0 - occlusion / float(kernelSize), power);
+}
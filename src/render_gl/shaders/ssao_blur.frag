@@ -0,0 +1,30 @@
+#version 330 core
+
+in vec2 TexCoords;
+out float FragColor;
+
+uniform sampler2D ssaoInput;
+uniform sampler2D gPosition;
+uniform int blurRadius;
+// how quickly samples at a different depth stop contributing, in view units
+uniform float depthSigma;
+
+// bilateral blur: a box filter that ignores samples across depth
+// discontinuities, so occlusion doesn't bleed over silhouettes
+void main()
+{
+	vec2 texelSize = 1.0 / vec2(textureSize(ssaoInput, 0));
+	float centerDepth = texture(gPosition, TexCoords).w;
+	float result = 0.0;
+	float totalWeight = 0.0;
+	for (int x = -blurRadius; x <= blurRadius; x++) {
+		for (int y = -blurRadius; y <= blurRadius; y++) {
+			vec2 uv = TexCoords + vec2(float(x), float(y)) * texelSize;
+			float depthDelta = texture(gPosition, uv).w - centerDepth;
+			float weight = exp(-(depthDelta * depthDelta) / (2.0 * depthSigma * depthSigma));
+			result += texture(ssaoInput, uv).r * weight;
+			totalWeight += weight;
+		}
+	}
+	FragColor = result / max(totalWeight, 0.0001);
+}
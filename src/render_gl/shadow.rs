@@ -0,0 +1,500 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use crate::render_gl::debug::{self, DebugGroup};
+use crate::render_gl::instancing;
+use crate::render_gl::light::{LightSet, SpotLight};
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, to_radians, Program, Shader};
+use gl::types::*;
+use std::ffi::CString;
+
+// keep in sync with shaders/shadows.glsl
+pub const MAX_CASCADES: usize = 4;
+
+// texture units reserved for shadow maps. material textures start at unit 0
+pub const DIR_SHADOW_UNIT: u32 = 13;
+pub const SPOT_SHADOW_UNIT: u32 = 14;
+pub const POINT_SHADOW_UNIT: u32 = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowSettings {
+    // width and height of every shadow map (and each cascade / cube face)
+    pub resolution: u32,
+    pub cascades: usize,
+    // 0.0 splits the cascades uniformly, 1.0 logarithmically
+    pub split_lambda: f32,
+    // cascades only cover the view frustum up to this distance
+    pub shadow_distance: f32,
+    // shader side depth bias: max(slope_bias * (1 - N.L), bias)
+    pub bias: f32,
+    pub slope_bias: f32,
+    // rasteriser side bias, see glPolygonOffset
+    pub offset_factor: f32,
+    pub offset_units: f32,
+    // PCF samples a (2 * pcf_radius + 1)^2 texel neighbourhood. 0 disables filtering
+    pub pcf_radius: i32,
+    // depth range of the spot and point light shadow maps
+    pub near: f32,
+    pub far: f32
+}
+
+impl Default for ShadowSettings {
+    fn default() -> ShadowSettings {
+        ShadowSettings {
+            resolution: 2048,
+            cascades: 3,
+            split_lambda: 0.75,
+            shadow_distance: 50.0,
+            bias: 0.0005,
+            slope_bias: 0.005,
+            offset_factor: 1.5,
+            offset_units: 4.0,
+            pcf_radius: 1,
+            near: 0.1,
+            far: 25.0
+        }
+    }
+}
+
+// Returns count + 1 view space distances; cascade i covers [splits[i], splits[i + 1]].
+// Source: https://developer.nvidia.com/gpugems/GPUGems3/gpugems3_ch10.html
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (0..count + 1).map(|i| {
+        let p = i as f32 / count as f32;
+        let log = near * (far / near).powf(p);
+        let uniform = near + (far - near) * p;
+        lambda * log + (1.0 - lambda) * uniform
+    }).collect()
+}
+
+// world space corners of the volume a view projection matrix maps onto the NDC cube
+pub fn frustum_corners(view_projection: &Mat4) -> Vec<Vec3> {
+    let inv = inverse(view_projection);
+    let mut corners = Vec::with_capacity(8);
+    for &x in [-1.0, 1.0].iter() {
+        for &y in [-1.0, 1.0].iter() {
+            for &z in [-1.0, 1.0].iter() {
+                let p = inv * make_vec4(&[x, y, z, 1.0]);
+                corners.push(make_vec3(&[p.x / p.w, p.y / p.w, p.z / p.w]));
+            }
+        }
+    }
+    corners
+}
+
+// an orthographic light space matrix covering `corners`. The box is fitted
+// around the bounding sphere of the corners and snapped to whole texels so
+// the shadow edges don't shimmer when the camera moves or turns
+pub fn directional_light_matrix(direction: &Vec3, corners: &[Vec3], resolution: u32) -> Mat4 {
+    let direction = normalize(direction);
+    let mut center = make_vec3(&[0.0, 0.0, 0.0]);
+    for c in corners {
+        center += c;
+    }
+    center /= corners.len() as f32;
+    let mut radius = corners.iter().map(|c| distance(c, &center)).fold(0.0, f32::max);
+    radius = (radius * 16.0).ceil() / 16.0;
+
+    let up = if direction.y.abs() > 0.99 { make_vec3(&[0.0, 0.0, 1.0]) } else { make_vec3(&[0.0, 1.0, 0.0]) };
+    // pull the eye back so casters between the light and the slice still land in the map
+    let eye = center - direction * (radius * 2.0);
+    let view = look_at(&eye, &center, &up);
+    let mut projection = ortho(-radius, radius, -radius, radius, 0.0, radius * 3.0);
+
+    let origin = projection * view * make_vec4(&[0.0, 0.0, 0.0, 1.0]);
+    let half = resolution as f32 * 0.5;
+    let offset_x = ((origin.x * half).round() - origin.x * half) / half;
+    let offset_y = ((origin.y * half).round() - origin.y * half) / half;
+    projection[(0, 3)] += offset_x;
+    projection[(1, 3)] += offset_y;
+    projection * view
+}
+
+pub fn cascade_matrices(camera: &Camera, direction: &Vec3, splits: &[f32], resolution: u32) -> Vec<Mat4> {
+    let view = camera.look_at();
+    splits.windows(2).map(|range| {
+        let projection = perspective(camera.aspect(), to_radians(camera.fov()), range[0], range[1]);
+        directional_light_matrix(direction, &frustum_corners(&(projection * view)), resolution)
+    }).collect()
+}
+
+pub fn spot_light_matrix(light: &SpotLight, near: f32, far: f32) -> Mat4 {
+    let up = if light.direction.y.abs() > 0.99 { make_vec3(&[0.0, 0.0, 1.0]) } else { make_vec3(&[0.0, 1.0, 0.0]) };
+    let view = look_at(&light.position, &(light.position + light.direction), &up);
+    perspective(1.0, to_radians(light.outer_cutoff * 2.0), near, far) * view
+}
+
+// one matrix per cube face, in GL_TEXTURE_CUBE_MAP_POSITIVE_X + i order
+pub fn point_light_matrices(position: &Vec3, near: f32, far: f32) -> Vec<Mat4> {
+    let projection = perspective(1.0, to_radians(90.0), near, far);
+    let faces = [
+        ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+        ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+    ];
+    faces.iter().map(|(dir, up)| {
+        projection * look_at(position, &(position + make_vec3(dir)), &make_vec3(up))
+    }).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowMapKind {
+    Single,
+    Cascaded(usize),
+    Cube
+}
+
+// a depth only framebuffer and the depth texture it renders into
+pub struct ShadowMap {
+    kind: ShadowMapKind,
+    resolution: u32,
+    fbo: GLuint,
+    texture: GLuint
+}
+
+impl ShadowMap {
+    pub fn new(kind: ShadowMapKind, resolution: u32) -> Result<ShadowMap, String> {
+        let size = resolution as GLsizei;
+        let mut texture: GLuint = 0;
+        let mut fbo: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            let target = target_of(kind);
+            state::bind_texture(target, texture);
+            match kind {
+                ShadowMapKind::Single => {
+                    gl::TexImage2D(gl::TEXTURE_2D, 0, gl::DEPTH_COMPONENT24 as GLint, size, size, 0,
+                                   gl::DEPTH_COMPONENT, gl::FLOAT, std::ptr::null());
+                }
+                ShadowMapKind::Cascaded(layers) => {
+                    gl::TexImage3D(gl::TEXTURE_2D_ARRAY, 0, gl::DEPTH_COMPONENT24 as GLint, size, size,
+                                   layers as GLsizei, 0, gl::DEPTH_COMPONENT, gl::FLOAT, std::ptr::null());
+                }
+                ShadowMapKind::Cube => {
+                    for face in 0..6 {
+                        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + face, 0, gl::DEPTH_COMPONENT24 as GLint,
+                                       size, size, 0, gl::DEPTH_COMPONENT, gl::FLOAT, std::ptr::null());
+                    }
+                }
+            }
+            // filtering is done by hand (PCF) in shadows.glsl
+            gl::TexParameteri(target, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(target, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            if kind == ShadowMapKind::Cube {
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
+            } else {
+                // everything outside the map counts as lit
+                let border: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+                gl::TexParameterfv(target, gl::TEXTURE_BORDER_COLOR, border.as_ptr());
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_BORDER as i32);
+                gl::TexParameteri(target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_BORDER as i32);
+            }
+            state::bind_texture(target, 0);
+
+            gl::GenFramebuffers(1, &mut fbo);
+        }
+        let name = match kind {
+            ShadowMapKind::Single => "shadow map",
+            ShadowMapKind::Cascaded(_) => "cascaded shadow map",
+            ShadowMapKind::Cube => "cube shadow map"
+        };
+        debug::label_texture(texture, name);
+        let map = ShadowMap { kind: kind, resolution: resolution, fbo: fbo, texture: texture };
+        map.bind_layer(0);
+        let status = unsafe {
+            gl::DrawBuffer(gl::NONE);
+            gl::ReadBuffer(gl::NONE);
+            gl::CheckFramebufferStatus(gl::FRAMEBUFFER)
+        };
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        debug::label(gl::FRAMEBUFFER, fbo, name);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("shadow map framebuffer incomplete: 0x{:x}", status));
+        }
+        Ok(map)
+    }
+
+    pub fn kind(&self) -> ShadowMapKind {
+        self.kind
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    pub fn target(&self) -> GLenum {
+        target_of(self.kind)
+    }
+
+    // binds the framebuffer with `layer` (cascade or cube face) as the depth
+    // attachment, sets the viewport and clears it
+    pub fn begin_layer(&self, layer: usize) {
+        self.bind_layer(layer);
+        unsafe {
+            state::viewport(0, 0, self.resolution as GLsizei, self.resolution as GLsizei);
+            gl::Clear(gl::DEPTH_BUFFER_BIT);
+        }
+    }
+
+    fn bind_layer(&self, layer: usize) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo);
+            match self.kind {
+                ShadowMapKind::Single => {
+                    gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::TEXTURE_2D, self.texture, 0);
+                }
+                ShadowMapKind::Cascaded(_) => {
+                    gl::FramebufferTextureLayer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, self.texture, 0, layer as GLint);
+                }
+                ShadowMapKind::Cube => {
+                    gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT,
+                                             gl::TEXTURE_CUBE_MAP_POSITIVE_X + layer as GLenum, self.texture, 0);
+                }
+            }
+        }
+    }
+}
+
+fn target_of(kind: ShadowMapKind) -> GLenum {
+    match kind {
+        ShadowMapKind::Single => gl::TEXTURE_2D,
+        ShadowMapKind::Cascaded(_) => gl::TEXTURE_2D_ARRAY,
+        ShadowMapKind::Cube => gl::TEXTURE_CUBE_MAP
+    }
+}
+
+impl Drop for ShadowMap {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.fbo);
+            state::delete_texture(self.texture);
+        }
+    }
+}
+
+// renders the shadow maps for the shadow casting lights of a LightSet and
+// hands them to the lighting shaders (see shaders/shadows.glsl)
+pub struct ShadowRenderer {
+    pub settings: ShadowSettings,
+    depth_program: Program,
+    point_program: Program,
+    dir_map: Option<ShadowMap>,
+    spot_map: Option<ShadowMap>,
+    point_map: Option<ShadowMap>,
+    dir_matrices: Vec<Mat4>,
+    splits: Vec<f32>,
+    spot_matrix: Mat4,
+    view: Mat4
+}
+
+impl ShadowRenderer {
+    pub fn new(settings: ShadowSettings) -> Result<ShadowRenderer, String> {
+        let vert = || Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/shadow_depth.vert"))?);
+        let depth_program = Program::from_shaders(&[
+            vert()?,
+            Shader::from_frag_source(&CString::new(include_str!("shaders/shadow_depth.frag")).unwrap())?
+        ])?;
+        let point_program = Program::from_shaders(&[
+            vert()?,
+            Shader::from_frag_source(&CString::new(include_str!("shaders/shadow_point.frag")).unwrap())?
+        ])?;
+        depth_program.set_label("shadow depth");
+        point_program.set_label("shadow point depth");
+        Ok(ShadowRenderer {
+            settings: settings,
+            depth_program: depth_program,
+            point_program: point_program,
+            dir_map: None,
+            spot_map: None,
+            point_map: None,
+            dir_matrices: Vec::new(),
+            splits: Vec::new(),
+            spot_matrix: Mat4::identity(),
+            view: Mat4::identity()
+        })
+    }
+
+    // `draw` is called once per cascade / face with the depth program in
+    // use; it should set the "model" uniform and draw every shadow caster,
+    // or draw them instanced after instancing::set_instanced(program, true)
+    pub fn render(&mut self, camera: &Camera, lights: &LightSet, draw: &mut dyn FnMut(&Program)) -> Result<(), String> {
+        lights.check_shadow_casters()?;
+        let _group = DebugGroup::new("shadows");
+        let viewport = state::current_viewport();
+        PipelineState::opaque().apply();
+        unsafe {
+            state::enable(gl::POLYGON_OFFSET_FILL);
+            gl::PolygonOffset(self.settings.offset_factor, self.settings.offset_units);
+        }
+        let result = self.render_maps(camera, lights, draw);
+        unsafe {
+            state::disable(gl::POLYGON_OFFSET_FILL);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            state::viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+        }
+        result
+    }
+
+    fn render_maps(&mut self, camera: &Camera, lights: &LightSet, draw: &mut dyn FnMut(&Program)) -> Result<(), String> {
+        let s = self.settings.clone();
+        self.view = camera.look_at();
+
+        if let Some(i) = lights.directional_shadow_caster() {
+            let _group = DebugGroup::new("directional");
+            let cascades = s.cascades.max(1).min(MAX_CASCADES);
+            let map = ensure_map(&mut self.dir_map, ShadowMapKind::Cascaded(cascades), s.resolution)?;
+            let far = s.shadow_distance.min(camera.far());
+            self.splits = cascade_splits(camera.near(), far, cascades, s.split_lambda);
+            self.dir_matrices = cascade_matrices(camera, &lights.directional[i].direction, &self.splits, s.resolution);
+            self.depth_program.set_used();
+            instancing::set_instanced(&self.depth_program, false);
+            for (layer, matrix) in self.dir_matrices.iter().enumerate() {
+                map.begin_layer(layer);
+                self.depth_program.set_uniform_mat4("lightSpace", matrix);
+                draw(&self.depth_program);
+            }
+        }
+
+        if let Some(i) = lights.spot_shadow_caster() {
+            let _group = DebugGroup::new("spot");
+            let light = &lights.spot[i];
+            let map = ensure_map(&mut self.spot_map, ShadowMapKind::Single, s.resolution)?;
+            self.spot_matrix = spot_light_matrix(light, s.near, s.far);
+            self.depth_program.set_used();
+            instancing::set_instanced(&self.depth_program, false);
+            map.begin_layer(0);
+            self.depth_program.set_uniform_mat4("lightSpace", &self.spot_matrix);
+            draw(&self.depth_program);
+        }
+
+        if let Some(i) = lights.point_shadow_caster() {
+            let _group = DebugGroup::new("point");
+            let position = lights.point[i].position.clone();
+            let map = ensure_map(&mut self.point_map, ShadowMapKind::Cube, s.resolution)?;
+            self.point_program.set_used();
+            instancing::set_instanced(&self.point_program, false);
+            self.point_program.set_uniform_vec3("lightPos", &position);
+            self.point_program.set_uniform_1f("farPlane", s.far);
+            for (face, matrix) in point_light_matrices(&position, s.near, s.far).iter().enumerate() {
+                map.begin_layer(face);
+                self.point_program.set_uniform_mat4("lightSpace", matrix);
+                draw(&self.point_program);
+            }
+        }
+        Ok(())
+    }
+
+    // uploads the matrices and bias settings and binds the maps to their
+    // reserved units. the lighting program must be in use
+    pub fn apply(&self, program: &Program) {
+        let s = &self.settings;
+        program.set_uniform_mat4("shadowView", &self.view);
+        program.set_uniform_1f("shadowBias", s.bias);
+        program.set_uniform_1f("shadowSlopeBias", s.slope_bias);
+        program.set_uniform_1i("pcfRadius", s.pcf_radius);
+        program.set_uniform_1i("numCascades", self.dir_matrices.len() as i32);
+        for (i, matrix) in self.dir_matrices.iter().enumerate() {
+            program.set_uniform_mat4(&format!("dirLightSpace[{}]", i), matrix);
+            program.set_uniform_1f(&format!("cascadeEnds[{}]", i), self.splits[i + 1]);
+        }
+        program.set_uniform_mat4("spotLightSpace", &self.spot_matrix);
+        program.set_uniform_1f("pointShadowFar", s.far);
+
+        let maps = [(&self.dir_map, DIR_SHADOW_UNIT), (&self.spot_map, SPOT_SHADOW_UNIT), (&self.point_map, POINT_SHADOW_UNIT)];
+        for (map, unit) in maps.iter() {
+            if let Some(map) = map {
+                state::active_texture(gl::TEXTURE0 + unit);
+                state::bind_texture(map.target(), map.texture());
+            }
+        }
+        state::active_texture(gl::TEXTURE0);
+    }
+}
+
+// (re)creates the map when the settings asked for a different layout
+fn ensure_map(slot: &mut Option<ShadowMap>, kind: ShadowMapKind, resolution: u32) -> Result<&ShadowMap, String> {
+    let stale = match slot {
+        Some(map) => map.kind != kind || map.resolution != resolution,
+        None => true
+    };
+    if stale {
+        *slot = Some(ShadowMap::new(kind, resolution)?);
+    }
+    Ok(slot.as_ref().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_ndc(m: &Mat4, p: &Vec3) -> Vec3 {
+        let clip = m * make_vec4(&[p.x, p.y, p.z, 1.0]);
+        make_vec3(&[clip.x / clip.w, clip.y / clip.w, clip.z / clip.w])
+    }
+
+    #[test]
+    fn splits_span_near_to_far() {
+        let splits = cascade_splits(0.1, 100.0, 4, 0.75);
+        assert_eq!(splits.len(), 5);
+        assert!((splits[0] - 0.1).abs() < 1e-5);
+        assert!((splits[4] - 100.0).abs() < 1e-3);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        let uniform = cascade_splits(10.0, 100.0, 3, 0.0);
+        assert_eq!(uniform, vec![10.0, 40.0, 70.0, 100.0]);
+    }
+
+    #[test]
+    fn directional_matrix_contains_the_slice() {
+        let camera = Camera::new(&make_vec3(&[0.0, 2.0, 5.0]), &make_vec3(&[0.0, 0.0, -1.0]),
+                                 &make_vec3(&[0.0, 1.0, 0.0]), 0.3);
+        let direction = make_vec3(&[-0.2, -1.0, -0.3]);
+        let splits = cascade_splits(camera.near(), 30.0, 3, 0.75);
+        let matrices = cascade_matrices(&camera, &direction, &splits, 1024);
+        assert_eq!(matrices.len(), 3);
+        for (i, m) in matrices.iter().enumerate() {
+            let slice = perspective(camera.aspect(), to_radians(camera.fov()), splits[i], splits[i + 1]);
+            for corner in frustum_corners(&(slice * camera.look_at())) {
+                let ndc = to_ndc(m, &corner);
+                assert!(ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0 && ndc.z.abs() <= 1.0, "{:?}", ndc);
+            }
+        }
+    }
+
+    #[test]
+    fn directional_matrix_is_texel_snapped() {
+        let corners = frustum_corners(&ortho(-3.3, 3.1, -2.0, 2.7, -1.0, 5.0));
+        let m = directional_light_matrix(&make_vec3(&[0.3, -1.0, 0.2]), &corners, 512);
+        let origin = m * make_vec4(&[0.0, 0.0, 0.0, 1.0]);
+        let texels = origin.x * 256.0;
+        assert!((texels - texels.round()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn spot_matrix_looks_down_the_cone() {
+        let light = SpotLight::new(&make_vec3(&[1.0, 2.0, 3.0]), &make_vec3(&[0.0, -1.0, -1.0]),
+                                   &make_vec3(&[1.0, 1.0, 1.0]), 50.0);
+        let m = spot_light_matrix(&light, 0.1, 50.0);
+        let ndc = to_ndc(&m, &(light.position + light.direction * 5.0));
+        assert!(ndc.x.abs() < 1e-4 && ndc.y.abs() < 1e-4);
+        assert!(ndc.z > -1.0 && ndc.z < 1.0);
+    }
+
+    #[test]
+    fn point_matrices_face_each_axis() {
+        let position = make_vec3(&[1.0, 1.0, 1.0]);
+        let matrices = point_light_matrices(&position, 0.1, 25.0);
+        let axes = [[3.0, 0.0, 0.0], [-3.0, 0.0, 0.0], [0.0, 3.0, 0.0], [0.0, -3.0, 0.0], [0.0, 0.0, 3.0], [0.0, 0.0, -3.0]];
+        for (m, axis) in matrices.iter().zip(axes.iter()) {
+            let ndc = to_ndc(m, &(position + make_vec3(axis)));
+            assert!(ndc.x.abs() < 1e-4 && ndc.y.abs() < 1e-4 && ndc.z.abs() < 1.0);
+        }
+    }
+}
@@ -1,7 +1,3 @@
-use crate::render_gl::to_radians;
-use rayon::prelude::*;
-use std::ops;
-
 //Source: http://www.songho.ca/opengl/gl_sphere.html
 //Returns an interleaved array with vertex and normal co-ordinates 
 //for each vertex and a vertex array with the index of co-ordinates
@@ -18,43 +14,57 @@ pub fn generate_sphere(sectors: u32, stacks: u32, radius: f32, normalized: bool)
     // a stack needs 2 sets of points. Ergo
     // n stacks need n+1 sets of points
     // similarly for sectors
-    (0.. stacks+1).into_par_iter()
-        .for_each(|stack| {
-            let stack_angle = (3.14/2.0) - ((stack as f32) * stack_step);
-            let xz = radius * stack_angle.cos();
-            let y  = radius * stack_angle.sin();
-            for j in 0..(sectors+1) {
-                let sector_angle = (j as f32) * sector_step;
-                let z = xz *  sector_angle.cos();
-                let x  = xz * sector_angle.sin();
-                if normalized {
-                    vertices.push(x * radius_inv);
-                    vertices.push(y * radius_inv);
-                    vertices.push(z * radius_inv);
-                } else {
-                    vertices.push(x);
-                    vertices.push(y);
-                    vertices.push(z);
-                }
-                let normal_x = x * radius_inv;
-                let normal_y = y * radius_inv;
-                let normal_z = z * radius_inv;
-                vertices.push(normal_x);
-                vertices.push(normal_y);
-                vertices.push(normal_z);
+    for stack in 0..(stacks+1) {
+        let stack_angle = (3.14/2.0) - ((stack as f32) * stack_step);
+        let xz = radius * stack_angle.cos();
+        let y  = radius * stack_angle.sin();
+        for j in 0..(sectors+1) {
+            let sector_angle = (j as f32) * sector_step;
+            let z = xz *  sector_angle.cos();
+            let x  = xz * sector_angle.sin();
+            if normalized {
+                vertices.push(x * radius_inv);
+                vertices.push(y * radius_inv);
+                vertices.push(z * radius_inv);
+            } else {
+                vertices.push(x);
+                vertices.push(y);
+                vertices.push(z);
             }
-        });
+            let normal_x = x * radius_inv;
+            let normal_y = y * radius_inv;
+            let normal_z = z * radius_inv;
+            vertices.push(normal_x);
+            vertices.push(normal_y);
+            vertices.push(normal_z);
+        }
+    }
     let indices = generate_indices(sectors, stacks);
     (vertices, indices)
 }
 
+// same layout as generate_sphere plus texture co-ordinates, i.e
+// position, normal, uv (8 floats per vertex)
+pub fn generate_uv_sphere(sectors: u32, stacks: u32, radius: f32) -> (Vec<f32>, Vec<u32>) {
+    let (with_normals, indices) = generate_sphere(sectors, stacks, radius, false);
+    let mut vertices: Vec<f32> = Vec::with_capacity(with_normals.len() / 6 * 8);
+    for (i, v) in with_normals.chunks(6).enumerate() {
+        let stack = i as u32 / (sectors + 1);
+        let sector = i as u32 % (sectors + 1);
+        vertices.extend_from_slice(v);
+        vertices.push(sector as f32 / sectors as f32);
+        vertices.push(stack as f32 / stacks as f32);
+    }
+    (vertices, indices)
+}
+
 pub fn generate_indices(sectors: u32, stacks: u32) -> Vec<u32> {
     let mut vertex_indices: Vec<u32> = Vec::new();
     let mut triangles = 0;
     for i in 0..stacks {
         let mut k1 = i * (sectors + 1) ;
         let mut k2 = k1 + sectors + 1;
-        for j in 0..sectors {
+        for _ in 0..sectors {
            if i != 0 {
              vertex_indices.push(k1);
              vertex_indices.push(k2);
@@ -71,7 +81,7 @@ pub fn generate_indices(sectors: u32, stacks: u32) -> Vec<u32> {
            k2 += 1;
         }
     }
-    println!("num triangles pushed is {}", triangles);
+    log::debug!("num triangles pushed is {}", triangles);
     vertex_indices
 }
 
@@ -0,0 +1,301 @@
+use crate::glm::*;
+use crate::render_gl::camera::Camera;
+use crate::render_gl::debug::{self, DebugGroup};
+use crate::render_gl::framebuffer::{ColorFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::PipelineState;
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+
+// keep in sync with shaders/ssao.frag
+pub const MAX_KERNEL_SIZE: usize = 64;
+
+// texture unit the occlusion map is bound to for the lighting shaders
+pub const SSAO_UNIT: u32 = 9;
+
+// the noise texture is tiled over the screen, one rotation per texel
+pub const NOISE_SIZE: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsaoSettings {
+    // hemisphere radius in world units
+    pub radius: f32,
+    // depth offset that stops flat surfaces from occluding themselves
+    pub bias: f32,
+    // kernel samples, at most MAX_KERNEL_SIZE
+    pub samples: usize,
+    // >1 darkens the occlusion
+    pub power: f32,
+    // the blur covers (2 * blur_radius + 1)^2 texels. NOISE_SIZE / 2 hides the noise pattern
+    pub blur_radius: i32,
+    pub blur_depth_sigma: f32,
+    pub seed: u32
+}
+
+impl Default for SsaoSettings {
+    fn default() -> SsaoSettings {
+        SsaoSettings {
+            radius: 0.5,
+            bias: 0.025,
+            samples: 32,
+            power: 1.0,
+            blur_radius: 2,
+            blur_depth_sigma: 0.5,
+            seed: 0x5eed
+        }
+    }
+}
+
+// xorshift32. the kernel and noise only need to look random, but the same
+// seed has to give the same image, so no thread_rng
+pub struct Rng {
+    state: u32
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Rng {
+        // xorshift gets stuck at 0
+        Rng { state: if seed == 0 { 0x9e37_79b9 } else { seed } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    // in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+// sample offsets in a unit hemisphere around +z, denser towards the centre
+// so occluders close to the fragment count more
+pub fn generate_kernel(samples: usize, rng: &mut Rng) -> Vec<Vec3> {
+    (0..samples).map(|i| {
+        let direction = make_vec3(&[
+            rng.next_f32() * 2.0 - 1.0,
+            rng.next_f32() * 2.0 - 1.0,
+            rng.next_f32()]);
+        let length = direction.norm();
+        let direction = if length > 0.0 { direction / length } else { make_vec3(&[0.0, 0.0, 1.0]) };
+        let t = i as f32 / samples as f32;
+        let scale = 0.1 + 0.9 * t * t;
+        direction * rng.next_f32() * scale
+    }).collect()
+}
+
+// random rotations around the normal (z is always 0)
+pub fn generate_noise(rng: &mut Rng) -> Vec<Vec3> {
+    (0..NOISE_SIZE * NOISE_SIZE).map(|_| {
+        make_vec3(&[rng.next_f32() * 2.0 - 1.0, rng.next_f32() * 2.0 - 1.0, 0.0])
+    }).collect()
+}
+
+// computes ambient occlusion from the G-buffer written by
+// deferred::DeferredRenderer, blurs it and hands it to the lighting shaders
+pub struct Ssao {
+    pub settings: SsaoSettings,
+    kernel: Vec<Vec3>,
+    kernel_seed: u32,
+    noise_texture: GLuint,
+    occlusion: Framebuffer,
+    blurred: Framebuffer,
+    ssao_program: Program,
+    blur_program: Program,
+    quad: Mesh
+}
+
+impl Ssao {
+    pub fn new(width: u32, height: u32, settings: SsaoSettings) -> Result<Ssao, String> {
+        let fullscreen = |name: &str, source: &str| -> Result<Program, String> {
+            let program = Program::from_shaders(&[
+                Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/fullscreen.vert"))?)?,
+                Shader::from_frag_source(&shader_lib::preprocess(source)?)?
+            ])?;
+            program.set_label(name);
+            Ok(program)
+        };
+        let mut rng = Rng::new(settings.seed);
+        let noise: Vec<f32> = generate_noise(&mut rng).iter().flat_map(|v| vec![v.x, v.y, v.z]).collect();
+        let mut noise_texture: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut noise_texture);
+            state::bind_texture(gl::TEXTURE_2D, noise_texture);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB16F as GLint, NOISE_SIZE as GLsizei, NOISE_SIZE as GLsizei, 0,
+                           gl::RGB, gl::FLOAT, noise.as_ptr() as *const GLvoid);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+            state::bind_texture(gl::TEXTURE_2D, 0);
+        }
+        debug::label_texture(noise_texture, "ssao noise");
+        let mut occlusion = Framebuffer::new(FramebufferDesc::new(width, height).color(ColorFormat::R8))?;
+        occlusion.set_label("ssao");
+        let mut blurred = Framebuffer::new(FramebufferDesc::new(width, height).color(ColorFormat::R8))?;
+        blurred.set_label("ssao blurred");
+        Ok(Ssao {
+            kernel: Vec::new(),
+            kernel_seed: settings.seed,
+            settings: settings,
+            noise_texture: noise_texture,
+            occlusion: occlusion,
+            blurred: blurred,
+            ssao_program: fullscreen("ssao", include_str!("shaders/ssao.frag"))?,
+            blur_program: fullscreen("ssao blur", include_str!("shaders/ssao_blur.frag"))?,
+            quad: Mesh::quad()
+        })
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.occlusion.resize(width, height)?;
+        self.blurred.resize(width, height)?;
+        Ok(())
+    }
+
+    // the blurred occlusion, 1.0 meaning unoccluded
+    pub fn texture(&self) -> GLuint {
+        self.blurred.color_texture(0)
+    }
+
+    pub fn kernel(&self) -> &[Vec3] {
+        &self.kernel
+    }
+
+    // `gbuffer` is the DeferredRenderer G-buffer. the settings may change
+    // between frames; the kernel is regenerated when the sample count does
+    pub fn render(&mut self, gbuffer: &Framebuffer, camera: &Camera) {
+        let _group = DebugGroup::new("ssao");
+        let samples = self.settings.samples.max(1).min(MAX_KERNEL_SIZE);
+        if self.kernel.len() != samples || self.kernel_seed != self.settings.seed {
+            self.kernel = generate_kernel(samples, &mut Rng::new(self.settings.seed));
+            self.kernel_seed = self.settings.seed;
+        }
+        let viewport = state::current_viewport();
+        PipelineState::fullscreen().apply();
+
+        let s = &self.settings;
+        let p = &self.ssao_program;
+        self.occlusion.begin();
+        p.set_used();
+        p.set_uniform_1i("gPosition", 0);
+        p.set_uniform_1i("gNormal", 1);
+        p.set_uniform_1i("noiseTexture", 2);
+        p.set_uniform_1i("kernelSize", self.kernel.len() as i32);
+        p.set_uniform_1f("radius", s.radius);
+        p.set_uniform_1f("bias", s.bias);
+        p.set_uniform_1f("power", s.power);
+        p.set_uniform_vec2("noiseScale", &make_vec2(&[
+            self.occlusion.width() as f32 / NOISE_SIZE as f32,
+            self.occlusion.height() as f32 / NOISE_SIZE as f32]));
+        p.set_uniform_mat4("view", &camera.look_at());
+        p.set_uniform_mat4("projection", &camera.projection());
+        for (i, sample) in self.kernel.iter().enumerate() {
+            p.set_uniform_vec3(&format!("samples[{}]", i), sample);
+        }
+        bind_textures(&[gbuffer.color_texture(0), gbuffer.color_texture(1), self.noise_texture]);
+        self.quad.draw();
+
+        let _blur = DebugGroup::new("ssao blur");
+        let p = &self.blur_program;
+        self.blurred.begin();
+        p.set_used();
+        p.set_uniform_1i("ssaoInput", 0);
+        p.set_uniform_1i("gPosition", 1);
+        p.set_uniform_1i("blurRadius", s.blur_radius);
+        p.set_uniform_1f("depthSigma", s.blur_depth_sigma);
+        bind_textures(&[self.occlusion.color_texture(0), gbuffer.color_texture(0)]);
+        self.quad.draw();
+
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            state::viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+        }
+        PipelineState::opaque().apply();
+    }
+}
+
+impl Drop for Ssao {
+    fn drop(&mut self) {
+        state::delete_texture(self.noise_texture);
+    }
+}
+
+// binds the occlusion map for a program that includes lights.glsl, or
+// turns ambient occlusion off for it. the program must be in use
+pub fn apply_ssao(program: &Program, ssao: Option<&Ssao>) {
+    program.set_uniform_1i("ssaoMap", SSAO_UNIT as i32);
+    program.set_uniform_1i("useSsao", ssao.is_some() as i32);
+    if let Some(ssao) = ssao {
+        state::active_texture(gl::TEXTURE0 + SSAO_UNIT);
+        state::bind_texture(gl::TEXTURE_2D, ssao.texture());
+        state::active_texture(gl::TEXTURE0);
+    }
+}
+
+fn bind_textures(textures: &[GLuint]) {
+    for (unit, texture) in textures.iter().enumerate() {
+        state::active_texture(gl::TEXTURE0 + unit as GLuint);
+        state::bind_texture(gl::TEXTURE_2D, *texture);
+    }
+    state::active_texture(gl::TEXTURE0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::{pbr, shadow};
+
+    #[test]
+    fn kernel_fills_the_hemisphere() {
+        let kernel = generate_kernel(64, &mut Rng::new(1));
+        assert_eq!(kernel.len(), 64);
+        for sample in &kernel {
+            assert!(sample.z >= 0.0);
+            assert!(sample.norm() <= 1.0);
+        }
+        // samples are scaled up along the kernel
+        let near: f32 = kernel[..16].iter().map(|s| s.norm()).sum();
+        let far: f32 = kernel[48..].iter().map(|s| s.norm()).sum();
+        assert!(far > near * 2.0);
+    }
+
+    #[test]
+    fn kernel_is_deterministic() {
+        assert_eq!(generate_kernel(16, &mut Rng::new(7)), generate_kernel(16, &mut Rng::new(7)));
+        assert_ne!(generate_kernel(16, &mut Rng::new(7)), generate_kernel(16, &mut Rng::new(8)));
+        // a zero seed still produces samples
+        assert!(generate_kernel(4, &mut Rng::new(0)).iter().any(|s| s.norm() > 0.0));
+    }
+
+    #[test]
+    fn noise_rotates_around_z() {
+        let noise = generate_noise(&mut Rng::new(3));
+        assert_eq!(noise.len(), (NOISE_SIZE * NOISE_SIZE) as usize);
+        for v in &noise {
+            assert_eq!(v.z, 0.0);
+            assert!(v.x.abs() <= 1.0 && v.y.abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn random_floats_stay_in_range() {
+        let mut rng = Rng::new(42);
+        for _ in 0..10000 {
+            let f = rng.next_f32();
+            assert!(f >= 0.0 && f < 1.0);
+        }
+    }
+
+    #[test]
+    fn kernel_limit_matches_the_shader() {
+        assert!(include_str!("shaders/ssao.frag").contains(&format!("#define MAX_KERNEL_SIZE {}", MAX_KERNEL_SIZE)));
+        assert!(SSAO_UNIT < pbr::IRRADIANCE_UNIT && SSAO_UNIT < shadow::DIR_SHADOW_UNIT);
+    }
+}
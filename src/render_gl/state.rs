@@ -0,0 +1,414 @@
+use gl::types::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// shadows the GL state render code changes most often and drops calls that
+// wouldn't change anything. everything starts out unknown, so the first
+// call after creation or reset() always reaches GL.
+//
+// the free functions below work on the cache of the current thread, which
+// is the one for the context current on it. code that changes this state
+// behind the cache's back, e.g another library sharing the context, has to
+// call reset() before handing control back
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateCounters {
+    pub issued: u32,
+    pub skipped: u32
+}
+
+impl StateCounters {
+    pub fn total(&self) -> u32 {
+        self.issued + self.skipped
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StateCache {
+    program: Option<GLuint>,
+    vertex_array: Option<GLuint>,
+    active_unit: Option<u32>,
+    // (unit, target) -> texture; missing means unknown
+    textures: BTreeMap<(u32, GLenum), GLuint>,
+    capabilities: BTreeMap<GLenum, bool>,
+    // rgb source, rgb destination, alpha source, alpha destination
+    blend_func: Option<(GLenum, GLenum, GLenum, GLenum)>,
+    blend_equation: Option<GLenum>,
+    depth_func: Option<GLenum>,
+    depth_mask: Option<GLboolean>,
+    cull_face: Option<GLenum>,
+    front_face: Option<GLenum>,
+    polygon_mode: Option<GLenum>,
+    color_mask: Option<[GLboolean; 4]>,
+    stencil_func: Option<(GLenum, GLint, GLuint)>,
+    stencil_op: Option<(GLenum, GLenum, GLenum)>,
+    stencil_mask: Option<GLuint>,
+    viewport: Option<[GLint; 4]>,
+    frame: StateCounters
+}
+
+// stores `value` and returns true if it differs from what is cached
+fn update<T: PartialEq>(cached: &mut Option<T>, value: T) -> bool {
+    if cached.as_ref() == Some(&value) {
+        false
+    } else {
+        *cached = Some(value);
+        true
+    }
+}
+
+impl StateCache {
+    pub fn new() -> StateCache {
+        StateCache::default()
+    }
+
+    // forget everything, the next call of each kind is issued
+    pub fn reset(&mut self) {
+        let frame = self.frame;
+        *self = StateCache::default();
+        self.frame = frame;
+    }
+
+    pub fn counters(&self) -> StateCounters {
+        self.frame
+    }
+
+    // counters of the frame that just ended; the next one starts at zero
+    pub fn end_frame(&mut self) -> StateCounters {
+        std::mem::take(&mut self.frame)
+    }
+
+    fn count(&mut self, changed: bool) -> bool {
+        if changed {
+            self.frame.issued += 1;
+        } else {
+            self.frame.skipped += 1;
+        }
+        changed
+    }
+
+    pub fn use_program(&mut self, program: GLuint) {
+        let changed = update(&mut self.program, program);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::UseProgram(program)) }
+        }
+    }
+
+    pub fn bind_vertex_array(&mut self, vertex_array: GLuint) {
+        let changed = update(&mut self.vertex_array, vertex_array);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::BindVertexArray(vertex_array)) }
+        }
+    }
+
+    // takes gl::TEXTURE0 + n like glActiveTexture
+    pub fn active_texture(&mut self, unit: GLenum) {
+        let changed = update(&mut self.active_unit, unit - gl::TEXTURE0);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::ActiveTexture(unit)) }
+        }
+    }
+
+    // binds to the active unit
+    pub fn bind_texture(&mut self, target: GLenum, texture: GLuint) {
+        let unit = match self.active_unit {
+            Some(unit) => unit,
+            None => {
+                self.count(true);
+                unsafe { crate::gl_check!(gl::BindTexture(target, texture)) }
+                return;
+            }
+        };
+        let changed = self.textures.insert((unit, target), texture) != Some(texture);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::BindTexture(target, texture)) }
+        }
+    }
+
+    pub fn bind_texture_unit(&mut self, unit: u32, target: GLenum, texture: GLuint) {
+        self.active_texture(gl::TEXTURE0 + unit);
+        self.bind_texture(target, texture);
+    }
+
+    pub fn set_capability(&mut self, capability: GLenum, enabled: bool) {
+        let changed = self.capabilities.insert(capability, enabled) != Some(enabled);
+        if self.count(changed) {
+            unsafe {
+                if enabled {
+                    crate::gl_check!(gl::Enable(capability));
+                } else {
+                    crate::gl_check!(gl::Disable(capability));
+                }
+            }
+        }
+    }
+
+    pub fn enable(&mut self, capability: GLenum) {
+        self.set_capability(capability, true);
+    }
+
+    pub fn disable(&mut self, capability: GLenum) {
+        self.set_capability(capability, false);
+    }
+
+    pub fn blend_func(&mut self, source: GLenum, destination: GLenum) {
+        self.blend_func_separate(source, destination, source, destination);
+    }
+
+    pub fn blend_func_separate(&mut self, source: GLenum, destination: GLenum,
+                               source_alpha: GLenum, destination_alpha: GLenum) {
+        let changed = update(&mut self.blend_func, (source, destination, source_alpha, destination_alpha));
+        if self.count(changed) {
+            if (source, destination) == (source_alpha, destination_alpha) {
+                unsafe { crate::gl_check!(gl::BlendFunc(source, destination)) }
+            } else {
+                unsafe { crate::gl_check!(gl::BlendFuncSeparate(source, destination, source_alpha, destination_alpha)) }
+            }
+        }
+    }
+
+    pub fn blend_equation(&mut self, mode: GLenum) {
+        let changed = update(&mut self.blend_equation, mode);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::BlendEquation(mode)) }
+        }
+    }
+
+    pub fn depth_func(&mut self, func: GLenum) {
+        let changed = update(&mut self.depth_func, func);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::DepthFunc(func)) }
+        }
+    }
+
+    pub fn depth_mask(&mut self, write: GLboolean) {
+        let changed = update(&mut self.depth_mask, write);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::DepthMask(write)) }
+        }
+    }
+
+    pub fn cull_face(&mut self, mode: GLenum) {
+        let changed = update(&mut self.cull_face, mode);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::CullFace(mode)) }
+        }
+    }
+
+    pub fn front_face(&mut self, mode: GLenum) {
+        let changed = update(&mut self.front_face, mode);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::FrontFace(mode)) }
+        }
+    }
+
+    // for both faces, core profiles have nothing else
+    pub fn polygon_mode(&mut self, mode: GLenum) {
+        let changed = update(&mut self.polygon_mode, mode);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::PolygonMode(gl::FRONT_AND_BACK, mode)) }
+        }
+    }
+
+    pub fn color_mask(&mut self, red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
+        let changed = update(&mut self.color_mask, [red, green, blue, alpha]);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::ColorMask(red, green, blue, alpha)) }
+        }
+    }
+
+    pub fn stencil_func(&mut self, func: GLenum, reference: GLint, mask: GLuint) {
+        let changed = update(&mut self.stencil_func, (func, reference, mask));
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::StencilFunc(func, reference, mask)) }
+        }
+    }
+
+    pub fn stencil_op(&mut self, stencil_fail: GLenum, depth_fail: GLenum, pass: GLenum) {
+        let changed = update(&mut self.stencil_op, (stencil_fail, depth_fail, pass));
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::StencilOp(stencil_fail, depth_fail, pass)) }
+        }
+    }
+
+    pub fn stencil_mask(&mut self, mask: GLuint) {
+        let changed = update(&mut self.stencil_mask, mask);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::StencilMask(mask)) }
+        }
+    }
+
+    pub fn viewport(&mut self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        let changed = update(&mut self.viewport, [x, y, width, height]);
+        if self.count(changed) {
+            unsafe { crate::gl_check!(gl::Viewport(x, y, width, height)) }
+        }
+    }
+
+    // x, y, width, height. asks GL only when it isn't known
+    pub fn current_viewport(&mut self) -> [GLint; 4] {
+        if let Some(viewport) = self.viewport {
+            return viewport;
+        }
+        let mut viewport = [0; 4];
+        unsafe {
+            gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+        }
+        self.viewport = Some(viewport);
+        viewport
+    }
+
+    // deleting an object unbinds it, and its name may be handed out again
+    pub fn forget_texture(&mut self, texture: GLuint) {
+        for bound in self.textures.values_mut() {
+            if *bound == texture {
+                *bound = 0;
+            }
+        }
+    }
+
+    pub fn forget_vertex_array(&mut self, vertex_array: GLuint) {
+        if self.vertex_array == Some(vertex_array) {
+            self.vertex_array = Some(0);
+        }
+    }
+
+    pub fn forget_program(&mut self, program: GLuint) {
+        // a deleted program stays in use until another one is, so only
+        // its name has to stop matching
+        if self.program == Some(program) {
+            self.program = None;
+        }
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<StateCache> = RefCell::new(StateCache::new());
+}
+
+pub fn with<R, F: FnOnce(&mut StateCache) -> R>(f: F) -> R {
+    CACHE.with(|cache| f(&mut cache.borrow_mut()))
+}
+
+pub fn reset() { with(|c| c.reset()) }
+pub fn counters() -> StateCounters { with(|c| c.counters()) }
+pub fn end_frame() -> StateCounters { with(|c| c.end_frame()) }
+
+pub fn use_program(program: GLuint) { with(|c| c.use_program(program)) }
+pub fn bind_vertex_array(vertex_array: GLuint) { with(|c| c.bind_vertex_array(vertex_array)) }
+pub fn active_texture(unit: GLenum) { with(|c| c.active_texture(unit)) }
+pub fn bind_texture(target: GLenum, texture: GLuint) { with(|c| c.bind_texture(target, texture)) }
+pub fn bind_texture_unit(unit: u32, target: GLenum, texture: GLuint) { with(|c| c.bind_texture_unit(unit, target, texture)) }
+pub fn enable(capability: GLenum) { with(|c| c.enable(capability)) }
+pub fn disable(capability: GLenum) { with(|c| c.disable(capability)) }
+pub fn set_capability(capability: GLenum, enabled: bool) { with(|c| c.set_capability(capability, enabled)) }
+pub fn blend_func(source: GLenum, destination: GLenum) { with(|c| c.blend_func(source, destination)) }
+pub fn blend_func_separate(source: GLenum, destination: GLenum, source_alpha: GLenum, destination_alpha: GLenum) {
+    with(|c| c.blend_func_separate(source, destination, source_alpha, destination_alpha))
+}
+pub fn blend_equation(mode: GLenum) { with(|c| c.blend_equation(mode)) }
+pub fn depth_func(func: GLenum) { with(|c| c.depth_func(func)) }
+pub fn depth_mask(write: GLboolean) { with(|c| c.depth_mask(write)) }
+pub fn cull_face(mode: GLenum) { with(|c| c.cull_face(mode)) }
+pub fn front_face(mode: GLenum) { with(|c| c.front_face(mode)) }
+pub fn polygon_mode(mode: GLenum) { with(|c| c.polygon_mode(mode)) }
+pub fn color_mask(red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) { with(|c| c.color_mask(red, green, blue, alpha)) }
+pub fn stencil_func(func: GLenum, reference: GLint, mask: GLuint) { with(|c| c.stencil_func(func, reference, mask)) }
+pub fn stencil_op(stencil_fail: GLenum, depth_fail: GLenum, pass: GLenum) { with(|c| c.stencil_op(stencil_fail, depth_fail, pass)) }
+pub fn stencil_mask(mask: GLuint) { with(|c| c.stencil_mask(mask)) }
+pub fn viewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei) { with(|c| c.viewport(x, y, width, height)) }
+pub fn current_viewport() -> [GLint; 4] { with(|c| c.current_viewport()) }
+
+// delete through these so the cache doesn't match a reused name
+pub fn delete_textures(textures: &[GLuint]) {
+    with(|c| textures.iter().for_each(|t| c.forget_texture(*t)));
+    if !textures.is_empty() {
+        unsafe { gl::DeleteTextures(textures.len() as GLsizei, textures.as_ptr()) }
+    }
+}
+
+pub fn delete_texture(texture: GLuint) {
+    delete_textures(&[texture]);
+}
+
+pub fn delete_vertex_array(vertex_array: GLuint) {
+    with(|c| c.forget_vertex_array(vertex_array));
+    unsafe { gl::DeleteVertexArrays(1, &vertex_array) }
+}
+
+pub fn delete_program(program: GLuint) {
+    with(|c| c.forget_program(program));
+    unsafe { gl::DeleteProgram(program) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::mock::MockGl;
+
+    #[test]
+    fn repeated_calls_are_skipped() {
+        let gl = MockGl::install();
+        let mut cache = StateCache::new();
+        for _ in 0..3 {
+            cache.enable(gl::DEPTH_TEST);
+            cache.viewport(0, 0, 800, 600);
+            cache.depth_mask(gl::TRUE);
+        }
+        assert_eq!(cache.counters(), StateCounters { issued: 3, skipped: 6 });
+        assert_eq!(gl.state().state_calls, 3);
+        cache.disable(gl::DEPTH_TEST);
+        assert!(!gl.state().capabilities.contains(&gl::DEPTH_TEST));
+        assert_eq!(cache.end_frame().issued, 4);
+        assert_eq!(cache.counters().total(), 0);
+    }
+
+    #[test]
+    fn textures_are_tracked_per_unit() {
+        let gl = MockGl::install();
+        let mut names = [0; 2];
+        unsafe { gl::GenTextures(2, names.as_mut_ptr()) }
+        let mut cache = StateCache::new();
+        cache.bind_texture_unit(0, gl::TEXTURE_2D, names[0]);
+        cache.bind_texture_unit(1, gl::TEXTURE_2D, names[1]);
+        cache.bind_texture_unit(0, gl::TEXTURE_2D, names[0]);
+        cache.bind_texture_unit(1, gl::TEXTURE_CUBE_MAP, names[0]);
+        let state = gl.state();
+        assert_eq!(state.bound_texture(0, gl::TEXTURE_2D), names[0]);
+        assert_eq!(state.bound_texture(1, gl::TEXTURE_2D), names[1]);
+        assert_eq!(state.bound_texture(1, gl::TEXTURE_CUBE_MAP), names[0]);
+        // unit 0 again is an ActiveTexture, the bind itself is skipped
+        assert_eq!(cache.counters(), StateCounters { issued: 7, skipped: 1 });
+    }
+
+    #[test]
+    fn reset_and_deletion_forget_state() {
+        let gl = MockGl::install();
+        let mut cache = StateCache::new();
+        cache.use_program(0);
+        cache.reset();
+        cache.use_program(0);
+        assert_eq!(cache.counters().issued, 2);
+
+        let mut texture = 0;
+        unsafe { gl::GenTextures(1, &mut texture) }
+        cache.bind_texture_unit(2, gl::TEXTURE_2D, texture);
+        cache.forget_texture(texture);
+        assert_eq!(cache.textures[&(2, gl::TEXTURE_2D)], 0);
+        // a new texture may get the same name and has to be bound again
+        let issued = cache.counters().issued;
+        cache.bind_texture(gl::TEXTURE_2D, texture);
+        assert_eq!(cache.counters().issued, issued + 1);
+        assert!(gl.state().errors.is_empty());
+    }
+
+    #[test]
+    fn viewport_is_queried_once() {
+        let _gl = MockGl::install();
+        unsafe { gl::Viewport(1, 2, 3, 4) }
+        let mut cache = StateCache::new();
+        assert_eq!(cache.current_viewport(), [1, 2, 3, 4]);
+        cache.viewport(1, 2, 3, 4);
+        assert_eq!(cache.counters(), StateCounters { issued: 0, skipped: 1 });
+    }
+}
@@ -1,17 +1,37 @@
+use crate::render_gl::debug;
+use crate::render_gl::state;
 use gl::types::*;
 
 pub enum TexType { RGB, RGBA }
+#[derive(Debug)]
 pub struct Texture {
     tex_id: GLuint,
-    name: String
+    name: String,
+    owned: bool
 }
 impl Texture {
+    // wraps a texture someone else deletes
     pub fn new(tex_id: GLuint, name: &String) -> Texture {
-        Texture {tex_id: tex_id, name: name.clone()}
+        Texture {tex_id: tex_id, name: name.clone(), owned: false}
+    }
+
+    // takes over the texture, it is deleted with this
+    pub fn owned(tex_id: GLuint, name: &String) -> Texture {
+        Texture {tex_id: tex_id, name: name.clone(), owned: true}
     }
     pub fn get_id(&self) -> GLuint { self.tex_id}
 
     pub fn get_name(&self) -> String { self.name.clone()}
 
+    pub fn set_label(&self, label: &str) { debug::label_texture(self.tex_id, label) }
+
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        if self.owned {
+            state::delete_texture(self.tex_id);
+        }
+    }
 }
 
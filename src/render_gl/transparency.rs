@@ -0,0 +1,319 @@
+use crate::glm::*;
+use crate::render_gl::debug::DebugGroup;
+use crate::render_gl::framebuffer::{ColorFormat, DepthFormat, Framebuffer, FramebufferDesc};
+use crate::render_gl::material::BlendMode;
+use crate::render_gl::mesh::Mesh;
+use crate::render_gl::pipeline::{BlendFactor, BlendOp, BlendState, CullMode, PipelineState};
+use crate::render_gl::state;
+use crate::render_gl::{shader_lib, Program, Shader};
+use gl::types::*;
+use std::cmp::Ordering;
+
+// transparent objects are drawn after everything opaque, against its depth.
+// how depends on their BlendMode:
+//
+// Alpha and Additive objects are sorted back to front by the distance of
+// their centre from the camera and blended straight into the target. that
+// is exact for separate objects but goes wrong where they intersect or
+// one surrounds another.
+//
+// WeightedBlended objects are accumulated into two extra targets in any
+// order and composited over the target in one full screen pass. the result
+// is an approximation (close, opaque surfaces get more weight), but it
+// doesn't depend on draw order at all
+//
+// shaders for transparent objects include "oit.glsl" and write their colour
+// with writeTransparent(), which works for both
+
+// the accumulation targets: premultiplied colour times weight in rgb and
+// revealage in alpha, and the sum of alpha times weight. weights go up to
+// 3000 and lit colours past 1, so half floats overflow after a few layers
+pub const ACCUMULATION_FORMAT: ColorFormat = ColorFormat::Rgba32F;
+pub const ACCUMULATED_ALPHA_FORMAT: ColorFormat = ColorFormat::R32F;
+
+// rgb and accumulated alpha add up, alpha multiplies to 1 - coverage.
+// GL 3.3 has no per target blending, so both targets share these factors
+pub fn accumulation_pipeline() -> PipelineState {
+    PipelineState::opaque()
+        .blend(Some(BlendState::new(BlendOp::Add, BlendFactor::One, BlendFactor::One)
+            .separate_alpha(BlendFactor::Zero, BlendFactor::OneMinusSrcAlpha)))
+        .depth_write(false)
+        .cull(CullMode::None)
+}
+
+// the average colour is laid over the target with alpha = revealage;
+// the target's own alpha is kept
+pub fn composite_pipeline() -> PipelineState {
+    PipelineState::fullscreen()
+        .blend(Some(BlendState::new(BlendOp::Add, BlendFactor::OneMinusSrcAlpha, BlendFactor::SrcAlpha)
+            .separate_alpha(BlendFactor::Zero, BlendFactor::One)))
+}
+
+// tells oit.glsl which pass `program` is drawn in
+pub fn set_blend_mode(program: &Program, mode: BlendMode) {
+    program.set_uniform_1i("weightedBlended", (mode == BlendMode::WeightedBlended) as i32);
+}
+
+struct QueueItem<T> {
+    center: Vec3,
+    blend: BlendMode,
+    item: T
+}
+
+// collects the transparent objects of a frame. `T` is whatever the draw
+// callback needs to draw one of them, e.g a material instance and a model
+// matrix
+pub struct TransparentQueue<T> {
+    items: Vec<QueueItem<T>>
+}
+
+impl<T> Default for TransparentQueue<T> {
+    fn default() -> TransparentQueue<T> {
+        TransparentQueue::new()
+    }
+}
+
+impl<T> TransparentQueue<T> {
+    pub fn new() -> TransparentQueue<T> {
+        TransparentQueue { items: Vec::new() }
+    }
+
+    // `center` in world space is what the sorting goes by. opaque items are
+    // accepted but drawn like alpha blended ones
+    pub fn push(&mut self, center: &Vec3, blend: BlendMode, item: T) {
+        self.items.push(QueueItem { center: *center, blend: blend, item: item });
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    // the items of the order independent pass, in submission order
+    pub fn weighted(&self) -> Vec<&T> {
+        self.items.iter()
+            .filter(|i| i.blend == BlendMode::WeightedBlended)
+            .map(|i| &i.item)
+            .collect()
+    }
+
+    // the items of the sorted pass, farthest from `eye` first, with the
+    // mode to draw them in. without an order independent pass the
+    // WeightedBlended items fall back to alpha blending and are sorted too.
+    // items at the same distance keep their submission order
+    pub fn back_to_front(&self, eye: &Vec3, order_independent: bool) -> Vec<(&T, BlendMode)> {
+        let mut sorted: Vec<(f32, &T, BlendMode)> = self.items.iter()
+            .filter(|i| !(order_independent && i.blend == BlendMode::WeightedBlended))
+            .map(|i| {
+                let blend = match i.blend {
+                    BlendMode::Opaque | BlendMode::WeightedBlended => BlendMode::Alpha,
+                    other => other
+                };
+                (distance2(&i.center, eye), &i.item, blend)
+            })
+            .collect();
+        sorted.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        sorted.into_iter().map(|(_, item, blend)| (item, blend)).collect()
+    }
+
+    // draws the queue into `target` (the default framebuffer when None),
+    // which must hold the opaque scene and its depth. `draw` gets each item
+    // with the mode it is drawn in and the pipeline already set. it has to
+    // use its program every time, the composite pass brings its own, and
+    // pass the mode on to set_blend_mode. leaves the opaque pipeline set
+    pub fn draw(&self, eye: &Vec3, oit: Option<&WeightedBlended>, target: Option<&Framebuffer>,
+                draw: &mut dyn FnMut(&T, BlendMode)) {
+        let weighted = self.weighted();
+        if let (Some(oit), false) = (oit, weighted.is_empty()) {
+            let _group = DebugGroup::new("weighted blended transparency");
+            oit.begin(target);
+            for item in weighted {
+                draw(item, BlendMode::WeightedBlended);
+            }
+            oit.composite(target);
+        }
+
+        let _group = DebugGroup::new("sorted transparency");
+        for (item, blend) in self.back_to_front(eye, oit.is_some()) {
+            blend.pipeline().apply();
+            draw(item, blend);
+        }
+        PipelineState::opaque().apply();
+    }
+}
+
+// the targets and composite pass of weighted blended order independent
+// transparency. the depth of the scene is copied in before accumulating,
+// so it has to be Depth24Stencil8 like ours (the default framebuffer
+// usually is)
+pub struct WeightedBlended {
+    targets: Framebuffer,
+    program: Program,
+    quad: Mesh
+}
+
+impl WeightedBlended {
+    pub fn new(width: u32, height: u32) -> Result<WeightedBlended, String> {
+        let mut targets = Framebuffer::new(FramebufferDesc::new(width, height)
+            .color(ACCUMULATION_FORMAT)
+            .color(ACCUMULATED_ALPHA_FORMAT)
+            .depth_renderbuffer(DepthFormat::Depth24Stencil8))?;
+        targets.set_label("transparency accumulation");
+        let program = Program::from_shaders(&[
+            Shader::from_vert_source(&shader_lib::preprocess(include_str!("shaders/fullscreen.vert"))?)?,
+            Shader::from_frag_source(&shader_lib::preprocess(include_str!("shaders/oit_composite.frag"))?)?
+        ])?;
+        program.set_label("transparency composite");
+        Ok(WeightedBlended { targets: targets, program: program, quad: Mesh::quad() })
+    }
+
+    pub fn targets(&self) -> &Framebuffer {
+        &self.targets
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.targets.resize(width, height)?;
+        Ok(())
+    }
+
+    // copies the depth of `scene` (the default framebuffer when None),
+    // clears the accumulation targets and binds them with
+    // accumulation_pipeline() set
+    pub fn begin(&self, scene: Option<&Framebuffer>) {
+        match scene {
+            Some(framebuffer) => framebuffer.blit(0, Some(&self.targets), gl::DEPTH_BUFFER_BIT, gl::NEAREST),
+            None => unsafe {
+                let (width, height) = (self.targets.width() as GLint, self.targets.height() as GLint);
+                gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+                gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.targets.id());
+                gl::BlitFramebuffer(0, 0, width, height, 0, 0, width, height, gl::DEPTH_BUFFER_BIT, gl::NEAREST);
+            }
+        }
+        self.targets.begin();
+        PipelineState::opaque().apply();
+        let nothing: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+        let zero: [f32; 4] = [0.0; 4];
+        unsafe {
+            gl::ClearBufferfv(gl::COLOR, 0, nothing.as_ptr());
+            gl::ClearBufferfv(gl::COLOR, 1, zero.as_ptr());
+        }
+        accumulation_pipeline().apply();
+    }
+
+    // blends the accumulated surfaces over `scene` and leaves it bound
+    pub fn composite(&self, scene: Option<&Framebuffer>) {
+        match scene {
+            Some(framebuffer) => framebuffer.begin(),
+            None => {
+                Framebuffer::bind_default();
+                state::viewport(0, 0, self.targets.width() as GLsizei, self.targets.height() as GLsizei);
+            }
+        }
+        composite_pipeline().apply();
+        state::bind_texture_unit(0, gl::TEXTURE_2D, self.targets.color_texture(0));
+        state::bind_texture_unit(1, gl::TEXTURE_2D, self.targets.color_texture(1));
+        state::active_texture(gl::TEXTURE0);
+        let p = &self.program;
+        p.set_used();
+        p.set_uniform_1i("accumulation", 0);
+        p.set_uniform_1i("accumulatedAlpha", 1);
+        self.quad.draw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_gl::headless::{self, HeadlessContext};
+    use std::ffi::CString;
+
+    fn queue() -> TransparentQueue<&'static str> {
+        let mut queue = TransparentQueue::new();
+        queue.push(&make_vec3(&[0.0, 0.0, -1.0]), BlendMode::Alpha, "near");
+        queue.push(&make_vec3(&[0.0, 0.0, -9.0]), BlendMode::Additive, "far");
+        queue.push(&make_vec3(&[0.0, 0.0, -5.0]), BlendMode::WeightedBlended, "smoke");
+        queue.push(&make_vec3(&[0.0, 5.0, 0.0]), BlendMode::Alpha, "above");
+        queue
+    }
+
+    #[test]
+    fn sorted_back_to_front() {
+        let queue = queue();
+        let eye = make_vec3(&[0.0, 0.0, 0.0]);
+        let names: Vec<&str> = queue.back_to_front(&eye, true).iter().map(|(n, _)| **n).collect();
+        assert_eq!(names, vec!["far", "above", "near"]);
+        assert_eq!(queue.back_to_front(&eye, true)[0].1, BlendMode::Additive);
+        assert_eq!(queue.weighted(), vec![&"smoke"]);
+
+        // moving the camera changes the order
+        let names: Vec<&str> = queue.back_to_front(&make_vec3(&[0.0, 0.0, -10.0]), true).iter().map(|(n, _)| **n).collect();
+        assert_eq!(names, vec!["above", "near", "far"]);
+    }
+
+    #[test]
+    fn weighted_items_fall_back_to_sorting() {
+        let queue = queue();
+        let sorted = queue.back_to_front(&make_vec3(&[0.0, 0.0, 0.0]), false);
+        assert_eq!(sorted.len(), 4);
+        assert_eq!(sorted[1], (&"smoke", BlendMode::Alpha));
+    }
+
+    // two overlapping layers give the same picture in either order.
+    // skipped when neither EGL nor OSMesa can give us a context
+    #[test]
+    fn weighted_blending_is_order_independent() {
+        let _gl = crate::render_gl::mock::lock_gl();
+        let _context = match HeadlessContext::for_test("weighted blending") {
+            Some(context) => context,
+            None => return
+        };
+        let (width, height) = (8, 8);
+        let target = headless::offscreen_target(width, height).unwrap();
+        let oit = WeightedBlended::new(width, height).unwrap();
+        let program = Program::from_shaders(&[
+            Shader::from_vert_source(&CString::new(include_str!("shaders/fullscreen.vert")).unwrap()).unwrap(),
+            Shader::from_frag_source(&shader_lib::preprocess(
+                "#version 330 core\n#include \"oit.glsl\"\nuniform vec4 color;\nvoid main() { writeTransparent(color); }\n"
+            ).unwrap()).unwrap()
+        ]).unwrap();
+        let quad = Mesh::quad();
+        let red = make_vec4(&[1.0, 0.0, 0.0, 0.5]);
+        let blue = make_vec4(&[0.0, 0.0, 1.0, 0.5]);
+
+        let render = |layers: &[&Vec4]| {
+            target.begin();
+            PipelineState::opaque().apply();
+            unsafe {
+                gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+            }
+            let mut queue = TransparentQueue::new();
+            for color in layers {
+                queue.push(&make_vec3(&[0.0, 0.0, 0.0]), BlendMode::WeightedBlended, *color);
+            }
+            queue.draw(&make_vec3(&[0.0, 0.0, 1.0]), Some(&oit), Some(&target), &mut |color, mode| {
+                program.set_used();
+                set_blend_mode(&program, mode);
+                program.set_uniform_vec4("color", color);
+                quad.draw();
+            });
+            unsafe {
+                gl::Finish();
+            }
+            headless::read_pixels(&target, 0)[0..4].to_vec()
+        };
+        let red_first = render(&[&red, &blue]);
+        let blue_first = render(&[&blue, &red]);
+        assert_eq!(red_first, blue_first);
+        // the average of both colours over a quarter of the black background
+        assert!((red_first[0] as i32 - 96).abs() <= 2 && (red_first[2] as i32 - 96).abs() <= 2, "{:?}", red_first);
+        assert_eq!(red_first[1], 0);
+    }
+}
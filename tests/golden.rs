@@ -0,0 +1,47 @@
+extern crate game;
+
+use game::demos::golden::{self, Tolerance};
+use game::render_gl::headless::HeadlessContext;
+use std::path::Path;
+use std::sync::Mutex;
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+
+// GL function pointers are process wide, so scenes render one at a time
+static GL: Mutex<()> = Mutex::new(());
+
+// renders `name` and compares it with tests/golden/<name>.png, see
+// HeadlessContext::for_test for when it is skipped. GAME_BLESS=1 updates
+// the reference
+fn golden(name: &str, tolerance: Tolerance) {
+    let _lock = GL.lock().unwrap_or_else(|e| e.into_inner());
+    let _context = match HeadlessContext::for_test(&format!("golden image {}", name)) {
+        Some(context) => context,
+        None => return
+    };
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let image = golden::render(name, WIDTH, HEIGHT).unwrap();
+    golden::check(name, &image, &root.join("tests/golden"), &root.join("target/golden"), &tolerance).unwrap();
+}
+
+#[test]
+fn cube() {
+    golden("cube", Tolerance::default());
+}
+
+#[test]
+fn sphere() {
+    golden("sphere", Tolerance::default());
+}
+
+#[test]
+fn materials() {
+    golden("materials", Tolerance::default());
+}
+
+// shadow map filtering differs the most between drivers
+#[test]
+fn lightcaster() {
+    golden("lightcaster", Tolerance { channel_threshold: 12, max_differing: 1.0, min_ssim: Some(0.95) });
+}